@@ -0,0 +1,310 @@
+//! Call tree module.
+//!
+//! This module merges aggregated callchain samples into a caller-callee prefix
+//! tree, root frames first, and prints it indented with inclusive percentages
+//! like `perf report --children`.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Share of all samples under which call tree nodes are collapsed by default.
+pub const DEFAULT_MIN_PERCENT: f64 = 1.0;
+
+/// A frame in a call tree and everything it called.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallTreeNode {
+    pub name: String,
+    /// Samples whose stack passes through this node, callees included
+    pub samples: u64,
+    /// Callees, most samples first, ties broken by name
+    pub children: Vec<CallTreeNode>,
+}
+
+impl CallTreeNode {
+    /// Child named `name`, added with no samples if missing.
+    fn child(&mut self, name: String) -> &mut CallTreeNode {
+        let index = match self.children.iter().position(|child| child.name == name) {
+            Some(index) => index,
+            None => {
+                self.children.push(CallTreeNode {
+                    name,
+                    samples: 0,
+                    children: Vec::new(),
+                });
+                self.children.len() - 1
+            }
+        };
+        &mut self.children[index]
+    }
+
+    /// Sort the children of this node and of its whole subtree.
+    fn sort(&mut self) {
+        self.children
+            .sort_by(|a, b| b.samples.cmp(&a.samples).then_with(|| a.name.cmp(&b.name)));
+        for child in &mut self.children {
+            child.sort();
+        }
+    }
+}
+
+/// Samples arranged as a prefix tree of their callchains.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallTree {
+    /// Every sample, including samples with an empty callchain
+    pub total_samples: u64,
+    /// Outermost frames (e.g. `_start`), most samples first
+    pub roots: Vec<CallTreeNode>,
+}
+
+impl CallTree {
+    /// Build the call tree of aggregated stacks (frames ordered leaf first).
+    ///
+    /// Frames are named using `names`, or as hex addresses when missing.
+    /// Consecutive frames sharing a name are kept: recursion shows as nesting.
+    pub fn from_stacks(stacks: &HashMap<Vec<u64>, u64>, names: &HashMap<u64, String>) -> Self {
+        // A sentinel root keeps the insertion code the same at every depth
+        let mut root = CallTreeNode {
+            name: String::new(),
+            samples: 0,
+            children: Vec::new(),
+        };
+        for (frames, &count) in stacks {
+            root.samples += count;
+            let mut node = &mut root;
+            for ip in frames.iter().rev() {
+                let name = names
+                    .get(ip)
+                    .cloned()
+                    .unwrap_or_else(|| format!("{:#x}", ip));
+                node = node.child(name);
+                node.samples += count;
+            }
+        }
+        root.sort();
+
+        CallTree {
+            total_samples: root.samples,
+            roots: root.children,
+        }
+    }
+
+    /// Share of all samples in percent.
+    fn percent(&self, samples: u64) -> f64 {
+        if self.total_samples == 0 {
+            0.0
+        } else {
+            samples as f64 * 100.0 / self.total_samples as f64
+        }
+    }
+}
+
+/// Render a call tree in Brendan Gregg's folded format, pruning small frames.
+///
+/// Frames under `min_percent` of all samples are dropped with their callees;
+/// the dropped callees of a frame are replaced by one `[pruned N frames]` frame
+/// holding their samples, so every frame keeps its width in a flamegraph.
+/// Samples without a callchain keep their empty-stack line. Lines are sorted.
+pub fn format_pruned_folded(tree: &CallTree, min_percent: f64) -> String {
+    let mut lines = Vec::new();
+    let unstacked = tree.total_samples - tree.roots.iter().map(|root| root.samples).sum::<u64>();
+    if unstacked > 0 {
+        lines.push(format!(" {}\n", unstacked));
+    }
+    fold_nodes(tree, &tree.roots, "", min_percent, &mut lines);
+    lines.sort();
+    lines.concat()
+}
+
+/// Add the folded lines of `nodes`, called from the frames in `prefix`.
+fn fold_nodes(
+    tree: &CallTree,
+    nodes: &[CallTreeNode],
+    prefix: &str,
+    min_percent: f64,
+    lines: &mut Vec<String>,
+) {
+    let mut pruned = 0;
+    let mut pruned_samples = 0;
+    for node in nodes {
+        if tree.percent(node.samples) < min_percent {
+            pruned += 1;
+            pruned_samples += node.samples;
+            continue;
+        }
+        let path = format!("{}{}", prefix, node.name);
+        let callee_samples: u64 = node.children.iter().map(|child| child.samples).sum();
+        if node.samples > callee_samples {
+            lines.push(format!("{} {}\n", path, node.samples - callee_samples));
+        }
+        fold_nodes(
+            tree,
+            &node.children,
+            &format!("{};", path),
+            min_percent,
+            lines,
+        );
+    }
+    if pruned > 0 {
+        lines.push(format!(
+            "{}[pruned {} frames] {}\n",
+            prefix, pruned, pruned_samples
+        ));
+    }
+}
+
+/// Print a call tree indented by depth with inclusive percentages.
+///
+/// Nodes under `min_percent` of all samples are not expanded; the callees of a
+/// node that are hidden this way are summed up on one line.
+pub fn print_call_tree(tree: &CallTree, min_percent: f64, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "Call Tree (callers above callees):")?;
+    writeln!(out, "{:-<50}", "")?;
+    writeln!(out, "  {:>8}  {}", "Total%", "Function")?;
+    print_nodes(tree, &tree.roots, 0, min_percent, out)
+}
+
+/// Print `nodes` at `depth` and, recursively, their callees.
+fn print_nodes(
+    tree: &CallTree,
+    nodes: &[CallTreeNode],
+    depth: usize,
+    min_percent: f64,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let indent = "  ".repeat(depth);
+    let mut hidden = 0;
+    let mut hidden_samples = 0;
+    for node in nodes {
+        let percent = tree.percent(node.samples);
+        if percent < min_percent {
+            hidden += 1;
+            hidden_samples += node.samples;
+            continue;
+        }
+        writeln!(out, "  {:>7.2}%  {}{}", percent, indent, node.name)?;
+        print_nodes(tree, &node.children, depth + 1, min_percent, out)?;
+    }
+    if hidden > 0 {
+        writeln!(
+            out,
+            "  {:>7.2}%  {}[{} more below {}%]",
+            tree.percent(hidden_samples),
+            indent,
+            hidden,
+            min_percent
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> HashMap<u64, String> {
+        [(1, "main"), (2, "parse"), (3, "compute"), (4, "alloc")]
+            .into_iter()
+            .map(|(ip, name)| (ip, name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_call_tree_counts() {
+        // Leaf first: main -> parse -> alloc, main -> compute -> alloc, ...
+        let stacks: HashMap<Vec<u64>, u64> = [
+            (vec![4, 2, 1], 3),
+            (vec![2, 1], 2),
+            (vec![4, 3, 1], 4),
+            (vec![3, 1], 1),
+            (vec![0x99], 1),
+            (vec![], 1),
+        ]
+        .into_iter()
+        .collect();
+        let tree = CallTree::from_stacks(&stacks, &names());
+
+        assert_eq!(tree.total_samples, 12);
+        assert_eq!(tree.roots.len(), 2);
+        let main = &tree.roots[0];
+        assert_eq!((main.name.as_str(), main.samples), ("main", 10));
+
+        let callees: Vec<(&str, u64)> = main
+            .children
+            .iter()
+            .map(|child| (child.name.as_str(), child.samples))
+            .collect();
+        assert_eq!(callees, vec![("compute", 5), ("parse", 5)]);
+        assert_eq!(main.children[0].children[0].name, "alloc");
+        assert_eq!(main.children[0].children[0].samples, 4);
+        assert_eq!(main.children[1].children[0].samples, 3);
+
+        // Unnamed frames fall back to their address
+        assert_eq!(
+            (tree.roots[1].name.as_str(), tree.roots[1].samples),
+            ("0x99", 1)
+        );
+    }
+
+    #[test]
+    fn test_call_tree_recursion() {
+        let stacks: HashMap<Vec<u64>, u64> =
+            [(vec![3, 3, 1], 2), (vec![3, 1], 1)].into_iter().collect();
+        let tree = CallTree::from_stacks(&stacks, &names());
+
+        let compute = &tree.roots[0].children[0];
+        assert_eq!(compute.samples, 3);
+        assert_eq!(compute.children[0].name, "compute");
+        assert_eq!(compute.children[0].samples, 2);
+    }
+
+    #[test]
+    fn test_print_call_tree() {
+        let stacks: HashMap<Vec<u64>, u64> = [(vec![3, 1], 90), (vec![2, 1], 6), (vec![4, 1], 4)]
+            .into_iter()
+            .collect();
+        let tree = CallTree::from_stacks(&stacks, &names());
+
+        let mut out = Vec::new();
+        print_call_tree(&tree, 5.0, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("   100.00%  main\n"));
+        assert!(report.contains("    90.00%    compute\n"));
+        assert!(report.contains("     6.00%    parse\n"));
+        assert!(report.contains("     4.00%    [1 more below 5%]\n"));
+        assert!(!report.contains("alloc"));
+    }
+
+    #[test]
+    fn test_format_pruned_folded() {
+        let stacks: HashMap<Vec<u64>, u64> = [
+            (vec![3, 1], 80),
+            (vec![4, 3, 1], 5),
+            (vec![2, 1], 6),
+            (vec![4, 1], 4),
+            (vec![1], 2),
+            (vec![0x99], 1),
+            (vec![0x98], 1),
+            (vec![], 1),
+        ]
+        .into_iter()
+        .collect();
+        let tree = CallTree::from_stacks(&stacks, &names());
+
+        assert_eq!(
+            format_pruned_folded(&tree, 5.0),
+            " 1\n\
+             [pruned 2 frames] 2\n\
+             main 2\n\
+             main;[pruned 1 frames] 4\n\
+             main;compute 80\n\
+             main;compute;alloc 5\n\
+             main;parse 6\n"
+        );
+        // Without a threshold the output matches the unpruned stacks
+        let unpruned = format_pruned_folded(&tree, 0.0);
+        assert_eq!(unpruned.lines().count(), stacks.len());
+        assert!(unpruned.contains("main;parse 6\n"));
+        assert!(unpruned.contains("0x99 1\n"));
+    }
+}
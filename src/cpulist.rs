@@ -0,0 +1,132 @@
+//! CPU list parsing module.
+//!
+//! This module parses and formats the kernel's cpulist syntax (e.g. `0-3,8,12-15`),
+//! as used by `/sys/devices/system/cpu/online` and the `--cpus` option.
+
+use anyhow::{Context, Result};
+
+/// Path listing the CPUs currently online.
+const ONLINE_CPUS_PATH: &str = "/sys/devices/system/cpu/online";
+
+/// Parse a cpulist string such as `0-3,8,12-15` into a sorted, deduplicated list.
+///
+/// # Arguments
+///
+/// * `list` - Comma-separated CPU ids and inclusive `start-end` ranges
+///
+/// # Returns
+///
+/// Returns the CPU ids in ascending order, or an error for malformed input.
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for part in list.trim().split(',') {
+        let part = part.trim();
+        if part.is_empty() {
+            anyhow::bail!("Empty entry in CPU list: {:?}", list);
+        }
+
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid CPU range: {:?}", part))?;
+                let end: usize = end
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid CPU range: {:?}", part))?;
+                if start > end {
+                    anyhow::bail!("Invalid CPU range {:?}: start is after end", part);
+                }
+                cpus.extend(start..=end);
+            }
+            None => {
+                let cpu = part
+                    .parse()
+                    .with_context(|| format!("Invalid CPU id: {:?}", part))?;
+                cpus.push(cpu);
+            }
+        }
+    }
+
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// Format CPU ids back into compact cpulist syntax, collapsing consecutive runs.
+pub fn format_cpu_list(cpus: &[usize]) -> String {
+    let mut parts = Vec::new();
+    let mut iter = cpus.iter().copied().peekable();
+
+    while let Some(start) = iter.next() {
+        let mut end = start;
+        while iter.peek() == Some(&(end + 1)) {
+            end = iter.next().unwrap_or(end);
+        }
+        if start == end {
+            parts.push(start.to_string());
+        } else {
+            parts.push(format!("{}-{}", start, end));
+        }
+    }
+
+    parts.join(",")
+}
+
+/// Read the list of online CPUs from sysfs.
+pub fn online_cpus() -> Result<Vec<usize>> {
+    let online = std::fs::read_to_string(ONLINE_CPUS_PATH)
+        .with_context(|| format!("Failed to read {}", ONLINE_CPUS_PATH))?;
+    parse_cpu_list(&online)
+}
+
+/// Ensure every requested CPU is currently online.
+pub fn validate_online(cpus: &[usize]) -> Result<()> {
+    let online = online_cpus()?;
+    for cpu in cpus {
+        if online.binary_search(cpu).is_err() {
+            anyhow::bail!(
+                "CPU {} is not online (online CPUs: {})",
+                cpu,
+                format_cpu_list(&online)
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,12-13").unwrap(),
+            vec![0, 1, 2, 3, 8, 12, 13]
+        );
+        assert_eq!(parse_cpu_list("0-3").unwrap(), vec![0, 1, 2, 3]);
+        assert_eq!(parse_cpu_list("1,2,4").unwrap(), vec![1, 2, 4]);
+        assert_eq!(parse_cpu_list("5\n").unwrap(), vec![5]);
+        assert_eq!(parse_cpu_list("2,1,1-2").unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_parse_cpu_list_errors() {
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("1,,2").is_err());
+        assert!(parse_cpu_list("a-b").is_err());
+        assert!(parse_cpu_list("1-").is_err());
+        assert!(parse_cpu_list("-1").is_err());
+    }
+
+    #[test]
+    fn test_format_cpu_list() {
+        assert_eq!(format_cpu_list(&[0, 1, 2, 3, 8, 12, 13]), "0-3,8,12-13");
+        assert_eq!(format_cpu_list(&[5]), "5");
+        assert_eq!(format_cpu_list(&[]), "");
+    }
+}
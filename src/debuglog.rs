@@ -0,0 +1,74 @@
+//! Debug log module.
+//!
+//! This module writes a terse line per processed record to a size-bounded file
+//! that wraps around like a ring buffer, so the last records before a crash
+//! can be inspected afterwards.
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Default upper bound on the debug log size.
+pub const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+
+/// A bounded, wrapping log of processed records.
+///
+/// Each line starts with a sequence number so the order can be recovered after
+/// the file has wrapped.
+pub struct DebugLog {
+    file: File,
+    max_bytes: u64,
+    position: u64,
+    sequence: u64,
+}
+
+impl DebugLog {
+    /// Create (or truncate) the log file at `path`.
+    pub fn create(path: &Path, max_bytes: u64) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create debug log: {}", path.display()))?;
+        Ok(DebugLog {
+            file,
+            max_bytes,
+            position: 0,
+            sequence: 0,
+        })
+    }
+
+    /// Append one record line, wrapping to the start of the file when full.
+    pub fn record(&mut self, kind: &str, size: usize, time_ns: Option<u64>) -> io::Result<()> {
+        let time = time_ns.map_or_else(|| "-".to_string(), |time| time.to_string());
+        let line = format!("{} {} {} {}\n", self.sequence, kind, size, time);
+        self.sequence += 1;
+
+        if self.position + line.len() as u64 > self.max_bytes {
+            self.file.seek(SeekFrom::Start(0))?;
+            self.position = 0;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.position += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_log_wraps() {
+        let path = std::env::temp_dir().join(format!("profiler-debuglog-{}", std::process::id()));
+        let mut log = DebugLog::create(&path, 64).unwrap();
+        for time in 0..20 {
+            log.record("Sample", 128, Some(time)).unwrap();
+        }
+        log.record("Mmap", 96, None).unwrap();
+        drop(log);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(contents.len() <= 64);
+        assert!(contents.contains("20 Mmap 96 -\n"));
+    }
+}
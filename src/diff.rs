@@ -0,0 +1,360 @@
+//! Profiling run comparison module.
+//!
+//! This module compares two counter-mode results saved with `perf --format json`,
+//! such as runs before and after a change, counter by counter and on the
+//! derived metrics, as a table or as JSON for CI.
+
+use crate::perf::{ProfilingResult, HARDWARE_EVENTS, SOFTWARE_EVENTS};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// One row of a JSON diff: a counter or metric before and after, and its change.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricDelta {
+    pub metric: &'static str,
+    pub before: Option<f64>,
+    pub after: Option<f64>,
+    pub abs_delta: Option<f64>,
+    /// Change relative to `before` in percent; null when `before` is zero
+    pub pct_delta: Option<f64>,
+    pub regression: bool,
+}
+
+impl From<CounterDiff> for MetricDelta {
+    fn from(counter: CounterDiff) -> Self {
+        MetricDelta {
+            metric: counter.name,
+            before: counter.base.map(|count| count as f64),
+            after: counter.new.map(|count| count as f64),
+            abs_delta: counter.delta().map(|delta| delta as f64),
+            pct_delta: counter.percent_change(),
+            regression: counter.is_regression(),
+        }
+    }
+}
+
+impl From<MetricDiff> for MetricDelta {
+    fn from(metric: MetricDiff) -> Self {
+        MetricDelta {
+            metric: metric.name,
+            before: metric.base,
+            after: metric.new,
+            abs_delta: metric.delta(),
+            pct_delta: metric.percent_change(),
+            regression: metric.is_regression(),
+        }
+    }
+}
+
+/// Change of one counter between two runs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(into = "MetricDelta")]
+pub struct CounterDiff {
+    pub name: &'static str,
+    /// Count in the base run, if the event was counted there
+    pub base: Option<u64>,
+    /// Count in the new run, if the event was counted there
+    pub new: Option<u64>,
+}
+
+impl CounterDiff {
+    /// Absolute change, if both runs counted the event.
+    pub fn delta(&self) -> Option<i128> {
+        Some(self.new? as i128 - self.base? as i128)
+    }
+
+    /// Change relative to the base count in percent, if both runs counted the
+    /// event and the base count is not zero.
+    pub fn percent_change(&self) -> Option<f64> {
+        let base = self.base.filter(|&base| base != 0)?;
+        Some(self.delta()? as f64 * 100.0 / base as f64)
+    }
+
+    /// Every counted event is a cost (cycles, misses, faults...), so more is worse.
+    pub fn is_regression(&self) -> bool {
+        self.delta().is_some_and(|delta| delta > 0)
+    }
+}
+
+/// Change of one derived metric between two runs.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(into = "MetricDelta")]
+pub struct MetricDiff {
+    pub name: &'static str,
+    pub base: Option<f64>,
+    pub new: Option<f64>,
+    /// Whether an increase is an improvement (IPC) rather than a regression
+    pub higher_is_better: bool,
+}
+
+impl MetricDiff {
+    /// Absolute change, if both runs have the metric.
+    pub fn delta(&self) -> Option<f64> {
+        Some(self.new? - self.base?)
+    }
+
+    /// Change relative to the base value in percent, if both runs have the
+    /// metric and the base value is not zero.
+    pub fn percent_change(&self) -> Option<f64> {
+        let base = self.base.filter(|&base| base != 0.0)?;
+        Some(self.delta()? * 100.0 / base)
+    }
+
+    /// Whether the metric moved in the wrong direction.
+    pub fn is_regression(&self) -> bool {
+        self.delta().is_some_and(|delta| {
+            if self.higher_is_better {
+                delta < 0.0
+            } else {
+                delta > 0.0
+            }
+        })
+    }
+}
+
+/// Comparison of two profiling runs.
+///
+/// Serializes as one flat array of [`MetricDelta`] rows, counters first.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(into = "Vec<MetricDelta>")]
+pub struct ProfilingDiff {
+    /// Every event counted by either run, in display order
+    pub counters: Vec<CounterDiff>,
+    pub metrics: Vec<MetricDiff>,
+}
+
+impl ProfilingDiff {
+    /// Whether any counter or metric regressed.
+    pub fn has_regression(&self) -> bool {
+        self.counters.iter().any(CounterDiff::is_regression)
+            || self.metrics.iter().any(MetricDiff::is_regression)
+    }
+}
+
+impl From<ProfilingDiff> for Vec<MetricDelta> {
+    /// Metrics neither run has are left out, as in [`print_diff`].
+    fn from(diff: ProfilingDiff) -> Self {
+        let metrics = diff
+            .metrics
+            .into_iter()
+            .filter(|metric| metric.base.is_some() || metric.new.is_some());
+        diff.counters
+            .into_iter()
+            .map(MetricDelta::from)
+            .chain(metrics.map(MetricDelta::from))
+            .collect()
+    }
+}
+
+/// Compare a new run against a base run.
+pub fn diff_results(base: &ProfilingResult, new: &ProfilingResult) -> ProfilingDiff {
+    let counters = HARDWARE_EVENTS
+        .iter()
+        .chain(SOFTWARE_EVENTS)
+        .filter(|event| base.count(event.name).is_some() || new.count(event.name).is_some())
+        .map(|event| CounterDiff {
+            name: event.name,
+            base: base.count(event.name),
+            new: new.count(event.name),
+        })
+        .collect();
+
+    let metric = |name, value: fn(&ProfilingResult) -> Option<f64>, higher_is_better| MetricDiff {
+        name,
+        base: value(base),
+        new: value(new),
+        higher_is_better,
+    };
+    let metrics = vec![
+        metric("IPC", ProfilingResult::ipc, true),
+        metric("Cache Miss Rate %", ProfilingResult::cache_miss_rate, false),
+        metric("Cache MPKI", ProfilingResult::cache_mpki, false),
+        metric("Branch MPKI", ProfilingResult::branch_mpki, false),
+    ];
+
+    ProfilingDiff { counters, metrics }
+}
+
+/// Load a result saved with `perf --format json`.
+pub fn load_result(path: &Path) -> Result<ProfilingResult> {
+    let json = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    serde_json::from_str(&json)
+        .with_context(|| format!("Failed to parse profiling result: {}", path.display()))
+}
+
+/// Serialize a diff as a JSON array of `{metric, before, after, abs_delta,
+/// pct_delta, regression}` objects.
+pub fn format_json(diff: &ProfilingDiff) -> Result<String> {
+    serde_json::to_string_pretty(diff).context("Failed to serialize profiling diff")
+}
+
+/// Format an optional value, or `-` when the run does not have it.
+fn or_dash<T>(value: Option<T>, format: impl Fn(T) -> String) -> String {
+    value.map_or_else(|| "-".to_string(), format)
+}
+
+/// Marker appended to the lines of regressions.
+fn regression_marker(regression: bool) -> &'static str {
+    if regression {
+        "  <- REGRESSION"
+    } else {
+        ""
+    }
+}
+
+/// Print a diff as a table; regressions are flagged at the end of their line.
+pub fn print_diff(diff: &ProfilingDiff, out: &mut dyn Write) -> io::Result<()> {
+    let percent = |change: Option<f64>| or_dash(change, |change| format!("{:+.2}%", change));
+
+    writeln!(out, "Profiling Diff (new vs base):")?;
+    writeln!(out, "{:=<78}", "")?;
+    writeln!(
+        out,
+        "  {:<19}{:>15}{:>15}{:>15}{:>10}",
+        "", "Base", "New", "Delta", "Change"
+    )?;
+    for counter in &diff.counters {
+        writeln!(
+            out,
+            "  {:<19}{:>15}{:>15}{:>15}{:>10}{}",
+            format!("{}:", counter.name),
+            or_dash(counter.base, |count| count.to_string()),
+            or_dash(counter.new, |count| count.to_string()),
+            or_dash(counter.delta(), |delta| format!("{:+}", delta)),
+            percent(counter.percent_change()),
+            regression_marker(counter.is_regression())
+        )?;
+    }
+    writeln!(out, "{:-<78}", "")?;
+    for metric in &diff.metrics {
+        if metric.base.is_none() && metric.new.is_none() {
+            continue;
+        }
+        writeln!(
+            out,
+            "  {:<19}{:>15}{:>15}{:>15}{:>10}{}",
+            format!("{}:", metric.name),
+            or_dash(metric.base, |value| format!("{:.3}", value)),
+            or_dash(metric.new, |value| format!("{:.3}", value)),
+            or_dash(metric.delta(), |delta| format!("{:+.3}", delta)),
+            percent(metric.percent_change()),
+            regression_marker(metric.is_regression())
+        )?;
+    }
+    writeln!(out, "{:=<78}", "")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn result(counts: &[(&'static str, u64)]) -> ProfilingResult {
+        ProfilingResult {
+            counts: counts.iter().copied().collect::<BTreeMap<_, _>>(),
+            duration_secs: 5.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_diff_counters() {
+        let base = result(&[
+            ("cpu-cycles", 1000),
+            ("instructions", 2000),
+            ("page-faults", 0),
+        ]);
+        let new = result(&[
+            ("cpu-cycles", 1500),
+            ("instructions", 1500),
+            ("page-faults", 3),
+        ]);
+        let diff = diff_results(&base, &new);
+
+        let cycles = &diff.counters[0];
+        assert_eq!(cycles.name, "cpu-cycles");
+        assert_eq!(cycles.delta(), Some(500));
+        assert_eq!(cycles.percent_change(), Some(50.0));
+        assert!(cycles.is_regression());
+
+        let instructions = &diff.counters[1];
+        assert_eq!(instructions.delta(), Some(-500));
+        assert_eq!(instructions.percent_change(), Some(-25.0));
+        assert!(!instructions.is_regression());
+
+        // No percentage against a zero base
+        let faults = diff
+            .counters
+            .iter()
+            .find(|c| c.name == "page-faults")
+            .unwrap();
+        assert_eq!(faults.delta(), Some(3));
+        assert_eq!(faults.percent_change(), None);
+    }
+
+    #[test]
+    fn test_diff_metrics() {
+        let base = result(&[("cpu-cycles", 1000), ("instructions", 2000)]);
+        let new = result(&[("cpu-cycles", 2000), ("instructions", 2000)]);
+        let diff = diff_results(&base, &new);
+
+        let ipc = &diff.metrics[0];
+        assert_eq!((ipc.base, ipc.new), (Some(2.0), Some(1.0)));
+        assert_eq!(ipc.percent_change(), Some(-50.0));
+        // Lower IPC is a regression
+        assert!(ipc.is_regression());
+
+        let miss_rate = &diff.metrics[1];
+        assert_eq!(miss_rate.delta(), None);
+        assert!(!miss_rate.is_regression());
+    }
+
+    #[test]
+    fn test_diff_event_in_one_run() {
+        let base = result(&[("cpu-cycles", 1000)]);
+        let new = result(&[("cpu-cycles", 900), ("cache-misses", 10)]);
+        let diff = diff_results(&base, &new);
+
+        assert_eq!(diff.counters.len(), 2);
+        let misses = &diff.counters[1];
+        assert_eq!((misses.base, misses.new), (None, Some(10)));
+        assert_eq!(misses.delta(), None);
+        assert!(!misses.is_regression());
+
+        let mut out = Vec::new();
+        print_diff(&diff, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("-100"));
+        assert!(!report.contains("REGRESSION"));
+        assert!(!report.contains("IPC"));
+    }
+
+    #[test]
+    fn test_diff_json() {
+        let base = result(&[("cpu-cycles", 1000), ("instructions", 2000)]);
+        let new = result(&[("cpu-cycles", 1500), ("instructions", 2000)]);
+        let diff = diff_results(&base, &new);
+        assert!(diff.has_regression());
+
+        let rows: serde_json::Value = serde_json::from_str(&format_json(&diff).unwrap()).unwrap();
+        let rows = rows.as_array().unwrap();
+        // Two counters and IPC; the cache and branch metrics are in neither run
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0]["metric"], "cpu-cycles");
+        assert_eq!(rows[0]["before"], 1000.0);
+        assert_eq!(rows[0]["after"], 1500.0);
+        assert_eq!(rows[0]["abs_delta"], 500.0);
+        assert_eq!(rows[0]["pct_delta"], 50.0);
+        assert_eq!(rows[0]["regression"], true);
+        assert_eq!(rows[1]["regression"], false);
+        assert_eq!(rows[2]["metric"], "IPC");
+        assert_eq!(rows[2]["regression"], true);
+
+        let same = diff_results(&base, &base);
+        assert!(!same.has_regression());
+    }
+}
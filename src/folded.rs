@@ -0,0 +1,226 @@
+//! Folded stacks import module.
+//!
+//! This module parses Brendan Gregg's folded stacks (`root;...;leaf count`
+//! lines), as written by `stackcollapse-*.pl`, other profilers or this crate's
+//! own `--export`, back into aggregated stacks so they can be rendered with the
+//! same reports and exporters as a callchain run, and merges several such
+//! profiles into one.
+
+use crate::table::{Align, Table};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Stacks read from a folded file.
+///
+/// Frames have no address, so each distinct frame name is given an id, in order
+/// of first appearance, that stands in for one in `stacks` and `names`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FoldedStacks {
+    /// Sample count of each stack, leaf frame first, as in a callchain result
+    pub stacks: HashMap<Vec<u64>, u64>,
+    /// Name of each frame id
+    pub names: HashMap<u64, String>,
+    /// Non-empty lines that were not `stack count` and were left out
+    pub skipped_lines: u64,
+}
+
+impl FoldedStacks {
+    /// Total number of samples across all stacks.
+    pub fn total_samples(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /// Add the stacks of `other`, summing the counts of stacks both have.
+    ///
+    /// Frames are matched by name, since ids are only meaningful within one
+    /// profile; names new to `self` get the next free ids.
+    pub fn merge(&mut self, other: &FoldedStacks) {
+        let mut ids: HashMap<String, u64> = self
+            .names
+            .iter()
+            .map(|(&id, name)| (name.clone(), id))
+            .collect();
+        let mut next_id = self.names.keys().max().map_or(0, |&id| id + 1);
+        for (frames, &count) in &other.stacks {
+            let stack = frames
+                .iter()
+                .map(|frame| {
+                    let name = other
+                        .names
+                        .get(frame)
+                        .cloned()
+                        .unwrap_or_else(|| format!("{:#x}", frame));
+                    *ids.entry(name).or_insert_with_key(|name| {
+                        self.names.insert(next_id, name.clone());
+                        next_id += 1;
+                        next_id - 1
+                    })
+                })
+                .collect();
+            *self.stacks.entry(stack).or_insert(0) += count;
+        }
+        self.skipped_lines += other.skipped_lines;
+    }
+}
+
+/// A saved profile that went into a merge.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergeSource {
+    pub path: PathBuf,
+    pub stacks: usize,
+    pub samples: u64,
+}
+
+impl MergeSource {
+    pub fn new(path: &Path, stacks: &FoldedStacks) -> Self {
+        MergeSource {
+            path: path.to_path_buf(),
+            stacks: stacks.stacks.len(),
+            samples: stacks.total_samples(),
+        }
+    }
+}
+
+/// Parse folded stacks, skipping and counting malformed lines.
+///
+/// The count is whatever follows the last space, so frame names may contain
+/// spaces (`operator new(unsigned long)`). A line is malformed when it has no
+/// count, its count is not a whole number, or it has an empty frame. Lines
+/// repeating a stack add their counts.
+pub fn parse_folded(text: &str) -> FoldedStacks {
+    let mut folded = FoldedStacks::default();
+    let mut ids: HashMap<&str, u64> = HashMap::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let parsed = line.rsplit_once(' ').and_then(|(stack, count)| {
+            let count = count.parse::<u64>().ok()?;
+            let frames: Vec<&str> = stack.trim_end().split(';').collect();
+            (!frames.iter().any(|frame| frame.is_empty())).then_some((frames, count))
+        });
+        let Some((frames, count)) = parsed else {
+            folded.skipped_lines += 1;
+            continue;
+        };
+        let mut stack: Vec<u64> = frames
+            .into_iter()
+            .map(|frame| {
+                let next_id = ids.len() as u64;
+                *ids.entry(frame).or_insert_with(|| {
+                    folded.names.insert(next_id, frame.to_string());
+                    next_id
+                })
+            })
+            .collect();
+        stack.reverse();
+        *folded.stacks.entry(stack).or_insert(0) += count;
+    }
+    folded
+}
+
+/// Read and parse a folded stacks file.
+pub fn read_folded(path: &Path) -> Result<FoldedStacks> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read folded stacks: {}", path.display()))?;
+    Ok(parse_folded(&text))
+}
+
+/// Print how many stacks and samples were imported, and how many lines were skipped.
+pub fn print_folded_summary(folded: &FoldedStacks, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "Imported Stacks:")?;
+    writeln!(out, "  Stacks: {:>13}", folded.stacks.len())?;
+    writeln!(out, "  Samples: {:>12}", folded.total_samples())?;
+    if folded.skipped_lines > 0 {
+        writeln!(
+            out,
+            "  Skipped Lines: {:>6} (malformed)",
+            folded.skipped_lines
+        )?;
+    }
+    Ok(())
+}
+
+/// Print the profiles that were merged, one row each, and the merged totals.
+pub fn print_merge_summary(
+    sources: &[MergeSource],
+    merged: &FoldedStacks,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    writeln!(out, "Merged Profiles:")?;
+    let mut table = Table::new(&[
+        ("Source", Align::Name),
+        ("Stacks", Align::Right),
+        ("Samples", Align::Right),
+    ]);
+    for source in sources {
+        table.push_row(vec![
+            source.path.display().to_string(),
+            source.stacks.to_string(),
+            source.samples.to_string(),
+        ]);
+    }
+    table.render(out)?;
+    writeln!(out)?;
+    print_folded_summary(merged, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perf::format_folded;
+
+    #[test]
+    fn test_parse_folded() {
+        let folded = parse_folded(
+            "main;parse;operator new(unsigned long) 3\n\
+             \n\
+             main;run 5\n\
+             main;parse;operator new(unsigned long) 2\n\
+             main;;run 1\n\
+             main;run\n\
+             main;run -4\n",
+        );
+
+        assert_eq!(folded.skipped_lines, 3);
+        assert_eq!(folded.total_samples(), 10);
+        assert_eq!(folded.stacks.len(), 2);
+        assert_eq!(folded.names[&0], "main");
+        assert_eq!(folded.stacks[&vec![2, 1, 0]], 5);
+        assert_eq!(folded.names[&2], "operator new(unsigned long)");
+    }
+
+    #[test]
+    fn test_merge() {
+        // Two hosts whose files number the same functions differently
+        let mut merged = parse_folded("main;run 5\nmain;parse;lex 2\n");
+        let other = parse_folded("main;parse;lex 1\nmain;idle 4\nmain;run 1\n");
+        let sources = [
+            MergeSource::new(Path::new("a.folded"), &merged),
+            MergeSource::new(Path::new("b.folded"), &other),
+        ];
+        merged.merge(&other);
+
+        assert_eq!(
+            format_folded(&merged.stacks, &merged.names),
+            "main;idle 4\nmain;parse;lex 3\nmain;run 6\n"
+        );
+        assert_eq!(merged.names.len(), 5);
+        assert_eq!(sources[1].samples, 6);
+
+        let mut out = Vec::new();
+        print_merge_summary(&sources, &merged, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("a.folded"));
+        assert!(text.contains("b.folded"));
+        assert!(text.contains("Samples:           13"));
+    }
+
+    #[test]
+    fn test_folded_round_trip() {
+        let text = "main;parse;lex 7\nmain;run 12\nstart_thread;worker;run 4\n";
+        let folded = parse_folded(text);
+
+        assert_eq!(folded.skipped_lines, 0);
+        assert_eq!(format_folded(&folded.stacks, &folded.names), text);
+    }
+}
@@ -0,0 +1,329 @@
+//! Histogram helpers module.
+//!
+//! This module provides small histogram types and ordering helpers shared by
+//! the latency, sampling-quality and per-name reports.
+
+use std::io::{self, Write};
+
+/// Histogram with power-of-two bucket boundaries.
+///
+/// Bucket 0 holds zero, and bucket `i > 0` holds values in `[2^(i-1), 2^i - 1]`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Log2Histogram {
+    pub buckets: Vec<u64>,
+}
+
+impl Log2Histogram {
+    /// Record a single value.
+    pub fn record(&mut self, value: u64) {
+        let index = (u64::BITS - value.leading_zeros()) as usize;
+        if self.buckets.len() <= index {
+            self.buckets.resize(index + 1, 0);
+        }
+        self.buckets[index] += 1;
+    }
+
+    /// Inclusive value range covered by a bucket.
+    pub fn bucket_range(index: usize) -> (u64, u64) {
+        match index {
+            0 => (0, 0),
+            _ => (1 << (index - 1), (1 << (index - 1)) * 2 - 1),
+        }
+    }
+
+    /// Total number of recorded values.
+    pub fn total(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Add the counts of another histogram to this one.
+    pub fn merge(&mut self, other: &Log2Histogram) {
+        if self.buckets.len() < other.buckets.len() {
+            self.buckets.resize(other.buckets.len(), 0);
+        }
+        for (bucket, count) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += count;
+        }
+    }
+
+    /// Upper bound of the bucket holding the `p`-th percentile (0-100), or
+    /// `None` if nothing was recorded.
+    pub fn percentile(&self, p: f64) -> Option<u64> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((p / 100.0 * total as f64).ceil() as u64).clamp(1, total);
+        let mut seen = 0;
+        for (index, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Self::bucket_range(index).1);
+            }
+        }
+        None
+    }
+
+    /// Print one `low -> high : count` line per non-empty bucket.
+    pub fn print(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (index, &count) in self.buckets.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            let (low, high) = Self::bucket_range(index);
+            writeln!(out, "  {:>10} -> {:<10} : {:>10}", low, high, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// Histogram with power-of-ten bucket boundaries.
+///
+/// Bucket 0 holds zero, and bucket `i > 0` holds values in `[10^(i-1), 10^i - 1]`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Log10Histogram {
+    pub buckets: Vec<u64>,
+}
+
+impl Log10Histogram {
+    /// Record a single value.
+    pub fn record(&mut self, value: u64) {
+        let index = match value.checked_ilog10() {
+            Some(log) => log as usize + 1,
+            None => 0,
+        };
+        if self.buckets.len() <= index {
+            self.buckets.resize(index + 1, 0);
+        }
+        self.buckets[index] += 1;
+    }
+
+    /// Inclusive value range covered by a bucket.
+    pub fn bucket_range(index: usize) -> (u64, u64) {
+        match index {
+            0 => (0, 0),
+            _ => {
+                let low = 10u64.pow(index as u32 - 1);
+                (low, low.checked_mul(10).map_or(u64::MAX, |high| high - 1))
+            }
+        }
+    }
+
+    /// Total number of recorded values.
+    pub fn total(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+/// Streaming summary of numeric values: count, sum, extremes and percentiles.
+///
+/// Percentiles come from a [`Log2Histogram`] of the values rounded to integers
+/// (negative values count as zero), so they are upper bounds accurate to a
+/// factor of two, capped at the maximum.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ValueSummary {
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    pub histogram: Log2Histogram,
+}
+
+impl ValueSummary {
+    /// Record a single value.
+    pub fn record(&mut self, value: f64) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.count += 1;
+        self.sum += value;
+        self.histogram.record(value.max(0.0).round() as u64);
+    }
+
+    /// Add the values summarized by another summary.
+    pub fn merge(&mut self, other: &ValueSummary) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            self.min = other.min;
+            self.max = other.max;
+        } else {
+            self.min = self.min.min(other.min);
+            self.max = self.max.max(other.max);
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.histogram.merge(&other.histogram);
+    }
+
+    /// Arithmetic mean, or `None` if nothing was recorded.
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+
+    /// Approximate `p`-th percentile (0-100), or `None` if nothing was recorded.
+    pub fn percentile(&self, p: f64) -> Option<f64> {
+        self.histogram
+            .percentile(p)
+            .map(|bound| (bound as f64).min(self.max))
+    }
+}
+
+/// Sort `(name, count)` pairs by count descending, then name ascending.
+///
+/// Aggregations are usually built in a `HashMap`, whose iteration order varies
+/// between runs; this gives reports a fully deterministic order.
+pub fn sorted_counts<K: Ord>(counts: impl IntoIterator<Item = (K, u64)>) -> Vec<(K, u64)> {
+    let mut sorted: Vec<(K, u64)> = counts.into_iter().collect();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    sorted
+}
+
+/// Glyphs used by `sparkline`, from lowest to highest.
+const SPARK_GLYPHS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Count timestamps into `buckets` equal-width bins spanning the first to last timestamp.
+pub fn bucket_counts(timestamps: &[u64], buckets: usize) -> Vec<u64> {
+    let mut counts = vec![0; buckets];
+    let (Some(&first), Some(&last)) = (timestamps.iter().min(), timestamps.iter().max()) else {
+        return counts;
+    };
+    if buckets == 0 {
+        return counts;
+    }
+
+    let span = (last - first).max(1);
+    for &time in timestamps {
+        let index = ((time - first) as u128 * buckets as u128 / span as u128) as usize;
+        counts[index.min(buckets - 1)] += 1;
+    }
+    counts
+}
+
+/// Render counts as a unicode sparkline scaled to the largest count.
+pub fn sparkline(counts: &[u64]) -> String {
+    let max = counts.iter().copied().max().unwrap_or(0);
+    counts
+        .iter()
+        .map(|&count| {
+            if max == 0 {
+                SPARK_GLYPHS[0]
+            } else {
+                let level = count * (SPARK_GLYPHS.len() as u64 - 1) / max;
+                SPARK_GLYPHS[level as usize]
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log2_histogram_buckets() {
+        let mut histogram = Log2Histogram::default();
+        for value in [0, 1, 2, 3, 4, 7, 8] {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.buckets, vec![1, 1, 2, 2, 1]);
+        assert_eq!(histogram.total(), 7);
+        assert_eq!(Log2Histogram::bucket_range(0), (0, 0));
+        assert_eq!(Log2Histogram::bucket_range(3), (4, 7));
+    }
+
+    #[test]
+    fn test_log2_histogram_percentile() {
+        let mut histogram = Log2Histogram::default();
+        assert_eq!(histogram.percentile(50.0), None);
+        for value in [1, 2, 3, 100] {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.percentile(50.0), Some(3));
+        assert_eq!(histogram.percentile(99.0), Some(127));
+        assert_eq!(histogram.percentile(0.0), Some(1));
+
+        let mut merged = Log2Histogram::default();
+        merged.record(0);
+        merged.merge(&histogram);
+        assert_eq!(merged.buckets, vec![1, 1, 2, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_log10_histogram_buckets() {
+        let mut histogram = Log10Histogram::default();
+        for value in [0, 1, 9, 10, 999, 1000, u64::MAX] {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.buckets[..5], [1, 2, 1, 1, 1]);
+        assert_eq!(histogram.buckets.len(), 21);
+        assert_eq!(histogram.total(), 7);
+        assert_eq!(Log10Histogram::bucket_range(0), (0, 0));
+        assert_eq!(Log10Histogram::bucket_range(4), (1000, 9999));
+        assert_eq!(Log10Histogram::bucket_range(20).1, u64::MAX);
+    }
+
+    #[test]
+    fn test_value_summary() {
+        let mut summary = ValueSummary::default();
+        assert_eq!(summary.mean(), None);
+        for value in [4096.0, 512.0, 8192.0, 512.0] {
+            summary.record(value);
+        }
+        assert_eq!(summary.count, 4);
+        assert!((summary.min - 512.0).abs() < f64::EPSILON);
+        assert!((summary.max - 8192.0).abs() < f64::EPSILON);
+        assert!((summary.mean().unwrap() - 3328.0).abs() < f64::EPSILON);
+        assert!((summary.percentile(50.0).unwrap() - 1023.0).abs() < f64::EPSILON);
+        // Capped at the largest value rather than its bucket's upper bound
+        assert!((summary.percentile(99.0).unwrap() - 8192.0).abs() < f64::EPSILON);
+
+        let mut other = ValueSummary::default();
+        other.record(-1.0);
+        summary.merge(&other);
+        assert_eq!(summary.count, 5);
+        assert!((summary.min + 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_sorted_counts_ties() {
+        let counts: std::collections::HashMap<&str, u64> = [
+            ("mmap", 5),
+            ("exit", 2),
+            ("comm", 5),
+            ("fork", 2),
+            ("lost", 9),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            sorted_counts(counts),
+            vec![
+                ("lost", 9),
+                ("comm", 5),
+                ("mmap", 5),
+                ("exit", 2),
+                ("fork", 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bucket_counts() {
+        assert_eq!(bucket_counts(&[0, 1, 2, 9, 10], 2), vec![3, 2]);
+        assert_eq!(bucket_counts(&[5, 5, 5], 3), vec![3, 0, 0]);
+        assert_eq!(bucket_counts(&[], 3), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0, 7, 14]), "▁▄█");
+        assert_eq!(sparkline(&[0, 0]), "▁▁");
+        assert_eq!(sparkline(&[]), "");
+    }
+}
@@ -0,0 +1,121 @@
+//! Ctrl-C and snapshot signal handling module.
+//!
+//! This module turns SIGINT into a flag that collection loops poll, so an
+//! interrupted run stops early and still reports what it collected. A second
+//! signal can be turned into a request for a snapshot of the run so far.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INSTALL_HANDLER: Once = Once::new();
+static SNAPSHOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Catches Ctrl-C for the lifetime of one collection run.
+///
+/// The process-wide handler is installed once; each scope starts with the flag
+/// cleared and clears it again when dropped, so an interrupt never carries over
+/// into the next run.
+pub struct InterruptScope(());
+
+impl InterruptScope {
+    pub fn enter() -> Self {
+        INSTALL_HANDLER.call_once(|| {
+            if let Err(e) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst)) {
+                eprintln!("Warning: Ctrl-C will not stop collection early: {}", e);
+            }
+        });
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        InterruptScope(())
+    }
+
+    /// Whether Ctrl-C was pressed since this scope started.
+    pub fn interrupted(&self) -> bool {
+        INTERRUPTED.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for InterruptScope {
+    fn drop(&mut self) {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+    }
+}
+
+extern "C" fn request_snapshot(_signal: libc::c_int) {
+    SNAPSHOT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Catches a signal as a snapshot request for the lifetime of one collection run.
+///
+/// The signal's previous disposition is restored when dropped, so e.g. SIGQUIT
+/// dumps core again once the run is over.
+pub struct SnapshotSignal {
+    signal: libc::c_int,
+    previous: libc::sighandler_t,
+}
+
+impl SnapshotSignal {
+    pub fn install(signal: libc::c_int) -> std::io::Result<Self> {
+        SNAPSHOT_REQUESTED.store(false, Ordering::SeqCst);
+        let handler = request_snapshot as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        let previous = unsafe { libc::signal(signal, handler) };
+        if previous == libc::SIG_ERR {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(SnapshotSignal { signal, previous })
+    }
+
+    /// Whether the signal arrived since the last call; the request is cleared.
+    pub fn take_request(&self) -> bool {
+        SNAPSHOT_REQUESTED.swap(false, Ordering::SeqCst)
+    }
+}
+
+impl Drop for SnapshotSignal {
+    fn drop(&mut self) {
+        // SAFETY: restores the disposition `install` replaced
+        unsafe { libc::signal(self.signal, self.previous) };
+        SNAPSHOT_REQUESTED.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_interrupt_scope() {
+        let scope = InterruptScope::enter();
+        assert!(!scope.interrupted());
+
+        // SAFETY: the handler installed above turns SIGINT into a flag
+        unsafe { libc::raise(libc::SIGINT) };
+        let start = Instant::now();
+        while !scope.interrupted() && start.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(scope.interrupted());
+
+        drop(scope);
+        assert!(!InterruptScope::enter().interrupted());
+    }
+
+    #[test]
+    fn test_snapshot_signal() {
+        let signal = SnapshotSignal::install(libc::SIGUSR2).unwrap();
+        assert!(!signal.take_request());
+
+        // SAFETY: the handler installed above turns SIGUSR2 into a request
+        unsafe { libc::raise(libc::SIGUSR2) };
+        assert!(signal.take_request());
+        // Taken once per signal
+        assert!(!signal.take_request());
+
+        drop(signal);
+        // SAFETY: SIGUSR2 is set to the default disposition it should already have
+        let restored = unsafe { libc::signal(libc::SIGUSR2, libc::SIG_DFL) };
+        assert_eq!(restored, libc::SIG_DFL);
+    }
+}
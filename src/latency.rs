@@ -0,0 +1,238 @@
+//! Paired-event latency module.
+//!
+//! This module measures the time between a "start" and an "end" tracepoint
+//! recorded in a perf.data file, pairing the two by a key field such as a
+//! request id, and reports the latencies as a log-scale histogram.
+
+use crate::histogram::Log10Histogram;
+use crate::tracepoint::sample_field;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use tracepoint_decode::{self as td, PerfEventHeaderType};
+use tracepoint_perf::{PerfDataFileEventOrder, PerfDataFileReader};
+
+/// Results of a paired-event latency analysis.
+#[derive(Debug, Default)]
+pub struct LatencyStats {
+    /// Distribution of start-to-end latencies in nanoseconds
+    pub histogram: Log10Histogram,
+    /// Number of start/end pairs that produced a latency
+    pub matched: u64,
+    /// Starts never ended, including starts replaced by a later start with the same key
+    pub unmatched_starts: u64,
+    /// Ends without an open start for their key
+    pub unmatched_ends: u64,
+    /// Start or end events whose key field was missing or not an integer
+    pub missing_key: u64,
+}
+
+/// Pairs start and end events by key and turns each pair into a latency.
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    /// Time of each open start, keyed by the key field
+    open: HashMap<u64, u64>,
+    stats: LatencyStats,
+}
+
+impl LatencyTracker {
+    /// Record a start event for `key` at `time_ns`.
+    pub fn on_start(&mut self, key: u64, time_ns: u64) {
+        if self.open.insert(key, time_ns).is_some() {
+            self.stats.unmatched_starts += 1;
+        }
+    }
+
+    /// Record an end event for `key` at `time_ns`.
+    pub fn on_end(&mut self, key: u64, time_ns: u64) {
+        match self.open.remove(&key) {
+            Some(started) => {
+                self.stats.histogram.record(time_ns.saturating_sub(started));
+                self.stats.matched += 1;
+            }
+            None => self.stats.unmatched_ends += 1,
+        }
+    }
+
+    /// Record a start or end event without a usable key.
+    pub fn on_missing_key(&mut self) {
+        self.stats.missing_key += 1;
+    }
+
+    /// Finish tracking and return the statistics; starts still open are unmatched.
+    pub fn finish(mut self) -> LatencyStats {
+        self.stats.unmatched_starts += self.open.len() as u64;
+        self.stats
+    }
+}
+
+/// Parse a key field value: a decimal (possibly negative) or `0x` hex integer.
+fn parse_key(value: &str) -> Option<u64> {
+    let value = value.trim();
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value
+            .parse::<u64>()
+            .ok()
+            .or_else(|| value.parse::<i64>().ok().map(|key| key as u64)),
+    }
+}
+
+/// Format a nanosecond value with the largest unit that keeps it whole.
+fn format_ns(ns: u64) -> String {
+    match ns {
+        0 => "0".to_string(),
+        _ if ns % 1_000_000_000 == 0 => format!("{}s", ns / 1_000_000_000),
+        _ if ns % 1_000_000 == 0 => format!("{}ms", ns / 1_000_000),
+        _ if ns % 1_000 == 0 => format!("{}us", ns / 1_000),
+        _ => format!("{}ns", ns),
+    }
+}
+
+/// Measure start-to-end latencies from a perf.data file.
+///
+/// # Arguments
+///
+/// * `file_path` - Path to a perf.data file recording both events
+/// * `start` - Name of the start event (e.g. `syscalls:sys_enter_read`)
+/// * `end` - Name of the end event
+/// * `key` - Field present in both events that pairs an end with its start
+///
+/// # Returns
+///
+/// Returns the latency histogram and the counts of unpaired events.
+pub fn analyze_latency(
+    file_path: &str,
+    start: &str,
+    end: &str,
+    key: &str,
+    out: &mut dyn Write,
+) -> Result<LatencyStats> {
+    if !Path::new(file_path).exists() {
+        anyhow::bail!("File not found: {}", file_path);
+    }
+
+    eprintln!("Analyzing latency from: {}", file_path);
+
+    let mut reader = PerfDataFileReader::new();
+    reader
+        .open_file(file_path, PerfDataFileEventOrder::Time)
+        .context("Failed to open perf.data file")?;
+
+    let mut enumerator_ctx = td::EventHeaderEnumeratorContext::new();
+    let mut tracker = LatencyTracker::default();
+
+    loop {
+        match reader.move_next_event() {
+            Err(e) => {
+                anyhow::bail!("Error reading event: {}", e);
+            }
+            Ok(false) => break, // EOF
+            Ok(true) => {}      // Got an event
+        }
+
+        let event = reader.current_event();
+        if event.header.ty != PerfEventHeaderType::Sample {
+            continue;
+        }
+
+        let Ok(info) = reader.get_sample_event_info(&event) else {
+            continue;
+        };
+        let is_start = info.name() == start;
+        if !is_start && info.name() != end {
+            continue;
+        }
+
+        match sample_field(&mut enumerator_ctx, &info, key).and_then(|value| parse_key(&value)) {
+            Some(id) if is_start => tracker.on_start(id, info.time),
+            Some(id) => tracker.on_end(id, info.time),
+            None => tracker.on_missing_key(),
+        }
+    }
+
+    let stats = tracker.finish();
+
+    // Print summary
+    writeln!(out, "Latency: {} -> {} (key {}):", start, end, key)?;
+    writeln!(out, "{:=<50}", "")?;
+    writeln!(out, "  Matched Pairs:     {:>10}", stats.matched)?;
+    writeln!(out, "  Unmatched Starts:  {:>10}", stats.unmatched_starts)?;
+    writeln!(out, "  Unmatched Ends:    {:>10}", stats.unmatched_ends)?;
+    if stats.missing_key > 0 {
+        writeln!(out, "  Missing Key:       {:>10}", stats.missing_key)?;
+    }
+    writeln!(out, "{:-<50}", "")?;
+    for (index, &count) in stats.histogram.buckets.iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        let (low, high) = Log10Histogram::bucket_range(index);
+        writeln!(
+            out,
+            "  {:>10} -> {:<10} : {:>10}",
+            format_ns(low),
+            format_ns(high.saturating_add(1)),
+            count
+        )?;
+    }
+    writeln!(out, "{:=<50}", "")?;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_start_then_end() {
+        let mut tracker = LatencyTracker::default();
+        tracker.on_start(7, 1_000);
+        tracker.on_start(8, 2_000);
+        tracker.on_end(8, 52_000);
+        tracker.on_end(7, 1_001_000);
+        let stats = tracker.finish();
+        assert_eq!(stats.matched, 2);
+        assert_eq!(stats.unmatched_starts, 0);
+        // 50us and 1ms
+        assert_eq!(stats.histogram.buckets[5], 1);
+        assert_eq!(stats.histogram.buckets[7], 1);
+    }
+
+    #[test]
+    fn test_unmatched_events() {
+        let mut tracker = LatencyTracker::default();
+        tracker.on_end(1, 500);
+        tracker.on_start(2, 1_000);
+        // A second start for an open key abandons the first
+        tracker.on_start(2, 3_000);
+        tracker.on_end(2, 4_000);
+        tracker.on_start(3, 5_000);
+        tracker.on_missing_key();
+        let stats = tracker.finish();
+        assert_eq!(stats.matched, 1);
+        assert_eq!(stats.unmatched_ends, 1);
+        assert_eq!(stats.unmatched_starts, 2);
+        assert_eq!(stats.missing_key, 1);
+        assert_eq!(stats.histogram.total(), 1);
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key("42"), Some(42));
+        assert_eq!(parse_key("0xff"), Some(255));
+        assert_eq!(parse_key("-1"), Some(u64::MAX));
+        assert_eq!(parse_key("req"), None);
+    }
+
+    #[test]
+    fn test_format_ns() {
+        assert_eq!(format_ns(0), "0");
+        assert_eq!(format_ns(1_000), "1us");
+        assert_eq!(format_ns(10_000_000), "10ms");
+        assert_eq!(format_ns(1_000_000_000), "1s");
+        assert_eq!(format_ns(100), "100ns");
+    }
+}
@@ -0,0 +1,271 @@
+//! Command launch module.
+//!
+//! This module starts a command held just before `exec`, so counters can be
+//! attached to it before it runs any of its own code, then releases it and
+//! waits for it to exit.
+
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::os::unix::io::RawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Exit code reported when the command could not be executed, as in shells.
+const EXEC_FAILED_EXIT_CODE: i32 = 127;
+
+/// How often [`HeldChild::release_and_wait_for`] checks whether the command exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How a command waited on with a timeout ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandExit {
+    /// Exit code, or `128 + signal` if it was killed
+    pub code: i32,
+    /// Whether the timeout ran out and the command was terminated
+    pub timed_out: bool,
+}
+
+/// A forked child blocked until [`HeldChild::release_and_wait`] is called.
+///
+/// Dropping it without releasing kills the child, so a command never runs
+/// unmeasured after a setup error.
+#[derive(Debug)]
+pub struct HeldChild {
+    pid: libc::pid_t,
+    /// Write end of the pipe the child waits on before calling `exec`
+    release_fd: RawFd,
+    released: bool,
+}
+
+impl HeldChild {
+    /// Fork a child that will `exec` `command` once released.
+    pub fn spawn(command: &[String]) -> Result<Self> {
+        let Some(program) = command.first() else {
+            anyhow::bail!("No command given");
+        };
+        // Everything the child needs is allocated before forking
+        let args = command
+            .iter()
+            .map(|arg| CString::new(arg.as_str()))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid argument for command: {}", program))?;
+        let mut argv: Vec<*const libc::c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+        argv.push(std::ptr::null());
+
+        let mut fds = [0; 2];
+        // SAFETY: `fds` is a valid array of two descriptors for pipe2 to fill
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to create pipe");
+        }
+        let [wait_fd, release_fd] = fds;
+
+        // SAFETY: the child only calls async-signal-safe functions before exec or _exit
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            let err = std::io::Error::last_os_error();
+            // SAFETY: both descriptors were just created and are owned here
+            unsafe {
+                libc::close(wait_fd);
+                libc::close(release_fd);
+            }
+            return Err(err).context("Failed to fork");
+        }
+
+        if pid == 0 {
+            // SAFETY: in the child; block until released (or the parent goes away),
+            // then replace the process image
+            unsafe {
+                libc::close(release_fd);
+                let mut byte = 0u8;
+                if libc::read(wait_fd, (&mut byte as *mut u8).cast(), 1) != 1 {
+                    libc::_exit(EXEC_FAILED_EXIT_CODE);
+                }
+                libc::execvp(argv[0], argv.as_ptr());
+                let message = b"profiler: failed to execute command\n";
+                libc::write(2, message.as_ptr().cast(), message.len());
+                libc::_exit(EXEC_FAILED_EXIT_CODE);
+            }
+        }
+
+        // SAFETY: the read end belongs to the child now
+        unsafe { libc::close(wait_fd) };
+        Ok(HeldChild {
+            pid,
+            release_fd,
+            released: false,
+        })
+    }
+
+    /// Process ID of the child.
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// Let the child `exec` the command and wait for it to exit.
+    ///
+    /// # Returns
+    ///
+    /// Returns the command's exit code, or `128 + signal` if it was killed.
+    pub fn release_and_wait(mut self) -> Result<i32> {
+        self.release()?;
+        let status = self.wait(0)?.unwrap_or_default();
+        Ok(exit_code(status))
+    }
+
+    /// Like [`HeldChild::release_and_wait`], but stop waiting after `timeout`
+    /// and terminate the command with `SIGTERM` if it is still running, as
+    /// `perf stat --timeout` does.
+    pub fn release_and_wait_for(mut self, timeout: Duration) -> Result<CommandExit> {
+        self.release()?;
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.wait(libc::WNOHANG)? {
+                return Ok(CommandExit {
+                    code: exit_code(status),
+                    timed_out: false,
+                });
+            }
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                break;
+            }
+            thread::sleep(POLL_INTERVAL.min(remaining));
+        }
+        // SAFETY: the child is ours and has not been reaped yet
+        unsafe { libc::kill(self.pid, libc::SIGTERM) };
+        let status = self.wait(0)?.unwrap_or_default();
+        Ok(CommandExit {
+            code: exit_code(status),
+            timed_out: true,
+        })
+    }
+
+    /// Let the child `exec` the command.
+    fn release(&mut self) -> Result<()> {
+        self.released = true;
+        // SAFETY: `release_fd` is owned by this struct and closed exactly once
+        let written = unsafe {
+            let written = libc::write(self.release_fd, [1u8].as_ptr().cast(), 1);
+            libc::close(self.release_fd);
+            written
+        };
+        if written != 1 {
+            return Err(std::io::Error::last_os_error()).context("Failed to start command");
+        }
+        Ok(())
+    }
+
+    /// Reap the child with `waitpid` `flags`, retrying on `EINTR`.
+    ///
+    /// Returns its status, or `None` if `WNOHANG` was given and it is still running.
+    fn wait(&self, flags: libc::c_int) -> Result<Option<libc::c_int>> {
+        let mut status = 0;
+        loop {
+            // SAFETY: waiting on our own child with a valid status pointer
+            match unsafe { libc::waitpid(self.pid, &mut status, flags) } {
+                0 => return Ok(None),
+                pid if pid > 0 => return Ok(Some(status)),
+                _ => {}
+            }
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err).context("Failed to wait for command");
+            }
+        }
+    }
+}
+
+impl Drop for HeldChild {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        // SAFETY: the child is ours and has not been reaped yet
+        unsafe {
+            libc::kill(self.pid, libc::SIGKILL);
+            libc::close(self.release_fd);
+            libc::waitpid(self.pid, std::ptr::null_mut(), 0);
+        }
+    }
+}
+
+/// Convert a `waitpid` status into a shell-style exit code.
+fn exit_code(status: libc::c_int) -> i32 {
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        128 + libc::WTERMSIG(status)
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(command: &[&str]) -> i32 {
+        let command: Vec<String> = command.iter().map(|arg| arg.to_string()).collect();
+        HeldChild::spawn(&command)
+            .unwrap()
+            .release_and_wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(run(&["true"]), 0);
+        assert_eq!(run(&["sh", "-c", "exit 3"]), 3);
+        assert_eq!(run(&["sh", "-c", "kill -9 $$"]), 128 + 9);
+    }
+
+    #[test]
+    fn test_release_and_wait_for() {
+        let spawn = |command: &[&str]| {
+            let command: Vec<String> = command.iter().map(|arg| arg.to_string()).collect();
+            HeldChild::spawn(&command).unwrap()
+        };
+
+        let exit = spawn(&["sh", "-c", "exit 3"])
+            .release_and_wait_for(Duration::from_secs(30))
+            .unwrap();
+        assert_eq!(
+            exit,
+            CommandExit {
+                code: 3,
+                timed_out: false
+            }
+        );
+
+        let start = Instant::now();
+        let exit = spawn(&["sleep", "30"])
+            .release_and_wait_for(Duration::from_millis(100))
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            exit,
+            CommandExit {
+                code: 128 + libc::SIGTERM,
+                timed_out: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_missing_command() {
+        assert_eq!(run(&["/nonexistent/command"]), EXEC_FAILED_EXIT_CODE);
+    }
+
+    #[test]
+    fn test_dropped_child_never_runs() {
+        let marker = std::env::temp_dir().join(format!("profiler-launch-{}", std::process::id()));
+        let command = vec!["touch".to_string(), marker.display().to_string()];
+        drop(HeldChild::spawn(&command).unwrap());
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_empty_command() {
+        assert!(HeldChild::spawn(&[]).is_err());
+    }
+}
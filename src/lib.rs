@@ -0,0 +1,29 @@
+//! A basic Rust-based profiler that listens to perf_events and tracepoints.
+//!
+//! This profiler uses Microsoft's LinuxTracepoints-Rust crates for tracepoint handling,
+//! Microsoft's one-collect for CPU profiling with callchain/stacktrace support,
+//! and the perf-event crate for live perf event monitoring.
+//!
+//! The `profiler` binary is a thin command line front end over this library;
+//! [`perf`] collects counters and callchains and [`tracepoint`] reads perf.data
+//! files.
+
+pub mod calltree;
+pub mod cpulist;
+mod debuglog;
+pub mod diff;
+pub mod folded;
+pub mod histogram;
+mod interrupt;
+pub mod latency;
+mod launch;
+pub mod manifest;
+pub mod perf;
+pub mod power;
+pub mod pprof;
+pub mod profile;
+pub mod runqlat;
+pub mod speedscope;
+pub mod symbols;
+pub mod table;
+pub mod tracepoint;
@@ -1,20 +1,54 @@
-//! A basic Rust-based profiler that listens to perf_events and tracepoints.
+//! Command line front end of the profiler.
 //!
-//! This profiler uses Microsoft's LinuxTracepoints-Rust crates for tracepoint handling,
-//! Microsoft's one-collect for CPU profiling with callchain/stacktrace support,
-//! and the perf-event crate for live perf event monitoring.
+//! Parses the subcommands and hands them to the `profiler` library, which
+//! does the collection and decoding.
 
-mod perf;
-mod tracepoint;
+use anyhow::{Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use profiler::calltree::{self, CallTree};
+use profiler::perf::{self, OutputFormat, StackFormat};
+use profiler::tracepoint::TraceFormat;
+use profiler::{
+    cpulist, diff, folded, latency, manifest, power, pprof, profile, runqlat, speedscope, table,
+    tracepoint,
+};
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+/// Seconds `perf` counts for when no `--duration` or command is given.
+const DEFAULT_PERF_DURATION_SECS: u64 = 5;
+
+/// Exit code used when a profiling run succeeded but collected no samples.
+const EXIT_NO_SAMPLES: u8 = 2;
+
+/// Exit code used with `--fail-on-alert` when an interval crossed an alert threshold.
+const EXIT_ALERT: u8 = 3;
+
+/// Exit code used with `diff --fail-on-regression` when a counter or metric regressed.
+const EXIT_REGRESSION: u8 = 4;
 
 /// A basic Rust-based profiler for perf_events and tracepoints
 #[derive(Parser)]
 #[command(name = "profiler")]
 #[command(about = "A Rust-based profiler using perf_events and tracepoints", long_about = None)]
 struct Cli {
+    /// Write the report of any subcommand to this file instead of stdout;
+    /// progress messages stay on stderr
+    #[arg(long, value_name = "PATH", global = true)]
+    output: Option<PathBuf>,
+
+    /// Append to the --output file instead of overwriting it
+    #[arg(long, requires = "output", global = true)]
+    append: bool,
+
+    /// Write a JSON manifest listing every file the run wrote (path, type and
+    /// size) and how it was run, for automation to pick up the artifacts
+    #[arg(long, value_name = "PATH", global = true)]
+    manifest: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -23,13 +57,85 @@ struct Cli {
 enum Commands {
     /// Profile using hardware performance counters
     Perf {
-        /// Duration in seconds to collect samples
-        #[arg(short, long, default_value = "5")]
-        duration: u64,
+        /// Duration in seconds to collect samples (default 5); with a command,
+        /// the most it is counted before being terminated (default: until it exits)
+        #[arg(short, long)]
+        duration: Option<u64>,
 
-        /// Target PID to profile (currently only profiles current process, PID targeting not yet implemented)
+        /// Target PID to profile (0 for the profiler's own process)
         #[arg(short, long, default_value = "0")]
         pid: i32,
+
+        /// Count only this thread of a process instead of a whole process
+        #[arg(long, conflicts_with_all = ["pid", "cpus", "per_cpu"])]
+        tid: Option<i32>,
+
+        /// Count every thread with this name (as set with prctl), within --pid
+        /// or across all processes, and sum them
+        #[arg(long, value_name = "NAME", conflicts_with_all = ["tid", "cpus", "per_cpu"])]
+        thread_name: Option<String>,
+
+        /// Also count this control (e.g. idle) process over the same window and
+        /// report the counts with its counts subtracted, clamped at zero
+        #[arg(long, value_name = "PID", conflicts_with_all = ["cpus", "per_cpu"])]
+        baseline_pid: Option<i32>,
+
+        /// Print counters in `perf stat -x,` CSV layout (value,unit,event,runtime,percent)
+        #[arg(long)]
+        perf_csv: bool,
+
+        /// Output format for the results
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text, conflicts_with = "perf_csv")]
+        format: OutputFormat,
+
+        /// Only collect while this file exists (bounded by --duration)
+        #[arg(long, value_name = "PATH")]
+        while_exists: Option<PathBuf>,
+
+        /// Count all processes on these CPUs (cpulist syntax, e.g. 0-3,8,12-15)
+        #[arg(long, value_name = "CPULIST", conflicts_with = "pid")]
+        cpus: Option<String>,
+
+        /// Count each CPU separately (all online CPUs unless --cpus is given)
+        /// and print a per-CPU breakdown
+        #[arg(long, conflicts_with = "pid")]
+        per_cpu: bool,
+
+        /// Also count child processes/threads created after counting starts.
+        /// By default only the target itself is measured.
+        #[arg(long)]
+        inherit: bool,
+
+        /// Comma-separated events to count (see `list-events`)
+        #[arg(long, value_delimiter = ',', value_name = "EVENTS")]
+        events: Vec<String>,
+
+        /// Also print the counts of every interval of this many milliseconds,
+        /// like `perf stat -I`
+        #[arg(long, value_name = "MS", conflicts_with = "format")]
+        interval: Option<u64>,
+
+        /// With --interval, print an alert when an interval's IPC drops below this value
+        #[arg(long, value_name = "IPC", requires = "interval")]
+        alert_ipc_below: Option<f64>,
+
+        /// With --interval, print an alert when an interval's cache miss rate
+        /// (in percent) rises above this value
+        #[arg(long, value_name = "PERCENT", requires = "interval")]
+        alert_cache_miss_above: Option<f64>,
+
+        /// Exit with status 3 if any alert was printed
+        #[arg(long)]
+        fail_on_alert: bool,
+
+        /// Run the measurement N times and report each counter's mean,
+        /// standard deviation and noise, like `perf stat -r`
+        #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["perf_csv", "format"])]
+        repeat: u64,
+
+        /// Command to launch and count until it exits, with its children
+        #[arg(last = true, value_name = "COMMAND", conflicts_with_all = ["pid", "tid", "thread_name", "cpus", "per_cpu", "while_exists", "interval"])]
+        command: Vec<String>,
     },
 
     /// CPU profiling with callchain/stacktrace collection using one-collect
@@ -42,43 +148,781 @@ enum Commands {
         #[arg(short, long, default_value = "0")]
         pid: i32,
 
+        /// Sample only this thread of a process instead of a whole process
+        #[arg(long, conflicts_with = "pid")]
+        tid: Option<i32>,
+
+        /// Only open ring buffers on these CPUs (cpulist syntax, e.g. 2,3,8-11)
+        #[arg(long, visible_alias = "cpu", value_name = "CPULIST")]
+        cpus: Option<String>,
+
         /// Sampling frequency in Hz (e.g., 99 for 99 samples/second)
         #[arg(short, long, default_value = "99")]
         frequency: u64,
+
+        /// Sample every N nanoseconds of CPU time instead of at a frequency;
+        /// N must divide one second evenly
+        #[arg(long, value_name = "NS", conflicts_with = "frequency")]
+        period: Option<u64>,
+
+        /// Only collect while this file exists (bounded by --duration)
+        #[arg(long, value_name = "PATH")]
+        while_exists: Option<PathBuf>,
+
+        /// Dump each raw sample record (size and hex preview) as it is read
+        #[arg(long)]
+        raw_records: bool,
+
+        /// Maximum number of raw records to dump with --raw-records
+        #[arg(long, default_value = "20", requires = "raw_records")]
+        limit: u64,
+
+        /// Print a sparkline of the sample rate over the run
+        #[arg(long)]
+        sparkline: bool,
+
+        /// Append one line per sample to this bounded, wrapping log file
+        #[arg(long, value_name = "PATH")]
+        debug_log: Option<PathBuf>,
+
+        /// Write the sampled stacks to this file
+        #[arg(long, value_name = "PATH")]
+        export: Option<PathBuf>,
+
+        /// Format of the --export file
+        #[arg(long, value_enum, default_value_t = StackFormat::Folded, requires = "export")]
+        format: StackFormat,
+
+        /// Merge the stacks of all threads in the folded export (the default)
+        #[arg(long, conflicts_with = "split_threads")]
+        merge_threads: bool,
+
+        /// Keep each thread's stacks apart in the folded export, under a root
+        /// frame naming the thread (`comm-tid`)
+        #[arg(long, requires = "export")]
+        split_threads: bool,
+
+        /// Data pages per CPU ring buffer (a power of two); raise it if samples are lost
+        #[arg(long, value_name = "N", default_value_t = perf::DEFAULT_PAGE_COUNT)]
+        page_count: usize,
+
+        /// Print the N functions with the most self samples, with self and total percentages
+        #[arg(long, value_name = "N")]
+        top: Option<usize>,
+
+        /// Cut function names in the --top table to this many characters
+        #[arg(long, value_name = "CHARS", default_value_t = table::DEFAULT_MAX_NAME_WIDTH, requires = "top")]
+        max_name_width: usize,
+
+        /// Print the N most sampled complete callchains, leaf first
+        #[arg(long, value_name = "N")]
+        top_stacks: Option<usize>,
+
+        /// Print the samples as a caller-callee tree with inclusive percentages
+        #[arg(long)]
+        tree: bool,
+
+        /// Aggregate symbolized frames by offset ranges of this many bytes
+        /// within their function (e.g. my_func+0x40) instead of by function
+        #[arg(long, value_name = "BYTES", default_value_t = 0)]
+        bucket: u64,
+
+        /// Give up symbolizing after this many seconds and leave the remaining
+        /// addresses as hex (each module also gets at most 5 s to load)
+        #[arg(long, value_name = "SECS")]
+        symbolize_timeout: Option<u64>,
+
+        /// Report the share of samples whose leaf frame resolved to a symbol,
+        /// per module, to spot stripped binaries or missing debug info
+        #[arg(long)]
+        symbol_coverage: bool,
+
+        /// Collapse call tree nodes under this percentage of all samples (default 1),
+        /// and prune frames under it from folded exports
+        #[arg(long, value_name = "PERCENT")]
+        min_percent: Option<f64>,
+
+        /// Only keep samples of tasks running this command (repeatable), e.g. with --pid -1
+        #[arg(long = "comm", value_name = "NAME")]
+        comms: Vec<String>,
+
+        /// Match --comm names anywhere in the command instead of the whole command
+        #[arg(long, requires = "comms")]
+        comm_substring: bool,
+
+        /// Drop samples taken in the hypervisor and hypervisor frames of
+        /// callchains (useful inside VMs)
+        #[arg(long)]
+        exclude_hv: bool,
+
+        /// Whenever this signal arrives, write the stacks so far to a timestamped
+        /// binary profile (callchain-snapshot-<time>.prof) and keep sampling:
+        /// QUIT (the default), USR1, USR2 or HUP
+        #[arg(long, value_name = "SIGNAL", num_args = 0..=1, default_missing_value = "QUIT", value_parser = perf::parse_signal)]
+        snapshot_signal: Option<i32>,
+    },
+
+    /// Measure energy use per RAPL domain (package, cores, DRAM)
+    Power {
+        /// Duration in seconds to measure
+        #[arg(short, long, default_value = "5")]
+        duration: u64,
     },
 
     /// Read and decode a perf.data file containing tracepoint events
     Tracepoint {
-        /// Path to the perf.data file
+        /// Path to the perf.data file; several files are read and reported combined
+        #[arg(short, long, num_args = 1.., required = true)]
+        file: Vec<String>,
+
+        /// Append one line per record to this bounded, wrapping log file
+        #[arg(long, value_name = "PATH")]
+        debug_log: Option<PathBuf>,
+
+        /// Only count and decode samples of this event (repeatable; a trailing
+        /// `*` matches any suffix, e.g. `sched:*`)
+        #[arg(long = "event", value_name = "PATTERN")]
+        events: Vec<String>,
+
+        /// Print every sample as one JSON object per line (NDJSON) instead of the report
+        #[arg(long)]
+        json: bool,
+
+        /// Maximum fields decoded per sample (default: all with --json, 3 otherwise)
+        #[arg(long, value_name = "N")]
+        max_fields: Option<usize>,
+
+        /// Also decode TraceFS common fields (common_pid, common_flags, ...)
+        #[arg(long)]
+        include_common: bool,
+
+        /// Print every perf.data header instead of decoding events
+        #[arg(long)]
+        headers: bool,
+
+        /// List the build-id of every file recorded in the capture instead of decoding events
+        #[arg(long, conflicts_with = "headers")]
+        build_ids: bool,
+
+        /// Number of rows in the "Top Events" table
+        #[arg(long, value_name = "N", default_value_t = tracepoint::DEFAULT_TOP_EVENTS)]
+        top: usize,
+
+        /// With several files, also print a summary line per file
+        #[arg(long)]
+        per_file: bool,
+
+        /// Print the executables and libraries mapped during the capture
+        #[arg(long)]
+        modules: bool,
+
+        /// Cut event and module names in tables to this many characters
+        #[arg(long, value_name = "CHARS", default_value_t = table::DEFAULT_MAX_NAME_WIDTH)]
+        max_name_width: usize,
+
+        /// Summarize this numeric field over all samples (count, sum, min,
+        /// max, mean and approximate p50/p90/p99)
+        #[arg(long, value_name = "NAME")]
+        stat_field: Option<String>,
+
+        /// Skip samples earlier than this many nanoseconds after the first sample
+        #[arg(long, value_name = "NS")]
+        since: Option<u64>,
+
+        /// Stop at the first sample later than this many nanoseconds after the first sample
+        #[arg(long, value_name = "NS")]
+        until: Option<u64>,
+
+        /// Read --since and --until as absolute perf timestamps
+        #[arg(long)]
+        absolute_time: bool,
+
+        /// Also export the samples in this format to --export
+        #[arg(long, value_enum, default_value_t = TraceFormat::Text, requires = "export")]
+        format: TraceFormat,
+
+        /// File the --format export is written to (needs a --format other than text)
+        #[arg(long, value_name = "PATH")]
+        export: Option<PathBuf>,
+    },
+
+    /// Compute run-queue latency from a perf.data file with sched tracepoints
+    RunqLatency {
+        /// Path to a perf.data file recorded with sched:sched_wakeup and sched:sched_switch
         #[arg(short, long)]
         file: String,
     },
 
+    /// Measure the latency between paired start and end tracepoints in a perf.data file
+    Latency {
+        /// Path to a perf.data file recording both events
+        #[arg(short, long)]
+        file: String,
+
+        /// Event that starts an interval (e.g. syscalls:sys_enter_read)
+        #[arg(long, value_name = "EVENT")]
+        start: String,
+
+        /// Event that ends an interval (e.g. syscalls:sys_exit_read)
+        #[arg(long, value_name = "EVENT")]
+        end: String,
+
+        /// Integer field present in both events that pairs an end with its start
+        #[arg(long, value_name = "FIELD")]
+        key: String,
+    },
+
+    /// Compare two runs saved with `perf --format json`, counter by counter
+    Diff {
+        /// Result of the run to compare against (e.g. before a change)
+        #[arg(long, value_name = "PATH")]
+        base: PathBuf,
+
+        /// Result of the run being judged (e.g. after a change)
+        #[arg(long, value_name = "PATH")]
+        new: PathBuf,
+
+        /// Output format for the comparison; json is an array of
+        /// {metric, before, after, abs_delta, pct_delta, regression} objects
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+
+        /// Exit with status 4 if any counter or metric regressed
+        #[arg(long)]
+        fail_on_regression: bool,
+    },
+
+    /// Read folded stacks (`root;...;leaf count` lines) from any profiler, or a
+    /// binary profile, and render them with the callchain reports and exporters
+    Import {
+        /// Folded stacks file, e.g. from stackcollapse-perf.pl, or a profile
+        /// saved with `--format binary`
+        #[arg(short, long)]
+        file: PathBuf,
+
+        #[command(flatten)]
+        render: RenderArgs,
+    },
+
+    /// Sum the stacks of several saved profiles (folded or binary, e.g. one per
+    /// host) into one and render it like `import`
+    Merge {
+        /// Profiles to merge (at least two); their frames are matched by name
+        #[arg(short, long, num_args = 1.., required = true)]
+        file: Vec<PathBuf>,
+
+        #[command(flatten)]
+        render: RenderArgs,
+    },
+
     /// Show available hardware events
-    ListEvents,
+    ListEvents {
+        /// Print the events as a JSON array instead of a table
+        #[arg(long)]
+        json: bool,
+
+        /// Try to open each event and report whether it is usable here
+        #[arg(long)]
+        probe: bool,
+    },
+}
+
+/// Reports and exports of saved stacks, shared by `import` and `merge`.
+#[derive(Args)]
+struct RenderArgs {
+    /// Write the stacks to this file
+    #[arg(long, value_name = "PATH")]
+    export: Option<PathBuf>,
+
+    /// Format of the --export file
+    #[arg(long, value_enum, default_value_t = StackFormat::Folded, requires = "export")]
+    format: StackFormat,
+
+    /// Print the N functions with the most self samples, with self and total percentages
+    #[arg(long, value_name = "N")]
+    top: Option<usize>,
+
+    /// Cut function names in the --top table to this many characters
+    #[arg(long, value_name = "CHARS", default_value_t = table::DEFAULT_MAX_NAME_WIDTH, requires = "top")]
+    max_name_width: usize,
+
+    /// Print the N most sampled complete callchains, leaf first
+    #[arg(long, value_name = "N")]
+    top_stacks: Option<usize>,
+
+    /// Print the samples as a caller-callee tree with inclusive percentages
+    #[arg(long)]
+    tree: bool,
+
+    /// Collapse call tree nodes under this percentage of all samples (default 1),
+    /// and prune frames under it from folded exports
+    #[arg(long, value_name = "PERCENT")]
+    min_percent: Option<f64>,
+}
+
+/// Open the report sink: the file at `path` (truncated unless `append`), or stdout.
+fn open_report(path: Option<&Path>, append: bool) -> Result<Box<dyn Write>> {
+    let Some(path) = path else {
+        return Ok(Box::new(io::stdout()));
+    };
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .with_context(|| format!("Failed to open report file: {}", path.display()))?;
+    Ok(Box::new(BufWriter::new(file)))
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     let cli = Cli::parse();
+    let start_time = SystemTime::now();
+    let start = Instant::now();
 
-    match cli.command {
-        Commands::Perf { duration, pid } => {
-            perf::run_perf_profiler(duration, pid)?;
+    let mut out = open_report(cli.output.as_deref(), cli.append)?;
+    let mut written = Vec::new();
+    let code = run(cli.command, &mut *out, &mut written)?;
+    out.flush().context("Failed to write report")?;
+
+    if let Some(path) = &cli.manifest {
+        let mut manifest =
+            manifest::Manifest::new(std::env::args().collect(), start_time, start.elapsed());
+        if let Some(report) = &cli.output {
+            manifest.add_output(report, "report")?;
+        }
+        for (output, kind) in &written {
+            manifest.add_output(output, kind)?;
+        }
+        manifest::write_manifest(path, &manifest)?;
+        eprintln!("Manifest written to: {}", path.display());
+    }
+    Ok(code)
+}
+
+/// Name of a `--format` value, used as the type of its file in the manifest.
+fn format_name(format: impl ValueEnum) -> String {
+    format
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string())
+}
+
+/// Run a subcommand, writing its report to `out` and adding every other file
+/// it writes, with its type, to `written`.
+fn run(
+    command: Commands,
+    out: &mut dyn Write,
+    written: &mut Vec<(PathBuf, String)>,
+) -> Result<ExitCode> {
+    match command {
+        Commands::Perf {
+            duration,
+            pid,
+            tid,
+            thread_name,
+            baseline_pid,
+            perf_csv,
+            format,
+            while_exists,
+            cpus,
+            per_cpu,
+            inherit,
+            events,
+            interval,
+            alert_ipc_below,
+            alert_cache_miss_above,
+            fail_on_alert,
+            repeat,
+            command,
+        } => {
+            let cpus = cpus.as_deref().map(cpulist::parse_cpu_list).transpose()?;
+            let options = perf::PerfOptions {
+                duration_secs: duration.unwrap_or(DEFAULT_PERF_DURATION_SECS),
+                pid,
+                tid,
+                thread_name,
+                baseline_pid,
+                perf_csv,
+                format,
+                while_exists,
+                cpus,
+                per_cpu,
+                inherit,
+                events,
+                command_timeout: duration
+                    .filter(|_| !command.is_empty())
+                    .map(Duration::from_secs),
+                command,
+                interval: interval.map(Duration::from_millis),
+                alerts: perf::AlertThresholds {
+                    ipc_below: alert_ipc_below,
+                    cache_miss_above: alert_cache_miss_above,
+                },
+            };
+            let mut results = Vec::with_capacity(repeat as usize);
+            let mut alerted = false;
+            for run in 1..=repeat {
+                if repeat > 1 {
+                    eprintln!("Run {} of {}", run, repeat);
+                }
+                let result = perf::run_perf_profiler(&options, |progress| {
+                    perf::print_perf_progress(&options, &progress, out)?;
+                    if let perf::PerfProgress::Interval { elapsed, delta } = progress {
+                        for alert in perf::check_alerts(delta, &options.alerts) {
+                            eprintln!("*** ALERT at {:.3} s: {} ***", elapsed.as_secs_f64(), alert);
+                            alerted = true;
+                        }
+                    }
+                    Ok(())
+                })?;
+                let interrupted = result.interrupted;
+                results.push(result);
+                if interrupted {
+                    break;
+                }
+            }
+            let result = results.last().expect("--repeat is at least 1");
+            if repeat > 1 {
+                perf::print_profiling_summary(&perf::summarize(&results), out)?;
+            } else if perf_csv {
+                write!(out, "{}", perf::format_perf_csv(result))?;
+            } else if format == OutputFormat::Json {
+                writeln!(out, "{}", perf::format_json(result)?)?;
+            } else {
+                perf::print_profiling_result(result, out)?;
+            }
+            // Like `perf stat`, exit with the launched command's status, unless
+            // the duration ran out and the profiler terminated it
+            if let Some(code) = result
+                .exit_code
+                .filter(|&code| code != 0 && !result.command_timed_out)
+            {
+                return Ok(ExitCode::from(code.clamp(1, 255) as u8));
+            }
+            if fail_on_alert && alerted {
+                return Ok(ExitCode::from(EXIT_ALERT));
+            }
         }
         Commands::Callchain {
             duration,
             pid,
+            tid,
+            cpus,
             frequency,
+            period,
+            while_exists,
+            raw_records,
+            limit,
+            sparkline,
+            debug_log,
+            export,
+            format,
+            merge_threads: _,
+            split_threads,
+            page_count,
+            top,
+            max_name_width,
+            top_stacks,
+            tree,
+            bucket,
+            symbolize_timeout,
+            symbol_coverage,
+            min_percent,
+            comms,
+            comm_substring,
+            exclude_hv,
+            snapshot_signal,
         } => {
-            perf::run_callchain_profiler(duration, pid, frequency)?;
+            if split_threads && format != StackFormat::Folded {
+                anyhow::bail!("--split-threads only applies to --format folded");
+            }
+            perf::warn_missing_privileges();
+            let options = perf::CallchainOptions {
+                duration_secs: duration,
+                pid,
+                tid,
+                cpus: cpus.as_deref().map(cpulist::parse_cpu_list).transpose()?,
+                sampling: match period {
+                    Some(period) => perf::SamplingMode::Period(period),
+                    None => perf::SamplingMode::Frequency(frequency),
+                },
+                while_exists,
+                raw_records: raw_records.then_some(limit),
+                sparkline,
+                debug_log,
+                page_count,
+                comm_filter: (!comms.is_empty()).then(|| perf::CommFilter {
+                    names: comms,
+                    substring: comm_substring,
+                }),
+                exclude_hv,
+                bucket,
+                symbolize_timeout: symbolize_timeout.map(Duration::from_secs),
+                thread_mode: if split_threads {
+                    perf::ThreadMode::Split
+                } else {
+                    perf::ThreadMode::Merge
+                },
+                snapshot_signal,
+            };
+            perf::print_callchain_header(&options)?;
+            if let Some(path) = &options.debug_log {
+                written.push((path.clone(), "debug-log".to_string()));
+            }
+            let result = perf::run_callchain_profiler(&options)?;
+            for path in &result.snapshots {
+                written.push((path.clone(), format_name(StackFormat::Binary)));
+            }
+            perf::print_callchain_result(&result, out)?;
+            if !result.is_empty() && (top.is_some() || top_stacks.is_some() || tree) {
+                let names = result.function_names();
+                if let Some(n) = top {
+                    let stats = perf::top_functions(result.folded_stacks(), &names, n);
+                    perf::print_top_functions(&stats, max_name_width, out)?;
+                }
+                if let Some(n) = top_stacks {
+                    let stats = perf::top_stacks(result.folded_stacks(), &names, n);
+                    perf::print_top_stacks(&stats, out)?;
+                }
+                if tree {
+                    let call_tree = CallTree::from_stacks(result.folded_stacks(), &names);
+                    calltree::print_call_tree(
+                        &call_tree,
+                        min_percent.unwrap_or(calltree::DEFAULT_MIN_PERCENT),
+                        out,
+                    )?;
+                }
+            }
+            if !result.is_empty() && symbol_coverage {
+                let coverage =
+                    perf::symbol_coverage(result.folded_stacks(), result.resolve_symbols());
+                perf::print_symbol_coverage(&coverage, out)?;
+            }
+            if let Some(path) = export {
+                match format {
+                    StackFormat::Folded => {
+                        perf::write_folded_stacks(&path, &result, min_percent)?;
+                        eprintln!("Folded stacks written to: {}", path.display());
+                    }
+                    StackFormat::Pprof => {
+                        pprof::write_pprof(&path, &result)?;
+                        eprintln!("pprof profile written to: {}", path.display());
+                    }
+                    StackFormat::Speedscope => {
+                        speedscope::write_speedscope(&path, &result)?;
+                        eprintln!("speedscope profile written to: {}", path.display());
+                    }
+                    StackFormat::Binary => {
+                        profile::write_callchain_profile(&path, &result)?;
+                        eprintln!("Binary profile written to: {}", path.display());
+                    }
+                }
+                written.push((path, format_name(format)));
+            }
+            if result.is_empty() {
+                return Ok(ExitCode::from(EXIT_NO_SAMPLES));
+            }
         }
-        Commands::Tracepoint { file } => {
-            tracepoint::read_tracepoint_file(&file)?;
+        Commands::Power { duration } => match power::run_power_profiler(duration)? {
+            Some(result) => power::print_power_result(&result, out)?,
+            None => writeln!(
+                out,
+                "RAPL not available: no `power` PMU (common in virtual machines)"
+            )?,
+        },
+        Commands::Tracepoint {
+            file,
+            debug_log,
+            events,
+            json,
+            max_fields,
+            include_common,
+            headers,
+            build_ids,
+            top,
+            per_file,
+            modules,
+            max_name_width,
+            stat_field,
+            since,
+            until,
+            absolute_time,
+            format,
+            export,
+        } => {
+            if export.is_some() && format == TraceFormat::Text {
+                anyhow::bail!(
+                    "--export needs a file format, e.g. --format chrome; \
+                     the text report goes to --output"
+                );
+            }
+            if headers || build_ids {
+                for path in &file {
+                    if headers {
+                        tracepoint::print_headers(path, out)?;
+                    } else {
+                        tracepoint::print_build_ids(path, out)?;
+                    }
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+            if let (Some(since), Some(until)) = (since, until) {
+                if since > until {
+                    anyhow::bail!("--since ({}) is after --until ({})", since, until);
+                }
+            }
+            let options = tracepoint::TracepointOptions {
+                debug_log,
+                filter: (!events.is_empty()).then_some(events),
+                json,
+                max_fields,
+                include_common,
+                top,
+                per_file,
+                modules,
+                max_name_width: Some(max_name_width),
+                stat_field,
+                timeline: format == TraceFormat::Chrome,
+                window: tracepoint::TimeWindow {
+                    since,
+                    until,
+                    absolute: absolute_time,
+                },
+            };
+            if let Some(path) = &options.debug_log {
+                written.push((path.clone(), "debug-log".to_string()));
+            }
+            let stats = match file.as_slice() {
+                [path] => tracepoint::read_tracepoint_file(path, &options, out)?,
+                paths => tracepoint::read_tracepoint_files(paths, &options, out)?,
+            };
+            if let (TraceFormat::Chrome, Some(path)) = (format, export) {
+                tracepoint::write_chrome_trace(&path, &stats.timeline)?;
+                eprintln!("Chrome trace written to: {}", path.display());
+                written.push((path, format_name(format)));
+            }
+        }
+        Commands::RunqLatency { file } => {
+            runqlat::analyze_runq_latency(&file, out)?;
+        }
+        Commands::Latency {
+            file,
+            start,
+            end,
+            key,
+        } => {
+            latency::analyze_latency(&file, &start, &end, &key, out)?;
+        }
+        Commands::Diff {
+            base,
+            new,
+            format,
+            fail_on_regression,
+        } => {
+            let diff = diff::diff_results(&diff::load_result(&base)?, &diff::load_result(&new)?);
+            if format == OutputFormat::Json {
+                writeln!(out, "{}", diff::format_json(&diff)?)?;
+            } else {
+                diff::print_diff(&diff, out)?;
+            }
+            if fail_on_regression && diff.has_regression() {
+                return Ok(ExitCode::from(EXIT_REGRESSION));
+            }
+        }
+        Commands::Import { file, render } => {
+            let imported = profile::read_stacks(&file)?;
+            folded::print_folded_summary(&imported, out)?;
+            return render_stacks(&imported, render, out, written);
+        }
+        Commands::Merge { file, render } => {
+            if file.len() < 2 {
+                anyhow::bail!("merge needs at least two profiles, got {}", file.len());
+            }
+            let mut merged = folded::FoldedStacks::default();
+            let mut sources = Vec::with_capacity(file.len());
+            for path in &file {
+                let stacks = profile::read_stacks(path)?;
+                sources.push(folded::MergeSource::new(path, &stacks));
+                merged.merge(&stacks);
+            }
+            folded::print_merge_summary(&sources, &merged, out)?;
+            return render_stacks(&merged, render, out, written);
         }
-        Commands::ListEvents => {
-            perf::list_available_events();
+        Commands::ListEvents { json, probe } => {
+            if probe {
+                let probes = perf::probe_events();
+                if json {
+                    writeln!(out, "{}", perf::probe_json(&probes)?)?;
+                } else {
+                    perf::print_probe_table(&probes, out)?;
+                }
+            } else if json {
+                writeln!(out, "{}", perf::events_json()?)?;
+            } else {
+                perf::list_available_events(out)?;
+            }
         }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Print the requested reports of saved stacks and write their export, adding
+/// it to `written`.
+fn render_stacks(
+    imported: &folded::FoldedStacks,
+    render: RenderArgs,
+    out: &mut dyn Write,
+    written: &mut Vec<(PathBuf, String)>,
+) -> Result<ExitCode> {
+    let RenderArgs {
+        export,
+        format,
+        top,
+        max_name_width,
+        top_stacks,
+        tree,
+        min_percent,
+    } = render;
+    let (stacks, names) = (&imported.stacks, &imported.names);
+    if let Some(n) = top {
+        let stats = perf::top_functions(stacks, names, n);
+        perf::print_top_functions(&stats, max_name_width, out)?;
+    }
+    if let Some(n) = top_stacks {
+        perf::print_top_stacks(&perf::top_stacks(stacks, names, n), out)?;
+    }
+    if tree {
+        let call_tree = CallTree::from_stacks(stacks, names);
+        calltree::print_call_tree(
+            &call_tree,
+            min_percent.unwrap_or(calltree::DEFAULT_MIN_PERCENT),
+            out,
+        )?;
+    }
+    if let Some(path) = export {
+        match format {
+            StackFormat::Folded => {
+                perf::write_folded(&path, stacks, names, min_percent)?;
+                eprintln!("Folded stacks written to: {}", path.display());
+            }
+            StackFormat::Pprof => {
+                pprof::write_stacks(&path, stacks, names, 0, 0)?;
+                eprintln!("pprof profile written to: {}", path.display());
+            }
+            StackFormat::Speedscope => {
+                speedscope::write_stacks(&path, stacks, names)?;
+                eprintln!("speedscope profile written to: {}", path.display());
+            }
+            StackFormat::Binary => {
+                profile::write_profile(&path, stacks, names)?;
+                eprintln!("Binary profile written to: {}", path.display());
+            }
+        }
+        written.push((path, format_name(format)));
+    }
+    if imported.stacks.is_empty() {
+        return Ok(ExitCode::from(EXIT_NO_SAMPLES));
+    }
+    Ok(ExitCode::SUCCESS)
 }
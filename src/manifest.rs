@@ -0,0 +1,102 @@
+//! Run manifest module.
+//!
+//! This module describes the files a run wrote, with their type and size, and
+//! the run itself as a small JSON document, so automation can find and upload
+//! the artifacts without knowing their names in advance.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A file written by the run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OutputFile {
+    pub path: PathBuf,
+    /// What the file holds, e.g. `report`, `folded` or `chrome-trace`
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Size in bytes once the run finished
+    pub size: u64,
+}
+
+/// The files a run wrote and how it was run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Manifest {
+    pub profiler_version: &'static str,
+    /// Command line of the run, program name first
+    pub command: Vec<String>,
+    /// When the run started, in seconds since the Unix epoch
+    pub start_time: u64,
+    pub duration_secs: f64,
+    pub outputs: Vec<OutputFile>,
+}
+
+impl Manifest {
+    /// Describe a run of `command` that started at `start` and took `duration`.
+    pub fn new(command: Vec<String>, start: SystemTime, duration: Duration) -> Self {
+        Manifest {
+            profiler_version: env!("CARGO_PKG_VERSION"),
+            command,
+            start_time: start
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            duration_secs: duration.as_secs_f64(),
+            outputs: Vec::new(),
+        }
+    }
+
+    /// List a file the run wrote, with its current size.
+    pub fn add_output(&mut self, path: &Path, kind: &str) -> Result<()> {
+        let size = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read output file: {}", path.display()))?
+            .len();
+        self.outputs.push(OutputFile {
+            path: path.to_path_buf(),
+            kind: kind.to_string(),
+            size,
+        });
+        Ok(())
+    }
+}
+
+/// Write the manifest to `path` as pretty-printed JSON.
+pub fn write_manifest(path: &Path, manifest: &Manifest) -> Result<()> {
+    let json = serde_json::to_string_pretty(manifest).context("Failed to serialize manifest")?;
+    std::fs::write(path, json + "\n")
+        .with_context(|| format!("Failed to write manifest: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let path =
+            std::env::temp_dir().join(format!("profiler-test-{}.folded", std::process::id()));
+        std::fs::write(&path, "main;run 5\n").unwrap();
+
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut manifest = Manifest::new(
+            vec!["profiler".to_string(), "import".to_string()],
+            start,
+            Duration::from_millis(1500),
+        );
+        manifest.add_output(&path, "folded").unwrap();
+        assert!(manifest
+            .add_output(Path::new("/nonexistent/report.txt"), "report")
+            .is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        let json = serde_json::to_value(&manifest).unwrap();
+        assert_eq!(json["start_time"], 1_700_000_000);
+        assert_eq!(json["duration_secs"], 1.5);
+        assert_eq!(json["command"][1], "import");
+        let outputs = json["outputs"].as_array().unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0]["path"], path.to_str().unwrap());
+        assert_eq!(outputs[0]["type"], "folded");
+        assert_eq!(outputs[0]["size"], 11);
+    }
+}
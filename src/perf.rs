@@ -4,19 +4,51 @@
 //! using the Linux perf_event subsystem, as well as CPU profiling with callchain/stacktrace
 //! support using microsoft/one-collect.
 
+use crate::calltree::{self, CallTree};
+use crate::cpulist;
+use crate::debuglog::{self, DebugLog};
+use crate::histogram::{self, Log2Histogram};
+use crate::interrupt::{InterruptScope, SnapshotSignal};
+use crate::launch::HeldChild;
+use crate::profile;
+use crate::symbols::{self, MemoryMap, ResolvedFrame, Symbolizer};
+use crate::table::{Align, Table};
+use crate::tracepoint;
 use anyhow::{Context, Result};
+use clap::ValueEnum;
 use one_collect::perf_event::{RingBufBuilder, RingBufOptions, RingBufSessionBuilder};
-use perf_event::events::Hardware;
-use perf_event::{Builder, Group};
-use std::cell::Cell;
+use perf_event::events::{Event, Hardware, Software};
+use perf_event::{Builder, Counter, Group};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often gating conditions such as `--while-exists` are re-checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Kind of counter an event is backed by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventCategory {
+    /// Counted by the CPU's performance monitoring unit
+    Hardware,
+    /// Counted by the kernel
+    Software,
+}
 
 /// Available hardware performance events that can be monitored.
+#[derive(Debug, Serialize)]
 pub struct PerfEvent {
     pub name: &'static str,
     pub description: &'static str,
+    pub category: EventCategory,
 }
 
 /// List of commonly available hardware performance events.
@@ -24,352 +56,4872 @@ pub const HARDWARE_EVENTS: &[PerfEvent] = &[
     PerfEvent {
         name: "cpu-cycles",
         description: "Total CPU cycles",
+        category: EventCategory::Hardware,
     },
     PerfEvent {
         name: "instructions",
         description: "Retired instructions",
+        category: EventCategory::Hardware,
     },
     PerfEvent {
         name: "cache-references",
         description: "Cache references",
+        category: EventCategory::Hardware,
     },
     PerfEvent {
         name: "cache-misses",
         description: "Cache misses",
+        category: EventCategory::Hardware,
     },
     PerfEvent {
         name: "branch-instructions",
         description: "Branch instructions",
+        category: EventCategory::Hardware,
     },
     PerfEvent {
         name: "branch-misses",
         description: "Branch mispredictions",
+        category: EventCategory::Hardware,
+    },
+];
+
+/// List of software events maintained by the kernel.
+///
+/// These are counted by the kernel rather than the PMU, so they never compete
+/// for hardware counters.
+pub const SOFTWARE_EVENTS: &[PerfEvent] = &[
+    PerfEvent {
+        name: "context-switches",
+        description: "Context switches",
+        category: EventCategory::Software,
+    },
+    PerfEvent {
+        name: "cpu-migrations",
+        description: "Task migrations between CPUs",
+        category: EventCategory::Software,
+    },
+    PerfEvent {
+        name: "page-faults",
+        description: "Page faults (minor and major)",
+        category: EventCategory::Software,
+    },
+    PerfEvent {
+        name: "minor-faults",
+        description: "Page faults served without I/O",
+        category: EventCategory::Software,
+    },
+    PerfEvent {
+        name: "major-faults",
+        description: "Page faults that required I/O",
+        category: EventCategory::Software,
+    },
+    PerfEvent {
+        name: "alignment-faults",
+        description: "Misaligned memory access faults",
+        category: EventCategory::Software,
+    },
+    PerfEvent {
+        name: "emulation-faults",
+        description: "Instructions emulated by the kernel",
+        category: EventCategory::Software,
     },
 ];
 
-/// Print a list of available hardware events.
-pub fn list_available_events() {
-    println!("Available hardware performance events:");
-    println!("{:-<50}", "");
+/// Print a list of available hardware and software events.
+pub fn list_available_events(out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "Available hardware performance events:")?;
+    writeln!(out, "{:-<50}", "")?;
     for event in HARDWARE_EVENTS {
-        println!("  {:<25} - {}", event.name, event.description);
+        writeln!(out, "  {:<25} - {}", event.name, event.description)?;
     }
-    println!();
-    println!("Note: Availability depends on your CPU and kernel configuration.");
-    println!("Some events may require root privileges or specific perf_event_paranoid settings.");
+    writeln!(out)?;
+    writeln!(
+        out,
+        "Available software events (counted by the kernel, never multiplexed):"
+    )?;
+    writeln!(out, "{:-<50}", "")?;
+    for event in SOFTWARE_EVENTS {
+        writeln!(out, "  {:<25} - {}", event.name, event.description)?;
+    }
+    writeln!(out)?;
+    writeln!(
+        out,
+        "Note: Availability depends on your CPU and kernel configuration."
+    )?;
+    writeln!(
+        out,
+        "Some events may require root privileges or specific perf_event_paranoid settings."
+    )?;
+    Ok(())
 }
 
-/// Results from a perf profiling session.
-#[derive(Debug)]
-pub struct ProfilingResult {
-    pub cpu_cycles: u64,
-    pub instructions: u64,
-    pub cache_references: u64,
-    pub cache_misses: u64,
-    pub duration_secs: u64,
+/// Serialize all known hardware and software events as a JSON array.
+pub fn events_json() -> Result<String> {
+    let events: Vec<&PerfEvent> = HARDWARE_EVENTS.iter().chain(SOFTWARE_EVENTS).collect();
+    serde_json::to_string_pretty(&events).context("Failed to serialize event list")
 }
 
-impl ProfilingResult {
-    /// Calculate instructions per cycle (IPC).
-    pub fn ipc(&self) -> f64 {
-        if self.cpu_cycles == 0 {
-            0.0
-        } else {
-            self.instructions as f64 / self.cpu_cycles as f64
+/// Outcome of trying to open an event on this machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ProbeStatus {
+    /// The event could be opened
+    Ok,
+    /// The CPU or kernel does not provide the event
+    Unsupported,
+    /// The event exists but the current privileges do not allow opening it
+    Permission,
+}
+
+impl ProbeStatus {
+    fn label(self) -> &'static str {
+        match self {
+            ProbeStatus::Ok => "OK",
+            ProbeStatus::Unsupported => "UNSUPPORTED",
+            ProbeStatus::Permission => "PERMISSION",
         }
     }
+}
 
-    /// Calculate cache miss rate.
-    pub fn cache_miss_rate(&self) -> f64 {
-        if self.cache_references == 0 {
-            0.0
-        } else {
-            self.cache_misses as f64 / self.cache_references as f64 * 100.0
-        }
+/// Availability of one event as determined by [`probe_events`].
+#[derive(Debug, Serialize)]
+pub struct EventProbe {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub category: EventCategory,
+    pub status: ProbeStatus,
+    pub available: bool,
+    pub reason: String,
+}
+
+/// Map a listed event name to the perf event it is backed by.
+fn event_kind(name: &str) -> Option<Event> {
+    let kind = match name {
+        "cpu-cycles" => Hardware::CPU_CYCLES.into(),
+        "instructions" => Hardware::INSTRUCTIONS.into(),
+        "cache-references" => Hardware::CACHE_REFERENCES.into(),
+        "cache-misses" => Hardware::CACHE_MISSES.into(),
+        "branch-instructions" => Hardware::BRANCH_INSTRUCTIONS.into(),
+        "branch-misses" => Hardware::BRANCH_MISSES.into(),
+        "context-switches" => Software::CONTEXT_SWITCHES.into(),
+        "cpu-migrations" => Software::CPU_MIGRATIONS.into(),
+        "page-faults" => Software::PAGE_FAULTS.into(),
+        "minor-faults" => Software::PAGE_FAULTS_MIN.into(),
+        "major-faults" => Software::PAGE_FAULTS_MAJ.into(),
+        "alignment-faults" => Software::ALIGNMENT_FAULTS.into(),
+        "emulation-faults" => Software::EMULATION_FAULTS.into(),
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// Classify a failure to open an event into a status and a short reason.
+fn classify_open_error(err: &io::Error) -> (ProbeStatus, String) {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => (
+            ProbeStatus::Permission,
+            "not permitted (check perf_event_paranoid)".to_string(),
+        ),
+        io::ErrorKind::NotFound => (
+            ProbeStatus::Unsupported,
+            "not provided by this CPU or kernel".to_string(),
+        ),
+        _ => (ProbeStatus::Unsupported, err.to_string()),
     }
+}
 
-    /// Calculate CPU cycles per second.
-    pub fn cycles_per_second(&self) -> f64 {
-        if self.duration_secs == 0 {
-            0.0
-        } else {
-            self.cpu_cycles as f64 / self.duration_secs as f64
-        }
+/// Try to open every listed event for the current process.
+///
+/// Events that opened successfully come first; the table order is kept otherwise.
+pub fn probe_events() -> Vec<EventProbe> {
+    let mut probes: Vec<EventProbe> = HARDWARE_EVENTS
+        .iter()
+        .chain(SOFTWARE_EVENTS)
+        .map(|event| {
+            let opened = match event_kind(event.name) {
+                Some(kind) => Builder::new().kind(kind).build().map(|_| ()),
+                None => Err(io::Error::from(io::ErrorKind::NotFound)),
+            };
+            let (status, reason) = match opened {
+                Ok(()) => (ProbeStatus::Ok, String::new()),
+                Err(e) => classify_open_error(&e),
+            };
+            EventProbe {
+                name: event.name,
+                description: event.description,
+                category: event.category,
+                status,
+                available: status == ProbeStatus::Ok,
+                reason,
+            }
+        })
+        .collect();
+    probes.sort_by_key(|probe| !probe.available);
+    probes
+}
+
+/// Print probe results as an aligned Name/Category/Status/Reason table.
+pub fn print_probe_table(probes: &[EventProbe], out: &mut dyn Write) -> io::Result<()> {
+    let mut table = Table::new(&[
+        ("Name", Align::Name),
+        ("Category", Align::Left),
+        ("Status", Align::Left),
+        ("Reason", Align::Left),
+    ]);
+    for probe in probes {
+        let category = match probe.category {
+            EventCategory::Hardware => "hardware",
+            EventCategory::Software => "software",
+        };
+        table.push_row(vec![
+            probe.name.to_string(),
+            category.to_string(),
+            probe.status.label().to_string(),
+            probe.reason.clone(),
+        ]);
     }
+    table.render(out)
 }
 
-/// Run the perf profiler for a specified duration.
+/// Serialize probe results as a JSON array.
+pub fn probe_json(probes: &[EventProbe]) -> Result<String> {
+    serde_json::to_string_pretty(probes).context("Failed to serialize probe results")
+}
+
+/// Read an integer sysctl value from procfs, returning `None` if unavailable.
+fn read_sysctl(path: &str) -> Option<i32> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// `CAP_SYS_ADMIN` bit in a capability set, which bypasses `perf_event_paranoid`.
+const CAP_SYS_ADMIN: u32 = 21;
+
+/// `CAP_PERFMON` bit in a capability set, the perf-only subset of `CAP_SYS_ADMIN`.
+const CAP_PERFMON: u32 = 38;
+
+/// Whether `/proc/<pid>/status` text shows an effective capability that lifts
+/// the `perf_event_paranoid` limits.
+///
+/// The kernel checks capabilities rather than the UID, so root without them
+/// (in a container, say) is held to `perf_event_paranoid` like anyone else.
+fn status_privileged(status: &str) -> bool {
+    status_capabilities(status) & (1 << CAP_PERFMON | 1 << CAP_SYS_ADMIN) != 0
+}
+
+/// Effective capability set in `/proc/<pid>/status` text; empty if missing.
+fn status_capabilities(status: &str) -> u64 {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .unwrap_or(0)
+}
+
+/// Whether the profiler runs with `CAP_PERFMON` or `CAP_SYS_ADMIN`.
+fn is_privileged() -> bool {
+    std::fs::read_to_string("/proc/self/status")
+        .map(|status| status_privileged(&status))
+        .unwrap_or(false)
+}
+
+/// Highest `kernel.perf_event_paranoid` allowing unprivileged users to open
+/// events on every process of a CPU.
+const PARANOID_SYSTEM_WIDE: i32 = 0;
+
+/// Highest `kernel.perf_event_paranoid` allowing unprivileged users to open
+/// events on their own processes; above it perf is disabled for them entirely.
+const PARANOID_OWN_PROCESS: i32 = 2;
+
+/// Fail with an actionable message if `perf_event_paranoid` forbids the
+/// requested collection, instead of a bare EACCES when counters are built.
 ///
 /// # Arguments
 ///
-/// * `duration_secs` - Duration in seconds to collect performance data
-/// * `_pid` - Target process ID (currently unused, always profiles current process)
+/// * `paranoid` - Value of `kernel.perf_event_paranoid`, if readable
+/// * `privileged` - Whether the profiler has `CAP_PERFMON` or `CAP_SYS_ADMIN`
+/// * `need_system_wide` - Whether all processes on the system will be profiled
+/// * `need_callchains` - Whether callchains will be sampled rather than events counted
+fn check_permissions(
+    paranoid: Option<i32>,
+    privileged: bool,
+    need_system_wide: bool,
+    need_callchains: bool,
+) -> Result<()> {
+    let Some(paranoid) = paranoid.filter(|_| !privileged) else {
+        return Ok(());
+    };
+    let required = if need_system_wide {
+        PARANOID_SYSTEM_WIDE
+    } else {
+        PARANOID_OWN_PROCESS
+    };
+    if paranoid <= required {
+        return Ok(());
+    }
+
+    let operation = match (need_system_wide, need_callchains) {
+        (true, true) => "System-wide callchain sampling",
+        (true, false) => "System-wide counting",
+        (false, true) => "Callchain sampling",
+        (false, false) => "Counting",
+    };
+    anyhow::bail!(
+        "{} requires kernel.perf_event_paranoid <= {} for non-root users, but it is {}.\n\
+         Run the profiler as root (sudo), or allow it with:\n    \
+         sudo sysctl kernel.perf_event_paranoid={}",
+        operation,
+        required,
+        paranoid,
+        required
+    )
+}
+
+/// Check up front that `perf_event_paranoid` allows the requested collection.
 ///
-/// # Returns
+/// # Arguments
 ///
-/// Returns a `ProfilingResult` containing the collected performance counters.
+/// * `need_system_wide` - Whether all processes on the system will be profiled
+/// * `need_callchains` - Whether callchains will be sampled rather than events counted
+pub fn check_perf_permissions(need_system_wide: bool, need_callchains: bool) -> Result<()> {
+    check_permissions(
+        read_sysctl("/proc/sys/kernel/perf_event_paranoid"),
+        is_privileged(),
+        need_system_wide,
+        need_callchains,
+    )
+}
+
+/// Build the list of privilege problems that limit callchains without
+/// preventing collection; see [`check_permissions`] for the ones that do.
 ///
-/// # Note
+/// # Arguments
 ///
-/// Currently only profiles the current process. PID targeting is not yet implemented.
-pub fn run_perf_profiler(duration_secs: u64, _pid: i32) -> Result<ProfilingResult> {
-    println!("Starting perf profiler...");
-    println!("Duration: {} seconds", duration_secs);
-    println!("Target: Current process (PID targeting not yet implemented)");
-    println!();
+/// * `paranoid` - Value of `kernel.perf_event_paranoid`, if readable
+/// * `kptr_restrict` - Value of `kernel.kptr_restrict`, if readable
+/// * `privileged` - Whether the profiler has `CAP_PERFMON` or `CAP_SYS_ADMIN`
+/// * `callchains` - Whether callchains (including kernel frames) will be collected
+fn privilege_warnings(
+    paranoid: Option<i32>,
+    kptr_restrict: Option<i32>,
+    privileged: bool,
+    callchains: bool,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if privileged || !callchains {
+        return warnings;
+    }
 
-    // Create a group to collect multiple counters atomically
-    let mut group = Group::new().context("Failed to create perf event group")?;
+    // Above PARANOID_OWN_PROCESS collection fails altogether, see check_permissions
+    if let Some(paranoid) = paranoid.filter(|&p| p > 1 && p <= PARANOID_OWN_PROCESS) {
+        warnings.push(format!(
+            "kernel callchain frames require perf_event_paranoid <= 1, current is {}; \
+             only user-space frames will be collected",
+            paranoid
+        ));
+    }
+    if let Some(kptr_restrict) = kptr_restrict.filter(|&k| k > 0) {
+        warnings.push(format!(
+            "kernel symbols are hidden by kptr_restrict = {}; \
+             run as root or set kernel.kptr_restrict=0 to resolve kernel frames",
+            kptr_restrict
+        ));
+    }
 
-    // Set up hardware counters
-    let cycles = Builder::new()
-        .group(&mut group)
-        .kind(Hardware::CPU_CYCLES)
-        .build()
-        .context("Failed to create CPU cycles counter")?;
+    warnings
+}
 
-    let instructions = Builder::new()
-        .group(&mut group)
-        .kind(Hardware::INSTRUCTIONS)
-        .build()
-        .context("Failed to create instructions counter")?;
+/// Warn on stderr about missing privileges that limit callchain collection.
+pub fn warn_missing_privileges() {
+    let warnings = privilege_warnings(
+        read_sysctl("/proc/sys/kernel/perf_event_paranoid"),
+        read_sysctl("/proc/sys/kernel/kptr_restrict"),
+        is_privileged(),
+        true,
+    );
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+}
 
-    let cache_refs = Builder::new()
-        .group(&mut group)
-        .kind(Hardware::CACHE_REFERENCES)
-        .build()
-        .context("Failed to create cache references counter")?;
+/// Ensure a `--while-exists` sentinel file is present before collection starts.
+fn check_sentinel(sentinel: Option<&Path>) -> Result<()> {
+    match sentinel {
+        Some(path) if !path.exists() => {
+            anyhow::bail!("Sentinel file does not exist: {}", path.display())
+        }
+        _ => Ok(()),
+    }
+}
 
-    let cache_misses = Builder::new()
-        .group(&mut group)
-        .kind(Hardware::CACHE_MISSES)
-        .build()
-        .context("Failed to create cache misses counter")?;
+/// Whether collection should stop because the sentinel file was removed.
+fn sentinel_removed(sentinel: Option<&Path>) -> bool {
+    sentinel.is_some_and(|path| !path.exists())
+}
 
-    // Enable counters and collect data
-    println!("Collecting performance data...");
-    group.enable().context("Failed to enable perf counters")?;
+/// Read the state letter (`R`, `S`, `Z`...) from `/proc/<pid>/stat` text.
+fn parse_stat_state(stat: &str) -> Option<char> {
+    // The command in parentheses may itself contain spaces and parentheses
+    let (_, rest) = stat.rsplit_once(')')?;
+    rest.trim_start().chars().next()
+}
 
-    // Sleep for the specified duration while counters are active
-    thread::sleep(Duration::from_secs(duration_secs));
+/// Whether every task in `tasks` has exited: it is gone from `/proc` or is a
+/// zombie waiting to be reaped. False when there are no tasks to watch.
+fn tasks_exited(tasks: &[i32]) -> bool {
+    !tasks.is_empty()
+        && tasks.iter().all(
+            |task| match std::fs::read_to_string(format!("/proc/{}/stat", task)) {
+                Ok(stat) => matches!(parse_stat_state(&stat), Some('Z' | 'X')),
+                Err(_) => true,
+            },
+        )
+}
 
-    group.disable().context("Failed to disable perf counters")?;
+/// Sleep until `max_duration` elapses, the sentinel file disappears, every
+/// task of `watched` exits, or Ctrl-C is pressed.
+///
+/// Returns the time actually spent waiting.
+fn wait_for_duration(
+    max_duration: Duration,
+    sentinel: Option<&Path>,
+    watched: &[i32],
+    interrupt: &InterruptScope,
+) -> Duration {
+    let start = Instant::now();
+    while start.elapsed() < max_duration
+        && !sentinel_removed(sentinel)
+        && !tasks_exited(watched)
+        && !interrupt.interrupted()
+    {
+        thread::sleep(POLL_INTERVAL.min(max_duration.saturating_sub(start.elapsed())));
+    }
+    start.elapsed()
+}
 
-    // Read the counter values
-    let counts = group.read().context("Failed to read perf counters")?;
+/// Like [`wait_for_duration`], but calls `report` every `interval` with the time
+/// since waiting started, and once more for the final partial interval.
+///
+/// Returns the time actually spent waiting.
+fn wait_with_intervals(
+    max_duration: Duration,
+    interval: Duration,
+    sentinel: Option<&Path>,
+    watched: &[i32],
+    interrupt: &InterruptScope,
+    mut report: impl FnMut(Duration) -> Result<()>,
+) -> Result<Duration> {
+    let start = Instant::now();
+    // Deadlines are multiples of the interval, so reports don't drift
+    let mut next_report = interval;
+    loop {
+        let deadline = next_report.min(max_duration);
+        wait_for_duration(
+            deadline.saturating_sub(start.elapsed()),
+            sentinel,
+            watched,
+            interrupt,
+        );
+        let elapsed = start.elapsed();
+        report(elapsed)?;
+        if elapsed >= max_duration
+            || sentinel_removed(sentinel)
+            || tasks_exited(watched)
+            || interrupt.interrupted()
+        {
+            return Ok(elapsed);
+        }
+        next_report += interval;
+    }
+}
 
-    let result = ProfilingResult {
-        cpu_cycles: counts[&cycles],
-        instructions: counts[&instructions],
-        cache_references: counts[&cache_refs],
-        cache_misses: counts[&cache_misses],
-        duration_secs,
-    };
+/// Number of leading bytes shown by `--raw-records`.
+const RAW_PREVIEW_BYTES: usize = 32;
 
-    // Print results
-    println!();
-    println!("Profiling Results:");
-    println!("{:=<50}", "");
-    println!("  CPU Cycles:        {:>15}", result.cpu_cycles);
-    println!("  Instructions:      {:>15}", result.instructions);
-    println!("  Cache References:  {:>15}", result.cache_references);
-    println!("  Cache Misses:      {:>15}", result.cache_misses);
-    println!("{:-<50}", "");
-    println!("  IPC:               {:>15.3}", result.ipc());
-    println!("  Cache Miss Rate:   {:>14.2}%", result.cache_miss_rate());
-    println!("{:=<50}", "");
+/// Format the first `max_bytes` of a record as space-separated hex.
+pub fn hex_preview(bytes: &[u8], max_bytes: usize) -> String {
+    let mut preview = bytes
+        .iter()
+        .take(max_bytes)
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if bytes.len() > max_bytes {
+        preview.push_str(" ...");
+    }
+    preview
+}
 
-    Ok(result)
+/// Results from a perf profiling session.
+///
+/// Deserializing reads back the output of `--format json`; the derived metrics
+/// in it are ignored and recomputed from the counts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProfilingResult {
+    /// Count per event name; optional events the kernel refused to open are absent
+    #[serde(deserialize_with = "deserialize_counts")]
+    pub counts: BTreeMap<&'static str, u64>,
+    /// Time the counter group was enabled, in nanoseconds
+    pub time_enabled_ns: u64,
+    /// Time the counter group was actually running on the PMU, in nanoseconds
+    pub time_running_ns: u64,
+    /// Whether the counters shared the PMU and ran for only part of the time
+    pub multiplexed: bool,
+    /// Whether counts were scaled by `time_enabled / time_running` to compensate
+    pub scaled: bool,
+    /// Time measured, in seconds; fractional so short runs are not rounded to zero
+    pub duration_secs: f64,
+    /// Process that was measured; `-1` when counting all processes on a set of CPUs
+    pub pid: i32,
+    /// Exit code of the command launched with `perf -- <command>`
+    pub exit_code: Option<i32>,
+    /// Thread that was measured when counting was scoped with `--tid`; `pid` is its process
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tid: Option<i32>,
+    /// CPU these counts belong to, for the entries of `per_cpu`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<usize>,
+    /// Per-CPU breakdown of a CPU-wide run, in CPU order; empty otherwise
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub per_cpu: Vec<ProfilingResult>,
+    /// Whether Ctrl-C stopped the run early
+    #[serde(skip)]
+    pub interrupted: bool,
+    /// Whether the run stopped early because the target exited
+    #[serde(skip)]
+    pub target_exited: bool,
+    /// Whether a launched command was still running when its timeout ran out
+    /// and was terminated, rather than exiting on its own
+    #[serde(skip)]
+    pub command_timed_out: bool,
+    /// Time actually measured, when the run's length was not fixed by its
+    /// duration (sentinel file, launched command, target exit or Ctrl-C)
+    #[serde(skip)]
+    pub active_duration: Option<Duration>,
+    /// Threads matched by `--thread-name`, whose counts were summed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub threads: Vec<ThreadMatch>,
+    /// Counts of the `--baseline-pid` control process over the same window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<Box<ProfilingResult>>,
 }
 
-/// Results from a CPU profiling session with callchain/stacktrace data.
-#[derive(Debug, Default)]
-pub struct CallchainProfilingResult {
-    /// Total number of samples collected
-    pub sample_count: u64,
-    /// Duration of the profiling session in seconds
-    pub duration_secs: u64,
-    /// Sampling frequency used (Hz)
-    pub sampling_frequency: u64,
+impl ProfilingResult {
+    /// Count collected for an event, if it was selected and could be opened.
+    pub fn count(&self, name: &str) -> Option<u64> {
+        self.counts.get(name).copied()
+    }
+
+    /// Calculate instructions per cycle (IPC), if both events were counted.
+    pub fn ipc(&self) -> Option<f64> {
+        let cycles = self.count("cpu-cycles")?;
+        let instructions = self.count("instructions")?;
+        Some(if cycles == 0 {
+            0.0
+        } else {
+            instructions as f64 / cycles as f64
+        })
+    }
+
+    /// Calculate cache miss rate, if both cache events were counted.
+    pub fn cache_miss_rate(&self) -> Option<f64> {
+        let references = self.count("cache-references")?;
+        let misses = self.count("cache-misses")?;
+        Some(if references == 0 {
+            0.0
+        } else {
+            misses as f64 / references as f64 * 100.0
+        })
+    }
+
+    /// Calculate cache misses per thousand instructions (MPKI), if both events were counted.
+    pub fn cache_mpki(&self) -> Option<f64> {
+        self.per_kilo_instruction("cache-misses")
+    }
+
+    /// Calculate branch misses per thousand instructions, if both events were counted.
+    pub fn branch_mpki(&self) -> Option<f64> {
+        self.per_kilo_instruction("branch-misses")
+    }
+
+    /// Count of an event per thousand instructions, if both were counted.
+    fn per_kilo_instruction(&self, name: &str) -> Option<f64> {
+        let count = self.count(name)?;
+        let instructions = self.count("instructions")?;
+        Some(if instructions == 0 {
+            0.0
+        } else {
+            count as f64 / instructions as f64 * 1000.0
+        })
+    }
+
+    /// Calculate CPU cycles per second, if cycles were counted.
+    pub fn cycles_per_second(&self) -> Option<f64> {
+        let cycles = self.count("cpu-cycles")?;
+        Some(if self.duration_secs <= 0.0 {
+            0.0
+        } else {
+            cycles as f64 / self.duration_secs
+        })
+    }
+
+    /// Counts minus those of the baseline process, clamped at zero, if a
+    /// baseline was measured.
+    pub fn adjusted_counts(&self) -> Option<BTreeMap<&'static str, u64>> {
+        let baseline = self.baseline.as_ref()?;
+        Some(
+            self.counts
+                .iter()
+                .map(|(&name, &count)| {
+                    (
+                        name,
+                        count.saturating_sub(baseline.count(name).unwrap_or(0)),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// Collected counters as `(event name, count)` pairs, in display order.
+    pub fn counters(&self) -> Vec<(&'static str, u64)> {
+        HARDWARE_EVENTS
+            .iter()
+            .chain(SOFTWARE_EVENTS)
+            .filter_map(|event| self.count(event.name).map(|count| (event.name, count)))
+            .collect()
+    }
 }
 
-/// Run CPU profiler with callchain/stacktrace collection using microsoft/one-collect.
-///
-/// This function collects CPU profiling samples with full callchain (stack trace) data
-/// using the perf_event subsystem via the one_collect crate.
-///
-/// # Arguments
-///
-/// * `duration_secs` - Duration in seconds to collect profiling data
-/// * `pid` - Target process ID (-1 for all processes, 0 for current process)
-/// * `sampling_frequency` - Sampling frequency in Hz (e.g., 99 for 99 samples/second)
-///
-/// # Returns
-///
-/// Returns a `CallchainProfilingResult` containing the profiling statistics.
-///
-/// # Example
-///
-/// ```no_run
-/// use profiler::perf::run_callchain_profiler;
-///
-/// // Profile for 5 seconds at 99 Hz
-/// let result = run_callchain_profiler(5, 0, 99).unwrap();
-/// println!("Collected {} samples", result.sample_count);
-/// ```
-pub fn run_callchain_profiler(
-    duration_secs: u64,
-    pid: i32,
-    sampling_frequency: u64,
-) -> Result<CallchainProfilingResult> {
-    println!("Starting callchain profiler with one_collect...");
-    println!("Duration: {} seconds", duration_secs);
-    println!("Sampling frequency: {} Hz", sampling_frequency);
-    println!("Target PID: {}", if pid == -1 { "all".to_string() } else if pid == 0 { "current".to_string() } else { pid.to_string() });
-    println!();
-
-    // Create a profiling builder with callchain support
-    let profiling_builder = RingBufBuilder::for_profiling(sampling_frequency)
-        .with_callchain_data()
-        .with_ip();
+/// Deserialize `counts`, mapping each name back to its entry in the event tables.
+fn deserialize_counts<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<&'static str, u64>, D::Error> {
+    BTreeMap::<String, u64>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, count)| {
+            HARDWARE_EVENTS
+                .iter()
+                .chain(SOFTWARE_EVENTS)
+                .find(|event| event.name == name)
+                .map(|event| (event.name, count))
+                .ok_or_else(|| D::Error::custom(format!("unknown event '{}'", name)))
+        })
+        .collect()
+}
 
-    // Build the session
-    let mut session_builder = RingBufSessionBuilder::new()
-        .with_page_count(64) // 64 pages for ring buffer
-        .with_profiling_events(profiling_builder);
+/// Share of the enabled time the counters were actually running, in percent.
+fn running_percent(result: &ProfilingResult) -> f64 {
+    if result.time_enabled_ns == 0 {
+        100.0
+    } else {
+        result.time_running_ns as f64 / result.time_enabled_ns as f64 * 100.0
+    }
+}
 
-    // Add target PID if specified (not -1 for all)
-    if pid >= 0 {
-        session_builder = session_builder.with_target_pid(pid);
+/// Format a result in the `perf stat -x,` CSV layout.
+///
+/// Each counter produces one `value,unit,event,runtime,percent` line, where
+/// `runtime` is the time the counter was running in nanoseconds and `percent`
+/// is the share of the enabled time it was actually counting.
+pub fn format_perf_csv(result: &ProfilingResult) -> String {
+    if !result.per_cpu.is_empty() {
+        // Like `perf stat -A -x,`, each line is prefixed with its CPU
+        return result
+            .per_cpu
+            .iter()
+            .map(|cpu_result| {
+                let prefix = format!("CPU{},", cpu_result.cpu.unwrap_or_default());
+                format_csv_lines(cpu_result, &prefix)
+            })
+            .collect();
     }
+    format_csv_lines(result, "")
+}
 
-    let mut session = session_builder
-        .build()
-        .context("Failed to build perf session")?;
+/// CSV lines for one result, each starting with `prefix`.
+fn format_csv_lines(result: &ProfilingResult, prefix: &str) -> String {
+    let percent = running_percent(result);
 
-    // Set up sample counter using Rc<Cell> for interior mutability in callback
-    let sample_count = Rc::new(Cell::new(0u64));
-    let sample_count_clone = sample_count.clone();
+    result
+        .counters()
+        .into_iter()
+        .map(|(name, count)| {
+            format!(
+                "{}{},,{},{},{:.2}\n",
+                prefix, count, name, result.time_running_ns, percent
+            )
+        })
+        .collect()
+}
 
-    // Add callback to the CPU profile event to count samples
-    session.cpu_profile_event().add_callback(move |_event_data| {
-        sample_count_clone.set(sample_count_clone.get() + 1);
-        Ok(())
-    });
+/// Format the per-CPU breakdown of a CPU-wide result as a table.
+///
+/// There is one row per CPU with a column per event, followed by a total row.
+pub fn format_per_cpu_table(result: &ProfilingResult) -> String {
+    let names: Vec<&str> = result
+        .counters()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    let widths: Vec<usize> = names.iter().map(|name| name.len().max(12)).collect();
 
-    // Enable the session and collect data
-    println!("Collecting callchain profiling data...");
-    session.enable().context("Failed to enable perf session")?;
+    let mut table = format!("  {:>5}", "CPU");
+    for (name, width) in names.iter().zip(&widths) {
+        table.push_str(&format!("  {:>width$}", name, width = width));
+    }
+    table.push('\n');
 
-    // Parse events for the specified duration
-    let duration = Duration::from_secs(duration_secs);
-    session
-        .parse_for_duration(duration)
-        .context("Failed to parse perf events")?;
+    let rows = result
+        .per_cpu
+        .iter()
+        .map(|cpu_result| {
+            let label = cpu_result
+                .cpu
+                .map_or_else(String::new, |cpu| cpu.to_string());
+            (label, cpu_result)
+        })
+        .chain(std::iter::once(("total".to_string(), result)));
+    for (label, row) in rows {
+        table.push_str(&format!("  {:>5}", label));
+        for (name, width) in names.iter().zip(&widths) {
+            let count = row.count(name).map(|count| count.to_string());
+            table.push_str(&format!(
+                "  {:>width$}",
+                count.as_deref().unwrap_or("-"),
+                width = width
+            ));
+        }
+        table.push('\n');
+    }
+    table
+}
+
+/// How counter-mode results are printed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable table
+    #[default]
+    Text,
+    /// A single JSON object with counts and derived metrics
+    Json,
+}
 
-    session.disable().context("Failed to disable perf session")?;
+/// File format for the stacks of a callchain run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StackFormat {
+    /// Brendan Gregg's folded stacks, for flamegraph.pl
+    #[default]
+    Folded,
+    /// Gzipped pprof `profile.proto`, for `go tool pprof`
+    Pprof,
+    /// speedscope JSON, for https://www.speedscope.app
+    Speedscope,
+    /// This profiler's compact binary profile, read back by `import`
+    Binary,
+}
 
-    let result = CallchainProfilingResult {
-        sample_count: sample_count.get(),
-        duration_secs,
-        sampling_frequency,
+/// A result together with its derived metrics, as emitted by `--format json`.
+#[derive(Serialize)]
+struct ProfilingReport<'a> {
+    #[serde(flatten)]
+    result: &'a ProfilingResult,
+    ipc: Option<f64>,
+    cache_miss_rate: Option<f64>,
+    cache_mpki: Option<f64>,
+    branch_mpki: Option<f64>,
+    cycles_per_second: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    adjusted_counts: Option<BTreeMap<&'static str, u64>>,
+}
+
+/// Serialize a result and its derived metrics as a JSON object.
+pub fn format_json(result: &ProfilingResult) -> Result<String> {
+    let report = ProfilingReport {
+        result,
+        ipc: result.ipc(),
+        cache_miss_rate: result.cache_miss_rate(),
+        cache_mpki: result.cache_mpki(),
+        branch_mpki: result.branch_mpki(),
+        cycles_per_second: result.cycles_per_second(),
+        adjusted_counts: result.adjusted_counts(),
     };
+    serde_json::to_string_pretty(&report).context("Failed to serialize profiling result")
+}
 
-    // Print results
-    println!();
-    println!("Callchain Profiling Results:");
-    println!("{:=<50}", "");
-    println!("  Samples Collected: {:>15}", result.sample_count);
-    println!("  Duration:          {:>12} s", result.duration_secs);
-    println!("  Sampling Freq:     {:>12} Hz", result.sampling_frequency);
-    println!(
-        "  Effective Rate:    {:>12.1} samples/s",
-        result.sample_count as f64 / result.duration_secs as f64
-    );
-    println!("{:=<50}", "");
+/// Events counted when `--events` is not given.
+pub const DEFAULT_EVENTS: &[&str] = &[
+    "cpu-cycles",
+    "instructions",
+    "cache-references",
+    "cache-misses",
+    "cpu-migrations",
+    "alignment-faults",
+    "emulation-faults",
+];
 
-    Ok(result)
+/// An event selected for counter mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CounterEvent {
+    name: &'static str,
+    /// Whether the run can continue if the kernel refuses to open it
+    optional: bool,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl CounterEvent {
+    fn kind(&self) -> Event {
+        event_kind(self.name).expect("selected events come from the event tables")
+    }
+}
 
-    #[test]
-    fn test_profiling_result_ipc() {
-        let result = ProfilingResult {
-            cpu_cycles: 1000,
-            instructions: 500,
-            cache_references: 100,
-            cache_misses: 10,
-            duration_secs: 1,
+/// Resolve `--events` names against the hardware and software event tables.
+///
+/// An empty selection picks [`DEFAULT_EVENTS`]. Software events are optional so
+/// that a kernel refusing one of them does not abort the run.
+fn select_events(names: &[String]) -> Result<Vec<CounterEvent>> {
+    let known = || HARDWARE_EVENTS.iter().chain(SOFTWARE_EVENTS);
+    let names: Vec<&str> = if names.is_empty() {
+        DEFAULT_EVENTS.to_vec()
+    } else {
+        names.iter().map(|name| name.trim()).collect()
+    };
+
+    let mut selected: Vec<CounterEvent> = Vec::with_capacity(names.len());
+    for name in names {
+        let Some(event) = known().find(|event| event.name == name) else {
+            let valid: Vec<&str> = known().map(|event| event.name).collect();
+            anyhow::bail!(
+                "unknown event '{}'; valid events: {}",
+                name,
+                valid.join(", ")
+            );
         };
-        assert!((result.ipc() - 0.5).abs() < f64::EPSILON);
+        if selected.iter().any(|chosen| chosen.name == event.name) {
+            continue;
+        }
+        selected.push(CounterEvent {
+            name: event.name,
+            optional: event.category == EventCategory::Software,
+        });
     }
+    Ok(selected)
+}
 
-    #[test]
-    fn test_profiling_result_ipc_zero_cycles() {
-        let result = ProfilingResult {
-            cpu_cycles: 0,
-            instructions: 500,
-            cache_references: 100,
-            cache_misses: 10,
-            duration_secs: 1,
-        };
-        assert!((result.ipc() - 0.0).abs() < f64::EPSILON);
+/// A single counter read with its enabled and running times.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct CounterRead {
+    count: u64,
+    time_enabled: u64,
+    time_running: u64,
+}
+
+impl CounterRead {
+    /// Whether the counter was descheduled for part of the time it was enabled.
+    fn multiplexed(&self) -> bool {
+        self.time_running < self.time_enabled
     }
 
-    #[test]
-    fn test_profiling_result_cache_miss_rate() {
-        let result = ProfilingResult {
-            cpu_cycles: 1000,
-            instructions: 500,
-            cache_references: 100,
-            cache_misses: 10,
-            duration_secs: 1,
-        };
-        assert!((result.cache_miss_rate() - 10.0).abs() < f64::EPSILON);
+    /// Whether [`scale_count`] adjusts this read; a counter that never ran stays at zero.
+    fn scaled(&self) -> bool {
+        self.multiplexed() && self.time_running > 0
     }
+}
 
-    #[test]
-    fn test_profiling_result_cache_miss_rate_zero_refs() {
-        let result = ProfilingResult {
-            cpu_cycles: 1000,
-            instructions: 500,
-            cache_references: 0,
-            cache_misses: 10,
-            duration_secs: 1,
-        };
-        assert!((result.cache_miss_rate() - 0.0).abs() < f64::EPSILON);
+/// Scale a raw count by `time_enabled / time_running` to correct for multiplexing.
+fn scale_count(count: u64, time_enabled: u64, time_running: u64) -> u64 {
+    if time_running == 0 || time_running >= time_enabled {
+        count
+    } else {
+        (count as u128 * time_enabled as u128 / time_running as u128) as u64
     }
+}
 
-    #[test]
-    fn test_profiling_result_cycles_per_second() {
-        let result = ProfilingResult {
-            cpu_cycles: 1000,
-            instructions: 500,
-            cache_references: 100,
-            cache_misses: 10,
-            duration_secs: 2,
-        };
-        assert!((result.cycles_per_second() - 500.0).abs() < f64::EPSILON);
+/// Sum per-CPU reads of one event.
+///
+/// Each CPU multiplexes its counters independently, so every read is scaled by
+/// its own enabled/running times before summing.
+fn sum_scaled(reads: &[CounterRead]) -> u64 {
+    reads
+        .iter()
+        .map(|read| scale_count(read.count, read.time_enabled, read.time_running))
+        .sum()
+}
+
+/// A counter group following one task, with the counters that opened in it.
+type TaskGroup = (i32, Group, Vec<(&'static str, Counter)>);
+
+/// Open one counter group following task `pid` (a process or a thread).
+fn open_task_group(pid: i32, events: &[CounterEvent], inherit: bool) -> Result<TaskGroup> {
+    // Create a group to collect multiple counters atomically
+    // The kernel only passes a group on to children when its leader is
+    // inherited too, whatever the members ask for
+    let mut group = Builder::new()
+        .observe_pid(pid)
+        .inherit(inherit)
+        .build_group()
+        .context("Failed to create perf event group")?;
+
+    let mut counters = Vec::with_capacity(events.len());
+    for event in events {
+        let built = Builder::new()
+            .observe_pid(pid)
+            .group(&mut group)
+            .inherit(inherit)
+            .kind(event.kind())
+            .build();
+        match built {
+            Ok(counter) => counters.push((event.name, counter)),
+            // Optional counters are skipped: a missing one shouldn't abort the run
+            Err(_) if event.optional => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create {} counter", event.name))
+            }
+        }
     }
+    Ok((pid, group, counters))
+}
 
-    #[test]
-    fn test_profiling_result_cycles_per_second_zero_duration() {
-        let result = ProfilingResult {
-            cpu_cycles: 1000,
-            instructions: 500,
-            cache_references: 100,
-            cache_misses: 10,
-            duration_secs: 0,
-        };
-        assert!((result.cycles_per_second() - 0.0).abs() < f64::EPSILON);
+/// Count tasks (processes or threads) with one counter group each, summing
+/// their counts.
+///
+/// Counters run while `wait` does and its result becomes the measured duration.
+/// `wait` is handed a function that reads the running counters, for interval
+/// reports. With `inherit`, children forked after the counters are created are
+/// counted too.
+fn count_tasks(
+    pids: &[i32],
+    events: &[CounterEvent],
+    inherit: bool,
+    wait: impl FnOnce(&mut dyn FnMut() -> Result<ProfilingResult>) -> Result<Duration>,
+) -> Result<(ProfilingResult, Duration)> {
+    let mut groups = pids
+        .iter()
+        .map(|&pid| open_task_group(pid, events, inherit))
+        .collect::<Result<Vec<TaskGroup>>>()?;
+
+    for (_, group, _) in &mut groups {
+        group.enable().context("Failed to enable perf counters")?;
+    }
+
+    let elapsed = wait(&mut || read_task_groups(&mut groups))?;
+
+    for (_, group, _) in &mut groups {
+        group.disable().context("Failed to disable perf counters")?;
+    }
+
+    let mut result = read_task_groups(&mut groups)?;
+    result.duration_secs = elapsed.as_secs_f64();
+
+    Ok((result, elapsed))
+}
+
+/// Read every task's group and sum them; a single task's read is returned as is.
+fn read_task_groups(groups: &mut [TaskGroup]) -> Result<ProfilingResult> {
+    let reads = groups
+        .iter_mut()
+        .map(|(pid, group, counters)| read_group(group, counters, *pid))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(sum_task_results(reads))
+}
+
+/// Sum the counts and times of several tasks, each already scaled for its own
+/// multiplexing. The sum keeps the tasks' PID if they share one, otherwise -1.
+fn sum_task_results(mut reads: Vec<ProfilingResult>) -> ProfilingResult {
+    if reads.len() == 1 {
+        return reads.remove(0);
+    }
+    let mut total = ProfilingResult {
+        pid: match reads.first() {
+            Some(first) if reads.iter().all(|read| read.pid == first.pid) => first.pid,
+            _ => -1,
+        },
+        ..Default::default()
+    };
+    for read in reads {
+        for (name, count) in read.counts {
+            *total.counts.entry(name).or_insert(0) += count;
+        }
+        total.time_enabled_ns += read.time_enabled_ns;
+        total.time_running_ns += read.time_running_ns;
+        total.multiplexed |= read.multiplexed;
+        total.scaled |= read.scaled;
+    }
+    total
+}
+
+/// Read the counters of a group and scale them for multiplexing.
+fn read_group(
+    group: &mut Group,
+    counters: &[(&'static str, Counter)],
+    pid: i32,
+) -> Result<ProfilingResult> {
+    // Members of a group are scheduled together, so the group's enabled/running
+    // times apply to every counter
+    let counts = group.read().context("Failed to read perf counters")?;
+    let times = CounterRead {
+        count: 0,
+        time_enabled: counts.time_enabled().unwrap_or(0),
+        time_running: counts.time_running().unwrap_or(0),
+    };
+
+    Ok(ProfilingResult {
+        counts: counters
+            .iter()
+            .map(|(name, counter)| {
+                let count = scale_count(counts[counter], times.time_enabled, times.time_running);
+                (*name, count)
+            })
+            .collect(),
+        time_enabled_ns: times.time_enabled,
+        time_running_ns: times.time_running,
+        multiplexed: times.multiplexed(),
+        scaled: times.scaled(),
+        pid,
+        ..Default::default()
+    })
+}
+
+/// Counters opened by [`count_on_cpus`]: for each selected event, the counters
+/// that opened, tagged with the index of their CPU.
+type CpuCounters = Vec<Vec<(usize, Counter)>>;
+
+/// Count all processes on each of the given CPUs and aggregate across them.
+///
+/// Counters run while `wait` does and its result becomes the measured duration.
+/// `wait` is handed a function that reads the running counters, for interval
+/// reports. The aggregate's `per_cpu` holds one result per CPU, in the order of `cpus`.
+fn count_on_cpus(
+    cpus: &[usize],
+    events: &[CounterEvent],
+    wait: impl FnOnce(&mut dyn FnMut() -> Result<ProfilingResult>) -> Result<Duration>,
+) -> Result<(ProfilingResult, Duration)> {
+    let mut counters: CpuCounters = events.iter().map(|_| Vec::new()).collect();
+    for (index, &cpu) in cpus.iter().enumerate() {
+        for (event, per_cpu) in events.iter().zip(&mut counters) {
+            match Builder::new()
+                .one_cpu(cpu)
+                .any_pid()
+                .kind(event.kind())
+                .build()
+            {
+                Ok(counter) => per_cpu.push((index, counter)),
+                Err(_) if event.optional => {}
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Failed to create {} counter on CPU {}", event.name, cpu)
+                    })
+                }
+            }
+        }
+    }
+
+    for (_, counter) in counters.iter_mut().flatten() {
+        counter.enable().context("Failed to enable perf counters")?;
+    }
+
+    let elapsed = wait(&mut || read_cpus(cpus, events, &mut counters))?;
+
+    for (_, counter) in counters.iter_mut().flatten() {
+        counter
+            .disable()
+            .context("Failed to disable perf counters")?;
+    }
+
+    let mut result = read_cpus(cpus, events, &mut counters)?;
+    result.duration_secs = elapsed.as_secs_f64();
+    for cpu_result in &mut result.per_cpu {
+        cpu_result.duration_secs = elapsed.as_secs_f64();
+    }
+
+    Ok((result, elapsed))
+}
+
+/// Read every CPU's counters, scaling each before summing them into the aggregate.
+fn read_cpus(
+    cpus: &[usize],
+    events: &[CounterEvent],
+    counters: &mut CpuCounters,
+) -> Result<ProfilingResult> {
+    let mut per_cpu: Vec<ProfilingResult> = cpus
+        .iter()
+        .map(|&cpu| ProfilingResult {
+            pid: -1,
+            cpu: Some(cpu),
+            ..Default::default()
+        })
+        .collect();
+    let mut totals = BTreeMap::new();
+    for (event, event_counters) in events.iter().zip(counters) {
+        let mut reads = Vec::with_capacity(event_counters.len());
+        for (index, counter) in event_counters.iter_mut() {
+            let read = counter
+                .read_count_and_time()
+                .context("Failed to read perf counters")?;
+            let read = CounterRead {
+                count: read.count,
+                time_enabled: read.time_enabled,
+                time_running: read.time_running,
+            };
+            let cpu_result = &mut per_cpu[*index];
+            cpu_result.counts.insert(
+                event.name,
+                scale_count(read.count, read.time_enabled, read.time_running),
+            );
+            cpu_result.time_enabled_ns += read.time_enabled;
+            cpu_result.time_running_ns += read.time_running;
+            cpu_result.multiplexed |= read.multiplexed();
+            cpu_result.scaled |= read.scaled();
+            reads.push(read);
+        }
+        if !reads.is_empty() {
+            totals.insert(event.name, sum_scaled(&reads));
+        }
+    }
+
+    Ok(ProfilingResult {
+        counts: totals,
+        time_enabled_ns: per_cpu.iter().map(|cpu| cpu.time_enabled_ns).sum(),
+        time_running_ns: per_cpu.iter().map(|cpu| cpu.time_running_ns).sum(),
+        multiplexed: per_cpu.iter().any(|cpu| cpu.multiplexed),
+        scaled: per_cpu.iter().any(|cpu| cpu.scaled),
+        pid: -1,
+        per_cpu,
+        ..Default::default()
+    })
+}
+
+/// Per-interval counts: the difference between two reads of the same counters.
+///
+/// Counts and times are `cur - prev`; per-CPU entries are matched by CPU, and
+/// one missing from `prev` counts from zero. The multiplexing flags describe
+/// the interval alone.
+pub fn interval_delta(prev: &ProfilingResult, cur: &ProfilingResult) -> ProfilingResult {
+    let time_enabled_ns = cur.time_enabled_ns.saturating_sub(prev.time_enabled_ns);
+    let time_running_ns = cur.time_running_ns.saturating_sub(prev.time_running_ns);
+    let times = CounterRead {
+        count: 0,
+        time_enabled: time_enabled_ns,
+        time_running: time_running_ns,
+    };
+
+    ProfilingResult {
+        counts: cur
+            .counts
+            .iter()
+            .map(|(&name, &count)| {
+                let previous = prev.count(name).unwrap_or(0);
+                (name, count.saturating_sub(previous))
+            })
+            .collect(),
+        time_enabled_ns,
+        time_running_ns,
+        multiplexed: times.multiplexed(),
+        scaled: times.scaled(),
+        duration_secs: (cur.duration_secs - prev.duration_secs).max(0.0),
+        pid: cur.pid,
+        exit_code: None,
+        cpu: cur.cpu,
+        per_cpu: cur
+            .per_cpu
+            .iter()
+            .map(|cur_cpu| {
+                let prev_cpu = prev
+                    .per_cpu
+                    .iter()
+                    .find(|prev_cpu| prev_cpu.cpu == cur_cpu.cpu);
+                interval_delta(prev_cpu.unwrap_or(&ProfilingResult::default()), cur_cpu)
+            })
+            .collect(),
+        tid: cur.tid,
+        ..Default::default()
+    }
+}
+
+/// Format one interval report in the layout of `perf stat -I`.
+///
+/// Each line is the time since counting started, in seconds, then the count
+/// during the interval and the event name.
+pub fn format_interval(elapsed: Duration, delta: &ProfilingResult) -> String {
+    delta
+        .counters()
+        .into_iter()
+        .map(|(name, count)| format!("{:>12.3} {:>15}  {}\n", elapsed.as_secs_f64(), count, name))
+        .collect()
+}
+
+/// Thresholds on derived metrics checked at the end of every interval.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AlertThresholds {
+    /// Alert when IPC drops below this value
+    pub ipc_below: Option<f64>,
+    /// Alert when the cache miss rate, in percent, rises above this value
+    pub cache_miss_above: Option<f64>,
+}
+
+/// A derived metric that crossed its threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alert {
+    IpcBelow { ipc: f64, threshold: f64 },
+    CacheMissAbove { rate: f64, threshold: f64 },
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Alert::IpcBelow { ipc, threshold } => {
+                write!(f, "IPC {:.3} is below {:.3}", ipc, threshold)
+            }
+            Alert::CacheMissAbove { rate, threshold } => {
+                write!(f, "cache miss rate {:.2}% is above {:.2}%", rate, threshold)
+            }
+        }
+    }
+}
+
+/// Check the derived metrics of a result against the alert thresholds.
+///
+/// A metric whose events were not counted never alerts, and neither does IPC
+/// over an interval without a single cycle (an idle target).
+pub fn check_alerts(result: &ProfilingResult, thresholds: &AlertThresholds) -> Vec<Alert> {
+    let mut alerts = Vec::new();
+    if let Some(threshold) = thresholds.ipc_below {
+        let busy = result.count("cpu-cycles").is_some_and(|cycles| cycles > 0);
+        if let Some(ipc) = result.ipc().filter(|&ipc| busy && ipc < threshold) {
+            alerts.push(Alert::IpcBelow { ipc, threshold });
+        }
+    }
+    if let Some(threshold) = thresholds.cache_miss_above {
+        if let Some(rate) = result.cache_miss_rate().filter(|&rate| rate > threshold) {
+            alerts.push(Alert::CacheMissAbove { rate, threshold });
+        }
+    }
+    alerts
+}
+
+/// Resolve the `--pid` argument to the process that will be counted.
+///
+/// `0` means the profiler itself; any other PID must name a running process.
+fn resolve_target_pid(pid: i32) -> Result<i32> {
+    if pid == 0 {
+        return Ok(std::process::id() as i32);
+    }
+    if pid < 0 {
+        anyhow::bail!("invalid PID: {} (use --cpus to count all processes)", pid);
+    }
+    if !Path::new(&format!("/proc/{}", pid)).exists() {
+        anyhow::bail!("no such process: {}", pid);
+    }
+    Ok(pid)
+}
+
+/// Read the thread group (process) ID from `/proc/<tid>/status` text.
+fn parse_tgid(status: &str) -> Option<i32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Tgid:"))
+        .and_then(|tgid| tgid.trim().parse().ok())
+}
+
+/// Resolve the `--tid` argument to the process the thread belongs to.
+///
+/// The TID must name a live task listed under `/proc/<pid>/task`.
+fn resolve_target_tid(tid: i32) -> Result<i32> {
+    if tid <= 0 {
+        anyhow::bail!("invalid TID: {}", tid);
+    }
+    let status = std::fs::read_to_string(format!("/proc/{}/status", tid))
+        .with_context(|| format!("no such thread: {}", tid))?;
+    let pid = parse_tgid(&status).with_context(|| format!("no Tgid for thread {}", tid))?;
+    if !Path::new(&format!("/proc/{}/task/{}", pid, tid)).exists() {
+        anyhow::bail!("thread {} is not a live task of process {}", tid, pid);
+    }
+    Ok(pid)
+}
+
+/// A thread found by its name, and the process it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThreadMatch {
+    pub pid: i32,
+    pub tid: i32,
+}
+
+/// Numeric entries of a `/proc`-like directory (PIDs or TIDs), sorted.
+fn numeric_entries(dir: &Path) -> Vec<i32> {
+    let mut ids: Vec<i32> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    ids.sort_unstable();
+    ids
+}
+
+/// Find the threads named `name` from their `<proc_root>/<pid>/task/<tid>/comm`,
+/// among the threads of `pid` or, when `None`, of every process.
+///
+/// Names are matched like `--comm`: whole, or on their first 15 bytes when
+/// longer, as the kernel truncates them. Tasks that exit while scanning are skipped.
+fn find_threads_by_name(proc_root: &Path, name: &str, pid: Option<i32>) -> Vec<ThreadMatch> {
+    let filter = CommFilter {
+        names: vec![name.to_string()],
+        substring: false,
+    };
+    let pids = match pid {
+        Some(pid) => vec![pid],
+        None => numeric_entries(proc_root),
+    };
+    let mut matches = Vec::new();
+    for pid in pids {
+        let tasks = proc_root.join(pid.to_string()).join("task");
+        for tid in numeric_entries(&tasks) {
+            let comm = std::fs::read_to_string(tasks.join(tid.to_string()).join("comm"));
+            if comm.is_ok_and(|comm| filter.matches(comm.trim_end_matches('\n'))) {
+                matches.push(ThreadMatch { pid, tid });
+            }
+        }
+    }
+    matches
+}
+
+/// Resolve `--thread-name` to the matching threads of `pid`, or of every
+/// process when `pid` is 0.
+fn resolve_thread_name(name: &str, pid: i32) -> Result<Vec<ThreadMatch>> {
+    let scope = (pid != 0).then(|| resolve_target_pid(pid)).transpose()?;
+    let threads = find_threads_by_name(Path::new("/proc"), name, scope);
+    if threads.is_empty() {
+        match scope {
+            Some(pid) => anyhow::bail!("no thread named '{}' in process {}", name, pid),
+            None => anyhow::bail!("no thread named '{}' in any process", name),
+        }
+    }
+    Ok(threads)
+}
+
+/// Describe which tasks a counter-mode run will measure.
+fn measurement_scope(system_wide: bool, inherit: bool) -> &'static str {
+    if system_wide {
+        "all processes on the selected CPUs"
+    } else if inherit {
+        "measuring target PID and its future children"
+    } else {
+        "target PID only (children are not counted; use --inherit to include them)"
+    }
+}
+
+/// Settings for a counter-mode run of [`run_perf_profiler`].
+#[derive(Debug, Default)]
+pub struct PerfOptions {
+    /// Duration in seconds to collect performance data
+    pub duration_secs: u64,
+    /// Target process ID (0 for the current process); ignored with `cpus`
+    pub pid: i32,
+    /// If set, count only this thread instead of `pid`
+    pub tid: Option<i32>,
+    /// If set, count every thread with this name: the threads of `pid`, or of
+    /// every process when `pid` is 0
+    pub thread_name: Option<String>,
+    /// If set, also count this control process over the same window and
+    /// report the target's counts with the control's subtracted
+    pub baseline_pid: Option<i32>,
+    /// Print counters in `perf stat -x,` CSV layout instead of a table
+    pub perf_csv: bool,
+    /// Output format used when `perf_csv` is not set
+    pub format: OutputFormat,
+    /// If set, stop counting as soon as this file disappears
+    pub while_exists: Option<PathBuf>,
+    /// If set, count all processes on exactly these CPUs and aggregate
+    pub cpus: Option<Vec<usize>>,
+    /// Count all processes on each CPU separately and report every CPU; uses
+    /// `cpus` if set, otherwise every online CPU
+    pub per_cpu: bool,
+    /// Also count children the target forks after counting starts
+    pub inherit: bool,
+    /// Event names to count; empty selects [`DEFAULT_EVENTS`]
+    pub events: Vec<String>,
+    /// If set, launch this command and count it and its children until it exits
+    pub command: Vec<String>,
+    /// If set with `command`, stop counting after this long and terminate the
+    /// command if it is still running; `duration_secs` is otherwise ignored
+    /// for a command
+    pub command_timeout: Option<Duration>,
+    /// If set, report the counts of every interval of this length as it ends
+    pub interval: Option<Duration>,
+    /// Metric thresholds checked against the counts of every interval
+    pub alerts: AlertThresholds,
+}
+
+/// Progress of [`run_perf_profiler`], reported while it runs.
+#[derive(Debug)]
+pub enum PerfProgress<'a> {
+    /// The target is resolved and counting is about to start
+    Started {
+        target_pid: i32,
+        /// CPUs counted, including the online CPUs picked for `per_cpu`
+        cpus: Option<&'a [usize]>,
+        /// Threads counted, when selected with `thread_name`
+        threads: &'a [ThreadMatch],
+    },
+    /// An `interval` ended `elapsed` after counting started
+    Interval {
+        elapsed: Duration,
+        delta: &'a ProfilingResult,
+    },
+}
+
+/// Run the perf profiler for a specified duration.
+///
+/// Nothing is printed; `progress` is called when counting starts and after
+/// every `interval`, and [`print_profiling_result`] prints the returned result.
+///
+/// # Arguments
+///
+/// * `options` - What to count and for how long
+/// * `progress` - Called with each [`PerfProgress`] update
+///
+/// # Returns
+///
+/// Returns a `ProfilingResult` containing the collected performance counters.
+pub fn run_perf_profiler(
+    options: &PerfOptions,
+    mut progress: impl FnMut(PerfProgress<'_>) -> Result<()>,
+) -> Result<ProfilingResult> {
+    let while_exists = options.while_exists.as_deref();
+
+    let events = select_events(&options.events)?;
+    check_sentinel(while_exists)?;
+    if let Some(interval) = options.interval {
+        if interval.is_zero() {
+            anyhow::bail!("--interval must be greater than zero");
+        }
+        if !options.command.is_empty() {
+            anyhow::bail!("--interval cannot be used when launching a command");
+        }
+        if options.format == OutputFormat::Json {
+            anyhow::bail!("--interval cannot be used with --format json");
+        }
+    }
+    // Per-CPU mode defaults to the online CPUs, which skips offline ones
+    let online_cpus = match &options.cpus {
+        None if options.per_cpu => Some(cpulist::online_cpus()?),
+        _ => None,
+    };
+    let cpus = options.cpus.as_deref().or(online_cpus.as_deref());
+    if let Some(cpus) = cpus {
+        cpulist::validate_online(cpus)?;
+    }
+    // A thread is counted on its own, as the target of a thread-scoped counter
+    let tid_process = options.tid.map(resolve_target_tid).transpose()?;
+    let threads = match (cpus, &options.thread_name) {
+        (None, Some(name)) => resolve_thread_name(name, options.pid)?,
+        _ => Vec::new(),
+    };
+    let target_pid = match (cpus, options.tid) {
+        (Some(_), _) => -1,
+        (None, Some(tid)) => tid,
+        (None, None) if !threads.is_empty() => threads[0].pid,
+        (None, None) if options.command.is_empty() => resolve_target_pid(options.pid)?,
+        // Replaced by the PID of the command once it is spawned
+        (None, None) => 0,
+    };
+    let baseline_pid = options.baseline_pid.map(resolve_target_pid).transpose()?;
+    // Checked after the target, so a bad PID is reported as such whatever the
+    // host's perf_event_paranoid
+    check_perf_permissions(cpus.is_some(), false)?;
+
+    // A launched command is held before exec until its counters are enabled
+    let mut child = if options.command.is_empty() {
+        None
+    } else {
+        Some(HeldChild::spawn(&options.command)?)
+    };
+    let target_pid = child.as_ref().map_or(target_pid, HeldChild::pid);
+    // A command is measured together with everything it starts
+    let inherit = options.inherit || child.is_some();
+
+    progress(PerfProgress::Started {
+        target_pid,
+        cpus,
+        threads: &threads,
+    })?;
+
+    // Enable counters and collect data
+    let max_duration = Duration::from_secs(options.duration_secs);
+    // Counting stops early once the targets exit, unless it follows their
+    // children, which may outlive them
+    let watched: Vec<i32> = match (cpus, &threads) {
+        (Some(_), _) => Vec::new(),
+        _ if inherit => Vec::new(),
+        (None, threads) if !threads.is_empty() => threads.iter().map(|t| t.tid).collect(),
+        // The profiler's own process never exits while it counts itself
+        (None, _) if options.pid == 0 && options.tid.is_none() => Vec::new(),
+        (None, _) => vec![target_pid],
+    };
+    let interrupt = InterruptScope::enter();
+    let mut exit_code = None;
+    let mut command_timed_out = false;
+    // The control process is counted over the same window as the target
+    let mut baseline = baseline_pid
+        .map(|pid| open_task_group(pid, &events, options.inherit))
+        .transpose()?;
+    let wait_target = |read: &mut dyn FnMut() -> Result<ProfilingResult>| -> Result<Duration> {
+        match child.take() {
+            Some(child) => {
+                let start = Instant::now();
+                match options.command_timeout {
+                    Some(timeout) => {
+                        let exit = child.release_and_wait_for(timeout)?;
+                        exit_code = Some(exit.code);
+                        command_timed_out = exit.timed_out;
+                    }
+                    None => exit_code = Some(child.release_and_wait()?),
+                }
+                Ok(start.elapsed())
+            }
+            None => match options.interval {
+                Some(interval) => {
+                    let mut previous = ProfilingResult::default();
+                    wait_with_intervals(
+                        max_duration,
+                        interval,
+                        while_exists,
+                        &watched,
+                        &interrupt,
+                        |elapsed| {
+                            let current = read()?;
+                            let delta = interval_delta(&previous, &current);
+                            progress(PerfProgress::Interval {
+                                elapsed,
+                                delta: &delta,
+                            })?;
+                            previous = current;
+                            Ok(())
+                        },
+                    )
+                }
+                None => Ok(wait_for_duration(
+                    max_duration,
+                    while_exists,
+                    &watched,
+                    &interrupt,
+                )),
+            },
+        }
+    };
+    let wait = |read: &mut dyn FnMut() -> Result<ProfilingResult>| -> Result<Duration> {
+        if let Some((_, group, _)) = &mut baseline {
+            group
+                .enable()
+                .context("Failed to enable baseline counters")?;
+        }
+        let elapsed = wait_target(read)?;
+        if let Some((_, group, _)) = &mut baseline {
+            group
+                .disable()
+                .context("Failed to disable baseline counters")?;
+        }
+        Ok(elapsed)
+    };
+    let (mut result, elapsed) = match cpus {
+        Some(cpus) => count_on_cpus(cpus, &events, wait)?,
+        None if !threads.is_empty() => {
+            let tids: Vec<i32> = threads.iter().map(|thread| thread.tid).collect();
+            let (mut result, elapsed) = count_tasks(&tids, &events, inherit, wait)?;
+            // Summed threads of one process keep it as their PID
+            if threads.iter().all(|thread| thread.pid == target_pid) {
+                result.pid = target_pid;
+            }
+            (result, elapsed)
+        }
+        None => count_tasks(&[target_pid], &events, inherit, wait)?,
+    };
+    result.threads = threads;
+    if let Some((pid, mut group, counters)) = baseline {
+        let mut control = read_group(&mut group, &counters, pid)?;
+        control.duration_secs = result.duration_secs;
+        result.baseline = Some(Box::new(control));
+    }
+    result.exit_code = exit_code;
+    result.command_timed_out = command_timed_out;
+    if let Some(pid) = tid_process {
+        result.pid = pid;
+        result.tid = options.tid;
+    }
+    if !options.per_cpu {
+        result.per_cpu.clear();
+    }
+    result.interrupted = interrupt.interrupted();
+    result.target_exited = elapsed < max_duration && tasks_exited(&watched);
+    result.active_duration = (while_exists.is_some()
+        || exit_code.is_some()
+        || result.interrupted
+        || result.target_exited)
+        .then_some(elapsed);
+
+    Ok(result)
+}
+
+/// Print a progress update of a counter-mode run.
+///
+/// Status lines go to stderr; interval counts are part of the report and go
+/// to `out`. Machine-readable formats print nothing but counts, so only
+/// interval lines are printed with `perf_csv`, and nothing with JSON output.
+pub fn print_perf_progress(
+    options: &PerfOptions,
+    progress: &PerfProgress<'_>,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    if options.format == OutputFormat::Json {
+        return Ok(());
+    }
+    match *progress {
+        PerfProgress::Started { .. } if options.perf_csv => {}
+        PerfProgress::Started {
+            target_pid,
+            cpus,
+            threads,
+        } => {
+            let launched = !options.command.is_empty();
+            eprintln!("Starting perf profiler...");
+            if let (true, Some(timeout)) = (launched, options.command_timeout) {
+                eprintln!(
+                    "Duration: until the command exits, at most {} seconds",
+                    timeout.as_secs_f64()
+                );
+            } else if launched {
+                eprintln!("Duration: until the command exits");
+            } else {
+                eprintln!("Duration: {} seconds", options.duration_secs);
+            }
+            match cpus {
+                _ if launched => eprintln!(
+                    "Target: Command `{}` (PID {})",
+                    options.command.join(" "),
+                    target_pid
+                ),
+                Some(cpus) => eprintln!("Target: CPUs {}", cpulist::format_cpu_list(cpus)),
+                None if options.tid.is_some() => eprintln!("Target: TID {}", target_pid),
+                None if !threads.is_empty() => eprintln!(
+                    "Target: {} threads named '{}': {}",
+                    threads.len(),
+                    options.thread_name.as_deref().unwrap_or_default(),
+                    format_threads(threads)
+                ),
+                None if options.pid == 0 => {
+                    eprintln!("Target: Current process (PID {})", target_pid)
+                }
+                None => eprintln!("Target: PID {}", target_pid),
+            }
+            let inherit = options.inherit || launched;
+            if options.tid.is_some() && !inherit {
+                eprintln!("Scope: target thread only (other threads are not counted)");
+            } else if !threads.is_empty() && !inherit {
+                eprintln!("Scope: matched threads only, summed (other threads are not counted)");
+            } else {
+                eprintln!("Scope: {}", measurement_scope(cpus.is_some(), inherit));
+            }
+            if let Some(pid) = options.baseline_pid {
+                eprintln!(
+                    "Baseline: PID {} (counted over the same window, subtracted)",
+                    pid
+                );
+            }
+            if let Some(path) = &options.while_exists {
+                eprintln!("Active while exists: {}", path.display());
+            }
+            eprintln!();
+            eprintln!("Collecting performance data...");
+            if options.interval.is_some() {
+                writeln!(out, "{:>12} {:>15}  event", "time (s)", "count")?;
+            }
+        }
+        PerfProgress::Interval { elapsed, delta } if options.perf_csv => {
+            // Like `perf stat -I -x,`, lines start with the timestamp
+            let prefix = format!("{:.3},", elapsed.as_secs_f64());
+            write!(out, "{}", format_csv_lines(delta, &prefix))?;
+        }
+        PerfProgress::Interval { elapsed, delta } => {
+            write!(out, "{}", format_interval(elapsed, delta))?;
+        }
+    }
+    Ok(())
+}
+
+/// List threads as `TID 12 (PID 10), ...`.
+fn format_threads(threads: &[ThreadMatch]) -> String {
+    threads
+        .iter()
+        .map(|thread| format!("TID {} (PID {})", thread.tid, thread.pid))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Print a counter-mode result as a table.
+pub fn print_profiling_result(result: &ProfilingResult, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "Profiling Results:")?;
+    writeln!(out, "{:=<50}", "")?;
+    if result.interrupted {
+        writeln!(out, "  Interrupted by Ctrl-C; partial results")?;
+    }
+    if result.target_exited {
+        writeln!(out, "  Target exited; stopped before the duration")?;
+    }
+    if let Some(elapsed) = result.active_duration {
+        writeln!(
+            out,
+            "  Active Duration:   {:>13.2} s",
+            elapsed.as_secs_f64()
+        )?;
+    }
+    if let Some(code) = result.exit_code {
+        let stopped_by = if result.command_timed_out {
+            "duration"
+        } else {
+            "command exit"
+        };
+        writeln!(out, "  Stopped By:        {:>15}", stopped_by)?;
+        writeln!(out, "  Exit Code:         {:>15}", code)?;
+    }
+    if !result.threads.is_empty() {
+        writeln!(out, "  Threads Matched:   {:>15}", result.threads.len())?;
+        writeln!(out, "    {}", format_threads(&result.threads))?;
+    }
+    for (name, count) in result.counters() {
+        writeln!(out, "  {:<19}{:>15}", format!("{}:", name), count)?;
+    }
+    writeln!(out, "{:-<50}", "")?;
+    // perf-event opens every counter with exclude_hv set
+    writeln!(out, "  Hypervisor:        {:>15}", "excluded")?;
+    if let Some(ipc) = result.ipc() {
+        writeln!(out, "  IPC:               {:>15.3}", ipc)?;
+    }
+    if let Some(rate) = result.cache_miss_rate() {
+        writeln!(out, "  Cache Miss Rate:   {:>14.2}%", rate)?;
+    }
+    if let Some(mpki) = result.cache_mpki() {
+        writeln!(out, "  Cache MPKI:        {:>15.3}", mpki)?;
+    }
+    if let Some(mpki) = result.branch_mpki() {
+        writeln!(out, "  Branch MPKI:       {:>15.3}", mpki)?;
+    }
+    writeln!(out, "{:=<50}", "")?;
+
+    if let (Some(baseline), Some(adjusted)) = (&result.baseline, result.adjusted_counts()) {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "Baseline-Adjusted Counts (minus PID {}, clamped at 0):",
+            baseline.pid
+        )?;
+        let mut table = Table::new(&[
+            ("Event", Align::Left),
+            ("Raw", Align::Right),
+            ("Baseline", Align::Right),
+            ("Adjusted", Align::Right),
+        ]);
+        for (name, count) in result.counters() {
+            table.push_row(vec![
+                name.to_string(),
+                count.to_string(),
+                baseline
+                    .count(name)
+                    .map_or("-".to_string(), |c| c.to_string()),
+                adjusted[name].to_string(),
+            ]);
+        }
+        table.render(out)?;
+    }
+
+    if !result.per_cpu.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "Per-CPU Counts:")?;
+        write!(out, "{}", format_per_cpu_table(result))?;
+    }
+
+    if result.multiplexed {
+        writeln!(
+            out,
+            "WARNING: counters were multiplexed and ran for {:.1}% of the enabled time; {}",
+            running_percent(result),
+            if result.scaled {
+                "values are estimates scaled by time_enabled/time_running"
+            } else {
+                "they never ran, so values are zero"
+            }
+        )?;
+    }
+    Ok(())
+}
+
+/// Mean and spread of one counter over repeated runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CounterSummary {
+    pub name: &'static str,
+    /// Number of runs that counted the event
+    pub runs: usize,
+    pub mean: f64,
+    /// Sample standard deviation; zero with fewer than two runs
+    pub stddev: f64,
+}
+
+impl CounterSummary {
+    /// Standard deviation relative to the mean, in percent (like `perf stat -r`).
+    pub fn noise_percent(&self) -> f64 {
+        if self.mean == 0.0 {
+            0.0
+        } else {
+            self.stddev / self.mean * 100.0
+        }
+    }
+}
+
+/// Per-counter statistics of repeated runs of the same measurement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfilingSummary {
+    pub runs: usize,
+    /// Every event counted by at least one run, in display order
+    pub counters: Vec<CounterSummary>,
+}
+
+/// Summarize repeated runs with the mean and sample standard deviation of each counter.
+pub fn summarize(results: &[ProfilingResult]) -> ProfilingSummary {
+    let counters = HARDWARE_EVENTS
+        .iter()
+        .chain(SOFTWARE_EVENTS)
+        .filter_map(|event| {
+            let values: Vec<f64> = results
+                .iter()
+                .filter_map(|result| result.count(event.name))
+                .map(|count| count as f64)
+                .collect();
+            if values.is_empty() {
+                return None;
+            }
+            let runs = values.len();
+            let mean = values.iter().sum::<f64>() / runs as f64;
+            let stddev = if runs < 2 {
+                0.0
+            } else {
+                let squares: f64 = values.iter().map(|value| (value - mean).powi(2)).sum();
+                (squares / (runs - 1) as f64).sqrt()
+            };
+            Some(CounterSummary {
+                name: event.name,
+                runs,
+                mean,
+                stddev,
+            })
+        })
+        .collect();
+
+    ProfilingSummary {
+        runs: results.len(),
+        counters,
+    }
+}
+
+/// Print the summary of repeated runs as a table.
+pub fn print_profiling_summary(summary: &ProfilingSummary, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "Profiling Results ({} runs):", summary.runs)?;
+    writeln!(out, "{:=<64}", "")?;
+    writeln!(
+        out,
+        "  {:<19}{:>17}{:>17}{:>9}",
+        "", "Mean", "Stddev", "Noise"
+    )?;
+    for counter in &summary.counters {
+        writeln!(
+            out,
+            "  {:<19}{:>17.1}{:>17.1}{:>8.2}%",
+            format!("{}:", counter.name),
+            counter.mean,
+            counter.stddev,
+            counter.noise_percent()
+        )?;
+    }
+    writeln!(out, "{:=<64}", "")?;
+    Ok(())
+}
+
+/// Event driving callchain sampling; one_collect's profiling builder uses the
+/// software CPU clock, which works on VMs and hosts without a usable PMU.
+const SAMPLING_EVENT: &str = "cpu-clock";
+
+/// Number of time buckets shown by `--sparkline`.
+const SPARKLINE_WIDTH: usize = 60;
+
+/// A gap this many times the expected sampling period is reported as suspicious.
+const GAP_WARNING_FACTOR: u64 = 10;
+
+/// Tracks the time between consecutive samples of each CPU to detect
+/// sampling gaps.
+///
+/// A CPU running nothing of the target is not sampled, so a long gap alone
+/// proves nothing. It is suspicious when LOST or THROTTLE records arrived
+/// during it, or when every CPU is always busy for the session (system-wide).
+#[derive(Debug, Default)]
+struct SampleGapTracker {
+    /// Time of the last sample of each CPU, and `disruptions` at that time
+    last_sample: HashMap<u32, (u64, u64)>,
+    histogram_us: Log2Histogram,
+    max_gap_ns: u64,
+    /// Gaps longer than this are checked for being suspicious; 0 checks none
+    threshold_ns: u64,
+    /// Whether the session samples every task, so that no CPU is ever idle
+    system_wide: bool,
+    /// LOST and THROTTLE records seen so far
+    disruptions: u64,
+    /// Gaps over `threshold_ns` that coincided with a disruption or a busy CPU
+    suspicious_gaps: u64,
+    /// Every sample timestamp, kept only when a sparkline was requested
+    timestamps: Option<Vec<u64>>,
+}
+
+impl SampleGapTracker {
+    /// Record the timestamp of the next sample of `cpu`.
+    fn record(&mut self, cpu: u32, time_ns: u64) {
+        let seen = (time_ns, self.disruptions);
+        if let Some((last, disruptions)) = self.last_sample.insert(cpu, seen) {
+            let gap = time_ns.saturating_sub(last);
+            self.histogram_us.record(gap / 1000);
+            self.max_gap_ns = self.max_gap_ns.max(gap);
+            let disrupted = disruptions != self.disruptions;
+            if self.threshold_ns > 0 && gap > self.threshold_ns && (disrupted || self.system_wide) {
+                self.suspicious_gaps += 1;
+            }
+        }
+        if let Some(timestamps) = &mut self.timestamps {
+            timestamps.push(time_ns);
+        }
+    }
+
+    /// Note a LOST or THROTTLE record, which breaks sampling on its CPU.
+    fn disrupted(&mut self) {
+        self.disruptions += 1;
+    }
+}
+
+/// Signals `--snapshot-signal` accepts, by name without the `SIG` prefix.
+const SNAPSHOT_SIGNALS: &[(&str, i32)] = &[
+    ("QUIT", libc::SIGQUIT),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("HUP", libc::SIGHUP),
+];
+
+/// Parse a snapshot signal name such as `QUIT`, `SIGUSR1` or `usr2`.
+pub fn parse_signal(name: &str) -> Result<i32> {
+    let upper = name.to_ascii_uppercase();
+    let bare = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SNAPSHOT_SIGNALS
+        .iter()
+        .find(|&&(signal_name, _)| signal_name == bare)
+        .map(|&(_, signal)| signal)
+        .with_context(|| {
+            let names: Vec<&str> = SNAPSHOT_SIGNALS.iter().map(|&(name, _)| name).collect();
+            format!(
+                "unsupported snapshot signal {:?} (use one of {})",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+/// Name of a snapshot signal, e.g. `SIGQUIT`.
+fn signal_name(signal: i32) -> String {
+    SNAPSHOT_SIGNALS
+        .iter()
+        .find(|&&(_, number)| number == signal)
+        .map_or_else(
+            || format!("signal {}", signal),
+            |(name, _)| format!("SIG{}", name),
+        )
+}
+
+/// File a snapshot taken `since_epoch` after the Unix epoch is written to, in
+/// the current directory, e.g. `callchain-snapshot-1700000000.250.prof`.
+fn snapshot_path(since_epoch: Duration) -> PathBuf {
+    PathBuf::from(format!(
+        "callchain-snapshot-{}.{:03}.prof",
+        since_epoch.as_secs(),
+        since_epoch.subsec_millis()
+    ))
+}
+
+/// Write the stacks collected so far to a timestamped binary profile, with
+/// symbols resolved the same way as at the end of the run.
+fn write_snapshot(
+    stacks: &HashMap<Vec<u64>, u64>,
+    maps: &MapTracker,
+    address_pids: &HashMap<u64, u32>,
+    options: &CallchainOptions,
+) -> Result<PathBuf> {
+    let snapshot = CallchainProfilingResult {
+        stacks: stacks.clone(),
+        maps: maps.maps.clone(),
+        address_pids: address_pids.clone(),
+        bucket: options.bucket,
+        symbolize_timeout: options.symbolize_timeout,
+        ..Default::default()
+    };
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let path = snapshot_path(since_epoch);
+    profile::write_callchain_profile(&path, &snapshot)?;
+    Ok(path)
+}
+
+/// Which tasks' samples `--comm` keeps, by command name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommFilter {
+    pub names: Vec<String>,
+    /// Match names anywhere in the command rather than the whole command
+    pub substring: bool,
+}
+
+/// Longest command name the kernel keeps (`TASK_COMM_LEN` without the NUL).
+const TASK_COMM_MAX: usize = 15;
+
+impl CommFilter {
+    /// Whether a task running `comm` is kept.
+    ///
+    /// The kernel truncates commands to 15 bytes, so a longer name matches
+    /// exactly when its first 15 bytes do.
+    pub fn matches(&self, comm: &str) -> bool {
+        self.names.iter().any(|name| {
+            if self.substring {
+                comm.contains(name.as_str())
+            } else {
+                comm == name
+                    || (name.len() > TASK_COMM_MAX
+                        && comm.as_bytes() == &name.as_bytes()[..TASK_COMM_MAX])
+            }
+        })
+    }
+}
+
+/// Command name of each task, kept current from COMM and FORK records.
+#[derive(Debug, Default)]
+struct CommTracker {
+    /// Command per TID; `None` when the task could not be looked up
+    comms: HashMap<u32, Option<String>>,
+}
+
+impl CommTracker {
+    /// A task set its command, by `exec` or `prctl(PR_SET_NAME)`.
+    fn on_comm(&mut self, tid: u32, comm: &str) {
+        self.comms.insert(tid, Some(comm.to_string()));
+    }
+
+    /// A new task starts out with the command of the task that created it.
+    fn on_fork(&mut self, parent_tid: u32, child_tid: u32) {
+        if let Some(comm) = self.comms.get(&parent_tid).cloned() {
+            self.comms.insert(child_tid, comm);
+        }
+    }
+
+    /// Command of task `tid`. Tasks no record was seen for, such as tasks
+    /// started before the session, are looked up once with `lookup`.
+    fn comm_or_else(
+        &mut self,
+        tid: u32,
+        lookup: impl FnOnce(u32) -> Option<String>,
+    ) -> Option<&str> {
+        self.comms
+            .entry(tid)
+            .or_insert_with(|| lookup(tid))
+            .as_deref()
+    }
+}
+
+/// Read the command of a running task from `/proc`.
+fn read_proc_comm(tid: u32) -> Option<String> {
+    let comm = std::fs::read_to_string(format!("/proc/{}/comm", tid)).ok()?;
+    Some(comm.trim_end_matches('\n').to_string())
+}
+
+/// Executable mappings of every sampled process, kept current from MMAP and
+/// FORK records.
+#[derive(Debug, Default)]
+struct MapTracker {
+    /// Mappings per PID, in the order they were made. Processes that existed
+    /// before any of their records were seen start from a `/proc` snapshot.
+    maps: HashMap<u32, Vec<MemoryMap>>,
+    /// Process of each TID; `None` when the task could not be looked up
+    processes: HashMap<u32, Option<u32>>,
+}
+
+impl MapTracker {
+    /// A process mapped an executable range.
+    fn on_mmap(&mut self, pid: u32, map: MemoryMap, snapshot: impl FnOnce(u32) -> Vec<MemoryMap>) {
+        self.maps
+            .entry(pid)
+            .or_insert_with(|| snapshot(pid))
+            .push(map);
+    }
+
+    /// A new task belongs to `fork.pid`; a new process starts out with a copy
+    /// of its parent's mappings.
+    fn on_fork(&mut self, fork: ForkRecord) {
+        self.processes.insert(fork.tid, Some(fork.pid));
+        if fork.pid != fork.ppid && !self.maps.contains_key(&fork.pid) {
+            if let Some(parent) = self.maps.get(&fork.ppid).cloned() {
+                self.maps.insert(fork.pid, parent);
+            }
+        }
+    }
+
+    /// Process of task `tid`, whose mappings are snapshotted with `snapshot`
+    /// if none are known yet. Tasks no FORK record was seen for are looked up
+    /// once with `lookup`.
+    fn process_of(
+        &mut self,
+        tid: u32,
+        lookup: impl FnOnce(u32) -> Option<u32>,
+        snapshot: impl FnOnce(u32) -> Vec<MemoryMap>,
+    ) -> Option<u32> {
+        let pid = (*self.processes.entry(tid).or_insert_with(|| lookup(tid)))?;
+        self.maps.entry(pid).or_insert_with(|| snapshot(pid));
+        Some(pid)
+    }
+}
+
+/// Read the process a running task belongs to from `/proc`.
+fn read_proc_tgid(tid: u32) -> Option<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", tid)).ok()?;
+    parse_tgid(&status)?.try_into().ok()
+}
+
+/// Read the executable mappings of a running process, or none if it is gone.
+fn snapshot_proc_maps(pid: u32) -> Vec<MemoryMap> {
+    i32::try_from(pid)
+        .ok()
+        .and_then(|pid| symbols::read_proc_maps(pid).ok())
+        .unwrap_or_default()
+}
+
+/// How the stacks of different threads are combined in folded exports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ThreadMode {
+    /// Stacks of every thread are merged, so the flame graph shows the target as a whole
+    #[default]
+    Merge,
+    /// Stacks of each thread are kept apart, under a root frame naming the thread
+    Split,
+}
+
+/// How often callchain samples are taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SamplingMode {
+    /// Samples per second
+    Frequency(u64),
+    /// Nanoseconds of CPU time between samples
+    Period(u64),
+}
+
+impl Default for SamplingMode {
+    fn default() -> Self {
+        SamplingMode::Frequency(99)
+    }
+}
+
+impl SamplingMode {
+    /// Frequency in Hz to request for the sampling event.
+    ///
+    /// The one_collect sampler only takes a frequency. `cpu-clock` is an hrtimer
+    /// event, though: the kernel replaces a requested frequency with
+    /// `sample_period = 1e9 / frequency` and clears `freq`, so a period that
+    /// divides a second evenly is sampled exactly. Other periods would be
+    /// rounded, and are rejected instead.
+    pub fn frequency(&self) -> Result<u64> {
+        match *self {
+            SamplingMode::Frequency(0) => anyhow::bail!("Sampling frequency must be at least 1 Hz"),
+            SamplingMode::Frequency(frequency) => Ok(frequency),
+            SamplingMode::Period(period) if period == 0 || period > NANOS_PER_SEC => {
+                anyhow::bail!(
+                    "Sampling period must be between 1 and {} ns of CPU time",
+                    NANOS_PER_SEC
+                )
+            }
+            SamplingMode::Period(period) if NANOS_PER_SEC % period != 0 => {
+                anyhow::bail!(
+                    "Sampling period {} ns does not divide one second evenly, so the \
+                     sampler cannot take it exactly; use a divisor of {} such as {} ns",
+                    period,
+                    NANOS_PER_SEC,
+                    exact_period_below(period)
+                )
+            }
+            SamplingMode::Period(period) => Ok(NANOS_PER_SEC / period),
+        }
+    }
+
+    /// Human-readable description, e.g. `99 Hz` or `every 1000000 ns`.
+    pub fn describe(&self) -> String {
+        match self {
+            SamplingMode::Frequency(frequency) => format!("{} Hz", frequency),
+            SamplingMode::Period(period) => format!("every {} ns", period),
+        }
+    }
+}
+
+/// Nanoseconds per second, for converting sampling periods.
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Largest period under `period` ns that divides a second evenly.
+fn exact_period_below(period: u64) -> u64 {
+    // The divisors of 10^9 are the products 2^a * 5^b with a, b <= 9
+    (0..=9)
+        .flat_map(|a| (0..=9).map(move |b| 2u64.pow(a) * 5u64.pow(b)))
+        .filter(|&divisor| divisor <= period)
+        .max()
+        .unwrap_or(1)
+}
+
+/// Results from a CPU profiling session with callchain/stacktrace data.
+#[derive(Debug, Default)]
+pub struct CallchainProfilingResult {
+    /// Total number of samples collected
+    pub sample_count: u64,
+    /// Duration of the profiling session in seconds, fractional for short runs
+    pub duration_secs: f64,
+    /// Sampling frequency used (Hz)
+    pub sampling_frequency: u64,
+    /// Whether sampling was requested by frequency or by period
+    pub sampling_mode: SamplingMode,
+    /// Event that actually drove sampling (e.g. `cpu-clock`)
+    pub sampling_event: &'static str,
+    /// Data pages in each per-CPU ring buffer
+    pub page_count: usize,
+    /// Distribution of time between consecutive samples of a CPU, in microseconds
+    pub gap_histogram_us: Log2Histogram,
+    /// Longest time between two consecutive samples of a CPU, in nanoseconds
+    pub max_gap_ns: u64,
+    /// Gaps over `GAP_WARNING_FACTOR` sampling periods during which samples
+    /// were lost or throttled, or the CPU was busy (system-wide sessions)
+    pub suspicious_gaps: u64,
+    /// Samples per time bucket over the run, when `--sparkline` was requested
+    pub rate_over_time: Option<Vec<u64>>,
+    /// Sample count per unique callchain, frames ordered leaf first
+    pub stacks: HashMap<Vec<u64>, u64>,
+    /// Sample count per unique callchain of each thread, by TID, when threads
+    /// were split; empty otherwise
+    pub thread_stacks: HashMap<u32, HashMap<Vec<u64>, u64>>,
+    /// Command of each thread in `thread_stacks`, when it could be found
+    pub thread_names: HashMap<u32, String>,
+    /// Executable mappings of each sampled process, by PID, from the session's
+    /// MMAP records on top of a `/proc` snapshot taken when the process was first seen
+    pub maps: HashMap<u32, Vec<MemoryMap>>,
+    /// Process each sampled address was first seen in, to pick the mappings
+    /// it is resolved against
+    pub address_pids: HashMap<u64, u32>,
+    /// Thread that was sampled when the session was scoped with `--tid`
+    pub tid: Option<i32>,
+    /// The first raw sample records, when `raw_records` was requested
+    pub raw_records: Vec<Vec<u8>>,
+    /// Samples the kernel dropped because the ring buffer was full (LOST records)
+    pub lost_samples: u64,
+    /// Times the kernel throttled sampling for exceeding its sample rate limit
+    pub throttle_events: u64,
+    /// Times the kernel lifted a throttle
+    pub unthrottle_events: u64,
+    /// Samples dropped because their task matched no `--comm` name
+    pub comm_filtered: u64,
+    /// Whether hypervisor samples and frames were dropped (`--exclude-hv`)
+    pub exclude_hv: bool,
+    /// Samples dropped with `--exclude-hv` because they were taken in the hypervisor
+    pub hv_samples: u64,
+    /// Bytes of function offsets aggregated together; 0 aggregates by function
+    pub bucket: u64,
+    /// Whether Ctrl-C stopped the run early
+    pub interrupted: bool,
+    /// Snapshots written on `snapshot_signal` during the run
+    pub snapshots: Vec<PathBuf>,
+    /// Time actually sampled, when the run's length was not fixed by its
+    /// duration (sentinel file or Ctrl-C)
+    pub active_duration: Option<Duration>,
+    /// Longest symbolizing the stacks may take before the remaining addresses
+    /// are left as hex
+    pub symbolize_timeout: Option<Duration>,
+    /// Frames resolved on first use, shared by every report and export
+    resolved: OnceCell<Vec<ResolvedFrame>>,
+}
+
+impl CallchainProfilingResult {
+    /// Whether the session ran but collected no samples at all.
+    pub fn is_empty(&self) -> bool {
+        self.sample_count == 0
+    }
+
+    /// Warnings about samples the kernel dropped or rate-limited, if any.
+    pub fn loss_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.lost_samples > 0 {
+            warnings.push(format!(
+                "WARNING: {} samples lost (buffer too small); sample counts are incomplete, \
+                 try a larger --page-count",
+                self.lost_samples
+            ));
+        }
+        if self.throttle_events > 0 {
+            warnings.push(format!(
+                "WARNING: the kernel throttled sampling {} times ({} unthrottles); \
+                 lower --frequency or raise kernel.perf_event_max_sample_rate",
+                self.throttle_events, self.unthrottle_events
+            ));
+        }
+        warnings
+    }
+
+    /// Whether a CPU went unsampled for far longer than the sampling period
+    /// while it should have been sampled, suggesting the profiler was starved
+    /// and the profile may be biased.
+    pub fn has_sampling_gaps(&self) -> bool {
+        self.suspicious_gaps > 0
+    }
+
+    /// Sample count per unique callchain, keyed by instruction pointers (leaf first).
+    pub fn folded_stacks(&self) -> &HashMap<Vec<u64>, u64> {
+        &self.stacks
+    }
+
+    /// Name of the function containing each sampled address, for [`top_functions`]
+    /// and call trees.
+    ///
+    /// Frames without a symbol are named by module and offset, or `[unknown]`.
+    /// With a `bucket`, symbolized frames are split by offset as well, see
+    /// [`aggregation_name`].
+    pub fn function_names(&self) -> HashMap<u64, String> {
+        self.resolve_symbols()
+            .iter()
+            .map(|frame| (frame.address, aggregation_name(frame, self.bucket)))
+            .collect()
+    }
+
+    /// Resolve every distinct address in the collected stacks, in address order.
+    ///
+    /// Each address is resolved against the mappings of the process it was
+    /// sampled in. Symbol names are demangled. Addresses outside any mapping
+    /// (kernel frames, or tasks that could not be looked up) are labelled `[unknown]`.
+    /// Addresses are resolved once, within `symbolize_timeout`; those left over
+    /// when it runs out are kept as hex and counted in a warning.
+    pub fn resolve_symbols(&self) -> &[ResolvedFrame] {
+        self.resolved.get_or_init(|| {
+            let mut addresses: Vec<u64> = self.stacks.keys().flatten().copied().collect();
+            addresses.sort_unstable();
+            addresses.dedup();
+
+            // Resolve one process at a time, so each module's symbols are read once
+            let mut by_process: BTreeMap<Option<u32>, Vec<u64>> = BTreeMap::new();
+            for address in addresses {
+                let pid = self.address_pids.get(&address).copied();
+                by_process.entry(pid).or_default().push(address);
+            }
+            let mut symbolizer = Symbolizer::new(Vec::new());
+            if let Some(timeout) = self.symbolize_timeout {
+                symbolizer = symbolizer.with_timeout(timeout);
+            }
+            let mut frames = Vec::new();
+            for (pid, addresses) in by_process {
+                let maps = pid.and_then(|pid| self.maps.get(&pid));
+                symbolizer.set_maps(maps.cloned().unwrap_or_default());
+                frames.extend(addresses.into_iter().map(|address| {
+                    let mut frame = symbolizer.resolve(address);
+                    frame.symbol = frame.symbol.map(|name| demangle_frame(&name));
+                    frame
+                }));
+            }
+            frames.sort_unstable_by_key(|frame| frame.address);
+            if symbolizer.unresolved_count() > 0 {
+                eprintln!(
+                    "WARNING: symbolization ran out of time; {} addresses left as hex \
+                     (raise --symbolize-timeout)",
+                    symbolizer.unresolved_count()
+                );
+            }
+            frames
+        })
+    }
+}
+
+/// Name a frame is aggregated under: its function, or with a non-zero
+/// `bucket` its function and offset rounded down to the bucket, e.g.
+/// `my_func+0x40`.
+///
+/// Frames without a symbol keep their module and offset, or `[unknown]`.
+fn aggregation_name(frame: &ResolvedFrame, bucket: u64) -> String {
+    match &frame.symbol {
+        Some(symbol) if bucket > 0 => {
+            format!("{}+{:#x}", symbol, frame.offset / bucket * bucket)
+        }
+        Some(symbol) => symbol.clone(),
+        None => frame.to_string(),
+    }
+}
+
+/// Demangle a Rust (legacy or v0) or Itanium C++ symbol name.
+///
+/// Names neither demangler recognizes are returned unchanged.
+pub fn demangle_frame(name: &str) -> String {
+    if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+        // The alternate form drops the trailing hash of legacy Rust symbols
+        return format!("{:#}", demangled);
+    }
+    if name.starts_with("_Z") {
+        let demangled = cpp_demangle::Symbol::new(name)
+            .ok()
+            .and_then(|symbol| symbol.demangle(&Default::default()).ok());
+        if let Some(demangled) = demangled {
+            return demangled;
+        }
+    }
+    name.to_string()
+}
+
+/// Callchain entries at or above this value are context markers
+/// (`PERF_CONTEXT_KERNEL`, `PERF_CONTEXT_USER`, ...) rather than addresses.
+const PERF_CONTEXT_MAX: u64 = u64::MAX - 4094;
+
+/// Context marker preceding the frames of a callchain taken in the hypervisor.
+const PERF_CONTEXT_HV: u64 = u64::MAX - 31;
+
+/// Decode the instruction pointers of a raw callchain, dropping context markers.
+///
+/// With `exclude_hv`, frames marked with `PERF_CONTEXT_HV` are dropped, and so
+/// is the whole sample (`None`) when it was taken in the hypervisor.
+fn parse_callchain(bytes: &[u8], exclude_hv: bool) -> Option<Vec<u64>> {
+    let mut frames = Vec::new();
+    let mut context = None;
+    for chunk in bytes.chunks_exact(8) {
+        let ip = u64::from_ne_bytes(chunk.try_into().unwrap());
+        if ip >= PERF_CONTEXT_MAX {
+            // The leaf is the first frame, so its context is the first marker
+            if exclude_hv && ip == PERF_CONTEXT_HV && frames.is_empty() {
+                return None;
+            }
+            context = Some(ip);
+        } else if !(exclude_hv && context == Some(PERF_CONTEXT_HV)) {
+            frames.push(ip);
+        }
+    }
+    Some(frames)
+}
+
+/// Render aggregated stacks in Brendan Gregg's folded format.
+///
+/// Each line is `root;...;leaf count`. Frames are printed using `names`, or as
+/// hex addresses when missing; lines are sorted so the output is stable across runs.
+pub fn format_folded(stacks: &HashMap<Vec<u64>, u64>, names: &HashMap<u64, String>) -> String {
+    let mut lines: Vec<String> = stacks
+        .iter()
+        .map(|(frames, count)| {
+            let folded: Vec<String> = frames
+                .iter()
+                .rev()
+                .map(|ip| {
+                    names
+                        .get(ip)
+                        .cloned()
+                        .unwrap_or_else(|| format!("{:#x}", ip))
+                })
+                .collect();
+            format!("{} {}\n", folded.join(";"), count)
+        })
+        .collect();
+    lines.sort();
+    lines.concat()
+}
+
+/// Samples attributed to one function, as in `perf report`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionStat {
+    pub name: String,
+    /// Samples whose leaf frame is in this function
+    pub self_samples: u64,
+    /// Samples with this function anywhere in the stack
+    pub total_samples: u64,
+    pub self_percent: f64,
+    pub total_percent: f64,
+}
+
+/// Tally self and inclusive samples per function and keep the `n` functions
+/// with the most self samples, ties broken by name.
+///
+/// Frames are named using `names`, or as hex addresses when missing; frames
+/// sharing a name are one function. A function appearing several times in
+/// one stack (recursion) counts that stack once towards its total.
+pub fn top_functions(
+    stacks: &HashMap<Vec<u64>, u64>,
+    names: &HashMap<u64, String>,
+    n: usize,
+) -> Vec<FunctionStat> {
+    let name_of = |ip: &u64| {
+        names
+            .get(ip)
+            .cloned()
+            .unwrap_or_else(|| format!("{:#x}", ip))
+    };
+
+    let mut self_samples: HashMap<String, u64> = HashMap::new();
+    let mut total_samples: HashMap<String, u64> = HashMap::new();
+    let mut sample_count = 0;
+    for (frames, &count) in stacks {
+        sample_count += count;
+        if let Some(leaf) = frames.first() {
+            *self_samples.entry(name_of(leaf)).or_default() += count;
+        }
+        let unique: BTreeSet<String> = frames.iter().map(name_of).collect();
+        for name in unique {
+            *total_samples.entry(name).or_default() += count;
+        }
+    }
+
+    let percent = |samples: u64| {
+        if sample_count == 0 {
+            0.0
+        } else {
+            samples as f64 * 100.0 / sample_count as f64
+        }
+    };
+    let mut stats: Vec<FunctionStat> = total_samples
+        .into_iter()
+        .map(|(name, total)| {
+            let self_count = self_samples.get(&name).copied().unwrap_or(0);
+            FunctionStat {
+                self_samples: self_count,
+                total_samples: total,
+                self_percent: percent(self_count),
+                total_percent: percent(total),
+                name,
+            }
+        })
+        .collect();
+    stats.sort_by(|a, b| {
+        b.self_samples
+            .cmp(&a.self_samples)
+            .then_with(|| a.name.cmp(&b.name))
+    });
+    stats.truncate(n);
+    stats
+}
+
+/// Print a top-functions table with self and total percentages.
+pub fn print_top_functions(
+    stats: &[FunctionStat],
+    max_name_width: usize,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "Top Functions:")?;
+    writeln!(out, "{:-<50}", "")?;
+    let mut table = Table::new(&[
+        ("Self%", Align::Right),
+        ("Total%", Align::Right),
+        ("Function", Align::Name),
+    ])
+    .with_max_name_width(max_name_width);
+    for stat in stats {
+        table.push_row(vec![
+            format!("{:.2}%", stat.self_percent),
+            format!("{:.2}%", stat.total_percent),
+            stat.name.clone(),
+        ]);
+    }
+    table.render(out)
+}
+
+/// Frames kept from the leaf end of a stack in the top-stacks report.
+const STACK_HEAD_FRAMES: usize = 3;
+
+/// Frames kept from the root end of a stack in the top-stacks report.
+const STACK_TAIL_FRAMES: usize = 2;
+
+/// One complete callchain and the samples that had it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackStat {
+    /// Function names, leaf first
+    pub frames: Vec<String>,
+    pub samples: u64,
+    pub percent: f64,
+}
+
+/// Keep the `n` most sampled complete callchains, ties broken by frames.
+///
+/// Frames are named using `names`, or as hex addresses when missing; stacks
+/// whose frames share names (different addresses in the same functions) are
+/// one callchain.
+pub fn top_stacks(
+    stacks: &HashMap<Vec<u64>, u64>,
+    names: &HashMap<u64, String>,
+    n: usize,
+) -> Vec<StackStat> {
+    let mut by_names: HashMap<Vec<String>, u64> = HashMap::new();
+    let mut sample_count = 0;
+    for (frames, &count) in stacks {
+        sample_count += count;
+        let named = frames
+            .iter()
+            .map(|ip| {
+                names
+                    .get(ip)
+                    .cloned()
+                    .unwrap_or_else(|| format!("{:#x}", ip))
+            })
+            .collect();
+        *by_names.entry(named).or_default() += count;
+    }
+
+    let mut stats: Vec<StackStat> = by_names
+        .into_iter()
+        .map(|(frames, samples)| StackStat {
+            frames,
+            samples,
+            percent: samples as f64 * 100.0 / sample_count as f64,
+        })
+        .collect();
+    stats.sort_by(|a, b| {
+        b.samples
+            .cmp(&a.samples)
+            .then_with(|| a.frames.cmp(&b.frames))
+    });
+    stats.truncate(n);
+    stats
+}
+
+/// Format a callchain on one line, leaf first, eliding the middle frames of
+/// deep stacks.
+fn format_stack(frames: &[String]) -> String {
+    if frames.is_empty() {
+        return "[no callchain]".to_string();
+    }
+    if frames.len() <= STACK_HEAD_FRAMES + STACK_TAIL_FRAMES + 1 {
+        return frames.join(" <- ");
+    }
+    let hidden = frames.len() - STACK_HEAD_FRAMES - STACK_TAIL_FRAMES;
+    let head = frames[..STACK_HEAD_FRAMES].join(" <- ");
+    let tail = frames[frames.len() - STACK_TAIL_FRAMES..].join(" <- ");
+    format!("{} <- ...{} frames... <- {}", head, hidden, tail)
+}
+
+/// Print a top-stacks table with sample counts and percentages.
+pub fn print_top_stacks(stats: &[StackStat], out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "Top Stacks (leaf first):")?;
+    writeln!(out, "{:-<50}", "")?;
+    let mut table = Table::new(&[
+        ("Samples", Align::Right),
+        ("Share", Align::Right),
+        ("Stack", Align::Left),
+    ]);
+    for stat in stats {
+        table.push_row(vec![
+            stat.samples.to_string(),
+            format!("{:.2}%", stat.percent),
+            format_stack(&stat.frames),
+        ]);
+    }
+    table.render(out)
+}
+
+/// Samples whose leaf frame is in one module, and how many of them resolved to a symbol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModuleCoverage {
+    /// Module file name, `[unknown]` outside every mapping, or `[timed out]`
+    /// when the symbolization budget ran out first
+    pub module: String,
+    pub samples: u64,
+    pub symbolized: u64,
+}
+
+/// How many samples had a leaf frame resolved to a symbol rather than left as
+/// a module offset or hex address, overall and per module.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolCoverage {
+    /// Samples with at least one frame
+    pub samples: u64,
+    pub symbolized: u64,
+    /// Most samples first, ties broken by module
+    pub modules: Vec<ModuleCoverage>,
+}
+
+/// Share of `part` in `total` in percent, 0 when `total` is 0.
+fn percent_of(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+/// Weigh the leaf frame of every stack by its samples to measure symbol coverage.
+///
+/// `frames` are the resolved addresses of the stacks; leaves missing from it
+/// count as unknown. Samples without a callchain are left out.
+pub fn symbol_coverage(
+    stacks: &HashMap<Vec<u64>, u64>,
+    frames: &[ResolvedFrame],
+) -> SymbolCoverage {
+    let by_address: HashMap<u64, &ResolvedFrame> =
+        frames.iter().map(|frame| (frame.address, frame)).collect();
+    let mut coverage = SymbolCoverage::default();
+    let mut modules: HashMap<&str, (u64, u64)> = HashMap::new();
+    for (stack, &count) in stacks {
+        let Some(leaf) = stack.first() else {
+            continue;
+        };
+        let (module, symbolized) = match by_address.get(leaf) {
+            Some(frame) if frame.module.is_empty() => ("[timed out]", false),
+            Some(frame) => (frame.module.as_str(), frame.symbol.is_some()),
+            None => (symbols::UNKNOWN_FRAME, false),
+        };
+        let entry = modules.entry(module).or_default();
+        entry.0 += count;
+        coverage.samples += count;
+        if symbolized {
+            entry.1 += count;
+            coverage.symbolized += count;
+        }
+    }
+
+    coverage.modules = modules
+        .into_iter()
+        .map(|(module, (samples, symbolized))| ModuleCoverage {
+            module: module.to_string(),
+            samples,
+            symbolized,
+        })
+        .collect();
+    coverage.modules.sort_by(|a, b| {
+        b.samples
+            .cmp(&a.samples)
+            .then_with(|| a.module.cmp(&b.module))
+    });
+    coverage
+}
+
+/// Print the overall symbol coverage and a table of it per module.
+pub fn print_symbol_coverage(coverage: &SymbolCoverage, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(
+        out,
+        "Symbol Coverage: {:.2}% of samples have a symbolized leaf frame",
+        percent_of(coverage.symbolized, coverage.samples)
+    )?;
+    writeln!(out, "{:-<50}", "")?;
+    let mut table = Table::new(&[
+        ("Module", Align::Name),
+        ("Samples", Align::Right),
+        ("Symbolized", Align::Right),
+    ]);
+    for module in &coverage.modules {
+        table.push_row(vec![
+            module.module.clone(),
+            module.samples.to_string(),
+            format!("{:.2}%", percent_of(module.symbolized, module.samples)),
+        ]);
+    }
+    table.render(out)?;
+    if coverage.symbolized < coverage.samples {
+        writeln!(
+            out,
+            "  Modules with low coverage may be stripped or lack debug symbols"
+        )?;
+    }
+    Ok(())
+}
+
+/// Write the stacks of a result to `path` in folded format, with resolved symbols.
+///
+/// When threads were split, each thread's stacks start with a root frame
+/// naming it (see [`thread_rooted_stacks`]). With `min_percent`, frames under
+/// that share of the samples are pruned (see [`calltree::format_pruned_folded`]).
+pub fn write_folded_stacks(
+    path: &Path,
+    result: &CallchainProfilingResult,
+    min_percent: Option<f64>,
+) -> Result<()> {
+    let mut names: HashMap<u64, String> = result
+        .resolve_symbols()
+        .iter()
+        .map(|frame| (frame.address, frame.to_string()))
+        .collect();
+    if result.thread_stacks.is_empty() {
+        return write_folded(path, result.folded_stacks(), &names, min_percent);
+    }
+    let stacks = thread_rooted_stacks(&result.thread_stacks, &result.thread_names, &mut names);
+    write_folded(path, &stacks, &names, min_percent)
+}
+
+/// Combine the stacks of each thread, each with a root frame naming its thread
+/// (`comm-tid`, or `[unknown]-tid`) added to `names`.
+///
+/// Thread frames take the smallest ids no frame address uses, in TID order.
+fn thread_rooted_stacks(
+    thread_stacks: &HashMap<u32, HashMap<Vec<u64>, u64>>,
+    thread_names: &HashMap<u32, String>,
+    names: &mut HashMap<u64, String>,
+) -> HashMap<Vec<u64>, u64> {
+    let addresses: HashSet<u64> = thread_stacks
+        .values()
+        .flat_map(HashMap::keys)
+        .flatten()
+        .copied()
+        .collect();
+    let free_ids = (0..).filter(|id| !addresses.contains(id));
+    let mut tids: Vec<u32> = thread_stacks.keys().copied().collect();
+    tids.sort_unstable();
+
+    let mut rooted = HashMap::new();
+    for (tid, id) in tids.into_iter().zip(free_ids) {
+        let comm = thread_names.get(&tid).map_or("[unknown]", String::as_str);
+        names.insert(id, format!("{}-{}", comm, tid));
+        for (frames, &count) in &thread_stacks[&tid] {
+            let mut stack = frames.clone();
+            stack.push(id);
+            rooted.insert(stack, count);
+        }
+    }
+    rooted
+}
+
+/// Write aggregated stacks to `path` in folded format, frames named using `names`.
+pub fn write_folded(
+    path: &Path,
+    stacks: &HashMap<Vec<u64>, u64>,
+    names: &HashMap<u64, String>,
+    min_percent: Option<f64>,
+) -> Result<()> {
+    let folded = match min_percent {
+        Some(min_percent) => {
+            calltree::format_pruned_folded(&CallTree::from_stacks(stacks, names), min_percent)
+        }
+        None => format_folded(stacks, names),
+    };
+    std::fs::write(path, folded)
+        .with_context(|| format!("Failed to write folded stacks: {}", path.display()))
+}
+
+/// Read the number of dropped samples from the body of a LOST record (`u64 id; u64 lost`).
+fn parse_lost_record(data: &[u8]) -> Option<u64> {
+    Some(u64::from_ne_bytes(data.get(8..16)?.try_into().ok()?))
+}
+
+/// Read the TID and new command from the body of a COMM record
+/// (`u32 pid, tid; char comm[]`, NUL-terminated).
+fn parse_comm_record(data: &[u8]) -> Option<(u32, &str)> {
+    let tid = u32::from_ne_bytes(data.get(4..8)?.try_into().ok()?);
+    let comm = data.get(8..)?;
+    let end = comm.iter().position(|&b| b == 0).unwrap_or(comm.len());
+    Some((tid, std::str::from_utf8(&comm[..end]).ok()?))
+}
+
+/// A task created by `fork` or `clone`, from a FORK record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ForkRecord {
+    /// Process of the new task; the parent's own process for a new thread
+    pid: u32,
+    /// Process of the task that created it
+    ppid: u32,
+    tid: u32,
+    ptid: u32,
+}
+
+/// Read the body of a FORK record (`u32 pid, ppid, tid, ptid; u64 time`).
+fn parse_fork_record(data: &[u8]) -> Option<ForkRecord> {
+    let field = |at: usize| -> Option<u32> {
+        Some(u32::from_ne_bytes(data.get(at..at + 4)?.try_into().ok()?))
+    };
+    Some(ForkRecord {
+        pid: field(0)?,
+        ppid: field(4)?,
+        tid: field(8)?,
+        ptid: field(12)?,
+    })
+}
+
+/// Default number of data pages in each per-CPU ring buffer.
+pub const DEFAULT_PAGE_COUNT: usize = 64;
+
+/// Largest accepted `page_count`: 256 MiB per CPU with 4 KiB pages, far past
+/// what `perf_event_mlock_kb` allows without privileges.
+const MAX_PAGE_COUNT: usize = 1 << 16;
+
+/// Check that a ring buffer page count is a power of two within bounds.
+fn validate_page_count(page_count: usize) -> Result<()> {
+    if !page_count.is_power_of_two() {
+        anyhow::bail!(
+            "Page count must be a power of two (e.g. {} or {}), got {}",
+            DEFAULT_PAGE_COUNT,
+            DEFAULT_PAGE_COUNT * 4,
+            page_count
+        );
+    }
+    if page_count > MAX_PAGE_COUNT {
+        anyhow::bail!(
+            "Page count {} is too large; at most {} pages are allowed per CPU",
+            page_count,
+            MAX_PAGE_COUNT
+        );
+    }
+    Ok(())
+}
+
+/// `kernel.perf_event_mlock_kb` assumed when it cannot be read: the kernel's
+/// default locked memory for perf buffers per online CPU, beyond which
+/// `RLIMIT_MEMLOCK` is charged.
+const DEFAULT_PERF_EVENT_MLOCK_KB: u64 = 516;
+
+/// `CAP_IPC_LOCK` bit in a capability set, which lifts `RLIMIT_MEMLOCK`.
+const CAP_IPC_LOCK: u32 = 14;
+
+/// Bytes in a MiB, for locked memory messages.
+const MIB: f64 = (1 << 20) as f64;
+
+/// Fail with an actionable message if the ring buffers need more locked memory
+/// than the kernel will grant, instead of a bare EPERM when they are mapped.
+///
+/// Each CPU's buffer is `page_count` data pages plus a header page. The kernel
+/// grants `perf_mlock_kb` per online CPU and charges the rest to `RLIMIT_MEMLOCK`.
+///
+/// # Arguments
+///
+/// * `cpu_count` - Number of CPUs a buffer is mapped for
+/// * `memlock_limit` - Soft `RLIMIT_MEMLOCK` in bytes; `None` if unlimited or
+///   lifted by `CAP_IPC_LOCK`
+fn check_locked_memory(
+    page_count: usize,
+    page_size: u64,
+    cpu_count: usize,
+    online_cpus: usize,
+    perf_mlock_kb: u64,
+    memlock_limit: Option<u64>,
+) -> Result<()> {
+    let Some(limit) = memlock_limit else {
+        return Ok(());
+    };
+    let required = (page_count as u64 + 1) * page_size * cpu_count as u64;
+    let granted = perf_mlock_kb * 1024 * online_cpus as u64;
+    if required <= granted.saturating_add(limit) {
+        return Ok(());
+    }
+    anyhow::bail!(
+        "Ring buffers of {} pages on {} CPUs need {:.1} MB of locked memory, but only \
+         {:.1} MB are allowed (RLIMIT_MEMLOCK of {:.1} MB plus {} KiB per CPU from \
+         kernel.perf_event_mlock_kb).\n\
+         Lower --page-count, or raise the limit with `ulimit -l` or by running as root",
+        page_count,
+        cpu_count,
+        required as f64 / MIB,
+        granted.saturating_add(limit) as f64 / MIB,
+        limit as f64 / MIB,
+        perf_mlock_kb
+    )
+}
+
+/// Check up front that ring buffers of `page_count` pages on `cpus` (every
+/// online CPU if `None`) fit in the locked memory the process may use.
+fn check_ring_buffer_memory(page_count: usize, cpus: Option<&[usize]>) -> Result<()> {
+    let online = cpulist::online_cpus()?.len();
+    let can_lock_all = std::fs::read_to_string("/proc/self/status")
+        .map(|status| status_capabilities(&status) & 1 << CAP_IPC_LOCK != 0)
+        .unwrap_or(false);
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes the struct it is given
+    let memlock_limit = (!can_lock_all
+        && unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } == 0
+        && limit.rlim_cur != libc::RLIM_INFINITY)
+        .then_some(limit.rlim_cur);
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+    let perf_mlock_kb = read_sysctl("/proc/sys/kernel/perf_event_mlock_kb")
+        .and_then(|kb| u64::try_from(kb).ok())
+        .unwrap_or(DEFAULT_PERF_EVENT_MLOCK_KB);
+    check_locked_memory(
+        page_count,
+        page_size,
+        cpus.map_or(online, <[usize]>::len),
+        online,
+        perf_mlock_kb,
+        memlock_limit,
+    )
+}
+
+/// Settings for a run of [`run_callchain_profiler`].
+#[derive(Debug)]
+pub struct CallchainOptions {
+    /// Duration in seconds to collect profiling data
+    pub duration_secs: u64,
+    /// Target process ID (-1 for all processes, 0 for current process)
+    pub pid: i32,
+    /// If set, sample only this thread instead of `pid`
+    pub tid: Option<i32>,
+    /// If set, open ring buffers only on these online CPUs
+    pub cpus: Option<Vec<usize>>,
+    /// Sampling frequency in Hz or period in ns of CPU time
+    pub sampling: SamplingMode,
+    /// If set, stop sampling as soon as this file disappears
+    pub while_exists: Option<PathBuf>,
+    /// If set, keep up to this many raw sample records in the result
+    pub raw_records: Option<u64>,
+    /// Record the sample rate over the run for a sparkline
+    pub sparkline: bool,
+    /// Optional path of a bounded log receiving one line per sample
+    pub debug_log: Option<PathBuf>,
+    /// Data pages in each per-CPU ring buffer; a power of two
+    pub page_count: usize,
+    /// If set, keep only the samples of tasks whose command matches
+    pub comm_filter: Option<CommFilter>,
+    /// Drop samples taken in the hypervisor and hypervisor frames of callchains
+    pub exclude_hv: bool,
+    /// Aggregate symbolized frames by offset ranges of this many bytes
+    /// instead of by function; 0 aggregates by function
+    pub bucket: u64,
+    /// Longest symbolizing the stacks may take; unlimited when `None`
+    pub symbolize_timeout: Option<Duration>,
+    /// Whether folded exports merge the stacks of all threads or split them by thread
+    pub thread_mode: ThreadMode,
+    /// If set, this signal writes a snapshot of the stacks so far to a
+    /// timestamped binary profile, and sampling goes on
+    pub snapshot_signal: Option<i32>,
+}
+
+impl Default for CallchainOptions {
+    fn default() -> Self {
+        CallchainOptions {
+            duration_secs: 5,
+            pid: 0,
+            tid: None,
+            cpus: None,
+            sampling: SamplingMode::default(),
+            while_exists: None,
+            raw_records: None,
+            sparkline: false,
+            debug_log: None,
+            page_count: DEFAULT_PAGE_COUNT,
+            comm_filter: None,
+            exclude_hv: false,
+            bucket: 0,
+            symbolize_timeout: None,
+            thread_mode: ThreadMode::Merge,
+            snapshot_signal: None,
+        }
+    }
+}
+
+/// Run CPU profiler with callchain/stacktrace collection using microsoft/one-collect.
+///
+/// This function collects CPU profiling samples with full callchain (stack trace) data
+/// using the perf_event subsystem via the one_collect crate. Nothing is printed;
+/// see [`print_callchain_header`] and [`print_callchain_result`].
+///
+/// # Arguments
+///
+/// * `options` - Target, sampling and collection settings
+///
+/// # Returns
+///
+/// Returns a `CallchainProfilingResult` containing the profiling statistics.
+///
+/// # Example
+///
+/// ```no_run
+/// use profiler::perf::{run_callchain_profiler, CallchainOptions, SamplingMode};
+///
+/// // Profile for 5 seconds at 99 Hz
+/// let options = CallchainOptions {
+///     sampling: SamplingMode::Frequency(99),
+///     ..Default::default()
+/// };
+/// let result = run_callchain_profiler(&options).unwrap();
+/// println!("Collected {} samples", result.sample_count);
+/// ```
+pub fn run_callchain_profiler(options: &CallchainOptions) -> Result<CallchainProfilingResult> {
+    let sampling = options.sampling;
+    let pid = options.pid;
+    let while_exists = options.while_exists.as_deref();
+    let raw_records = options.raw_records;
+    let sampling_frequency = sampling.frequency()?;
+    validate_page_count(options.page_count)?;
+    check_ring_buffer_memory(options.page_count, options.cpus.as_deref())?;
+    if let Some(tid) = options.tid {
+        resolve_target_tid(tid)?;
+    }
+    if let Some(cpus) = &options.cpus {
+        cpulist::validate_online(cpus)?;
+    }
+    check_sentinel(while_exists)?;
+    check_perf_permissions(options.tid.is_none() && pid == -1, true)?;
+    let debug_log = options
+        .debug_log
+        .as_deref()
+        .map(|path| DebugLog::create(path, debuglog::DEFAULT_MAX_BYTES))
+        .transpose()?
+        .map(RefCell::new);
+
+    // Create a profiling builder with callchain support; the CPU of each
+    // sample lets gaps be measured per CPU
+    let profiling_builder = RingBufBuilder::for_profiling(sampling_frequency)
+        .with_callchain_data()
+        .with_ip()
+        .with_tid()
+        .with_cpu();
+
+    // Build the session
+    let mut session_builder = RingBufSessionBuilder::new()
+        .with_page_count(options.page_count)
+        .with_profiling_events(profiling_builder);
+
+    if let Some(cpus) = &options.cpus {
+        session_builder = session_builder.with_cpus(cpus);
+    }
+
+    // MMAP and FORK records keep the mappings of every sampled process current
+    // for symbolization; COMM records keep the command of every task current
+    // for --comm and for naming split threads
+    let split_threads = options.thread_mode == ThreadMode::Split;
+    let track_comms = options.comm_filter.is_some() || split_threads;
+    let mut kernel_builder = RingBufBuilder::for_kernel()
+        .with_mmap_records()
+        .with_task_records();
+    if track_comms {
+        kernel_builder = kernel_builder.with_comm_records();
+    }
+    session_builder = session_builder.with_kernel_events(kernel_builder);
+
+    // Add target PID if specified (not -1 for all); a TID scopes the
+    // session to that one thread
+    if let Some(tid) = options.tid {
+        session_builder = session_builder.with_target_pid(tid);
+    } else if pid >= 0 {
+        session_builder = session_builder.with_target_pid(pid);
+    }
+
+    let mut session = session_builder
+        .build()
+        .context("Failed to build perf session")?;
+
+    // Set up sample counter using Rc<Cell> for interior mutability in callback
+    let sample_count = Rc::new(Cell::new(0u64));
+    let sample_count_clone = sample_count.clone();
+    let gaps = Rc::new(RefCell::new(SampleGapTracker {
+        threshold_ns: NANOS_PER_SEC / sampling_frequency * GAP_WARNING_FACTOR,
+        system_wide: options.tid.is_none() && pid == -1,
+        timestamps: options.sparkline.then(Vec::new),
+        ..Default::default()
+    }));
+    let gaps_clone = gaps.clone();
+    let lost_gaps = gaps.clone();
+    let throttled_gaps = gaps.clone();
+    let stacks = Rc::new(RefCell::new(HashMap::new()));
+    let stacks_clone = stacks.clone();
+    let thread_stacks = Rc::new(RefCell::new(HashMap::<u32, HashMap<Vec<u64>, u64>>::new()));
+    let thread_stacks_clone = thread_stacks.clone();
+    let raw = Rc::new(RefCell::new(Vec::new()));
+    let raw_clone = raw.clone();
+    let lost = Rc::new(Cell::new(0u64));
+    let lost_clone = lost.clone();
+    let throttled = Rc::new(Cell::new(0u64));
+    let throttled_clone = throttled.clone();
+    let unthrottled = Rc::new(Cell::new(0u64));
+    let unthrottled_clone = unthrottled.clone();
+    let comms = Rc::new(RefCell::new(CommTracker::default()));
+    let comms_clone = comms.clone();
+    let maps = Rc::new(RefCell::new(MapTracker::default()));
+    let maps_clone = maps.clone();
+    let address_pids = Rc::new(RefCell::new(HashMap::new()));
+    let address_pids_clone = address_pids.clone();
+    let comm_filter = options.comm_filter.clone();
+    let comm_filtered = Rc::new(Cell::new(0u64));
+    let comm_filtered_clone = comm_filtered.clone();
+    let exclude_hv = options.exclude_hv;
+    let hv_samples = Rc::new(Cell::new(0u64));
+    let hv_samples_clone = hv_samples.clone();
+    let time_field = session.time_data_ref();
+    let cpu_field = session.cpu_data_ref();
+    let tid_field = session.tid_data_ref();
+    let callchain_field = session.callchain_data_ref();
+
+    // Add callback to the CPU profile event to count samples
+    session.cpu_profile_event().add_callback(move |event_data| {
+        // Gaps measure the sampler itself, so every sample counts towards them
+        let time = time_field.get_u64(event_data.full_data()).ok();
+        let cpu = cpu_field.get_u32(event_data.full_data()).ok();
+        if let (Some(time), Some(cpu)) = (time, cpu) {
+            gaps_clone.borrow_mut().record(cpu, time);
+        }
+
+        let tid = tid_field.get_u32(event_data.full_data()).ok();
+        if let Some(filter) = &comm_filter {
+            let matched = tid.is_some_and(|tid| {
+                comms_clone
+                    .borrow_mut()
+                    .comm_or_else(tid, read_proc_comm)
+                    .is_some_and(|comm| filter.matches(comm))
+            });
+            if !matched {
+                comm_filtered_clone.set(comm_filtered_clone.get() + 1);
+                return Ok(());
+            }
+        }
+
+        let callchain = match callchain_field.get_data(event_data.full_data()) {
+            Ok(callchain) => match parse_callchain(callchain, exclude_hv) {
+                Some(frames) => Some(frames),
+                None => {
+                    hv_samples_clone.set(hv_samples_clone.get() + 1);
+                    return Ok(());
+                }
+            },
+            Err(_) => None,
+        };
+
+        let count = sample_count_clone.get() + 1;
+        sample_count_clone.set(count);
+
+        if let Some(frames) = callchain {
+            let pid = tid.and_then(|tid| {
+                maps_clone
+                    .borrow_mut()
+                    .process_of(tid, read_proc_tgid, snapshot_proc_maps)
+            });
+            if let Some(pid) = pid {
+                let mut address_pids = address_pids_clone.borrow_mut();
+                for &address in &frames {
+                    address_pids.entry(address).or_insert(pid);
+                }
+            }
+            if let (true, Some(tid)) = (split_threads, tid) {
+                // Looked up while the thread is alive, so it can still be named
+                comms_clone.borrow_mut().comm_or_else(tid, read_proc_comm);
+                *thread_stacks_clone
+                    .borrow_mut()
+                    .entry(tid)
+                    .or_default()
+                    .entry(frames.clone())
+                    .or_insert(0) += 1;
+            }
+            *stacks_clone.borrow_mut().entry(frames).or_insert(0) += 1;
+        }
+
+        if let Some(log) = &debug_log {
+            log.borrow_mut()
+                .record("SAMPLE", event_data.full_data().len(), time)?;
+        }
+
+        if raw_records.is_some_and(|limit| count <= limit) {
+            raw_clone.borrow_mut().push(event_data.full_data().to_vec());
+        }
+        Ok(())
+    });
+
+    // The kernel reports ring buffer overflows and rate limiting out of band;
+    // without these the sample counts would look complete when they aren't
+    session.lost_event().add_callback(move |event_data| {
+        let dropped = parse_lost_record(event_data.event_data()).unwrap_or(0);
+        lost_clone.set(lost_clone.get() + dropped);
+        lost_gaps.borrow_mut().disrupted();
+        Ok(())
+    });
+    session.throttle_event().add_callback(move |_| {
+        throttled_clone.set(throttled_clone.get() + 1);
+        throttled_gaps.borrow_mut().disrupted();
+        Ok(())
+    });
+    session.unthrottle_event().add_callback(move |_| {
+        unthrottled_clone.set(unthrottled_clone.get() + 1);
+        Ok(())
+    });
+    if track_comms {
+        let comms_clone = comms.clone();
+        session.comm_event().add_callback(move |event_data| {
+            if let Some((tid, comm)) = parse_comm_record(event_data.event_data()) {
+                comms_clone.borrow_mut().on_comm(tid, comm);
+            }
+            Ok(())
+        });
+    }
+    let comms_clone = comms.clone();
+    let fork_maps = maps.clone();
+    session.fork_event().add_callback(move |event_data| {
+        if let Some(fork) = parse_fork_record(event_data.event_data()) {
+            comms_clone.borrow_mut().on_fork(fork.ptid, fork.tid);
+            fork_maps.borrow_mut().on_fork(fork);
+        }
+        Ok(())
+    });
+    let mmap_maps = maps.clone();
+    session.mmap_event().add_callback(move |event_data| {
+        if let Some((pid, map)) = tracepoint::parse_live_mmap_record(event_data.full_data()) {
+            mmap_maps.borrow_mut().on_mmap(pid, map, snapshot_proc_maps);
+        }
+        Ok(())
+    });
+
+    // Snapshot the target's mappings before it can change them, for the
+    // modules it loaded before the session started
+    if let Some(target) = options.tid.or((pid >= 0).then_some(pid)) {
+        let target = if target == 0 {
+            std::process::id()
+        } else {
+            target as u32
+        };
+        maps.borrow_mut()
+            .process_of(target, read_proc_tgid, snapshot_proc_maps);
+    }
+
+    // Enable the session and collect data
+    session.enable().context("Failed to enable perf session")?;
+
+    // Parse events for the specified duration, or until the sentinel disappears
+    // or Ctrl-C is pressed
+    let duration = Duration::from_secs(options.duration_secs);
+    let interrupt = InterruptScope::enter();
+    let snapshot_signal = options
+        .snapshot_signal
+        .map(|signal| {
+            SnapshotSignal::install(signal)
+                .with_context(|| format!("Failed to catch {}", signal_name(signal)))
+        })
+        .transpose()?;
+    let snapshots = RefCell::new(Vec::new());
+    let start = Instant::now();
+    session
+        .parse_until(|| {
+            // Checked between batches of records, so a snapshot never splits one
+            if snapshot_signal
+                .as_ref()
+                .is_some_and(SnapshotSignal::take_request)
+            {
+                match write_snapshot(
+                    &stacks.borrow(),
+                    &maps.borrow(),
+                    &address_pids.borrow(),
+                    options,
+                ) {
+                    Ok(path) => {
+                        eprintln!("Snapshot written to: {}", path.display());
+                        snapshots.borrow_mut().push(path);
+                    }
+                    Err(e) => eprintln!("WARNING: snapshot failed: {:#}", e),
+                }
+            }
+            start.elapsed() >= duration || sentinel_removed(while_exists) || interrupt.interrupted()
+        })
+        .context("Failed to parse perf events")?;
+    let elapsed = start.elapsed();
+    let interrupted = interrupt.interrupted();
+
+    session
+        .disable()
+        .context("Failed to disable perf session")?;
+
+    let gaps = gaps.take();
+    let thread_stacks = thread_stacks.take();
+    let thread_names = thread_stacks
+        .keys()
+        .filter_map(|&tid| {
+            let comm = comms
+                .borrow_mut()
+                .comm_or_else(tid, read_proc_comm)?
+                .to_string();
+            Some((tid, comm))
+        })
+        .collect();
+    Ok(CallchainProfilingResult {
+        sample_count: sample_count.get(),
+        duration_secs: elapsed.as_secs_f64(),
+        sampling_frequency,
+        sampling_mode: sampling,
+        sampling_event: SAMPLING_EVENT,
+        page_count: options.page_count,
+        gap_histogram_us: gaps.histogram_us,
+        max_gap_ns: gaps.max_gap_ns,
+        suspicious_gaps: gaps.suspicious_gaps,
+        rate_over_time: gaps
+            .timestamps
+            .map(|timestamps| histogram::bucket_counts(&timestamps, SPARKLINE_WIDTH)),
+        stacks: stacks.take(),
+        thread_stacks,
+        thread_names,
+        maps: maps.take().maps,
+        address_pids: address_pids.take(),
+        tid: options.tid,
+        raw_records: raw.take(),
+        lost_samples: lost.get(),
+        throttle_events: throttled.get(),
+        unthrottle_events: unthrottled.get(),
+        comm_filtered: comm_filtered.get(),
+        exclude_hv,
+        hv_samples: hv_samples.get(),
+        bucket: options.bucket,
+        interrupted,
+        snapshots: snapshots.into_inner(),
+        active_duration: (while_exists.is_some() || interrupted).then_some(elapsed),
+        symbolize_timeout: options.symbolize_timeout,
+        resolved: OnceCell::new(),
+    })
+}
+
+/// Print the settings of a callchain run to stderr before it starts.
+///
+/// Fails if the sampling mode or page count is invalid, like [`run_callchain_profiler`].
+pub fn print_callchain_header(options: &CallchainOptions) -> Result<()> {
+    let sampling = options.sampling;
+    let pid = options.pid;
+    let sampling_frequency = sampling.frequency()?;
+    validate_page_count(options.page_count)?;
+    eprintln!("Starting callchain profiler with one_collect...");
+    eprintln!("Duration: {} seconds", options.duration_secs);
+    match sampling {
+        SamplingMode::Frequency(_) => eprintln!("Sampling frequency: {} Hz", sampling_frequency),
+        SamplingMode::Period(_) => eprintln!(
+            "Sampling period: {} ({} Hz)",
+            sampling.describe(),
+            sampling_frequency
+        ),
+    }
+    eprintln!("Sampling event: {} (software)", SAMPLING_EVENT);
+    match options.tid {
+        Some(tid) => eprintln!("Target TID: {}", tid),
+        None => eprintln!(
+            "Target PID: {}",
+            if pid == -1 {
+                "all".to_string()
+            } else if pid == 0 {
+                "current".to_string()
+            } else {
+                pid.to_string()
+            }
+        ),
+    }
+    if let Some(path) = &options.while_exists {
+        eprintln!("Active while exists: {}", path.display());
+    }
+    if let Some(cpus) = &options.cpus {
+        eprintln!("CPUs: {}", cpulist::format_cpu_list(cpus));
+    }
+    if let Some(filter) = &options.comm_filter {
+        eprintln!(
+            "Commands: {}{}",
+            filter.names.join(", "),
+            if filter.substring { " (substring)" } else { "" }
+        );
+    }
+    eprintln!("Ring buffer: {} pages per CPU", options.page_count);
+    if options.thread_mode == ThreadMode::Split {
+        eprintln!("Threads: split (one root frame per thread in folded exports)");
+    }
+    if let Some(signal) = options.snapshot_signal {
+        eprintln!(
+            "Snapshots: on {} (kill -{} {}), to callchain-snapshot-<time>.prof",
+            signal_name(signal),
+            signal_name(signal).trim_start_matches("SIG"),
+            std::process::id()
+        );
+    }
+    eprintln!();
+
+    eprintln!("Collecting callchain profiling data...");
+    Ok(())
+}
+
+/// Print a callchain result, preceded by any raw records it kept.
+pub fn print_callchain_result(
+    result: &CallchainProfilingResult,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    for record in &result.raw_records {
+        writeln!(
+            out,
+            "  [raw] SAMPLE size={}: {}",
+            record.len(),
+            hex_preview(record, RAW_PREVIEW_BYTES)
+        )?;
+    }
+
+    for warning in result.loss_warnings() {
+        writeln!(out, "{}", warning)?;
+    }
+
+    if result.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "No samples were collected. Possible causes:")?;
+        writeln!(out, "  - The target was idle or exited during the run")?;
+        writeln!(
+            out,
+            "  - The sampling frequency is too low for the duration (try --frequency 999)"
+        )?;
+        writeln!(
+            out,
+            "  - The PID does not exist or is not the intended process"
+        )?;
+        writeln!(
+            out,
+            "  - Insufficient permissions (check /proc/sys/kernel/perf_event_paranoid)"
+        )?;
+        if result.comm_filtered > 0 {
+            writeln!(
+                out,
+                "  - No sampled task matched --comm ({} samples of other tasks dropped)",
+                result.comm_filtered
+            )?;
+        }
+        return Ok(());
+    }
+
+    writeln!(out)?;
+    writeln!(out, "Callchain Profiling Results:")?;
+    writeln!(out, "{:=<50}", "")?;
+    if result.interrupted {
+        writeln!(out, "  Interrupted by Ctrl-C; partial results")?;
+    }
+    writeln!(out, "  Samples Collected: {:>15}", result.sample_count)?;
+    writeln!(out, "  Unique Stacks:     {:>15}", result.stacks.len())?;
+    if result.comm_filtered > 0 {
+        writeln!(out, "  Other Commands:    {:>15}", result.comm_filtered)?;
+    }
+    if result.exclude_hv {
+        writeln!(out, "  Hypervisor:        {:>15}", "excluded")?;
+        writeln!(out, "  Hypervisor Samples:{:>15}", result.hv_samples)?;
+    }
+    if let Some(elapsed) = result.active_duration {
+        writeln!(
+            out,
+            "  Active Duration:   {:>13.2} s",
+            elapsed.as_secs_f64()
+        )?;
+    }
+    writeln!(out, "  Duration:          {:>13.2} s", result.duration_secs)?;
+    writeln!(
+        out,
+        "  Sampling Freq:     {:>12} Hz",
+        result.sampling_frequency
+    )?;
+    if let SamplingMode::Period(period) = result.sampling_mode {
+        writeln!(out, "  Sampling Period:   {:>12} ns", period)?;
+    }
+    writeln!(out, "  Sampling Event:    {:>15}", result.sampling_event)?;
+    writeln!(out, "  Ring Buffer:       {:>9} pages", result.page_count)?;
+    writeln!(
+        out,
+        "  Effective Rate:    {:>12.1} samples/s",
+        result.sample_count as f64 / result.duration_secs
+    )?;
+    writeln!(
+        out,
+        "  Max Sample Gap:    {:>12.3} ms",
+        result.max_gap_ns as f64 / 1_000_000.0
+    )?;
+    writeln!(out, "{:-<50}", "")?;
+    writeln!(out, "  Inter-sample gaps (us):")?;
+    result.gap_histogram_us.print(out)?;
+    if let Some(rate) = &result.rate_over_time {
+        writeln!(out, "{:-<50}", "")?;
+        writeln!(
+            out,
+            "  Sample rate over time (max {} per bucket):",
+            rate.iter().max().unwrap_or(&0)
+        )?;
+        writeln!(out, "  {}", histogram::sparkline(rate))?;
+    }
+    writeln!(out, "{:=<50}", "")?;
+
+    if result.has_sampling_gaps() {
+        writeln!(
+            out,
+            "WARNING: {} gaps between samples of a CPU exceeded {}x the sampling period \
+             while samples were lost or throttled, or the CPU was busy; the profiler \
+             may have been starved and the profile may be biased",
+            result.suspicious_gaps, GAP_WARNING_FACTOR
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiling_result_json_round_trip() {
+        let result = ProfilingResult {
+            counts: BTreeMap::from([("cpu-cycles", 2000), ("instructions", 3000)]),
+            duration_secs: 5.0,
+            pid: 42,
+            ..Default::default()
+        };
+        let parsed: ProfilingResult = serde_json::from_str(&format_json(&result).unwrap()).unwrap();
+        assert_eq!(parsed.counts, result.counts);
+        assert!((parsed.duration_secs - 5.0).abs() < f64::EPSILON);
+        assert_eq!(parsed.pid, 42);
+        assert_eq!(parsed.ipc(), Some(1.5));
+
+        let unknown = r#"{"counts": {"bogus": 1}, "time_enabled_ns": 0, "time_running_ns": 0,
+            "multiplexed": false, "scaled": false, "duration_secs": 1, "pid": 0, "exit_code": null}"#;
+        let err = serde_json::from_str::<ProfilingResult>(unknown).unwrap_err();
+        assert!(err.to_string().contains("unknown event 'bogus'"));
+    }
+
+    #[test]
+    fn test_top_functions() {
+        let names: HashMap<u64, String> = [
+            (0x10, "leaf_a".to_string()),
+            (0x11, "leaf_a".to_string()),
+            (0x20, "leaf_b".to_string()),
+            (0x30, "main".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let stacks: HashMap<Vec<u64>, u64> = [
+            (vec![0x10, 0x30], 3),
+            (vec![0x11, 0x10, 0x30], 1),
+            (vec![0x20, 0x30], 4),
+            (vec![0x99], 2),
+        ]
+        .into_iter()
+        .collect();
+
+        let top = top_functions(&stacks, &names, 10);
+        let rows: Vec<(&str, u64, u64)> = top
+            .iter()
+            .map(|stat| (stat.name.as_str(), stat.self_samples, stat.total_samples))
+            .collect();
+        // leaf_a and leaf_b tie on self samples and sort by name; the recursive
+        // stack counts once towards leaf_a's total
+        assert_eq!(
+            rows,
+            [
+                ("leaf_a", 4, 4),
+                ("leaf_b", 4, 4),
+                ("0x99", 2, 2),
+                ("main", 0, 8)
+            ]
+        );
+        assert!((top[0].self_percent - 40.0).abs() < f64::EPSILON);
+        assert!((top[3].total_percent - 80.0).abs() < f64::EPSILON);
+
+        assert_eq!(top_functions(&stacks, &names, 2).len(), 2);
+        assert!(top_functions(&HashMap::new(), &names, 5).is_empty());
+    }
+
+    #[test]
+    fn test_print_top_functions() {
+        let stats = [FunctionStat {
+            name: "main".to_string(),
+            self_samples: 1,
+            total_samples: 4,
+            self_percent: 25.0,
+            total_percent: 100.0,
+        }];
+        let mut out = Vec::new();
+        print_top_functions(&stats, 60, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("Top Functions:"));
+        assert!(report.contains("   Self%   Total%  Function\n"));
+        assert!(report.ends_with("  25.00%  100.00%  main\n"));
+    }
+
+    #[test]
+    fn test_top_stacks() {
+        let names: HashMap<u64, String> = [
+            (0x10, "leaf".to_string()),
+            (0x11, "leaf".to_string()),
+            (0x20, "work".to_string()),
+            (0x30, "main".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let stacks: HashMap<Vec<u64>, u64> = [
+            (vec![0x10, 0x20, 0x30], 3),
+            (vec![0x11, 0x20, 0x30], 2),
+            (vec![0x20, 0x30], 4),
+            (vec![0x99], 1),
+        ]
+        .into_iter()
+        .collect();
+
+        let top = top_stacks(&stacks, &names, 2);
+        assert_eq!(top.len(), 2);
+        // Both addresses in `leaf` make one callchain
+        assert_eq!(top[0].frames, ["leaf", "work", "main"]);
+        assert_eq!(top[0].samples, 5);
+        assert!((top[0].percent - 50.0).abs() < f64::EPSILON);
+        assert_eq!(top[1].frames, ["work", "main"]);
+        assert_eq!(top_stacks(&stacks, &names, 10).len(), 3);
+    }
+
+    #[test]
+    fn test_symbol_coverage() {
+        let frame = |address: u64, module: &str, symbol: Option<&str>| ResolvedFrame {
+            address,
+            module: module.to_string(),
+            symbol: symbol.map(str::to_string),
+            offset: 0,
+        };
+        let frames = [
+            frame(0x10, "app", Some("main")),
+            frame(0x20, "app", None),
+            frame(0x30, "libc.so.6", Some("memcpy")),
+            ResolvedFrame::unresolved(0x40),
+        ];
+        let stacks: HashMap<Vec<u64>, u64> = [
+            (vec![0x10], 6),
+            (vec![0x20, 0x10], 2),
+            (vec![0x30, 0x10], 1),
+            (vec![0x40], 1),
+            (vec![0x99], 2),
+            (vec![], 5),
+        ]
+        .into_iter()
+        .collect();
+
+        let coverage = symbol_coverage(&stacks, &frames);
+        assert_eq!((coverage.samples, coverage.symbolized), (12, 7));
+        let modules: Vec<(&str, u64, u64)> = coverage
+            .modules
+            .iter()
+            .map(|module| (module.module.as_str(), module.samples, module.symbolized))
+            .collect();
+        assert_eq!(
+            modules,
+            [
+                ("app", 8, 6),
+                ("[unknown]", 2, 0),
+                ("[timed out]", 1, 0),
+                ("libc.so.6", 1, 1)
+            ]
+        );
+
+        let mut out = Vec::new();
+        print_symbol_coverage(&coverage, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("Symbol Coverage: 58.33% of samples"));
+        assert!(report.contains("  app                8      75.00%\n"));
+    }
+
+    #[test]
+    fn test_format_stack() {
+        let frames = |n: usize| -> Vec<String> { (0..n).map(|i| format!("f{}", i)).collect() };
+        assert_eq!(format_stack(&frames(3)), "f0 <- f1 <- f2");
+        assert_eq!(format_stack(&frames(6)), "f0 <- f1 <- f2 <- f3 <- f4 <- f5");
+        assert_eq!(
+            format_stack(&frames(9)),
+            "f0 <- f1 <- f2 <- ...4 frames... <- f7 <- f8"
+        );
+        assert_eq!(format_stack(&[]), "[no callchain]");
+    }
+
+    #[test]
+    fn test_validate_page_count() {
+        assert!(validate_page_count(DEFAULT_PAGE_COUNT).is_ok());
+        assert!(validate_page_count(1).is_ok());
+        assert!(validate_page_count(MAX_PAGE_COUNT).is_ok());
+        let err = validate_page_count(100).unwrap_err();
+        assert!(err.to_string().contains("power of two"));
+        assert!(validate_page_count(0).is_err());
+        let err = validate_page_count(MAX_PAGE_COUNT * 2).unwrap_err();
+        assert!(err.to_string().contains("too large"));
+    }
+
+    #[test]
+    fn test_check_locked_memory() {
+        // 64 data pages and a header page fit in the 516 KiB granted per CPU
+        assert!(check_locked_memory(64, 4096, 8, 8, 516, Some(0)).is_ok());
+        assert!(check_locked_memory(4096, 4096, 8, 8, 516, None).is_ok());
+        // 8 CPUs of 1025 pages need 32.0 MiB, past 4 MiB of limit and 4 MiB granted
+        let err = check_locked_memory(1024, 4096, 8, 8, 516, Some(4 << 20)).unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("need 32.0 MB of locked memory"),
+            "{}",
+            message
+        );
+        assert!(message.contains("RLIMIT_MEMLOCK of 4.0 MB"), "{}", message);
+        // Only the counted CPUs need buffers, but every online CPU adds to the grant
+        assert!(check_locked_memory(1024, 4096, 1, 8, 516, Some(4 << 20)).is_ok());
+    }
+
+    #[test]
+    fn test_thread_rooted_stacks() {
+        let stack = |count| HashMap::from([(vec![0x1, 0x10], count)]);
+        let thread_stacks = HashMap::from([(10, stack(3)), (11, stack(2))]);
+        let thread_names = HashMap::from([(10, "worker".to_string())]);
+        let mut names = HashMap::from([(0x1, "leaf".to_string()), (0x10, "main".to_string())]);
+
+        let rooted = thread_rooted_stacks(&thread_stacks, &thread_names, &mut names);
+        // Thread frames skip ids frame addresses already use
+        assert_eq!(rooted[&vec![0x1, 0x10, 0]], 3);
+        assert_eq!(rooted[&vec![0x1, 0x10, 2]], 2);
+        assert_eq!(
+            format_folded(&rooted, &names),
+            "[unknown]-11;main;leaf 2\nworker-10;main;leaf 3\n"
+        );
+    }
+
+    #[test]
+    fn test_loss_warnings() {
+        let mut result = CallchainProfilingResult::default();
+        assert!(result.loss_warnings().is_empty());
+
+        let mut record = 7u64.to_ne_bytes().to_vec();
+        record.extend_from_slice(&42u64.to_ne_bytes());
+        result.lost_samples = parse_lost_record(&record).unwrap();
+        result.throttle_events = 3;
+        result.unthrottle_events = 2;
+        let warnings = result.loss_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("WARNING: 42 samples lost (buffer too small)"));
+        assert!(warnings[0].contains("--page-count"));
+        assert!(warnings[1].contains("throttled sampling 3 times (2 unthrottles)"));
+
+        assert!(parse_lost_record(&record[..12]).is_none());
+    }
+
+    #[test]
+    fn test_parse_tgid() {
+        let status = "Name:\tworker\nState:\tS (sleeping)\nTgid:\t4242\nPid:\t4250\n";
+        assert_eq!(parse_tgid(status), Some(4242));
+        assert_eq!(parse_tgid("Name:\tworker\n"), None);
+    }
+
+    #[test]
+    fn test_resolve_target_tid() {
+        // The main thread's TID is the process ID
+        let pid = std::process::id() as i32;
+        assert_eq!(resolve_target_tid(pid).unwrap(), pid);
+        let err = resolve_target_tid(999999999).unwrap_err();
+        assert!(err.to_string().contains("no such thread: 999999999"));
+        assert!(resolve_target_tid(0).is_err());
+    }
+
+    #[test]
+    fn test_find_threads_by_name() {
+        let root = std::env::temp_dir().join(format!("profiler-proc-{}", std::process::id()));
+        for (pid, tid, comm) in [
+            (100, 100, "app"),
+            (100, 101, "net-io"),
+            (100, 102, "worker"),
+            (200, 201, "net-io"),
+            (300, 300, "network-dispatc"),
+        ] {
+            let task = root.join(format!("{}/task/{}", pid, tid));
+            std::fs::create_dir_all(&task).unwrap();
+            std::fs::write(task.join("comm"), format!("{}\n", comm)).unwrap();
+        }
+        std::fs::create_dir_all(root.join("sys")).unwrap();
+
+        let thread = |pid, tid| ThreadMatch { pid, tid };
+        let all = find_threads_by_name(&root, "net-io", None);
+        let within = find_threads_by_name(&root, "net-io", Some(200));
+        // The kernel keeps 15 bytes of the name
+        let truncated = find_threads_by_name(&root, "network-dispatcher", None);
+        let missing = find_threads_by_name(&root, "net", None);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(all, [thread(100, 101), thread(200, 201)]);
+        assert_eq!(within, [thread(200, 201)]);
+        assert_eq!(truncated, [thread(300, 300)]);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn test_parse_stat_state() {
+        assert_eq!(parse_stat_state("1234 (bash) S 1 1234 1234"), Some('S'));
+        assert_eq!(parse_stat_state("77 (a (b) c) Z 1 77"), Some('Z'));
+        assert_eq!(parse_stat_state("garbage"), None);
+    }
+
+    #[test]
+    fn test_wait_stops_when_target_exits() {
+        let own = std::process::id() as i32;
+        assert!(!tasks_exited(&[]));
+        assert!(!tasks_exited(&[own]));
+        assert!(tasks_exited(&[999999999]));
+        assert!(!tasks_exited(&[own, 999999999]));
+
+        let interrupt = InterruptScope::enter();
+        let waited = wait_for_duration(Duration::from_secs(30), None, &[999999999], &interrupt);
+        assert!(waited < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_adjusted_counts() {
+        let mut result = result_with(&[("cpu-cycles", 1000), ("instructions", 500)], 1);
+        assert_eq!(result.adjusted_counts(), None);
+
+        result.baseline = Some(Box::new(ProfilingResult {
+            pid: 42,
+            ..result_with(&[("cpu-cycles", 300), ("instructions", 800)], 1)
+        }));
+        let adjusted = result.adjusted_counts().unwrap();
+        assert_eq!(adjusted["cpu-cycles"], 700);
+        // A busier control clamps at zero rather than going negative
+        assert_eq!(adjusted["instructions"], 0);
+
+        let mut out = Vec::new();
+        print_profiling_result(&result, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("Baseline-Adjusted Counts (minus PID 42, clamped at 0):"));
+        assert!(report.contains("  cpu-cycles    1000       300       700\n"));
+    }
+
+    #[test]
+    fn test_print_command_stop_reason() {
+        let report = |command_timed_out| {
+            let result = ProfilingResult {
+                exit_code: Some(143),
+                command_timed_out,
+                ..result_with(&[("cpu-cycles", 1000)], 1)
+            };
+            let mut out = Vec::new();
+            print_profiling_result(&result, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert!(report(true).contains("  Stopped By:               duration\n"));
+        assert!(report(false).contains("  Stopped By:           command exit\n"));
+    }
+
+    #[test]
+    fn test_sum_task_results() {
+        let read = |pid, cycles, enabled, running| ProfilingResult {
+            counts: [("cpu-cycles", cycles)].into_iter().collect(),
+            time_enabled_ns: enabled,
+            time_running_ns: running,
+            multiplexed: running < enabled,
+            pid,
+            ..Default::default()
+        };
+        let total = sum_task_results(vec![read(10, 100, 50, 50), read(10, 300, 50, 25)]);
+        assert_eq!(total.count("cpu-cycles"), Some(400));
+        assert_eq!((total.time_enabled_ns, total.time_running_ns), (100, 75));
+        assert!(total.multiplexed);
+        assert_eq!(total.pid, 10);
+
+        let mixed = sum_task_results(vec![read(10, 1, 1, 1), read(20, 1, 1, 1)]);
+        assert_eq!(mixed.pid, -1);
+        assert_eq!(
+            sum_task_results(vec![read(10, 7, 1, 1)]).count("cpu-cycles"),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn test_run_perf_profiler_rejects_missing_pid() {
+        let options = PerfOptions {
+            duration_secs: 1,
+            pid: 999999999,
+            ..Default::default()
+        };
+        let err = run_perf_profiler(&options, |_| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("no such process: 999999999"));
+    }
+
+    #[test]
+    fn test_count_tasks_inherit_reaches_children() {
+        // Counters follow the calling thread, which is the one forking below
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+        let events = select_events(&["page-faults".to_string()]).unwrap();
+        let faults = |inherit| {
+            let wait = |_: &mut dyn FnMut() -> Result<ProfilingResult>| -> Result<Duration> {
+                std::process::Command::new("sh")
+                    .args(["-c", "seq 1 20000 > /dev/null"])
+                    .status()?;
+                Ok(Duration::ZERO)
+            };
+            count_tasks(&[tid], &events, inherit, wait)
+                .ok()
+                .and_then(|(result, _)| result.count("page-faults"))
+        };
+        // Without perf events (containers, paranoid > 2) there is nothing to check
+        let (Some(inherited), Some(own)) = (faults(true), faults(false)) else {
+            return;
+        };
+        // Exec'ing sh and seq faults in hundreds of pages, all in the children
+        assert!(
+            inherited >= own + 100,
+            "children not counted: {} faults with inherit, {} without",
+            inherited,
+            own
+        );
+    }
+
+    #[test]
+    fn test_resolve_target_pid() {
+        assert_eq!(resolve_target_pid(0).unwrap(), std::process::id() as i32);
+        let own = std::process::id() as i32;
+        assert_eq!(resolve_target_pid(own).unwrap(), own);
+        assert!(resolve_target_pid(-1).is_err());
+    }
+
+    fn result_with(counts: &[(&'static str, u64)], duration_secs: u64) -> ProfilingResult {
+        ProfilingResult {
+            counts: counts.iter().copied().collect(),
+            time_enabled_ns: 1000,
+            time_running_ns: 1000,
+            multiplexed: false,
+            scaled: false,
+            duration_secs: duration_secs as f64,
+            ..Default::default()
+        }
+    }
+
+    const DEFAULT_COUNTS: &[(&str, u64)] = &[
+        ("cpu-cycles", 1000),
+        ("instructions", 500),
+        ("cache-references", 100),
+        ("cache-misses", 10),
+    ];
+
+    #[test]
+    fn test_profiling_result_ipc() {
+        let result = result_with(DEFAULT_COUNTS, 1);
+        assert!((result.ipc().unwrap() - 0.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_profiling_result_ipc_zero_cycles() {
+        let result = result_with(&[("cpu-cycles", 0), ("instructions", 500)], 1);
+        assert!((result.ipc().unwrap() - 0.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_profiling_result_ipc_requires_both_events() {
+        let result = result_with(&[("cpu-cycles", 1000), ("branch-misses", 5)], 1);
+        assert_eq!(result.ipc(), None);
+        assert_eq!(result.cache_miss_rate(), None);
+    }
+
+    #[test]
+    fn test_profiling_result_cache_miss_rate() {
+        let result = result_with(DEFAULT_COUNTS, 1);
+        assert!((result.cache_miss_rate().unwrap() - 10.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_profiling_result_cache_miss_rate_zero_refs() {
+        let result = result_with(&[("cache-references", 0), ("cache-misses", 10)], 1);
+        assert!((result.cache_miss_rate().unwrap() - 0.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_profiling_result_mpki() {
+        let result = result_with(
+            &[
+                ("instructions", 2_000_000),
+                ("cache-misses", 3_000),
+                ("branch-misses", 500),
+            ],
+            1,
+        );
+        assert!((result.cache_mpki().unwrap() - 1.5).abs() < f64::EPSILON);
+        assert!((result.branch_mpki().unwrap() - 0.25).abs() < f64::EPSILON);
+        assert_eq!(result_with(DEFAULT_COUNTS, 1).branch_mpki(), None);
+    }
+
+    #[test]
+    fn test_profiling_result_mpki_zero_instructions() {
+        let result = result_with(
+            &[
+                ("instructions", 0),
+                ("cache-misses", 10),
+                ("branch-misses", 5),
+            ],
+            1,
+        );
+        assert_eq!(result.cache_mpki(), Some(0.0));
+        assert_eq!(result.branch_mpki(), Some(0.0));
+    }
+
+    #[test]
+    fn test_check_alerts() {
+        let thresholds = AlertThresholds {
+            ipc_below: Some(0.6),
+            cache_miss_above: Some(5.0),
+        };
+        // IPC 0.5 and a 10% miss rate cross both thresholds
+        let alerts = check_alerts(&result_with(DEFAULT_COUNTS, 1), &thresholds);
+        assert_eq!(
+            alerts,
+            [
+                Alert::IpcBelow {
+                    ipc: 0.5,
+                    threshold: 0.6
+                },
+                Alert::CacheMissAbove {
+                    rate: 10.0,
+                    threshold: 5.0
+                }
+            ]
+        );
+        assert_eq!(alerts[0].to_string(), "IPC 0.500 is below 0.600");
+
+        let relaxed = AlertThresholds {
+            ipc_below: Some(0.5),
+            cache_miss_above: Some(10.0),
+        };
+        assert!(check_alerts(&result_with(DEFAULT_COUNTS, 1), &relaxed).is_empty());
+        assert!(
+            check_alerts(&result_with(DEFAULT_COUNTS, 1), &AlertThresholds::default()).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_check_alerts_missing_or_idle() {
+        let thresholds = AlertThresholds {
+            ipc_below: Some(0.5),
+            cache_miss_above: Some(1.0),
+        };
+        let idle = result_with(&[("cpu-cycles", 0), ("instructions", 0)], 1);
+        assert!(check_alerts(&idle, &thresholds).is_empty());
+        let cycles_only = result_with(&[("cpu-cycles", 1000)], 1);
+        assert!(check_alerts(&cycles_only, &thresholds).is_empty());
+    }
+
+    #[test]
+    fn test_summarize() {
+        let results = [
+            result_with(&[("cpu-cycles", 10), ("instructions", 100)], 1),
+            result_with(&[("cpu-cycles", 12), ("instructions", 100)], 1),
+            result_with(&[("cpu-cycles", 14)], 1),
+        ];
+        let summary = summarize(&results);
+        assert_eq!(summary.runs, 3);
+
+        let cycles = &summary.counters[0];
+        assert_eq!((cycles.name, cycles.runs), ("cpu-cycles", 3));
+        assert!((cycles.mean - 12.0).abs() < f64::EPSILON);
+        // sqrt(((10-12)^2 + 0 + (14-12)^2) / (3 - 1)) = 2
+        assert!((cycles.stddev - 2.0).abs() < f64::EPSILON);
+        assert!((cycles.noise_percent() - 100.0 / 6.0).abs() < 1e-9);
+
+        let instructions = &summary.counters[1];
+        assert_eq!(instructions.runs, 2);
+        assert_eq!(instructions.stddev, 0.0);
+        assert_eq!(instructions.noise_percent(), 0.0);
+    }
+
+    #[test]
+    fn test_summarize_single_run() {
+        let summary = summarize(&[result_with(&[("page-faults", 0)], 1)]);
+        let faults = &summary.counters[0];
+        assert_eq!((faults.mean, faults.stddev), (0.0, 0.0));
+        assert_eq!(faults.noise_percent(), 0.0);
+        assert!(summarize(&[]).counters.is_empty());
+    }
+
+    #[test]
+    fn test_profiling_result_cycles_per_second() {
+        let result = result_with(DEFAULT_COUNTS, 2);
+        assert!((result.cycles_per_second().unwrap() - 500.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_profiling_result_cycles_per_second_zero_duration() {
+        let result = result_with(DEFAULT_COUNTS, 0);
+        assert!((result.cycles_per_second().unwrap() - 0.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_select_events_default() {
+        let events = select_events(&[]).unwrap();
+        let names: Vec<&str> = events.iter().map(|event| event.name).collect();
+        assert_eq!(names, DEFAULT_EVENTS);
+        assert!(!events[0].optional);
+        assert!(events.last().unwrap().optional);
+    }
+
+    #[test]
+    fn test_select_events_custom() {
+        let names = [
+            "cpu-cycles",
+            "branch-instructions",
+            " branch-misses",
+            "cpu-cycles",
+        ]
+        .map(String::from);
+        let events = select_events(&names).unwrap();
+        let names: Vec<&str> = events.iter().map(|event| event.name).collect();
+        assert_eq!(
+            names,
+            ["cpu-cycles", "branch-instructions", "branch-misses"]
+        );
+    }
+
+    #[test]
+    fn test_select_events_unknown() {
+        let err = select_events(&["l1-misses".to_string()]).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("unknown event 'l1-misses'"));
+        assert!(message.contains("branch-misses"));
+    }
+
+    #[test]
+    fn test_counters_follow_table_order() {
+        let result = result_with(&[("cpu-migrations", 2), ("branch-misses", 7)], 1);
+        assert_eq!(
+            result.counters(),
+            vec![("branch-misses", 7), ("cpu-migrations", 2)]
+        );
+    }
+
+    #[test]
+    fn test_format_perf_csv() {
+        let mut counts = DEFAULT_COUNTS.to_vec();
+        counts.push(("cpu-migrations", 3));
+        let mut result = result_with(&counts, 1);
+        result.time_enabled_ns = 2000;
+        let csv = format_perf_csv(&result);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "1000,,cpu-cycles,1000,50.00");
+        assert_eq!(lines[4], "3,,cpu-migrations,1000,50.00");
+        for line in lines {
+            assert_eq!(line.split(',').count(), 5);
+        }
+    }
+
+    #[test]
+    fn test_per_cpu_output() {
+        let per_cpu: Vec<ProfilingResult> = [(0, 100), (2, 300)]
+            .into_iter()
+            .map(|(cpu, cycles)| ProfilingResult {
+                cpu: Some(cpu),
+                ..result_with(&[("cpu-cycles", cycles)], 1)
+            })
+            .collect();
+        let result = ProfilingResult {
+            per_cpu,
+            ..result_with(&[("cpu-cycles", 400)], 1)
+        };
+
+        let table = format_per_cpu_table(&result);
+        let rows: Vec<Vec<&str>> = table
+            .lines()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                vec!["CPU", "cpu-cycles"],
+                vec!["0", "100"],
+                vec!["2", "300"],
+                vec!["total", "400"],
+            ]
+        );
+
+        let csv = format_perf_csv(&result);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "CPU0,100,,cpu-cycles,1000,100.00",
+                "CPU2,300,,cpu-cycles,1000,100.00"
+            ]
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&format_json(&result).unwrap()).unwrap();
+        assert_eq!(json["per_cpu"][1]["cpu"], 2);
+        assert!(json.get("cpu").is_none());
+    }
+
+    #[test]
+    fn test_interval_delta() {
+        let prev = result_with(&[("cpu-cycles", 1000), ("instructions", 400)], 1);
+        let mut cur = result_with(&[("cpu-cycles", 2500), ("instructions", 900)], 2);
+        cur.time_enabled_ns = 3000;
+        cur.time_running_ns = 2000;
+
+        let delta = interval_delta(&prev, &cur);
+        assert_eq!(delta.count("cpu-cycles"), Some(1500));
+        assert_eq!(delta.count("instructions"), Some(500));
+        assert_eq!(delta.time_enabled_ns, 2000);
+        assert_eq!(delta.time_running_ns, 1000);
+        assert!(delta.multiplexed);
+        assert!((delta.duration_secs - 1.0).abs() < f64::EPSILON);
+
+        // The first interval is measured from zero
+        let first = interval_delta(&ProfilingResult::default(), &prev);
+        assert_eq!(first.counts, prev.counts);
+
+        let lines = format_interval(Duration::from_millis(2000), &delta);
+        let fields: Vec<&str> = lines.lines().next().unwrap().split_whitespace().collect();
+        assert_eq!(fields, ["2.000", "1500", "cpu-cycles"]);
+    }
+
+    #[test]
+    fn test_format_json() {
+        let result = result_with(DEFAULT_COUNTS, 2);
+        let json: serde_json::Value = serde_json::from_str(&format_json(&result).unwrap()).unwrap();
+        assert_eq!(json["counts"]["cpu-cycles"], 1000);
+        assert_eq!(json["duration_secs"], 2.0);
+        assert_eq!(json["pid"], 0);
+        assert_eq!(json["ipc"], 0.5);
+        assert_eq!(json["cache_miss_rate"], 10.0);
+        assert_eq!(json["cycles_per_second"], 500.0);
+    }
+
+    #[test]
+    fn test_format_json_missing_metrics_are_null() {
+        let result = result_with(&[("branch-misses", 7)], 1);
+        let json: serde_json::Value = serde_json::from_str(&format_json(&result).unwrap()).unwrap();
+        assert_eq!(json["counts"]["branch-misses"], 7);
+        assert!(json["ipc"].is_null());
+    }
+
+    #[test]
+    fn test_callchain_result_is_empty() {
+        let mut result = CallchainProfilingResult::default();
+        assert!(result.is_empty());
+        result.sample_count = 1;
+        assert!(!result.is_empty());
+    }
+
+    #[test]
+    fn test_scale_count() {
+        assert_eq!(scale_count(100, 1000, 1000), 100);
+        assert_eq!(scale_count(100, 1000, 500), 200);
+        assert_eq!(scale_count(0, 1000, 0), 0);
+    }
+
+    #[test]
+    fn test_counter_read_multiplexing() {
+        let full = CounterRead {
+            count: 10,
+            time_enabled: 100,
+            time_running: 100,
+        };
+        assert!(!full.multiplexed());
+        assert!(!full.scaled());
+
+        let partial = CounterRead {
+            time_running: 25,
+            ..full
+        };
+        assert!(partial.multiplexed());
+        assert!(partial.scaled());
+
+        let never_ran = CounterRead {
+            time_running: 0,
+            ..full
+        };
+        assert!(never_ran.multiplexed());
+        assert!(!never_ran.scaled());
+    }
+
+    #[test]
+    fn test_sum_scaled_per_cpu() {
+        // CPU 0 ran the whole time, CPU 1 was multiplexed in for a quarter of it
+        let reads = [
+            CounterRead {
+                count: 1000,
+                time_enabled: 400,
+                time_running: 400,
+            },
+            CounterRead {
+                count: 100,
+                time_enabled: 400,
+                time_running: 100,
+            },
+        ];
+        assert_eq!(sum_scaled(&reads), 1400);
+        // Summing raw then scaling by the pooled times would give 1760
+        assert_ne!(scale_count(1100, 800, 500), sum_scaled(&reads));
+    }
+
+    #[test]
+    fn test_measurement_scope() {
+        assert!(measurement_scope(false, true).contains("future children"));
+        assert!(measurement_scope(false, false).starts_with("target PID only"));
+        assert!(measurement_scope(true, false).contains("selected CPUs"));
+    }
+
+    #[test]
+    fn test_sample_gap_tracker() {
+        let mut tracker = SampleGapTracker {
+            threshold_ns: 10_000_000,
+            ..Default::default()
+        };
+        // Interleaved CPUs: gaps are measured per CPU, not across them
+        for (cpu, time_ns) in [
+            (0, 1_000_000),
+            (1, 1_500_000),
+            (0, 2_000_000),
+            (1, 2_500_000),
+        ] {
+            tracker.record(cpu, time_ns);
+        }
+        assert_eq!(tracker.max_gap_ns, 1_000_000);
+        assert_eq!(tracker.histogram_us.total(), 2);
+
+        // CPU 1 idles: a long gap, but nothing says it should have been sampled
+        tracker.record(1, 52_500_000);
+        assert_eq!(tracker.max_gap_ns, 50_000_000);
+        assert_eq!(tracker.suspicious_gaps, 0);
+
+        // CPU 0 goes quiet while the kernel reports lost samples
+        tracker.disrupted();
+        tracker.record(0, 60_000_000);
+        assert_eq!(tracker.suspicious_gaps, 1);
+        // CPU 1 was sampled since, so the loss is not held against its next gap
+        tracker.record(1, 62_500_000);
+        assert_eq!(tracker.suspicious_gaps, 1);
+    }
+
+    #[test]
+    fn test_sample_gap_tracker_system_wide() {
+        let mut tracker = SampleGapTracker {
+            threshold_ns: 10_000_000,
+            system_wide: true,
+            ..Default::default()
+        };
+        for time_ns in [1_000_000, 2_000_000, 52_000_000] {
+            tracker.record(3, time_ns);
+        }
+        assert_eq!(tracker.suspicious_gaps, 1);
+    }
+
+    #[test]
+    fn test_comm_tracker() {
+        let mut tracker = CommTracker::default();
+        tracker.on_comm(10, "nginx");
+        // Children inherit the command until they set their own
+        tracker.on_fork(10, 11);
+        tracker.on_fork(10, 12);
+        tracker.on_comm(12, "worker");
+        // An unknown parent leaves the child to be looked up
+        tracker.on_fork(99, 13);
+
+        let no_lookup = |_| -> Option<String> { panic!("unexpected lookup") };
+        assert_eq!(tracker.comm_or_else(10, no_lookup), Some("nginx"));
+        assert_eq!(tracker.comm_or_else(11, no_lookup), Some("nginx"));
+        assert_eq!(tracker.comm_or_else(12, no_lookup), Some("worker"));
+        assert_eq!(
+            tracker.comm_or_else(13, |tid| Some(format!("task-{}", tid))),
+            Some("task-13")
+        );
+        // Failed lookups are remembered too
+        assert_eq!(tracker.comm_or_else(14, |_| None), None);
+        assert_eq!(tracker.comm_or_else(14, no_lookup), None);
+    }
+
+    fn test_map(path: &str) -> MemoryMap {
+        MemoryMap {
+            start: 0x1000,
+            end: 0x3000,
+            file_offset: 0,
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_map_tracker() {
+        let mut tracker = MapTracker::default();
+        let snapshot = |_| vec![test_map("/usr/bin/app")];
+        let no_snapshot = |_| -> Vec<MemoryMap> { panic!("unexpected snapshot") };
+        let no_lookup = |_| -> Option<u32> { panic!("unexpected lookup") };
+
+        // A task running before the session is looked up, and its process snapshotted once
+        assert_eq!(tracker.process_of(11, |_| Some(10), snapshot), Some(10));
+        assert_eq!(tracker.process_of(11, no_lookup, no_snapshot), Some(10));
+        tracker.on_mmap(10, test_map("/usr/lib/plugin.so"), no_snapshot);
+        assert_eq!(tracker.maps[&10].len(), 2);
+
+        // A forked process inherits the mappings, a new thread shares them
+        let fork = |pid, ppid, tid| ForkRecord {
+            pid,
+            ppid,
+            tid,
+            ptid: ppid,
+        };
+        tracker.on_fork(fork(20, 10, 20));
+        tracker.on_fork(fork(10, 10, 12));
+        assert_eq!(tracker.process_of(20, no_lookup, no_snapshot), Some(20));
+        assert_eq!(tracker.process_of(12, no_lookup, no_snapshot), Some(10));
+        assert_eq!(tracker.maps[&20], tracker.maps[&10]);
+
+        // A task that exited before it could be looked up has no process
+        assert_eq!(tracker.process_of(30, |_| None, no_snapshot), None);
+    }
+
+    #[test]
+    fn test_resolve_symbols_per_process() {
+        let result = CallchainProfilingResult {
+            stacks: HashMap::from([(vec![0x1010, 0x2010, 0xffff_ffff_8100_0000], 1)]),
+            maps: HashMap::from([
+                (10, vec![test_map("/nonexistent/libold.so")]),
+                (20, vec![test_map("/nonexistent/libnew.so")]),
+            ]),
+            address_pids: HashMap::from([(0x1010, 10), (0x2010, 20), (0xffff_ffff_8100_0000, 20)]),
+            ..Default::default()
+        };
+        let frames = result.resolve_symbols();
+        let modules: Vec<&str> = frames.iter().map(|frame| frame.module.as_str()).collect();
+        assert_eq!(modules, ["libold.so", "libnew.so", "[unknown]"]);
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("QUIT").unwrap(), libc::SIGQUIT);
+        assert_eq!(parse_signal("SIGUSR1").unwrap(), libc::SIGUSR1);
+        assert_eq!(parse_signal("usr2").unwrap(), libc::SIGUSR2);
+        // Signals that already stop the run are not snapshot signals
+        let error = parse_signal("INT").unwrap_err();
+        assert!(error.to_string().contains("QUIT, USR1, USR2, HUP"));
+        assert_eq!(signal_name(libc::SIGQUIT), "SIGQUIT");
+
+        assert_eq!(
+            snapshot_path(Duration::from_millis(1_700_000_000_250)),
+            Path::new("callchain-snapshot-1700000000.250.prof")
+        );
+    }
+
+    #[test]
+    fn test_comm_filter() {
+        let exact = CommFilter {
+            names: vec!["nginx".to_string(), "very-long-daemon-name".to_string()],
+            substring: false,
+        };
+        assert!(exact.matches("nginx"));
+        assert!(!exact.matches("nginx-worker"));
+        // The kernel keeps the first 15 bytes of a command
+        assert!(exact.matches("very-long-daemo"));
+        assert!(!exact.matches("very-long"));
+
+        let substring = CommFilter {
+            names: vec!["ngin".to_string()],
+            substring: true,
+        };
+        assert!(substring.matches("nginx-worker"));
+        assert!(!substring.matches("apache2"));
+    }
+
+    #[test]
+    fn test_parse_comm_and_fork_records() {
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&7u32.to_ne_bytes());
+        comm.extend_from_slice(&8u32.to_ne_bytes());
+        comm.extend_from_slice(b"nginx\0\0\0");
+        assert_eq!(parse_comm_record(&comm), Some((8, "nginx")));
+        assert!(parse_comm_record(&comm[..6]).is_none());
+
+        let mut fork = Vec::new();
+        for id in [20u32, 7, 21, 8] {
+            fork.extend_from_slice(&id.to_ne_bytes());
+        }
+        fork.extend_from_slice(&0u64.to_ne_bytes());
+        assert_eq!(
+            parse_fork_record(&fork),
+            Some(ForkRecord {
+                pid: 20,
+                ppid: 7,
+                tid: 21,
+                ptid: 8
+            })
+        );
+        assert!(parse_fork_record(&fork[..12]).is_none());
+    }
+
+    #[test]
+    fn test_has_sampling_gaps() {
+        let mut result = CallchainProfilingResult {
+            sampling_frequency: 100,
+            max_gap_ns: 150_000_000,
+            ..Default::default()
+        };
+        // A long gap alone can be an idle CPU
+        assert!(!result.has_sampling_gaps());
+        result.suspicious_gaps = 1;
+        assert!(result.has_sampling_gaps());
+    }
+
+    #[test]
+    fn test_parse_callchain_drops_context_markers() {
+        let mut bytes = Vec::new();
+        for ip in [PERF_CONTEXT_MAX + 1, 0x1000, u64::MAX - 511, 0x2000] {
+            bytes.extend_from_slice(&ip.to_ne_bytes());
+        }
+        assert_eq!(parse_callchain(&bytes, false), Some(vec![0x1000, 0x2000]));
+    }
+
+    #[test]
+    fn test_parse_callchain_exclude_hv() {
+        let callchain =
+            |ips: &[u64]| -> Vec<u8> { ips.iter().flat_map(|ip| ip.to_ne_bytes()).collect() };
+        let kernel = u64::MAX - 127;
+        let user = u64::MAX - 511;
+        // Taken in the kernel, after a hypercall returned
+        let bytes = callchain(&[kernel, 0x1000, PERF_CONTEXT_HV, 0x2000, user, 0x3000]);
+        assert_eq!(parse_callchain(&bytes, true), Some(vec![0x1000, 0x3000]));
+        assert_eq!(
+            parse_callchain(&bytes, false),
+            Some(vec![0x1000, 0x2000, 0x3000])
+        );
+
+        // Taken in the hypervisor itself
+        let bytes = callchain(&[PERF_CONTEXT_HV, 0x2000, kernel, 0x1000]);
+        assert_eq!(parse_callchain(&bytes, true), None);
+        assert_eq!(parse_callchain(&bytes, false), Some(vec![0x2000, 0x1000]));
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let mut result = CallchainProfilingResult::default();
+        result.stacks.insert(vec![0x30, 0x20, 0x10], 5);
+        result.stacks.insert(vec![0x40, 0x10], 2);
+        assert_eq!(result.folded_stacks().len(), 2);
+        assert_eq!(result.folded_stacks()[&vec![0x40, 0x10]], 2);
+        assert_eq!(
+            format_folded(result.folded_stacks(), &HashMap::new()),
+            "0x10;0x20;0x30 5\n0x10;0x40 2\n"
+        );
+        let names = HashMap::from([(0x10, "main+0x4".to_string())]);
+        assert_eq!(
+            format_folded(result.folded_stacks(), &names),
+            "main+0x4;0x20;0x30 5\nmain+0x4;0x40 2\n"
+        );
+    }
+
+    #[test]
+    fn test_resolve_symbols_labels_unmapped_frames() {
+        let mut result = CallchainProfilingResult::default();
+        result.stacks.insert(vec![0x30, 0x10], 1);
+        result.stacks.insert(vec![0x10], 1);
+        let frames = result.resolve_symbols();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], ResolvedFrame::unknown(0x10));
+        assert_eq!(frames[1].to_string(), "[unknown]");
+    }
+
+    #[test]
+    fn test_aggregation_name() {
+        let frame = ResolvedFrame {
+            address: 0x1234,
+            module: "app".to_string(),
+            symbol: Some("my_func".to_string()),
+            offset: 0x4c,
+        };
+        assert_eq!(aggregation_name(&frame, 0), "my_func");
+        assert_eq!(aggregation_name(&frame, 0x40), "my_func+0x40");
+        assert_eq!(aggregation_name(&frame, 16), "my_func+0x40");
+        assert_eq!(aggregation_name(&frame, 1), "my_func+0x4c");
+
+        // Unsymbolized frames are not bucketed
+        let unresolved = ResolvedFrame {
+            symbol: None,
+            ..frame
+        };
+        assert_eq!(aggregation_name(&unresolved, 0x40), "app+0x4c");
+        assert_eq!(
+            aggregation_name(&ResolvedFrame::unknown(0x10), 0x40),
+            "[unknown]"
+        );
+    }
+
+    #[test]
+    fn test_demangle_frame() {
+        assert_eq!(demangle_frame("_RNvC6_123foo3bar"), "123foo::bar");
+        assert_eq!(
+            demangle_frame("_ZN4core3fmt5write17h0123456789abcdefE"),
+            "core::fmt::write"
+        );
+        assert_eq!(demangle_frame("_Z3fooi"), "foo(int)");
+        assert_eq!(demangle_frame("main"), "main");
+        assert_eq!(demangle_frame("_Znot-a-symbol"), "_Znot-a-symbol");
+    }
+
+    #[test]
+    fn test_sampling_mode_frequency() {
+        assert_eq!(SamplingMode::Frequency(99).frequency().unwrap(), 99);
+        assert_eq!(SamplingMode::Period(1_000_000).frequency().unwrap(), 1000);
+        assert_eq!(SamplingMode::Period(NANOS_PER_SEC).frequency().unwrap(), 1);
+        assert!(SamplingMode::Frequency(0).frequency().is_err());
+        assert!(SamplingMode::Period(0).frequency().is_err());
+        assert!(SamplingMode::Period(NANOS_PER_SEC + 1).frequency().is_err());
+
+        // A period the kernel would round is refused, naming an exact one
+        let error = SamplingMode::Period(3_000_000).frequency().unwrap_err();
+        assert!(error.to_string().contains("such as 2500000 ns"));
+        assert_eq!(exact_period_below(1_000_000), 1_000_000);
+    }
+
+    #[test]
+    fn test_sampling_mode_recorded() {
+        let result = CallchainProfilingResult {
+            sampling_mode: SamplingMode::Period(1_000_000),
+            ..Default::default()
+        };
+        assert_eq!(result.sampling_mode, SamplingMode::Period(1_000_000));
+        assert_eq!(result.sampling_mode.describe(), "every 1000000 ns");
+        assert_eq!(SamplingMode::Frequency(99).describe(), "99 Hz");
+        let json = serde_json::to_value(SamplingMode::Period(5)).unwrap();
+        assert_eq!(json["period"], 5);
+    }
+
+    #[test]
+    fn test_hex_preview() {
+        assert_eq!(hex_preview(&[0x01, 0xab, 0xff], 8), "01 ab ff");
+        assert_eq!(hex_preview(&[0x01, 0xab, 0xff], 2), "01 ab ...");
+        assert_eq!(hex_preview(&[], 8), "");
+    }
+
+    #[test]
+    fn test_every_listed_event_has_a_kind() {
+        for event in HARDWARE_EVENTS.iter().chain(SOFTWARE_EVENTS) {
+            assert!(event_kind(event.name).is_some(), "{}", event.name);
+        }
+        let names = ["cpu-cycles", "context-switches", "page-faults"].map(String::from);
+        let events = select_events(&names).unwrap();
+        assert!(!events[0].optional);
+        assert!(events[1].optional && events[2].optional);
+    }
+
+    #[test]
+    fn test_events_json() {
+        let json: serde_json::Value = serde_json::from_str(&events_json().unwrap()).unwrap();
+        let events = json.as_array().unwrap();
+        assert_eq!(events.len(), HARDWARE_EVENTS.len() + SOFTWARE_EVENTS.len());
+        assert_eq!(events[0]["name"], "cpu-cycles");
+        assert_eq!(events[0]["category"], "hardware");
+        assert_eq!(events.last().unwrap()["category"], "software");
+    }
+
+    fn probe(name: &'static str, status: ProbeStatus, reason: &str) -> EventProbe {
+        EventProbe {
+            name,
+            description: "",
+            category: EventCategory::Hardware,
+            status,
+            available: status == ProbeStatus::Ok,
+            reason: reason.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_print_probe_table() {
+        let probes = [
+            probe("cpu-cycles", ProbeStatus::Ok, ""),
+            probe("branch-misses", ProbeStatus::Permission, "not permitted"),
+        ];
+        let mut out = Vec::new();
+        print_probe_table(&probes, &mut out).unwrap();
+        let table = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "  Name           Category  Status      Reason");
+        assert_eq!(lines[1], "  cpu-cycles     hardware  OK");
+        assert_eq!(
+            lines[2],
+            "  branch-misses  hardware  PERMISSION  not permitted"
+        );
+    }
+
+    #[test]
+    fn test_classify_open_error() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_eq!(classify_open_error(&denied).0, ProbeStatus::Permission);
+        let missing = io::Error::from(io::ErrorKind::NotFound);
+        assert_eq!(classify_open_error(&missing).0, ProbeStatus::Unsupported);
+    }
+
+    #[test]
+    fn test_probe_events_available_first() {
+        let probes = probe_events();
+        assert_eq!(probes.len(), HARDWARE_EVENTS.len() + SOFTWARE_EVENTS.len());
+        let first_unavailable = probes.iter().position(|p| !p.available);
+        if let Some(index) = first_unavailable {
+            assert!(probes[index..].iter().all(|p| !p.available));
+        }
+    }
+
+    #[test]
+    fn test_probe_json() {
+        let json = probe_json(&[probe("cpu-cycles", ProbeStatus::Unsupported, "no PMU")]).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value[0]["status"], "UNSUPPORTED");
+        assert_eq!(value[0]["available"], false);
+        assert_eq!(value[0]["reason"], "no PMU");
+    }
+
+    #[test]
+    fn test_list_available_events() {
+        let mut out = Vec::new();
+        list_available_events(&mut out).unwrap();
+        let listing = String::from_utf8(out).unwrap();
+        assert!(listing.contains("  cpu-cycles"));
+        assert!(listing.contains("  page-faults"));
+    }
+
+    #[test]
+    fn test_check_permissions_system_wide() {
+        let error = check_permissions(Some(2), false, true, true).unwrap_err();
+        let message = error.to_string();
+        assert!(message.starts_with("System-wide callchain sampling requires"));
+        assert!(message.contains("perf_event_paranoid <= 0 for non-root users, but it is 2"));
+        assert!(message.contains("sudo sysctl kernel.perf_event_paranoid=0"));
+        assert!(check_permissions(Some(0), false, true, false).is_ok());
+        assert!(check_permissions(Some(-1), false, true, true).is_ok());
+    }
+
+    #[test]
+    fn test_check_permissions_own_process() {
+        assert!(check_permissions(Some(2), false, false, true).is_ok());
+        assert!(check_permissions(Some(2), false, false, false).is_ok());
+        let error = check_permissions(Some(3), false, false, false).unwrap_err();
+        assert!(error.to_string().starts_with("Counting requires"));
+        assert!(error.to_string().contains("perf_event_paranoid=2"));
+    }
+
+    #[test]
+    fn test_check_permissions_root_or_unknown() {
+        assert!(check_permissions(Some(4), true, true, true).is_ok());
+        assert!(check_permissions(None, false, true, true).is_ok());
+    }
+
+    #[test]
+    fn test_status_privileged() {
+        let status = |uid: &str, caps: &str| {
+            format!(
+                "Name:\tprofiler\nUid:\t{0}\t{0}\t{0}\t{0}\nCapEff:\t{1}\n",
+                uid, caps
+            )
+        };
+        assert!(status_privileged(&status("0", "000001ffffffffff")));
+        assert!(status_privileged(&status("1000", "0000004000000000")));
+        assert!(status_privileged(&status("1000", "0000000000200000")));
+        assert!(!status_privileged(&status("1000", "0000000000000000")));
+        // Other capabilities do not bypass perf_event_paranoid
+        assert!(!status_privileged(&status("1000", "0000000000000400")));
+        // Root in a container without capabilities is held to perf_event_paranoid
+        assert!(!status_privileged(&status("0", "0000000000000000")));
+        assert!(!status_privileged("Name:\tprofiler\n"));
+    }
+
+    #[test]
+    fn test_privilege_warnings_user_only_callchains() {
+        let warnings = privilege_warnings(Some(2), Some(0), false, true);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("perf_event_paranoid <= 1, current is 2"));
+        // check_permissions reports paranoid levels that block collection
+        assert!(privilege_warnings(Some(3), Some(0), false, true).is_empty());
+    }
+
+    #[test]
+    fn test_privilege_warnings_kptr_restrict() {
+        let warnings = privilege_warnings(Some(1), Some(1), false, true);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("kptr_restrict = 1"));
+    }
+
+    #[test]
+    fn test_privilege_warnings_privileged() {
+        assert!(privilege_warnings(Some(3), Some(2), true, true).is_empty());
     }
 
     #[test]
-    fn test_list_available_events_runs() {
-        // Just verify it doesn't panic
-        list_available_events();
+    fn test_privilege_warnings_counters_only() {
+        assert!(privilege_warnings(Some(2), Some(1), false, false).is_empty());
     }
 }
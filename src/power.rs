@@ -0,0 +1,337 @@
+//! RAPL energy measurement module.
+//!
+//! This module reads the Running Average Power Limit energy counters that the
+//! kernel exposes through the `power` PMU, and reports the energy consumed by
+//! each domain (package, cores, DRAM, ...) together with the average power.
+
+use crate::cpulist;
+use crate::interrupt::InterruptScope;
+use crate::perf;
+use anyhow::{Context, Result};
+use perf_event_open_sys::bindings::perf_event_attr;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sysfs directory of the RAPL PMU; absent when the CPU or hypervisor does not expose it.
+const POWER_PMU_PATH: &str = "/sys/devices/power";
+
+/// How often Ctrl-C is checked while measuring.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Prefix of the event files that describe RAPL energy domains.
+const ENERGY_EVENT_PREFIX: &str = "energy-";
+
+/// A RAPL energy domain advertised by the `power` PMU.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RaplDomain {
+    /// Domain name without the `energy-` prefix (`pkg`, `cores`, `ram`, ...)
+    pub name: String,
+    /// Event config to pass to `perf_event_open`
+    pub config: u64,
+    /// Joules per counter increment
+    pub scale: f64,
+}
+
+/// Energy consumed by one domain over a measurement.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DomainEnergy {
+    pub name: String,
+    pub joules: f64,
+    /// Average power over the measurement
+    pub watts: f64,
+}
+
+/// Results of a RAPL energy measurement.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PowerResult {
+    /// Time actually measured, in seconds
+    pub duration_secs: f64,
+    /// Energy per domain, sorted by domain name
+    pub domains: Vec<DomainEnergy>,
+    /// Whether Ctrl-C stopped the measurement early
+    pub interrupted: bool,
+}
+
+impl PowerResult {
+    /// Build a result from joules per domain, deriving the average power.
+    pub fn new(duration: Duration, joules: Vec<(String, f64)>) -> Self {
+        let duration_secs = duration.as_secs_f64();
+        let domains = joules
+            .into_iter()
+            .map(|(name, joules)| DomainEnergy {
+                name,
+                joules,
+                watts: if duration_secs > 0.0 {
+                    joules / duration_secs
+                } else {
+                    0.0
+                },
+            })
+            .collect();
+        PowerResult {
+            duration_secs,
+            domains,
+            interrupted: false,
+        }
+    }
+}
+
+/// Parse the `event=0x..` config of a PMU event file.
+fn parse_event_config(text: &str) -> Result<u64> {
+    let value = text
+        .trim()
+        .split(',')
+        .find_map(|term| term.trim().strip_prefix("event="))
+        .with_context(|| format!("No event= term in {:?}", text.trim()))?;
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.with_context(|| format!("Invalid event config: {:?}", value))
+}
+
+/// Discover the energy domains listed in a PMU's `events/` directory.
+///
+/// Each domain has an `energy-<name>` file holding its config and an
+/// `energy-<name>.scale` file holding joules per increment.
+fn discover_domains(events_dir: &Path) -> Result<Vec<RaplDomain>> {
+    let entries = std::fs::read_dir(events_dir)
+        .with_context(|| format!("Failed to read {}", events_dir.display()))?;
+
+    let mut domains = Vec::new();
+    for entry in entries {
+        let file_name = entry?.file_name();
+        let Some(event) = file_name.to_str() else {
+            continue;
+        };
+        let Some(name) = event.strip_prefix(ENERGY_EVENT_PREFIX) else {
+            continue;
+        };
+        if name.contains('.') {
+            continue;
+        }
+
+        let read = |suffix: &str| {
+            let path = events_dir.join(format!("{}{}", event, suffix));
+            std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))
+        };
+        let scale = read(".scale")?;
+        domains.push(RaplDomain {
+            name: name.to_string(),
+            config: parse_event_config(&read("")?)?,
+            scale: scale
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid scale for {}: {:?}", event, scale.trim()))?,
+        });
+    }
+
+    domains.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(domains)
+}
+
+/// An open RAPL counter on one CPU.
+#[derive(Debug)]
+struct RaplCounter {
+    fd: RawFd,
+}
+
+impl RaplCounter {
+    /// Open a counter of the `power` PMU on `cpu`; RAPL events are CPU-wide only.
+    fn open(pmu_type: u32, config: u64, cpu: usize) -> Result<Self> {
+        let mut attr = perf_event_attr {
+            type_: pmu_type,
+            size: std::mem::size_of::<perf_event_attr>() as u32,
+            config,
+            ..Default::default()
+        };
+        // SAFETY: `attr` is a fully initialized attribute struct of the stated size
+        let fd = unsafe { perf_event_open_sys::perf_event_open(&mut attr, -1, cpu as i32, -1, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to open RAPL counter on CPU {}", cpu));
+        }
+        Ok(RaplCounter { fd })
+    }
+
+    /// Read the raw energy count.
+    fn read(&self) -> Result<u64> {
+        let mut value = 0u64;
+        // SAFETY: reading exactly 8 bytes into a u64 from a descriptor we own
+        let read = unsafe {
+            libc::read(
+                self.fd,
+                (&mut value as *mut u64).cast(),
+                std::mem::size_of::<u64>(),
+            )
+        };
+        if read != std::mem::size_of::<u64>() as isize {
+            return Err(std::io::Error::last_os_error()).context("Failed to read RAPL counter");
+        }
+        Ok(value)
+    }
+}
+
+impl Drop for RaplCounter {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is owned by this counter and closed once
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Measure the energy consumed by every RAPL domain for a specified duration.
+///
+/// Counters are opened on one CPU per package (the PMU's `cpumask`) and summed.
+///
+/// # Arguments
+///
+/// * `duration_secs` - Duration in seconds to measure; Ctrl-C stops early
+///
+/// # Returns
+///
+/// Returns `None` if the `power` PMU is not available (common in VMs), otherwise
+/// a `PowerResult` with the joules and average watts of each domain.
+pub fn run_power_profiler(duration_secs: u64) -> Result<Option<PowerResult>> {
+    let pmu = Path::new(POWER_PMU_PATH);
+    if !pmu.exists() {
+        return Ok(None);
+    }
+
+    let read = |name: &str| {
+        let path = pmu.join(name);
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))
+    };
+    let pmu_type: u32 = read("type")?
+        .trim()
+        .parse()
+        .context("Invalid power PMU type")?;
+    let cpus = cpulist::parse_cpu_list(&read("cpumask")?)?;
+    let domains = discover_domains(&pmu.join("events"))?;
+    if domains.is_empty() {
+        return Ok(None);
+    }
+    // RAPL counters are per-CPU events, which need system-wide permission
+    perf::check_perf_permissions(true, false)?;
+
+    let counters = domains
+        .iter()
+        .map(|domain| {
+            cpus.iter()
+                .map(|&cpu| RaplCounter::open(pmu_type, domain.config, cpu))
+                .collect::<Result<Vec<_>>>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let read_all = || -> Result<Vec<Vec<u64>>> {
+        counters
+            .iter()
+            .map(|per_cpu| per_cpu.iter().map(RaplCounter::read).collect())
+            .collect()
+    };
+
+    let interrupt = InterruptScope::enter();
+    let start = Instant::now();
+    let before = read_all()?;
+    let max_duration = Duration::from_secs(duration_secs);
+    while start.elapsed() < max_duration && !interrupt.interrupted() {
+        thread::sleep(POLL_INTERVAL.min(max_duration.saturating_sub(start.elapsed())));
+    }
+    let after = read_all()?;
+    let elapsed = start.elapsed();
+
+    let joules = domains
+        .iter()
+        .zip(before.iter().zip(&after))
+        .map(|(domain, (before, after))| {
+            let increments: u64 = before
+                .iter()
+                .zip(after)
+                .map(|(before, after)| after.wrapping_sub(*before))
+                .sum();
+            (domain.name.clone(), increments as f64 * domain.scale)
+        })
+        .collect();
+
+    let mut result = PowerResult::new(elapsed, joules);
+    result.interrupted = interrupt.interrupted();
+    Ok(Some(result))
+}
+
+/// Print a power measurement as a table.
+pub fn print_power_result(result: &PowerResult, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "Power Results:")?;
+    writeln!(out, "{:=<50}", "")?;
+    if result.interrupted {
+        writeln!(out, "  Interrupted by Ctrl-C; partial results")?;
+    }
+    writeln!(out, "  Duration:          {:>13.2} s", result.duration_secs)?;
+    writeln!(out, "{:-<50}", "")?;
+    writeln!(
+        out,
+        "  {:<12}{:>16}{:>16}",
+        "Domain", "Energy (J)", "Power (W)"
+    )?;
+    for domain in &result.domains {
+        writeln!(
+            out,
+            "  {:<12}{:>16.3}{:>16.3}",
+            domain.name, domain.joules, domain.watts
+        )?;
+    }
+    writeln!(out, "{:=<50}", "")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_event_config() {
+        assert_eq!(parse_event_config("event=0x02\n").unwrap(), 2);
+        assert_eq!(parse_event_config("event=0x3b,umask=0x1").unwrap(), 0x3b);
+        assert_eq!(parse_event_config("event=5").unwrap(), 5);
+        assert!(parse_event_config("umask=0x1").is_err());
+    }
+
+    #[test]
+    fn test_discover_domains() {
+        let dir = std::env::temp_dir().join(format!("profiler-rapl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in [
+            ("energy-pkg", "event=0x02\n"),
+            ("energy-pkg.scale", "2.3283064365386962890625e-10\n"),
+            ("energy-pkg.unit", "Joules\n"),
+            ("energy-cores", "event=0x01\n"),
+            ("energy-cores.scale", "2.3283064365386962890625e-10\n"),
+            ("cycles", "event=0x3c\n"),
+        ] {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let domains = discover_domains(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let names: Vec<&str> = domains.iter().map(|domain| domain.name.as_str()).collect();
+        assert_eq!(names, ["cores", "pkg"]);
+        assert_eq!(domains[1].config, 2);
+        assert!((domains[1].scale - 2.3283064365386962890625e-10).abs() < 1e-20);
+    }
+
+    #[test]
+    fn test_power_result_watts() {
+        let result = PowerResult::new(
+            Duration::from_secs(2),
+            vec![("pkg".to_string(), 30.0), ("ram".to_string(), 3.0)],
+        );
+        assert!((result.domains[0].watts - 15.0).abs() < f64::EPSILON);
+        assert!((result.domains[1].watts - 1.5).abs() < f64::EPSILON);
+
+        let empty = PowerResult::new(Duration::ZERO, vec![("pkg".to_string(), 1.0)]);
+        assert!((empty.domains[0].watts - 0.0).abs() < f64::EPSILON);
+    }
+}
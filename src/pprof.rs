@@ -0,0 +1,285 @@
+//! pprof export module.
+//!
+//! This module converts aggregated callchain samples into the `profile.proto`
+//! format read by `go tool pprof` and compatible tools, and writes it gzipped.
+
+use crate::perf::CallchainProfilingResult;
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prost::Message;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Nanoseconds per second, for the sampling period.
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The subset of `perftools.profiles.Profile` this profiler fills in.
+#[derive(Clone, PartialEq, Message)]
+pub struct Profile {
+    #[prost(message, repeated, tag = "1")]
+    pub sample_type: Vec<ValueType>,
+    #[prost(message, repeated, tag = "2")]
+    pub sample: Vec<Sample>,
+    #[prost(message, repeated, tag = "4")]
+    pub location: Vec<Location>,
+    #[prost(message, repeated, tag = "5")]
+    pub function: Vec<Function>,
+    /// Every string referenced by index; entry 0 must be empty
+    #[prost(string, repeated, tag = "6")]
+    pub string_table: Vec<String>,
+    #[prost(int64, tag = "10")]
+    pub duration_nanos: i64,
+    #[prost(message, optional, tag = "11")]
+    pub period_type: Option<ValueType>,
+    #[prost(int64, tag = "12")]
+    pub period: i64,
+}
+
+/// A value's kind and unit, as string table indices.
+#[derive(Clone, PartialEq, Message)]
+pub struct ValueType {
+    #[prost(int64, tag = "1")]
+    pub r#type: i64,
+    #[prost(int64, tag = "2")]
+    pub unit: i64,
+}
+
+/// One unique stack and its sample count.
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    /// Location ids, leaf first
+    #[prost(uint64, repeated, tag = "1")]
+    pub location_id: Vec<u64>,
+    #[prost(int64, repeated, tag = "2")]
+    pub value: Vec<i64>,
+}
+
+/// A sampled instruction address.
+#[derive(Clone, PartialEq, Message)]
+pub struct Location {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(uint64, tag = "3")]
+    pub address: u64,
+    #[prost(message, repeated, tag = "4")]
+    pub line: Vec<Line>,
+}
+
+/// The function a location belongs to.
+#[derive(Clone, PartialEq, Message)]
+pub struct Line {
+    #[prost(uint64, tag = "1")]
+    pub function_id: u64,
+}
+
+/// A function name, as a string table index.
+#[derive(Clone, PartialEq, Message)]
+pub struct Function {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+    #[prost(int64, tag = "2")]
+    pub name: i64,
+}
+
+/// Interns strings into a pprof string table.
+struct StringTable {
+    strings: Vec<String>,
+    index: HashMap<String, i64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        StringTable {
+            strings: vec![String::new()],
+            index: HashMap::from([(String::new(), 0)]),
+        }
+    }
+
+    fn intern(&mut self, s: &str) -> i64 {
+        if let Some(&index) = self.index.get(s) {
+            return index;
+        }
+        let index = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.index.insert(s.to_string(), index);
+        index
+    }
+}
+
+/// Build a pprof profile from aggregated stacks.
+///
+/// # Arguments
+///
+/// * `stacks` - Sample count per unique callchain, frames ordered leaf first
+/// * `names` - Function name per address; missing addresses are named in hex
+/// * `sampling_frequency` - Sampling frequency in Hz, giving the sampling period
+/// * `duration_nanos` - Length of the run
+///
+/// # Returns
+///
+/// Returns a profile with a single `cpu/samples` sample type. Locations are in
+/// address order and samples in stack order, so the output is deterministic.
+pub fn build_profile(
+    stacks: &HashMap<Vec<u64>, u64>,
+    names: &HashMap<u64, String>,
+    sampling_frequency: u64,
+    duration_nanos: u64,
+) -> Profile {
+    let mut strings = StringTable::new();
+    let sample_type = ValueType {
+        r#type: strings.intern("cpu"),
+        unit: strings.intern("samples"),
+    };
+    let period_type = ValueType {
+        r#type: strings.intern("cpu"),
+        unit: strings.intern("nanoseconds"),
+    };
+
+    let mut addresses: Vec<u64> = stacks.keys().flatten().copied().collect();
+    addresses.sort_unstable();
+    addresses.dedup();
+
+    let mut functions: Vec<Function> = Vec::new();
+    let mut function_ids: HashMap<String, u64> = HashMap::new();
+    let mut location_ids: HashMap<u64, u64> = HashMap::new();
+    let mut locations = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        let name = names
+            .get(&address)
+            .cloned()
+            .unwrap_or_else(|| format!("{:#x}", address));
+        let function_id = match function_ids.get(&name) {
+            Some(&id) => id,
+            None => {
+                let id = functions.len() as u64 + 1;
+                functions.push(Function {
+                    id,
+                    name: strings.intern(&name),
+                });
+                function_ids.insert(name, id);
+                id
+            }
+        };
+        let id = locations.len() as u64 + 1;
+        location_ids.insert(address, id);
+        locations.push(Location {
+            id,
+            address,
+            line: vec![Line { function_id }],
+        });
+    }
+
+    let mut sorted_stacks: Vec<(&Vec<u64>, &u64)> = stacks.iter().collect();
+    sorted_stacks.sort();
+    let sample = sorted_stacks
+        .into_iter()
+        .map(|(frames, &count)| Sample {
+            location_id: frames.iter().map(|address| location_ids[address]).collect(),
+            value: vec![count as i64],
+        })
+        .collect();
+
+    Profile {
+        sample_type: vec![sample_type],
+        sample,
+        location: locations,
+        function: functions,
+        string_table: strings.strings,
+        duration_nanos: duration_nanos as i64,
+        period_type: Some(period_type),
+        period: NANOS_PER_SEC.checked_div(sampling_frequency).unwrap_or(0) as i64,
+    }
+}
+
+/// Serialize a profile and gzip it, as `profile.pb.gz` files are stored.
+pub fn encode_gzip(profile: &Profile) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(&profile.encode_to_vec())
+        .context("Failed to compress pprof profile")?;
+    encoder.finish().context("Failed to compress pprof profile")
+}
+
+/// Write the stacks of a result to `path` as a gzipped pprof profile, with resolved symbols.
+pub fn write_pprof(path: &Path, result: &CallchainProfilingResult) -> Result<()> {
+    let duration = result.active_duration.map_or(
+        (result.duration_secs * NANOS_PER_SEC as f64) as u64,
+        |elapsed| elapsed.as_nanos() as u64,
+    );
+    write_stacks(
+        path,
+        result.folded_stacks(),
+        &result.function_names(),
+        result.sampling_frequency,
+        duration,
+    )
+}
+
+/// Write aggregated stacks to `path` as a gzipped pprof profile, frames named using `names`.
+pub fn write_stacks(
+    path: &Path,
+    stacks: &HashMap<Vec<u64>, u64>,
+    names: &HashMap<u64, String>,
+    sampling_frequency: u64,
+    duration_nanos: u64,
+) -> Result<()> {
+    let profile = build_profile(stacks, names, sampling_frequency, duration_nanos);
+    std::fs::write(path, encode_gzip(&profile)?)
+        .with_context(|| format!("Failed to write pprof profile: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_pprof_round_trip() {
+        let names: HashMap<u64, String> = [
+            (0x10, "leaf".to_string()),
+            (0x11, "leaf".to_string()),
+            (0x30, "main".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let stacks: HashMap<Vec<u64>, u64> = [
+            (vec![0x10, 0x30], 3),
+            (vec![0x11, 0x30], 1),
+            (vec![0x99], 2),
+        ]
+        .into_iter()
+        .collect();
+
+        let bytes = encode_gzip(&build_profile(&stacks, &names, 100, 5 * NANOS_PER_SEC)).unwrap();
+        let mut decoded = Vec::new();
+        GzDecoder::new(bytes.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        let profile = Profile::decode(decoded.as_slice()).unwrap();
+
+        let total: i64 = profile.sample.iter().map(|sample| sample.value[0]).sum();
+        assert_eq!(total, 6);
+        assert_eq!(profile.string_table[0], "");
+        let sample_type = &profile.sample_type[0];
+        assert_eq!(profile.string_table[sample_type.r#type as usize], "cpu");
+        assert_eq!(profile.string_table[sample_type.unit as usize], "samples");
+        assert_eq!(profile.period, 10_000_000);
+
+        // Two addresses in `leaf` share one function
+        assert_eq!(profile.location.len(), 4);
+        let function_names: Vec<&str> = profile
+            .function
+            .iter()
+            .map(|function| profile.string_table[function.name as usize].as_str())
+            .collect();
+        assert_eq!(function_names, ["leaf", "main", "0x99"]);
+        for sample in &profile.sample {
+            for id in &sample.location_id {
+                assert!(profile.location.iter().any(|location| location.id == *id));
+            }
+        }
+    }
+}
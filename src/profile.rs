@@ -0,0 +1,296 @@
+//! Binary profile module.
+//!
+//! This module saves aggregated callchain samples in a compact, versioned
+//! binary format and reads them back, so a run can be rendered again later with
+//! `import` without the size and parsing cost of text or JSON.
+//!
+//! A file starts with [`MAGIC`] and a format version, followed by a string table
+//! and the stacks. Every number after the magic is an unsigned LEB128 varint:
+//!
+//! ```text
+//! magic  version
+//! string_count  (len utf8_bytes){string_count}
+//! stack_count   (frame_count string_index{frame_count} count){stack_count}
+//! ```
+//!
+//! Frames are string table indices, leaf first, as in a callchain result.
+
+use crate::folded::{self, FoldedStacks};
+use crate::perf::CallchainProfilingResult;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// First bytes of every binary profile, to tell it apart from folded text.
+pub const MAGIC: &[u8; 8] = b"PROFSTK\0";
+
+/// Version written by this build, and the newest it reads.
+pub const VERSION: u64 = 1;
+
+/// Append `value` as an unsigned LEB128 varint.
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Sequential reader over the varints of a binary profile.
+struct VarintReader<'a> {
+    data: &'a [u8],
+}
+
+impl VarintReader<'_> {
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.data.split_first().context("truncated profile")?;
+            self.data = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        anyhow::bail!("malformed profile: varint longer than 64 bits")
+    }
+
+    /// Read a count of items that each take at least one byte, so a corrupt
+    /// count fails here instead of reserving memory for it.
+    fn read_len(&mut self) -> Result<usize> {
+        let len = self.read_varint()?;
+        usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= self.data.len())
+            .context("truncated profile")
+    }
+
+    fn read_string(&mut self) -> Result<String> {
+        let len = self.read_len()?;
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        String::from_utf8(bytes.to_vec()).context("malformed profile: string is not UTF-8")
+    }
+}
+
+/// Whether `data` starts like a binary profile.
+pub fn is_profile(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encode aggregated stacks, frames named using `names` or in hex when missing.
+///
+/// Frames sharing a name share a string, so stacks that only differ by
+/// addresses of the same function are stored once with their counts summed.
+/// Strings and stacks are written in sorted order so the output is deterministic.
+pub fn encode_profile(stacks: &HashMap<Vec<u64>, u64>, names: &HashMap<u64, String>) -> Vec<u8> {
+    let name_of = |address: &u64| {
+        names
+            .get(address)
+            .cloned()
+            .unwrap_or_else(|| format!("{:#x}", address))
+    };
+    let mut strings: Vec<String> = stacks.keys().flatten().map(name_of).collect();
+    strings.sort_unstable();
+    strings.dedup();
+    let index: HashMap<&str, u64> = strings
+        .iter()
+        .enumerate()
+        .map(|(index, name)| (name.as_str(), index as u64))
+        .collect();
+
+    let mut named: HashMap<Vec<u64>, u64> = HashMap::new();
+    for (frames, &count) in stacks {
+        let frames = frames
+            .iter()
+            .map(|address| index[name_of(address).as_str()])
+            .collect();
+        *named.entry(frames).or_insert(0) += count;
+    }
+    let mut named: Vec<(Vec<u64>, u64)> = named.into_iter().collect();
+    named.sort_unstable();
+
+    let mut out = MAGIC.to_vec();
+    write_varint(&mut out, VERSION);
+    write_varint(&mut out, strings.len() as u64);
+    for string in &strings {
+        write_varint(&mut out, string.len() as u64);
+        out.extend_from_slice(string.as_bytes());
+    }
+    write_varint(&mut out, named.len() as u64);
+    for (frames, count) in named {
+        write_varint(&mut out, frames.len() as u64);
+        for frame in frames {
+            write_varint(&mut out, frame);
+        }
+        write_varint(&mut out, count);
+    }
+    out
+}
+
+/// Decode a binary profile into stacks whose frame ids are string table indices.
+///
+/// Fails on a missing magic, a newer version, or a truncated or corrupt file.
+pub fn decode_profile(data: &[u8]) -> Result<FoldedStacks> {
+    let Some(data) = data.strip_prefix(MAGIC.as_slice()) else {
+        anyhow::bail!("not a binary profile (bad magic)");
+    };
+    let mut reader = VarintReader { data };
+    let version = reader.read_varint()?;
+    if version == 0 || version > VERSION {
+        anyhow::bail!(
+            "unsupported profile version {} (this build reads up to {})",
+            version,
+            VERSION
+        );
+    }
+
+    let mut profile = FoldedStacks::default();
+    let string_count = reader.read_len()?;
+    for index in 0..string_count as u64 {
+        profile.names.insert(index, reader.read_string()?);
+    }
+    let stack_count = reader.read_len()?;
+    for _ in 0..stack_count {
+        let frame_count = reader.read_len()?;
+        let frames = (0..frame_count)
+            .map(|_| {
+                let frame = reader.read_varint()?;
+                if frame >= string_count as u64 {
+                    anyhow::bail!("malformed profile: frame {} has no string", frame);
+                }
+                Ok(frame)
+            })
+            .collect::<Result<Vec<u64>>>()?;
+        let count = reader.read_varint()?;
+        *profile.stacks.entry(frames).or_insert(0) += count;
+    }
+    if !reader.data.is_empty() {
+        anyhow::bail!("malformed profile: {} trailing bytes", reader.data.len());
+    }
+    Ok(profile)
+}
+
+/// Write aggregated stacks to `path` as a binary profile, frames named using `names`.
+pub fn write_profile(
+    path: &Path,
+    stacks: &HashMap<Vec<u64>, u64>,
+    names: &HashMap<u64, String>,
+) -> Result<()> {
+    std::fs::write(path, encode_profile(stacks, names))
+        .with_context(|| format!("Failed to write binary profile: {}", path.display()))
+}
+
+/// Write the stacks of a result to `path` as a binary profile, with resolved symbols.
+pub fn write_callchain_profile(path: &Path, result: &CallchainProfilingResult) -> Result<()> {
+    write_profile(path, result.folded_stacks(), &result.function_names())
+}
+
+/// Read a binary profile.
+pub fn read_profile(path: &Path) -> Result<FoldedStacks> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Failed to read binary profile: {}", path.display()))?;
+    decode_profile(&data).with_context(|| format!("Failed to decode {}", path.display()))
+}
+
+/// Read saved stacks in either format: a binary profile, recognized by its
+/// magic, or folded text.
+pub fn read_stacks(path: &Path) -> Result<FoldedStacks> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if is_profile(&data) {
+        return decode_profile(&data)
+            .with_context(|| format!("Failed to decode {}", path.display()));
+    }
+    let text = String::from_utf8(data).with_context(|| {
+        format!(
+            "Neither a binary profile nor folded text: {}",
+            path.display()
+        )
+    })?;
+    Ok(folded::parse_folded(&text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perf::format_folded;
+
+    #[test]
+    fn test_varint_round_trip() {
+        let mut out = Vec::new();
+        for value in [0, 1, 0x7f, 0x80, 300, u64::MAX] {
+            write_varint(&mut out, value);
+        }
+        assert_eq!(&out[..5], &[0x00, 0x01, 0x7f, 0x80, 0x01]);
+        let mut reader = VarintReader { data: &out };
+        for value in [0, 1, 0x7f, 0x80, 300, u64::MAX] {
+            assert_eq!(reader.read_varint().unwrap(), value);
+        }
+        assert!(reader.read_varint().is_err());
+    }
+
+    #[test]
+    fn test_profile_round_trip() {
+        let names: HashMap<u64, String> = [
+            (0x10, "parse".to_string()),
+            (0x11, "parse".to_string()),
+            (0x30, "main".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let stacks: HashMap<Vec<u64>, u64> = [
+            (vec![0x10, 0x30], 3),
+            (vec![0x11, 0x30], 1),
+            (vec![0x99, 0x30], 2),
+        ]
+        .into_iter()
+        .collect();
+
+        let data = encode_profile(&stacks, &names);
+        assert!(is_profile(&data));
+        let profile = decode_profile(&data).unwrap();
+        // Addresses of the same function were stored as one stack
+        assert_eq!(profile.stacks.len(), 2);
+        assert_eq!(profile.total_samples(), 6);
+        assert_eq!(
+            format_folded(&profile.stacks, &profile.names),
+            "main;0x99 2\nmain;parse 4\n"
+        );
+        // Deterministic, so re-encoding gives the same bytes
+        assert_eq!(encode_profile(&profile.stacks, &profile.names), data);
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_input() {
+        let data = encode_profile(&HashMap::from([(vec![1], 5)]), &HashMap::new());
+        assert!(decode_profile(b"main;run 5\n").is_err());
+        assert!(decode_profile(&data[..data.len() - 1]).is_err());
+
+        let mut newer = MAGIC.to_vec();
+        write_varint(&mut newer, VERSION + 1);
+        let error = decode_profile(&newer).unwrap_err();
+        assert!(error.to_string().contains("unsupported profile version 2"));
+    }
+
+    #[test]
+    fn test_read_stacks_detects_format() {
+        let dir = std::env::temp_dir();
+        let binary = dir.join(format!("profiler-test-{}.prof", std::process::id()));
+        let text = dir.join(format!("profiler-test-{}.folded", std::process::id()));
+        let folded = folded::parse_folded("main;run 5\nmain;parse 2\n");
+        write_profile(&binary, &folded.stacks, &folded.names).unwrap();
+        std::fs::write(&text, "main;run 5\nmain;parse 2\n").unwrap();
+
+        for path in [&binary, &text] {
+            let read = read_stacks(path).unwrap();
+            assert_eq!(
+                format_folded(&read.stacks, &read.names),
+                "main;parse 2\nmain;run 5\n"
+            );
+        }
+        assert_eq!(read_profile(&binary).unwrap().total_samples(), 7);
+        assert!(read_profile(&text).is_err());
+        std::fs::remove_file(binary).unwrap();
+        std::fs::remove_file(text).unwrap();
+    }
+}
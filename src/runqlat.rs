@@ -0,0 +1,212 @@
+//! Run-queue latency analysis module.
+//!
+//! This module measures how long tasks wait on the run queue between being
+//! woken up and being switched in, using the `sched:sched_wakeup` and
+//! `sched:sched_switch` tracepoints recorded in a perf.data file.
+
+use crate::histogram::Log2Histogram;
+use crate::tracepoint::{decode_sched_switch, decode_sched_wakeup, SchedSwitch};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use tracepoint_decode::PerfEventHeaderType;
+use tracepoint_perf::{PerfDataFileEventOrder, PerfDataFileReader};
+
+/// Number of offenders shown in the per-command report.
+const TOP_OFFENDERS: usize = 10;
+
+/// `prev_state` value of a task that was preempted while still runnable.
+const TASK_RUNNING: i64 = 0;
+
+/// Run-queue latency totals for one command name.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CommLatency {
+    pub count: u64,
+    pub total_us: u64,
+    pub max_us: u64,
+}
+
+/// Results of a run-queue latency analysis.
+#[derive(Debug, Default)]
+pub struct RunqLatencyStats {
+    /// Distribution of run-queue latencies in microseconds
+    pub histogram: Log2Histogram,
+    /// Latency totals keyed by the command that waited
+    pub by_comm: HashMap<String, CommLatency>,
+    /// Number of wakeup/switch pairs that produced a latency
+    pub observations: u64,
+}
+
+/// Tracks pending wakeups and turns matching switches into latencies.
+#[derive(Debug, Default)]
+pub struct RunqLatencyTracker {
+    /// Time each runnable task was queued, keyed by PID
+    queued_at: HashMap<i32, u64>,
+    stats: RunqLatencyStats,
+}
+
+impl RunqLatencyTracker {
+    /// Record a task becoming runnable at `time_ns`.
+    pub fn on_wakeup(&mut self, pid: i32, time_ns: u64) {
+        if pid != 0 {
+            self.queued_at.insert(pid, time_ns);
+        }
+    }
+
+    /// Record a context switch at `time_ns`.
+    pub fn on_switch(&mut self, switch: &SchedSwitch, time_ns: u64) {
+        // A preempted task goes straight back onto the run queue
+        if switch.prev_state == TASK_RUNNING {
+            self.on_wakeup(switch.prev_pid, time_ns);
+        }
+
+        if let Some(queued) = self.queued_at.remove(&switch.next_pid) {
+            let latency_us = time_ns.saturating_sub(queued) / 1000;
+            self.stats.histogram.record(latency_us);
+            self.stats.observations += 1;
+
+            let comm = self
+                .stats
+                .by_comm
+                .entry(switch.next_comm.clone())
+                .or_default();
+            comm.count += 1;
+            comm.total_us += latency_us;
+            comm.max_us = comm.max_us.max(latency_us);
+        }
+    }
+
+    /// Finish tracking and return the accumulated statistics.
+    pub fn finish(self) -> RunqLatencyStats {
+        self.stats
+    }
+}
+
+/// Compute run-queue latency from a perf.data file with sched tracepoints.
+///
+/// # Arguments
+///
+/// * `file_path` - Path to a perf.data file recorded with `sched:sched_wakeup`
+///   and `sched:sched_switch`
+///
+/// # Returns
+///
+/// Returns the latency histogram and per-command totals.
+pub fn analyze_runq_latency(file_path: &str, out: &mut dyn Write) -> Result<RunqLatencyStats> {
+    if !Path::new(file_path).exists() {
+        anyhow::bail!("File not found: {}", file_path);
+    }
+
+    eprintln!("Analyzing run-queue latency from: {}", file_path);
+
+    let mut reader = PerfDataFileReader::new();
+    reader
+        .open_file(file_path, PerfDataFileEventOrder::Time)
+        .context("Failed to open perf.data file")?;
+
+    let mut tracker = RunqLatencyTracker::default();
+
+    loop {
+        match reader.move_next_event() {
+            Err(e) => {
+                anyhow::bail!("Error reading event: {}", e);
+            }
+            Ok(false) => break, // EOF
+            Ok(true) => {}      // Got an event
+        }
+
+        let event = reader.current_event();
+        if event.header.ty != PerfEventHeaderType::Sample {
+            continue;
+        }
+
+        let Ok(info) = reader.get_sample_event_info(&event) else {
+            continue;
+        };
+
+        if let Some(wakeup) = decode_sched_wakeup(&info) {
+            tracker.on_wakeup(wakeup.pid, info.time);
+        } else if let Some(switch) = decode_sched_switch(&info) {
+            tracker.on_switch(&switch, info.time);
+        }
+    }
+
+    let stats = tracker.finish();
+
+    // Print summary
+    writeln!(out, "Run-queue Latency (us):")?;
+    writeln!(out, "{:=<50}", "")?;
+    writeln!(out, "  Observations:      {:>10}", stats.observations)?;
+    writeln!(out, "{:-<50}", "")?;
+    stats.histogram.print(out)?;
+
+    let mut offenders: Vec<(&String, &CommLatency)> = stats.by_comm.iter().collect();
+    // Same deterministic order as other reports: total desc, then name asc
+    offenders.sort_by(|a, b| b.1.total_us.cmp(&a.1.total_us).then_with(|| a.0.cmp(b.0)));
+
+    writeln!(out)?;
+    writeln!(out, "Top Offenders:")?;
+    writeln!(out, "{:-<50}", "")?;
+    writeln!(
+        out,
+        "  {:<16} {:>8} {:>10} {:>10}",
+        "COMM", "COUNT", "AVG us", "MAX us"
+    )?;
+    for (comm, latency) in offenders.iter().take(TOP_OFFENDERS) {
+        writeln!(
+            out,
+            "  {:<16} {:>8} {:>10} {:>10}",
+            comm,
+            latency.count,
+            latency.total_us / latency.count,
+            latency.max_us
+        )?;
+    }
+    writeln!(out, "{:=<50}", "")?;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn switch(prev_pid: i32, prev_state: i64, next_comm: &str, next_pid: i32) -> SchedSwitch {
+        SchedSwitch {
+            prev_comm: "prev".to_string(),
+            prev_pid,
+            prev_state,
+            next_comm: next_comm.to_string(),
+            next_pid,
+            cpu: None,
+        }
+    }
+
+    #[test]
+    fn test_wakeup_then_switch() {
+        let mut tracker = RunqLatencyTracker::default();
+        tracker.on_wakeup(42, 1_000_000);
+        tracker.on_switch(&switch(7, 1, "worker", 42), 1_250_000);
+        let stats = tracker.finish();
+        assert_eq!(stats.observations, 1);
+        assert_eq!(stats.by_comm["worker"].max_us, 250);
+    }
+
+    #[test]
+    fn test_preempted_task_requeued() {
+        let mut tracker = RunqLatencyTracker::default();
+        tracker.on_switch(&switch(42, TASK_RUNNING, "other", 9), 1_000_000);
+        tracker.on_switch(&switch(9, 1, "worker", 42), 1_010_000);
+        let stats = tracker.finish();
+        assert_eq!(stats.observations, 1);
+        assert_eq!(stats.by_comm["worker"].total_us, 10);
+    }
+
+    #[test]
+    fn test_switch_without_wakeup_ignored() {
+        let mut tracker = RunqLatencyTracker::default();
+        tracker.on_switch(&switch(7, 1, "worker", 42), 1_000_000);
+        assert_eq!(tracker.finish().observations, 0);
+    }
+}
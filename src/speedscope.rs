@@ -0,0 +1,200 @@
+//! speedscope export module.
+//!
+//! This module converts aggregated callchain samples into a speedscope
+//! "sampled" profile document, viewable at https://www.speedscope.app.
+
+use crate::perf::CallchainProfilingResult;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// JSON schema URL speedscope uses to recognize its own file format.
+const SCHEMA_URL: &str = "https://www.speedscope.app/file-format-schema.json";
+
+/// A frame of the shared frame table.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Frame {
+    pub name: String,
+}
+
+/// Frames of a profile, one per function name, and the frame of each address.
+#[derive(Debug, Default)]
+pub struct FrameTable {
+    frames: Vec<Frame>,
+    index: HashMap<u64, usize>,
+}
+
+impl FrameTable {
+    /// Build the frame table of every address in `stacks`.
+    ///
+    /// Addresses are named using `names`, or in hex when missing; addresses
+    /// sharing a name share a frame. Frames are numbered in address order.
+    pub fn new(stacks: &HashMap<Vec<u64>, u64>, names: &HashMap<u64, String>) -> Self {
+        let mut addresses: Vec<u64> = stacks.keys().flatten().copied().collect();
+        addresses.sort_unstable();
+        addresses.dedup();
+
+        let mut table = FrameTable::default();
+        let mut by_name: HashMap<String, usize> = HashMap::new();
+        for address in addresses {
+            let name = names
+                .get(&address)
+                .cloned()
+                .unwrap_or_else(|| format!("{:#x}", address));
+            let frame = *by_name.entry(name.clone()).or_insert_with(|| {
+                table.frames.push(Frame { name });
+                table.frames.len() - 1
+            });
+            table.index.insert(address, frame);
+        }
+        table
+    }
+
+    /// Index of the frame of `address`, if it is in the table.
+    pub fn frame_of(&self, address: u64) -> Option<usize> {
+        self.index.get(&address).copied()
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+}
+
+/// Data shared by every profile of a file.
+#[derive(Debug, Serialize)]
+pub struct Shared {
+    pub frames: Vec<Frame>,
+}
+
+/// A speedscope "sampled" profile: stacks of frame indices, root first, with weights.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SampledProfile {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub name: String,
+    pub unit: &'static str,
+    pub start_value: u64,
+    pub end_value: u64,
+    pub samples: Vec<Vec<usize>>,
+    pub weights: Vec<u64>,
+}
+
+/// A speedscope file document.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpeedscopeFile {
+    #[serde(rename = "$schema")]
+    pub schema: &'static str,
+    pub shared: Shared,
+    pub profiles: Vec<SampledProfile>,
+    pub name: String,
+    pub active_profile_index: usize,
+    /// Name and version of the program that wrote the file
+    pub exporter: String,
+}
+
+/// Build a speedscope document with a single sampled profile from aggregated stacks.
+///
+/// Stacks are emitted in sorted order so the output is deterministic; frames
+/// missing from `frames` are dropped from their stack.
+pub fn to_speedscope(stacks: &HashMap<Vec<u64>, u64>, frames: &FrameTable) -> SpeedscopeFile {
+    let mut sorted_stacks: Vec<(&Vec<u64>, &u64)> = stacks.iter().collect();
+    sorted_stacks.sort();
+
+    let (samples, weights): (Vec<Vec<usize>>, Vec<u64>) = sorted_stacks
+        .into_iter()
+        .map(|(addresses, &count)| {
+            // speedscope stacks are root first; collected stacks are leaf first
+            let stack = addresses
+                .iter()
+                .rev()
+                .filter_map(|&address| frames.frame_of(address))
+                .collect();
+            (stack, count)
+        })
+        .unzip();
+
+    SpeedscopeFile {
+        schema: SCHEMA_URL,
+        shared: Shared {
+            frames: frames.frames().to_vec(),
+        },
+        profiles: vec![SampledProfile {
+            kind: "sampled",
+            name: "cpu samples".to_string(),
+            unit: "none",
+            start_value: 0,
+            end_value: weights.iter().sum(),
+            samples,
+            weights,
+        }],
+        name: "profiler callchain".to_string(),
+        active_profile_index: 0,
+        exporter: format!("profiler@{}", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+/// Write the stacks of a result to `path` as speedscope JSON, with resolved symbols.
+pub fn write_speedscope(path: &Path, result: &CallchainProfilingResult) -> Result<()> {
+    write_stacks(path, result.folded_stacks(), &result.function_names())
+}
+
+/// Write aggregated stacks to `path` as speedscope JSON, frames named using `names`.
+pub fn write_stacks(
+    path: &Path,
+    stacks: &HashMap<Vec<u64>, u64>,
+    names: &HashMap<u64, String>,
+) -> Result<()> {
+    let frames = FrameTable::new(stacks, names);
+    let json = serde_json::to_string(&to_speedscope(stacks, &frames))
+        .context("Failed to serialize speedscope profile")?;
+    std::fs::write(path, json)
+        .with_context(|| format!("Failed to write speedscope profile: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speedscope_round_trip() {
+        let names: HashMap<u64, String> = [
+            (0x10, "leaf".to_string()),
+            (0x11, "leaf".to_string()),
+            (0x30, "main".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let stacks: HashMap<Vec<u64>, u64> = [
+            (vec![0x10, 0x30], 3),
+            (vec![0x11, 0x30], 1),
+            (vec![0x99], 2),
+        ]
+        .into_iter()
+        .collect();
+        let frames = FrameTable::new(&stacks, &names);
+        assert_eq!(frames.frames().len(), 3);
+
+        let json = serde_json::to_string(&to_speedscope(&stacks, &frames)).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["$schema"], SCHEMA_URL);
+        let frame_count = value["shared"]["frames"].as_array().unwrap().len();
+        let profile = &value["profiles"][0];
+        assert_eq!(profile["type"], "sampled");
+        assert_eq!(profile["endValue"], 6);
+
+        let samples = profile["samples"].as_array().unwrap();
+        let weights = profile["weights"].as_array().unwrap();
+        assert_eq!(samples.len(), weights.len());
+        for sample in samples {
+            for index in sample.as_array().unwrap() {
+                assert!((index.as_u64().unwrap() as usize) < frame_count);
+            }
+        }
+        // Root first: `main` (frame 1) then `leaf` (frame 0)
+        assert_eq!(samples[0], serde_json::json!([1, 0]));
+        assert_eq!(weights[0], 3);
+    }
+}
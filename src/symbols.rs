@@ -0,0 +1,551 @@
+//! Symbol resolution module.
+//!
+//! This module maps sampled instruction pointers to the module they were
+//! executing in (from `/proc/<pid>/maps`) and, when the module's ELF symbol
+//! table is readable, to the enclosing function. Symbolization runs under a
+//! time budget so that a huge or broken module cannot stall a report.
+
+use anyhow::{Context, Result};
+use object::{Object, ObjectSegment, ObjectSymbol, SymbolKind};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Label used for frames that fall outside every known mapping.
+pub const UNKNOWN_FRAME: &str = "[unknown]";
+
+/// Longest a single module's symbol table may take to load.
+pub const MODULE_LOAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An executable file mapping of a process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub start: u64,
+    pub end: u64,
+    /// Offset of `start` within the mapped file
+    pub file_offset: u64,
+    pub path: String,
+}
+
+impl MemoryMap {
+    /// Whether `address` lies inside this mapping.
+    pub fn contains(&self, address: u64) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+
+    /// Translate a virtual address inside this mapping to an offset in the file.
+    ///
+    /// This removes the load bias, `start - file_offset`, that ASLR added when
+    /// the module was mapped; symbol lookups must use the result (translated
+    /// through the ELF segments), never the raw sampled address.
+    pub fn file_offset_of(&self, address: u64) -> u64 {
+        address.wrapping_sub(self.start.wrapping_sub(self.file_offset))
+    }
+
+    /// Short module name: the file name of the mapped path.
+    pub fn module_name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or(&self.path)
+    }
+}
+
+/// Parse the executable, file-backed mappings out of `/proc/<pid>/maps` text.
+///
+/// Anonymous and pseudo mappings (`[heap]`, `[vdso]`, ...) are skipped.
+pub fn parse_proc_maps(text: &str) -> Vec<MemoryMap> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let range = fields.next()?;
+            let perms = fields.next()?;
+            let offset = fields.next()?;
+            let _device = fields.next()?;
+            let _inode = fields.next()?;
+            let path = fields.collect::<Vec<_>>().join(" ");
+            if !perms.contains('x') || !path.starts_with('/') {
+                return None;
+            }
+            let (start, end) = range.split_once('-')?;
+            Some(MemoryMap {
+                start: u64::from_str_radix(start, 16).ok()?,
+                end: u64::from_str_radix(end, 16).ok()?,
+                file_offset: u64::from_str_radix(offset, 16).ok()?,
+                path,
+            })
+        })
+        .collect()
+}
+
+/// Read the executable mappings of a process.
+///
+/// # Arguments
+///
+/// * `pid` - Process ID, or 0 for the current process
+pub fn read_proc_maps(pid: i32) -> Result<Vec<MemoryMap>> {
+    let path = if pid == 0 {
+        "/proc/self/maps".to_string()
+    } else {
+        format!("/proc/{}/maps", pid)
+    };
+    let text =
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path))?;
+    Ok(parse_proc_maps(&text))
+}
+
+/// A function symbol with its address range in the ELF file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+/// Function symbols of one ELF file, with the data needed to translate file
+/// offsets to the addresses the symbol table uses.
+#[derive(Debug, Default)]
+struct SymbolTable {
+    /// Sorted by address
+    symbols: Vec<Symbol>,
+    /// Loadable segments as `(file offset, file size, virtual address)`
+    segments: Vec<(u64, u64, u64)>,
+}
+
+impl SymbolTable {
+    /// Load the static and dynamic function symbols of an ELF file.
+    fn load(path: &str) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path))?;
+        let file =
+            object::File::parse(&*data).with_context(|| format!("Failed to parse {}", path))?;
+
+        let segments = file
+            .segments()
+            .map(|segment| {
+                let (offset, size) = segment.file_range();
+                (offset, size, segment.address())
+            })
+            .collect();
+
+        let mut symbols: Vec<Symbol> = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.address() != 0)
+            .filter_map(|symbol| {
+                Some(Symbol {
+                    address: symbol.address(),
+                    size: symbol.size(),
+                    name: symbol.name().ok()?.to_string(),
+                })
+            })
+            .collect();
+        symbols.sort_by_key(|symbol| symbol.address);
+        symbols.dedup_by_key(|symbol| symbol.address);
+
+        Ok(SymbolTable { symbols, segments })
+    }
+
+    /// Convert a file offset to the virtual address used by the symbol table.
+    fn address_of(&self, file_offset: u64) -> u64 {
+        self.segments
+            .iter()
+            .find(|&&(offset, size, _)| (offset..offset + size).contains(&file_offset))
+            .map_or(file_offset, |&(offset, _, address)| {
+                file_offset - offset + address
+            })
+    }
+
+    /// Find the function containing `address`, returning its name and the offset into it.
+    fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+        let index = self
+            .symbols
+            .partition_point(|symbol| symbol.address <= address)
+            .checked_sub(1)?;
+        let symbol = &self.symbols[index];
+        let offset = address - symbol.address;
+        // Symbols without a recorded size are matched up to the next symbol
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((symbol.name.as_str(), offset))
+    }
+}
+
+/// An instruction pointer resolved to its module and, if known, its function.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResolvedFrame {
+    pub address: u64,
+    /// Module file name, [`UNKNOWN_FRAME`] if no mapping contains the address,
+    /// or empty if the symbolization budget ran out before it was resolved
+    pub module: String,
+    pub symbol: Option<String>,
+    /// Offset into `symbol` when it is known, otherwise into the module file
+    pub offset: u64,
+}
+
+impl ResolvedFrame {
+    /// Frame for an address outside every known mapping.
+    pub fn unknown(address: u64) -> Self {
+        ResolvedFrame {
+            address,
+            module: UNKNOWN_FRAME.to_string(),
+            symbol: None,
+            offset: 0,
+        }
+    }
+
+    /// Frame for an address left unresolved because the budget ran out.
+    pub fn unresolved(address: u64) -> Self {
+        ResolvedFrame {
+            address,
+            module: String::new(),
+            symbol: None,
+            offset: 0,
+        }
+    }
+}
+
+impl fmt::Display for ResolvedFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.symbol {
+            Some(symbol) => write!(f, "{}+{:#x}", symbol, self.offset),
+            None if self.module.is_empty() => write!(f, "{:#x}", self.address),
+            None if self.module == UNKNOWN_FRAME => write!(f, "{}", UNKNOWN_FRAME),
+            None => write!(f, "{}+{:#x}", self.module, self.offset),
+        }
+    }
+}
+
+/// Load a module's symbol table on a worker thread, giving up after `timeout`.
+///
+/// A load that times out is left to finish in the background and discarded.
+fn load_with_timeout(
+    path: &str,
+    timeout: Duration,
+) -> Result<Option<SymbolTable>, mpsc::RecvTimeoutError> {
+    let (sender, receiver) = mpsc::channel();
+    let path = path.to_string();
+    thread::spawn(move || {
+        let _ = sender.send(SymbolTable::load(&path).ok());
+    });
+    receiver.recv_timeout(timeout)
+}
+
+/// Resolves addresses against a set of mappings, loading each module's
+/// symbol table on first use. Where mappings overlap, the later one wins, as
+/// it does in a process that maps over an old range.
+///
+/// Each module gets [`MODULE_LOAD_TIMEOUT`] to load and the whole symbolizer an
+/// optional overall timeout; addresses the budget leaves unresolved are
+/// returned as [`ResolvedFrame::unresolved`] and counted.
+#[derive(Debug)]
+pub struct Symbolizer {
+    maps: Vec<MemoryMap>,
+    /// `None` caches a module whose symbols could not be read
+    tables: HashMap<String, Option<SymbolTable>>,
+    /// Modules whose symbol table took longer than `module_timeout` to load
+    timed_out: HashSet<String>,
+    module_timeout: Duration,
+    deadline: Option<Instant>,
+    unresolved: u64,
+}
+
+impl Symbolizer {
+    pub fn new(maps: Vec<MemoryMap>) -> Self {
+        Symbolizer {
+            maps,
+            tables: HashMap::new(),
+            timed_out: HashSet::new(),
+            module_timeout: MODULE_LOAD_TIMEOUT,
+            deadline: None,
+            unresolved: 0,
+        }
+    }
+
+    /// Resolve further addresses against `maps`, e.g. those of another
+    /// process. Loaded symbol tables and the overall budget are kept.
+    pub fn set_maps(&mut self, maps: Vec<MemoryMap>) {
+        self.maps = maps;
+    }
+
+    /// Stop resolving addresses once `timeout` has passed from now.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Some(Instant::now() + timeout);
+        self
+    }
+
+    /// Give each module's symbol table at most `timeout` to load.
+    pub fn with_module_timeout(mut self, timeout: Duration) -> Self {
+        self.module_timeout = timeout;
+        self
+    }
+
+    /// Addresses left unresolved so far because the budget ran out.
+    pub fn unresolved_count(&self) -> u64 {
+        self.unresolved
+    }
+
+    /// Resolve one address to a frame, labelling it `[unknown]` when unmapped.
+    pub fn resolve(&mut self, address: u64) -> ResolvedFrame {
+        let Some(map) = self.maps.iter().rev().find(|map| map.contains(address)) else {
+            return ResolvedFrame::unknown(address);
+        };
+        let file_offset = map.file_offset_of(address);
+
+        let remaining = self
+            .deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if remaining == Some(Duration::ZERO) || self.timed_out.contains(&map.path) {
+            self.unresolved += 1;
+            return ResolvedFrame::unresolved(address);
+        }
+        if !self.tables.contains_key(&map.path) {
+            let timeout = remaining.map_or(self.module_timeout, |remaining| {
+                remaining.min(self.module_timeout)
+            });
+            match load_with_timeout(&map.path, timeout) {
+                Ok(table) => {
+                    self.tables.insert(map.path.clone(), table);
+                }
+                Err(_) => {
+                    self.timed_out.insert(map.path.clone());
+                    self.unresolved += 1;
+                    return ResolvedFrame::unresolved(address);
+                }
+            }
+        }
+        let symbol = self.tables[&map.path]
+            .as_ref()
+            .and_then(|table| table.lookup(table.address_of(file_offset)));
+
+        match symbol {
+            Some((name, offset)) => ResolvedFrame {
+                address,
+                module: map.module_name().to_string(),
+                symbol: Some(name.to_string()),
+                offset,
+            },
+            None => ResolvedFrame {
+                address,
+                module: map.module_name().to_string(),
+                symbol: None,
+                offset: file_offset,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPS: &str = "\
+55d4c8a00000-55d4c8a2a000 r--p 00000000 fd:01 1234 /usr/bin/profiler
+55d4c8a2a000-55d4c8b00000 r-xp 0002a000 fd:01 1234 /usr/bin/profiler
+7f1e2c000000-7f1e2c021000 rw-p 00000000 00:00 0
+7f1e2d228000-7f1e2d3bd000 r-xp 00028000 fd:01 5678 /usr/lib/x86_64-linux-gnu/libc.so.6
+7ffd5a1f0000-7ffd5a1f2000 r-xp 00000000 00:00 0 [vdso]
+";
+
+    #[test]
+    fn test_parse_proc_maps() {
+        let maps = parse_proc_maps(MAPS);
+        assert_eq!(maps.len(), 2);
+        assert_eq!(maps[0].start, 0x55d4c8a2a000);
+        assert_eq!(maps[0].end, 0x55d4c8b00000);
+        assert_eq!(maps[0].file_offset, 0x2a000);
+        assert_eq!(maps[1].module_name(), "libc.so.6");
+    }
+
+    #[test]
+    fn test_file_offset_of() {
+        let maps = parse_proc_maps(MAPS);
+        assert_eq!(maps[0].file_offset_of(0x55d4c8a2a010), 0x2a010);
+        assert_eq!(maps[1].file_offset_of(0x7f1e2d228100), 0x28100);
+    }
+
+    #[test]
+    fn test_symbol_table_lookup() {
+        let table = SymbolTable {
+            symbols: vec![
+                Symbol {
+                    address: 0x1000,
+                    size: 0x20,
+                    name: "main".to_string(),
+                },
+                Symbol {
+                    address: 0x1100,
+                    size: 0,
+                    name: "helper".to_string(),
+                },
+            ],
+            segments: vec![(0x0, 0x2000, 0x400000)],
+        };
+        assert_eq!(table.lookup(0x1010), Some(("main", 0x10)));
+        assert_eq!(table.lookup(0x1030), None);
+        assert_eq!(table.lookup(0x1180), Some(("helper", 0x80)));
+        assert_eq!(table.lookup(0x0fff), None);
+        assert_eq!(table.address_of(0x1010), 0x401010);
+    }
+
+    #[test]
+    fn test_resolve_removes_load_bias() {
+        // A PIE loaded at a random base: its text segment is at file offset
+        // 0x2a000 but virtual address 0x2b000 in the ELF file, and is mapped
+        // twice, as if split by an mprotect
+        let maps = vec![
+            MemoryMap {
+                start: 0x55d4c8a2a000,
+                end: 0x55d4c8a80000,
+                file_offset: 0x2a000,
+                path: "/usr/bin/app".to_string(),
+            },
+            MemoryMap {
+                start: 0x55d4c8a90000,
+                end: 0x55d4c8b00000,
+                file_offset: 0x80000,
+                path: "/usr/bin/app".to_string(),
+            },
+        ];
+        let table = SymbolTable {
+            symbols: vec![
+                // Where the raw file offset of the samples would land
+                Symbol {
+                    address: 0x2a000,
+                    size: 0x1000,
+                    name: "wrong_by_file_offset".to_string(),
+                },
+                Symbol {
+                    address: 0x2b000,
+                    size: 0x100,
+                    name: "parse".to_string(),
+                },
+                Symbol {
+                    address: 0x81000,
+                    size: 0x100,
+                    name: "render".to_string(),
+                },
+            ],
+            segments: vec![(0x2a000, 0x80000, 0x2b000)],
+        };
+        let mut symbolizer = Symbolizer::new(maps);
+        symbolizer
+            .tables
+            .insert("/usr/bin/app".to_string(), Some(table));
+
+        // 0x55d4c8a2a010 - (0x55d4c8a2a000 - 0x2a000) = file offset 0x2a010
+        let frame = symbolizer.resolve(0x55d4c8a2a010);
+        assert_eq!(frame.symbol.as_deref(), Some("parse"));
+        assert_eq!(frame.offset, 0x10);
+        // The second mapping has its own bias: file offset 0x80020
+        let frame = symbolizer.resolve(0x55d4c8a90020);
+        assert_eq!(frame.symbol.as_deref(), Some("render"));
+        assert_eq!(frame.to_string(), "render+0x20");
+    }
+
+    #[test]
+    fn test_resolve_unmapped_address() {
+        let mut symbolizer = Symbolizer::new(parse_proc_maps(MAPS));
+        let frame = symbolizer.resolve(0x10);
+        assert_eq!(frame, ResolvedFrame::unknown(0x10));
+        assert_eq!(frame.to_string(), "[unknown]");
+    }
+
+    #[test]
+    fn test_resolve_without_symbols_uses_module_offset() {
+        let mut symbolizer = Symbolizer::new(vec![MemoryMap {
+            start: 0x1000,
+            end: 0x2000,
+            file_offset: 0x3000,
+            path: "/nonexistent/libfoo.so".to_string(),
+        }]);
+        let frame = symbolizer.resolve(0x1010);
+        assert_eq!(frame.module, "libfoo.so");
+        assert_eq!(frame.symbol, None);
+        assert_eq!(frame.to_string(), "libfoo.so+0x3010");
+    }
+
+    #[test]
+    fn test_resolve_prefers_later_mapping() {
+        let map = |path: &str| MemoryMap {
+            start: 0x1000,
+            end: 0x2000,
+            file_offset: 0,
+            path: path.to_string(),
+        };
+        // A library unloaded and another mapped at the same range
+        let mut symbolizer =
+            Symbolizer::new(vec![map("/nonexistent/old.so"), map("/nonexistent/new.so")]);
+        assert_eq!(symbolizer.resolve(0x1010).module, "new.so");
+
+        symbolizer.set_maps(vec![map("/nonexistent/other.so")]);
+        assert_eq!(symbolizer.resolve(0x1010).module, "other.so");
+    }
+
+    #[test]
+    fn test_resolve_past_deadline_leaves_hex() {
+        let mut symbolizer = Symbolizer::new(vec![MemoryMap {
+            start: 0x1000,
+            end: 0x2000,
+            file_offset: 0,
+            path: "/nonexistent/libfoo.so".to_string(),
+        }])
+        .with_timeout(Duration::ZERO);
+        let frame = symbolizer.resolve(0x1010);
+        assert_eq!(frame, ResolvedFrame::unresolved(0x1010));
+        assert_eq!(frame.to_string(), "0x1010");
+        // Unmapped addresses need no symbols and are not charged to the budget
+        assert_eq!(symbolizer.resolve(0x10), ResolvedFrame::unknown(0x10));
+        assert_eq!(symbolizer.unresolved_count(), 1);
+    }
+
+    #[test]
+    fn test_resolve_module_load_timeout() {
+        // Opening a FIFO blocks until a writer shows up, like a module on a hung mount
+        let fifo = std::env::temp_dir().join(format!("profiler-fifo-{}", std::process::id()));
+        let path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        // SAFETY: `path` is a valid NUL-terminated string
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+
+        let mut symbolizer = Symbolizer::new(vec![MemoryMap {
+            start: 0x1000,
+            end: 0x2000,
+            file_offset: 0,
+            path: fifo.to_str().unwrap().to_string(),
+        }])
+        .with_module_timeout(Duration::from_millis(50));
+        let start = Instant::now();
+        assert_eq!(
+            symbolizer.resolve(0x1010),
+            ResolvedFrame::unresolved(0x1010)
+        );
+        // The module is not retried for later addresses
+        assert_eq!(
+            symbolizer.resolve(0x1020),
+            ResolvedFrame::unresolved(0x1020)
+        );
+        assert!(start.elapsed() < MODULE_LOAD_TIMEOUT);
+        assert_eq!(symbolizer.unresolved_count(), 2);
+
+        // Let the abandoned load finish
+        drop(std::fs::OpenOptions::new().write(true).open(&fifo));
+        std::fs::remove_file(&fifo).unwrap();
+    }
+
+    #[inline(never)]
+    fn symbolizer_test_marker() -> u64 {
+        std::hint::black_box(42)
+    }
+
+    #[test]
+    fn test_resolve_own_function() {
+        assert_eq!(symbolizer_test_marker(), 42);
+        let address = symbolizer_test_marker as fn() -> u64 as usize as u64;
+        let mut symbolizer = Symbolizer::new(read_proc_maps(0).unwrap());
+        let frame = symbolizer.resolve(address);
+        assert_ne!(frame.module, UNKNOWN_FRAME);
+        assert!(frame
+            .symbol
+            .is_some_and(|symbol| symbol.contains("symbolizer_test_marker")));
+    }
+}
@@ -0,0 +1,175 @@
+//! Text table module.
+//!
+//! This module renders reports as tables with aligned columns: numbers are
+//! right-aligned, names left-aligned and cut with an ellipsis to a maximum
+//! width, so that long C++ and Rust symbols keep the other columns in place.
+
+use std::io::{self, Write};
+
+/// Widest a name column gets unless `--max-name-width` says otherwise.
+pub const DEFAULT_MAX_NAME_WIDTH: usize = 60;
+
+/// Marker ending a name that was cut to fit its column.
+const ELLIPSIS: &str = "...";
+
+/// How the cells of a column are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    /// Left-aligned and cut to the table's maximum name width (symbols, paths)
+    Name,
+    /// Left-aligned and never cut (short labels such as a status)
+    Left,
+    /// Right-aligned (counts, percentages, addresses)
+    Right,
+}
+
+/// A table whose column widths fit its widest cells.
+#[derive(Debug, Clone)]
+pub struct Table {
+    columns: Vec<(&'static str, Align)>,
+    rows: Vec<Vec<String>>,
+    max_name_width: usize,
+}
+
+impl Table {
+    /// Create an empty table with these column headers and alignments.
+    pub fn new(columns: &[(&'static str, Align)]) -> Self {
+        Table {
+            columns: columns.to_vec(),
+            rows: Vec::new(),
+            max_name_width: DEFAULT_MAX_NAME_WIDTH,
+        }
+    }
+
+    /// Cut the cells of `Align::Name` columns to `width` characters.
+    pub fn with_max_name_width(mut self, width: usize) -> Self {
+        self.max_name_width = width;
+        self
+    }
+
+    /// Add a row; missing trailing cells are left empty.
+    pub fn push_row(&mut self, cells: Vec<String>) {
+        self.rows.push(cells);
+    }
+
+    /// Write the header and rows, indented by two spaces, two spaces apart.
+    pub fn render(&self, out: &mut dyn Write) -> io::Result<()> {
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| {
+                self.columns
+                    .iter()
+                    .enumerate()
+                    .map(|(index, &(_, align))| {
+                        let cell = row.get(index).map_or("", String::as_str);
+                        match align {
+                            Align::Name => truncate_name(cell, self.max_name_width),
+                            Align::Left | Align::Right => cell.to_string(),
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        let widths: Vec<usize> = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(index, (header, _))| {
+                rows.iter()
+                    .map(|row| row[index].chars().count())
+                    .chain([header.chars().count()])
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        let headers: Vec<String> = self.columns.iter().map(|(h, _)| h.to_string()).collect();
+        for row in [&headers].into_iter().chain(&rows) {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&self.columns)
+                .zip(&widths)
+                .map(|((cell, &(_, align)), &width)| match align {
+                    Align::Right => format!("{:>width$}", cell),
+                    Align::Name | Align::Left => format!("{:<width$}", cell),
+                })
+                .collect();
+            writeln!(out, "  {}", cells.join("  ").trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// Cut `name` to at most `width` characters, ending it with an ellipsis when cut.
+pub fn truncate_name(name: &str, width: usize) -> String {
+    if name.chars().count() <= width {
+        return name.to_string();
+    }
+    if width <= ELLIPSIS.len() {
+        return name.chars().take(width).collect();
+    }
+    let kept: String = name.chars().take(width - ELLIPSIS.len()).collect();
+    kept + ELLIPSIS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(table: &Table) -> Vec<String> {
+        let mut out = Vec::new();
+        table.render(&mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_table_alignment() {
+        let mut table = Table::new(&[("Count", Align::Right), ("Name", Align::Name)]);
+        table.push_row(vec!["7".to_string(), "main".to_string()]);
+        table.push_row(vec!["12345".to_string(), "parse_args".to_string()]);
+        table.push_row(vec!["42".to_string()]);
+
+        assert_eq!(
+            render(&table),
+            [
+                "  Count  Name",
+                "      7  main",
+                "  12345  parse_args",
+                "     42",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_table_truncates_names() {
+        let mut table =
+            Table::new(&[("Name", Align::Name), ("Status", Align::Left)]).with_max_name_width(10);
+        table.push_row(vec![
+            "std::collections::HashMap::insert".to_string(),
+            "OK".to_string(),
+        ]);
+        table.push_row(vec!["short".to_string(), "UNSUPPORTED".to_string()]);
+
+        assert_eq!(
+            render(&table),
+            [
+                "  Name        Status",
+                "  std::co...  OK",
+                "  short       UNSUPPORTED",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncate_name() {
+        assert_eq!(truncate_name("main", 10), "main");
+        assert_eq!(truncate_name("0123456789ab", 10), "0123456...");
+        assert_eq!(truncate_name("ünïcödé_name", 8), "ünïcö...");
+        assert_eq!(truncate_name("abcdef", 2), "ab");
+    }
+}
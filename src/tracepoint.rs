@@ -3,8 +3,19 @@
 //! This module provides functionality to read and decode perf.data files
 //! containing tracepoint events using Microsoft's LinuxTracepoints-Rust crates.
 
+use crate::debuglog::{self, DebugLog};
+use crate::histogram::{sorted_counts, ValueSummary};
+use crate::perf::hex_preview;
+use crate::symbols::MemoryMap;
+use crate::table::{self, Align, Table};
 use anyhow::{Context, Result};
-use std::path::Path;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use tracepoint_decode::{self as td, PerfEventHeaderType};
 use tracepoint_perf::{PerfDataFileEventOrder, PerfDataFileReader, PerfHeaderIndex};
 
@@ -14,6 +25,1255 @@ pub struct TracepointStats {
     pub total_events: u64,
     pub sample_events: u64,
     pub non_sample_events: u64,
+    /// Sample events skipped because they matched no `--event` pattern
+    pub filtered_events: u64,
+    /// Fields decoded across all samples that were decoded
+    pub fields_decoded: u64,
+    /// Sample count per event name
+    pub by_name: HashMap<String, u64>,
+    /// Non-sample record counts by record type (MMAP, COMM, ...), most frequent first
+    pub non_sample_by_type: Vec<(String, u64)>,
+    /// Distribution of the `--stat-field` field over the samples that have it
+    pub field_summary: ValueSummary,
+    /// Every timestamped sample, when `timeline` was requested
+    pub timeline: Vec<SampleRecord>,
+    /// Executable mappings from MMAP/MMAP2 records as (pid, mapping), in record order
+    pub mappings: Vec<(u32, MemoryMap)>,
+    /// True if the capture's byte order or word size differs from the host
+    pub cross_arch: bool,
+    /// Capture metadata from the perf.data header
+    pub file_info: FileInfo,
+    /// Samples before, within and after the `--since`/`--until` window
+    pub window: WindowCounts,
+}
+
+/// Capture metadata read from the perf.data header; absent headers are `None`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    pub hostname: Option<String>,
+    pub os_release: Option<String>,
+    pub arch: Option<String>,
+    /// Whether the capture was recorded on a big-endian machine
+    pub big_endian: bool,
+    /// Command line of the `perf record` invocation
+    pub cmdline: Option<Vec<String>>,
+    pub nr_cpus_available: Option<u32>,
+    pub nr_cpus_online: Option<u32>,
+    pub total_mem_kb: Option<u64>,
+    /// CPU lists sharing a core, one entry per core
+    pub core_siblings: Option<Vec<String>>,
+    /// CPU lists sharing hardware threads, one entry per thread set
+    pub thread_siblings: Option<Vec<String>>,
+    /// How sampled stacks were captured, if any event recorded them
+    pub stack_type: Option<StackType>,
+}
+
+/// `perf_event_attr.sample_type` bit for kernel-walked callchains.
+const PERF_SAMPLE_CALLCHAIN: u64 = 1 << 5;
+/// `perf_event_attr.sample_type` bit for a copy of the user stack (DWARF unwinding).
+const PERF_SAMPLE_STACK_USER: u64 = 1 << 13;
+
+/// How a capture recorded call stacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackType {
+    /// Callchains walked by the kernel using frame pointers
+    FramePointer,
+    /// Raw user stack and registers, to be unwound with DWARF CFI
+    Dwarf,
+}
+
+impl StackType {
+    /// Determine the stack type recorded for an event from its `sample_type` bits.
+    pub fn from_sample_type(sample_type: u64) -> Option<Self> {
+        if sample_type & PERF_SAMPLE_STACK_USER != 0 {
+            Some(StackType::Dwarf)
+        } else if sample_type & PERF_SAMPLE_CALLCHAIN != 0 {
+            Some(StackType::FramePointer)
+        } else {
+            None
+        }
+    }
+
+    /// Human-readable name for reports.
+    pub fn name(self) -> &'static str {
+        match self {
+            StackType::FramePointer => "frame-pointer callchains",
+            StackType::Dwarf => "DWARF user stacks",
+        }
+    }
+}
+
+impl FileInfo {
+    /// Read the capture metadata from an opened perf.data file.
+    pub fn from_reader(reader: &PerfDataFileReader) -> Self {
+        let big_endian = reader.byte_reader().source_big_endian();
+        let string_header = |index| {
+            let value = reader.header_string(index);
+            (!value.is_empty()).then(|| String::from_utf8_lossy(value).into_owned())
+        };
+
+        let nr_cpus = parse_nr_cpus(reader.header(PerfHeaderIndex::NrCpus), big_endian);
+        let topology = parse_cpu_topology(reader.header(PerfHeaderIndex::CpuTopology), big_endian);
+
+        FileInfo {
+            hostname: string_header(PerfHeaderIndex::Hostname),
+            os_release: string_header(PerfHeaderIndex::OSRelease),
+            arch: string_header(PerfHeaderIndex::Arch),
+            big_endian,
+            cmdline: parse_string_list(reader.header(PerfHeaderIndex::Cmdline), big_endian),
+            nr_cpus_available: nr_cpus.map(|(available, _)| available),
+            nr_cpus_online: nr_cpus.map(|(_, online)| online),
+            total_mem_kb: HeaderCursor::new(reader.header(PerfHeaderIndex::TotalMem), big_endian)
+                .read_u64(),
+            core_siblings: topology.as_ref().map(|(cores, _)| cores.clone()),
+            thread_siblings: topology.map(|(_, threads)| threads),
+            // A DWARF capture needs different handling than a frame-pointer one,
+            // so it takes precedence when events disagree
+            stack_type: reader
+                .event_desc_list()
+                .iter()
+                .filter_map(|desc| StackType::from_sample_type(desc.attr().sample_type.0))
+                .max_by_key(|stack_type| *stack_type == StackType::Dwarf),
+        }
+    }
+
+    /// Whether the capture's byte order or word size differs from the host.
+    pub fn is_cross_arch(&self) -> bool {
+        let word_size = self.arch.as_deref().and_then(arch_word_size);
+        self.big_endian != cfg!(target_endian = "big")
+            || word_size.is_some_and(|bits| bits != usize::BITS)
+    }
+}
+
+/// Sequential reader over a perf.data header section or record.
+struct HeaderCursor<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> HeaderCursor<'a> {
+    fn new(data: &'a [u8], big_endian: bool) -> Self {
+        HeaderCursor { data, big_endian }
+    }
+
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.data.get(..N)?.try_into().ok()?;
+        self.data = &self.data[N..];
+        Some(bytes)
+    }
+
+    fn read_u16(&mut self) -> Option<u16> {
+        let bytes = self.take::<2>()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        let bytes = self.take::<4>()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn read_u64(&mut self) -> Option<u64> {
+        let bytes = self.take::<8>()?;
+        Some(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    /// Read a `perf_header_string`: a u32 padded length followed by a NUL-terminated string.
+    fn read_string(&mut self) -> Option<String> {
+        let len = self.read_u32()? as usize;
+        let bytes = self.data.get(..len)?;
+        self.data = &self.data[len..];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+
+    /// Read a `perf_header_string_list`: a u32 count followed by that many strings.
+    fn read_string_list(&mut self) -> Option<Vec<String>> {
+        let count = self.read_u32()?;
+        (0..count).map(|_| self.read_string()).collect()
+    }
+
+    /// Like [`Self::read_string`], but only accepts printable UTF-8 text.
+    fn read_text(&mut self) -> Option<String> {
+        let len = self.read_u32()? as usize;
+        let bytes = self.data.get(..len)?;
+        self.data = &self.data[len..];
+        let end = bytes.iter().position(|&b| b == 0)?;
+        let text = std::str::from_utf8(&bytes[..end]).ok()?;
+        (!text.is_empty() && !text.chars().any(char::is_control)).then(|| text.to_string())
+    }
+}
+
+/// Parse a header holding a list of strings, such as `Cmdline`.
+fn parse_string_list(data: &[u8], big_endian: bool) -> Option<Vec<String>> {
+    HeaderCursor::new(data, big_endian).read_string_list()
+}
+
+/// Parse the `NrCpus` header into (available, online) CPU counts.
+fn parse_nr_cpus(data: &[u8], big_endian: bool) -> Option<(u32, u32)> {
+    let mut cursor = HeaderCursor::new(data, big_endian);
+    Some((cursor.read_u32()?, cursor.read_u32()?))
+}
+
+/// Parse the `CpuTopology` header into (core siblings, thread siblings) lists.
+fn parse_cpu_topology(data: &[u8], big_endian: bool) -> Option<(Vec<String>, Vec<String>)> {
+    let mut cursor = HeaderCursor::new(data, big_endian);
+    Some((cursor.read_string_list()?, cursor.read_string_list()?))
+}
+
+/// Print the capture metadata section of the report.
+fn print_file_info(info: &FileInfo, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "File Information:")?;
+    writeln!(out, "{:-<50}", "")?;
+
+    if let Some(hostname) = &info.hostname {
+        writeln!(out, "  Hostname: {}", hostname)?;
+    }
+    if let Some(os_release) = &info.os_release {
+        writeln!(out, "  OS Release: {}", os_release)?;
+    }
+    if let Some(arch) = &info.arch {
+        writeln!(out, "  Architecture: {}", arch)?;
+    }
+    if let Some(cmdline) = &info.cmdline {
+        writeln!(out, "  Command Line: {}", cmdline.join(" "))?;
+    }
+    if let (Some(available), Some(online)) = (info.nr_cpus_available, info.nr_cpus_online) {
+        writeln!(out, "  CPUs: {} online, {} available", online, available)?;
+    }
+    if let Some(total_mem_kb) = info.total_mem_kb {
+        writeln!(out, "  Total Memory: {} kB", total_mem_kb)?;
+    }
+    if let Some(cores) = &info.core_siblings {
+        writeln!(out, "  Core Siblings: {}", cores.join(" | "))?;
+    }
+    if let Some(threads) = &info.thread_siblings {
+        writeln!(out, "  Thread Siblings: {}", threads.join(" | "))?;
+    }
+
+    if let Some(stack_type) = info.stack_type {
+        writeln!(out, "  Stack Type: {}", stack_type.name())?;
+        if stack_type == StackType::Dwarf {
+            writeln!(
+                out,
+                "  Warning: DWARF stack unwinding is not supported; \
+                 sampled stacks in this capture cannot be unwound"
+            )?;
+        }
+    }
+
+    // The decoder byte-swaps fields using the capture's byte order, so all
+    // field values below are interpreted in the capture's order, not the host's
+    writeln!(out, "  Byte Order: {}", byte_order_name(info.big_endian))?;
+    if info.is_cross_arch() {
+        writeln!(
+            out,
+            "  Note: cross-architecture decode active (capture is {}{}, host is {}-bit {}); \
+             fields are byte-swapped as needed",
+            byte_order_name(info.big_endian),
+            info.arch
+                .as_deref()
+                .and_then(arch_word_size)
+                .map_or(String::new(), |bits| format!(" {}-bit", bits)),
+            usize::BITS,
+            byte_order_name(cfg!(target_endian = "big")),
+        )?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Names of the perf.data header features, indexed by `PerfHeaderIndex` value.
+const HEADER_NAMES: &[&str] = &[
+    "Reserved",
+    "TracingData",
+    "BuildId",
+    "Hostname",
+    "OSRelease",
+    "Version",
+    "Arch",
+    "NrCpus",
+    "CpuDesc",
+    "CpuId",
+    "TotalMem",
+    "Cmdline",
+    "EventDesc",
+    "CpuTopology",
+    "NumaTopology",
+    "BranchStack",
+    "PmuMappings",
+    "GroupDesc",
+    "AuxTrace",
+    "Stat",
+    "Cache",
+    "SampleTime",
+    "MemTopology",
+    "ClockId",
+    "DirFormat",
+    "BpfProgInfo",
+    "BpfBtf",
+    "Compressed",
+    "CpuPmuCaps",
+    "ClockData",
+    "HybridTopology",
+    "PmuCaps",
+];
+
+/// Number of leading bytes hex-dumped for binary headers.
+const HEADER_HEX_BYTES: usize = 64;
+
+/// Render one perf.data header for `--headers`.
+///
+/// Headers holding a string or a string list are shown as text, `NrCpus` and
+/// `TotalMem` are decoded, and anything else is hex-dumped rather than passed
+/// through a lossy UTF-8 conversion.
+fn format_header(name: &str, data: &[u8], big_endian: bool) -> String {
+    if data.is_empty() {
+        return "(empty)".to_string();
+    }
+    match name {
+        "NrCpus" => {
+            if let Some((available, online)) = parse_nr_cpus(data, big_endian) {
+                return format!("{} online, {} available", online, available);
+            }
+        }
+        "TotalMem" => {
+            if let Some(total_mem_kb) = HeaderCursor::new(data, big_endian).read_u64() {
+                return format!("{} kB", total_mem_kb);
+            }
+        }
+        _ => {}
+    }
+
+    let mut cursor = HeaderCursor::new(data, big_endian);
+    if let Some(text) = cursor.read_text().filter(|_| cursor.data.is_empty()) {
+        return text;
+    }
+    let mut cursor = HeaderCursor::new(data, big_endian);
+    let count = cursor.read_u32().unwrap_or(0);
+    let list: Option<Vec<String>> = (0..count).map(|_| cursor.read_text()).collect();
+    if let Some(list) = list.filter(|list| !list.is_empty() && cursor.data.is_empty()) {
+        return list.join(" | ");
+    }
+
+    format!(
+        "(binary, {} bytes) {}",
+        data.len(),
+        hex_preview(data, HEADER_HEX_BYTES)
+    )
+}
+
+/// Open a perf.data file for reading its headers.
+fn open_reader(file_path: &str) -> Result<PerfDataFileReader> {
+    if !Path::new(file_path).exists() {
+        anyhow::bail!("File not found: {}", file_path);
+    }
+
+    let mut reader = PerfDataFileReader::new();
+    reader
+        .open_file(file_path, PerfDataFileEventOrder::Time)
+        .context("Failed to open perf.data file")?;
+    Ok(reader)
+}
+
+/// Print every header feature of a perf.data file, for debugging capture environments.
+pub fn print_headers(file_path: &str, out: &mut dyn Write) -> Result<()> {
+    let reader = open_reader(file_path)?;
+    let big_endian = reader.byte_reader().source_big_endian();
+
+    writeln!(out, "Headers of: {}", file_path)?;
+    writeln!(out, "{:-<50}", "")?;
+    for (index, name) in HEADER_NAMES.iter().enumerate().skip(1) {
+        let data = reader.header(PerfHeaderIndex(index as u8));
+        writeln!(
+            out,
+            "  {:<16} {}",
+            name,
+            format_header(name, data, big_endian)
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Size of a `build_id_event` before its file name: header, pid and build-id.
+const BUILD_ID_RECORD_HEADER: usize = 8 + 4 + 24;
+
+/// Length of a SHA-1 build-id, used when a record does not state its size.
+const BUILD_ID_DEFAULT_SIZE: usize = 20;
+
+/// `misc` flag set when byte 20 of the build-id field holds the build-id size.
+const PERF_RECORD_MISC_BUILD_ID_SIZE: u16 = 1 << 15;
+
+/// Parse the `BuildId` header into (file name, hex build-id) pairs.
+///
+/// The header is a sequence of `build_id_event` records, each a perf event
+/// header, a pid, a 24-byte build-id field and a NUL-padded file name.
+fn parse_build_ids(data: &[u8], big_endian: bool) -> Vec<(String, String)> {
+    let mut cursor = HeaderCursor::new(data, big_endian);
+    let mut build_ids = Vec::new();
+    while !cursor.data.is_empty() {
+        let (Some(_ty), Some(misc), Some(size)) =
+            (cursor.read_u32(), cursor.read_u16(), cursor.read_u16())
+        else {
+            break;
+        };
+        let Some(name_len) = (size as usize).checked_sub(BUILD_ID_RECORD_HEADER) else {
+            break;
+        };
+        let (Some(_pid), Some(build_id)) = (cursor.read_u32(), cursor.take::<24>()) else {
+            break;
+        };
+        let Some(name) = cursor.data.get(..name_len) else {
+            break;
+        };
+        cursor.data = &cursor.data[name_len..];
+
+        let len = if misc & PERF_RECORD_MISC_BUILD_ID_SIZE != 0 {
+            (build_id[BUILD_ID_DEFAULT_SIZE] as usize).min(BUILD_ID_DEFAULT_SIZE)
+        } else {
+            BUILD_ID_DEFAULT_SIZE
+        };
+        let hex: String = build_id[..len]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        build_ids.push((String::from_utf8_lossy(&name[..end]).into_owned(), hex));
+    }
+    build_ids
+}
+
+/// List the (file name, hex build-id) pairs recorded in a perf.data file.
+///
+/// Returns an empty list if the file has no `BuildId` header.
+pub fn list_build_ids(reader: &PerfDataFileReader) -> Vec<(String, String)> {
+    parse_build_ids(
+        reader.header(PerfHeaderIndex::BuildId),
+        reader.byte_reader().source_big_endian(),
+    )
+}
+
+/// Print the build-ids of a perf.data file, like `perf buildid-list`.
+pub fn print_build_ids(file_path: &str, out: &mut dyn Write) -> Result<()> {
+    let reader = open_reader(file_path)?;
+    let build_ids = list_build_ids(&reader);
+
+    writeln!(out, "Build-IDs of: {}", file_path)?;
+    writeln!(out, "{:-<50}", "")?;
+    if build_ids.is_empty() {
+        writeln!(
+            out,
+            "  No build-id section; the capture was recorded without build-ids"
+        )?;
+        writeln!(
+            out,
+            "  (e.g. `perf record --no-buildid`), so it cannot be symbolized elsewhere"
+        )?;
+    }
+    for (file_name, build_id) in &build_ids {
+        writeln!(out, "  {} -> {}", file_name, build_id)?;
+    }
+
+    Ok(())
+}
+
+/// Size of the `perf_event_header` that starts every record.
+const PERF_EVENT_HEADER_SIZE: usize = 8;
+
+/// `misc` flag of an MMAP record describing a data (non-executable) mapping.
+const PERF_RECORD_MISC_MMAP_DATA: u16 = 1 << 13;
+
+/// `prot` bit of an MMAP2 record for an executable mapping.
+const PROT_EXEC: u32 = 0x4;
+
+/// `type` of the kernel's MMAP and MMAP2 records.
+const PERF_RECORD_MMAP: u32 = 1;
+const PERF_RECORD_MMAP2: u32 = 10;
+
+/// Parse an MMAP or MMAP2 record into (pid, mapping).
+///
+/// `record` holds the whole record, header included. Returns `None` for other
+/// record types, non-executable mappings and truncated records.
+fn parse_mmap_record(
+    ty: PerfEventHeaderType,
+    misc: u16,
+    record: &[u8],
+    big_endian: bool,
+) -> Option<(u32, MemoryMap)> {
+    let mmap2 = match ty {
+        PerfEventHeaderType::Mmap => false,
+        PerfEventHeaderType::Mmap2 => true,
+        _ => return None,
+    };
+    parse_mmap_body(mmap2, misc, record, big_endian)
+}
+
+/// Parse an MMAP or MMAP2 record delivered by a live session, in the host's
+/// byte order, into (pid, mapping).
+///
+/// Like [`parse_mmap_record`], but the record type and misc flags are read
+/// from the record's own header.
+pub fn parse_live_mmap_record(record: &[u8]) -> Option<(u32, MemoryMap)> {
+    let big_endian = cfg!(target_endian = "big");
+    let mut header = HeaderCursor::new(record, big_endian);
+    let mmap2 = match header.read_u32()? {
+        PERF_RECORD_MMAP => false,
+        PERF_RECORD_MMAP2 => true,
+        _ => return None,
+    };
+    let misc = header.read_u16()?;
+    parse_mmap_body(mmap2, misc, record, big_endian)
+}
+
+fn parse_mmap_body(
+    mmap2: bool,
+    misc: u16,
+    record: &[u8],
+    big_endian: bool,
+) -> Option<(u32, MemoryMap)> {
+    let mut cursor = HeaderCursor::new(record.get(PERF_EVENT_HEADER_SIZE..)?, big_endian);
+    let pid = cursor.read_u32()?;
+    let _tid = cursor.read_u32()?;
+    let start = cursor.read_u64()?;
+    let len = cursor.read_u64()?;
+    let file_offset = cursor.read_u64()?;
+    let executable = if mmap2 {
+        // Device and inode numbers, or a build-id, then prot and flags
+        cursor.take::<24>()?;
+        let prot = cursor.read_u32()?;
+        let _flags = cursor.read_u32()?;
+        prot & PROT_EXEC != 0
+    } else {
+        misc & PERF_RECORD_MISC_MMAP_DATA == 0
+    };
+    if !executable {
+        return None;
+    }
+
+    let end = cursor.data.iter().position(|&b| b == 0)?;
+    let path = String::from_utf8_lossy(&cursor.data[..end]).into_owned();
+    Some((
+        pid,
+        MemoryMap {
+            start,
+            end: start.checked_add(len)?,
+            file_offset,
+            path,
+        },
+    ))
+}
+
+/// Group mappings by file name into sorted, de-duplicated address ranges.
+pub fn loaded_modules(mappings: &[(u32, MemoryMap)]) -> BTreeMap<&str, Vec<(u64, u64)>> {
+    let mut modules: BTreeMap<&str, Vec<(u64, u64)>> = BTreeMap::new();
+    for (_, map) in mappings {
+        modules
+            .entry(map.path.as_str())
+            .or_default()
+            .push((map.start, map.end));
+    }
+    for ranges in modules.values_mut() {
+        ranges.sort_unstable();
+        ranges.dedup();
+    }
+    modules
+}
+
+/// Word size in bits implied by a perf.data `Arch` header (e.g. `x86_64`).
+fn arch_word_size(arch: &str) -> Option<u32> {
+    match arch {
+        "x86_64" | "aarch64" | "arm64" | "ppc64" | "ppc64le" | "s390x" | "riscv64" | "mips64"
+        | "loongarch64" | "sparc64" => Some(64),
+        "i386" | "i486" | "i586" | "i686" | "x86" | "armv6l" | "armv7l" | "arm" | "ppc"
+        | "mips" | "riscv32" => Some(32),
+        _ => None,
+    }
+}
+
+/// Human-readable name for a byte order.
+fn byte_order_name(big_endian: bool) -> &'static str {
+    if big_endian {
+        "big-endian"
+    } else {
+        "little-endian"
+    }
+}
+
+/// Typed view of a `sched:sched_switch` tracepoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchedSwitch {
+    pub prev_comm: String,
+    pub prev_pid: i32,
+    pub prev_state: i64,
+    pub next_comm: String,
+    pub next_pid: i32,
+    /// CPU the switch happened on, if the capture recorded it
+    pub cpu: Option<u32>,
+}
+
+impl SchedSwitch {
+    /// Build from a field lookup returning each field's displayed value.
+    fn from_fields(field: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        Some(SchedSwitch {
+            prev_comm: field("prev_comm")?,
+            prev_pid: field("prev_pid")?.parse().ok()?,
+            prev_state: field("prev_state")?.parse().ok()?,
+            next_comm: field("next_comm")?,
+            next_pid: field("next_pid")?.parse().ok()?,
+            cpu: None,
+        })
+    }
+}
+
+/// Typed view of a `sched:sched_wakeup` (or `sched_wakeup_new`) tracepoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchedWakeup {
+    pub comm: String,
+    pub pid: i32,
+    pub target_cpu: i32,
+    /// CPU the wakeup was issued from, if the capture recorded it
+    pub cpu: Option<u32>,
+}
+
+impl SchedWakeup {
+    /// Build from a field lookup returning each field's displayed value.
+    fn from_fields(field: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        Some(SchedWakeup {
+            comm: field("comm")?,
+            pid: field("pid")?.parse().ok()?,
+            target_cpu: field("target_cpu")?.parse().ok()?,
+            cpu: None,
+        })
+    }
+}
+
+/// `perf_event_attr.sample_type` bit for the sampling CPU.
+const PERF_SAMPLE_CPU: u64 = 1 << 7;
+
+/// CPU a sample was taken on, or `None` if the capture did not record it.
+pub fn sample_cpu(info: &td::PerfSampleEventInfo) -> Option<u32> {
+    (info.sample_type().0 & PERF_SAMPLE_CPU != 0).then_some(info.cpu)
+}
+
+/// Look up a TraceFS-format field by name and return its displayed value.
+fn field_display(info: &td::PerfSampleEventInfo, name: &str) -> Option<String> {
+    let format = info.format()?;
+    let field = format.fields().iter().find(|field| field.name() == name)?;
+    Some(field.get_field_value(info).display().to_string())
+}
+
+/// Decode a `sched:sched_switch` sample into its typed fields.
+///
+/// Returns `None` for other events or if a field is missing or malformed.
+pub fn decode_sched_switch(info: &td::PerfSampleEventInfo) -> Option<SchedSwitch> {
+    if info.name() != "sched:sched_switch" {
+        return None;
+    }
+    let switch = SchedSwitch::from_fields(|name| field_display(info, name))?;
+    Some(SchedSwitch {
+        cpu: sample_cpu(info),
+        ..switch
+    })
+}
+
+/// Decode a `sched:sched_wakeup` or `sched:sched_wakeup_new` sample into its typed fields.
+///
+/// Returns `None` for other events or if a field is missing or malformed.
+pub fn decode_sched_wakeup(info: &td::PerfSampleEventInfo) -> Option<SchedWakeup> {
+    if info.name() != "sched:sched_wakeup" && info.name() != "sched:sched_wakeup_new" {
+        return None;
+    }
+    let wakeup = SchedWakeup::from_fields(|name| field_display(info, name))?;
+    Some(SchedWakeup {
+        cpu: sample_cpu(info),
+        ..wakeup
+    })
+}
+
+/// Whether an event name matches a filter pattern.
+///
+/// A trailing `*` matches any suffix (`sched:*`); otherwise the name must match exactly.
+pub fn matches_event_pattern(name: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+/// `perf_event_attr.sample_type` bit for the sampling PID and TID.
+const PERF_SAMPLE_TID: u64 = 1 << 1;
+/// `perf_event_attr.sample_type` bit for the sample timestamp.
+const PERF_SAMPLE_TIME: u64 = 1 << 2;
+
+/// Timestamp of a sample in nanoseconds, or `None` if the capture did not record it.
+fn sample_time(info: &td::PerfSampleEventInfo) -> Option<u64> {
+    (info.sample_type().0 & PERF_SAMPLE_TIME != 0).then_some(info.time)
+}
+
+/// Default number of rows in the "Top Events" table.
+pub const DEFAULT_TOP_EVENTS: usize = 10;
+
+/// The `top` most frequent events, most frequent first (ties by name).
+pub fn top_events(by_name: &HashMap<String, u64>, top: usize) -> Vec<(&str, u64)> {
+    let mut sorted = sorted_counts(by_name.iter().map(|(name, &count)| (name.as_str(), count)));
+    sorted.truncate(top);
+    sorted
+}
+
+/// Number of fields shown per event in the human-readable preview.
+const PREVIEW_FIELDS: usize = 3;
+
+/// Number of samples shown in the human-readable preview.
+const PREVIEW_SAMPLES: u64 = 5;
+
+/// Decode every field of a sample as (name, displayed value) pairs.
+///
+/// EventHeader events are walked with the enumerator; other events use their
+/// TraceFS format, whose leading common fields (`common_pid`, ...) are counted
+/// in the returned number.
+fn decode_fields(
+    enumerator_ctx: &mut td::EventHeaderEnumeratorContext,
+    info: &td::PerfSampleEventInfo,
+) -> (Vec<(String, String)>, usize) {
+    let mut fields = Vec::new();
+
+    if let Ok(mut enumerator) = enumerator_ctx.enumerate(info) {
+        // Move past the initial state onto the first item
+        enumerator.move_next();
+        while enumerator.state() >= td::EventHeaderEnumeratorState::BeforeFirstItem {
+            let item_info = enumerator.item_info();
+            fields.push((
+                item_info.name_and_tag_display().to_string(),
+                item_info.value().display().to_string(),
+            ));
+            if !enumerator.move_next_sibling() {
+                break;
+            }
+        }
+        (fields, 0)
+    } else if let Some(event_format) = info.format() {
+        for field_format in event_format.fields() {
+            let field_value = field_format.get_field_value(info);
+            fields.push((
+                field_format.name().to_string(),
+                field_value.display().to_string(),
+            ));
+        }
+        (fields, event_format.common_field_count())
+    } else {
+        (fields, 0)
+    }
+}
+
+/// Parse a decoded field value as a number; hex values (`0x...`) are accepted.
+fn parse_numeric(value: &str) -> Option<f64> {
+    let value = value.trim();
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok().map(|v| v as f64),
+        None => value.parse().ok(),
+    }
+}
+
+/// Decode the field `name` of a sample, whether EventHeader or TraceFS.
+pub fn sample_field(
+    enumerator_ctx: &mut td::EventHeaderEnumeratorContext,
+    info: &td::PerfSampleEventInfo,
+    name: &str,
+) -> Option<String> {
+    decode_fields(enumerator_ctx, info)
+        .0
+        .into_iter()
+        .find_map(|(field, value)| (field == name).then_some(value))
+}
+
+/// Read the numeric value of the field `name` of a sample.
+///
+/// Returns `Ok(None)` if the sample has no such field, and an error naming the
+/// field if its value is not a number.
+fn numeric_field(event: &DecodedEvent, name: &str) -> Result<Option<f64>> {
+    let Some(value) = event.field(name) else {
+        return Ok(None);
+    };
+    match parse_numeric(value) {
+        Some(number) => Ok(Some(number)),
+        None => anyhow::bail!(
+            "Field {:?} of {} is not numeric: {:?}",
+            name,
+            event.name,
+            value
+        ),
+    }
+}
+
+/// One record of a perf.data file, decoded by [`TracepointReader`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedEvent {
+    /// Record type: `Sample` for tracepoint samples, `Mmap2`, `Comm`, ... otherwise
+    pub ty: PerfEventHeaderType,
+    /// Size of the record in bytes, header included
+    pub size: u16,
+    /// Event name of a sample (e.g. `sched:sched_switch`), or the record type name
+    pub name: String,
+    /// Timestamp in nanoseconds, if the capture recorded it (samples only)
+    pub time: Option<u64>,
+    pub cpu: Option<u32>,
+    pub pid: Option<u32>,
+    pub tid: Option<u32>,
+    /// Every field as (name, displayed value); empty for other records and
+    /// when field decoding is off
+    pub fields: Vec<(String, String)>,
+    /// Number of leading TraceFS common fields (`common_pid`, ...) in `fields`
+    pub common_fields: usize,
+    /// Executable mapping announced by an MMAP/MMAP2 record, as (pid, mapping)
+    pub mapping: Option<(u32, MemoryMap)>,
+}
+
+impl DecodedEvent {
+    /// Whether this record is a tracepoint sample.
+    pub fn is_sample(&self) -> bool {
+        self.ty == PerfEventHeaderType::Sample
+    }
+
+    /// Displayed value of the field `name`, common fields included.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find_map(|(field, value)| (field == name).then_some(value.as_str()))
+    }
+
+    /// The fields of the event itself, without TraceFS common fields.
+    pub fn payload_fields(&self) -> &[(String, String)] {
+        &self.fields[self.common_fields.min(self.fields.len())..]
+    }
+}
+
+/// A sample whose event information could not be read; reading can go on.
+#[derive(Debug)]
+pub struct SampleInfoError {
+    /// Size of the sample record in bytes
+    pub size: u16,
+    pub message: String,
+}
+
+impl fmt::Display for SampleInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "error getting info: {}", self.message)
+    }
+}
+
+impl std::error::Error for SampleInfoError {}
+
+/// Range of sample timestamps to read, inclusive at both ends.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    /// Earliest timestamp read, in nanoseconds
+    pub since: Option<u64>,
+    /// Latest timestamp read, in nanoseconds
+    pub until: Option<u64>,
+    /// Whether `since` and `until` are absolute timestamps rather than offsets
+    /// from the first sample
+    pub absolute: bool,
+}
+
+impl TimeWindow {
+    /// Whether the window excludes anything at all.
+    pub fn is_set(&self) -> bool {
+        self.since.is_some() || self.until.is_some()
+    }
+
+    /// Where `time` falls relative to the window: `Less` before it, `Equal`
+    /// inside it and `Greater` after it. `first_time` is the timestamp of the
+    /// first sample, the origin of a relative window.
+    pub fn position(&self, time: u64, first_time: u64) -> Ordering {
+        let time = if self.absolute {
+            time
+        } else {
+            time.saturating_sub(first_time)
+        };
+        if self.since.is_some_and(|since| time < since) {
+            Ordering::Less
+        } else if self.until.is_some_and(|until| time > until) {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    }
+}
+
+/// Number of samples read before, within and after a [`TimeWindow`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WindowCounts {
+    pub before: u64,
+    /// Samples in the window, including samples without a timestamp
+    pub within: u64,
+    /// Samples past the window that were read; reading stops at the first one
+    pub after: u64,
+    /// Whether reading stopped at the end of the window rather than of the file
+    pub stopped_early: bool,
+}
+
+impl WindowCounts {
+    /// Add the counts of another file.
+    pub fn merge(&mut self, other: WindowCounts) {
+        self.before += other.before;
+        self.within += other.within;
+        self.after += other.after;
+        self.stopped_early |= other.stopped_early;
+    }
+}
+
+/// Iterator over the decoded records of a perf.data file, in time order.
+///
+/// Each item is a [`DecodedEvent`]. A sample whose event information cannot be
+/// read is yielded as a [`SampleInfoError`] and iteration continues; any other
+/// error ends the iteration.
+pub struct TracepointReader {
+    reader: PerfDataFileReader,
+    enumerator_ctx: td::EventHeaderEnumeratorContext,
+    file_info: FileInfo,
+    filter: Option<Vec<String>>,
+    filtered: u64,
+    window: TimeWindow,
+    /// Timestamp of the first sample, the origin of a relative window
+    first_time: Option<u64>,
+    window_counts: WindowCounts,
+    decode_fields: bool,
+    done: bool,
+}
+
+impl TracepointReader {
+    /// Open a perf.data file; fields are decoded and no sample is filtered out.
+    pub fn open(file_path: &str) -> Result<Self> {
+        let reader = open_reader(file_path)?;
+        let file_info = FileInfo::from_reader(&reader);
+        Ok(TracepointReader {
+            reader,
+            enumerator_ctx: td::EventHeaderEnumeratorContext::new(),
+            file_info,
+            filter: None,
+            filtered: 0,
+            window: TimeWindow::default(),
+            first_time: None,
+            window_counts: WindowCounts::default(),
+            decode_fields: true,
+            done: false,
+        })
+    }
+
+    /// Only yield the samples matching one of these patterns (see
+    /// [`matches_event_pattern`]); other records are always yielded.
+    pub fn with_filter(mut self, patterns: Option<Vec<String>>) -> Self {
+        self.filter = patterns;
+        self
+    }
+
+    /// Only yield the samples inside `window`; other records are always
+    /// yielded. Reading stops at the first sample past the window.
+    pub fn with_window(mut self, window: TimeWindow) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Whether sample fields are decoded, from the next record on. Turning it
+    /// off makes passes that only count events much faster.
+    pub fn set_decode_fields(&mut self, decode_fields: bool) {
+        self.decode_fields = decode_fields;
+    }
+
+    /// Capture metadata from the perf.data header.
+    pub fn file_info(&self) -> &FileInfo {
+        &self.file_info
+    }
+
+    /// The underlying reader, for headers and event descriptors.
+    pub fn perf_reader(&self) -> &PerfDataFileReader {
+        &self.reader
+    }
+
+    /// Number of samples skipped so far because they matched no filter pattern.
+    pub fn filtered_events(&self) -> u64 {
+        self.filtered
+    }
+
+    /// Samples read so far before, within and after the time window.
+    pub fn window_counts(&self) -> WindowCounts {
+        self.window_counts
+    }
+}
+
+impl Iterator for TracepointReader {
+    type Item = Result<DecodedEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        loop {
+            match self.reader.move_next_event() {
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(anyhow::anyhow!("Error reading event: {}", e)));
+                }
+                Ok(false) => {
+                    // EOF
+                    self.done = true;
+                    return None;
+                }
+                Ok(true) => {} // Got an event
+            }
+
+            let event = self.reader.current_event();
+            let ty = event.header.ty;
+            let size = event.header.size;
+            if ty != PerfEventHeaderType::Sample {
+                let big_endian = self.file_info.big_endian;
+                return Some(Ok(DecodedEvent {
+                    ty,
+                    size,
+                    name: ty.to_string(),
+                    time: None,
+                    cpu: None,
+                    pid: None,
+                    tid: None,
+                    fields: Vec::new(),
+                    common_fields: 0,
+                    mapping: parse_mmap_record(ty, event.header.misc, event.data, big_endian),
+                }));
+            }
+
+            let info = self.reader.get_sample_event_info(&event);
+            // Samples without a timestamp can't be placed, so they are kept
+            let time = info.as_ref().ok().and_then(sample_time);
+            if let (true, Some(time)) = (self.window.is_set(), time) {
+                let first_time = *self.first_time.get_or_insert(time);
+                match self.window.position(time, first_time) {
+                    Ordering::Less => {
+                        self.window_counts.before += 1;
+                        continue;
+                    }
+                    Ordering::Greater => {
+                        // Events are in time order: nothing later can be in the window
+                        self.window_counts.after += 1;
+                        self.window_counts.stopped_early = true;
+                        self.done = true;
+                        return None;
+                    }
+                    Ordering::Equal => self.window_counts.within += 1,
+                }
+            }
+
+            // Samples whose name can't be read can't match a filter either
+            if let Some(patterns) = &self.filter {
+                let matched = info.as_ref().is_ok_and(|info| {
+                    patterns
+                        .iter()
+                        .any(|pattern| matches_event_pattern(info.name(), pattern))
+                });
+                if !matched {
+                    self.filtered += 1;
+                    continue;
+                }
+            }
+            let info = match info {
+                Ok(info) => info,
+                Err(e) => {
+                    let error = SampleInfoError {
+                        size,
+                        message: e.to_string(),
+                    };
+                    return Some(Err(error.into()));
+                }
+            };
+
+            let (fields, common_fields) = if self.decode_fields {
+                decode_fields(&mut self.enumerator_ctx, &info)
+            } else {
+                (Vec::new(), 0)
+            };
+            let sample_type = info.sample_type().0;
+            let has_tid = sample_type & PERF_SAMPLE_TID != 0;
+            return Some(Ok(DecodedEvent {
+                ty,
+                size,
+                name: info.name().to_string(),
+                time,
+                cpu: sample_cpu(&info),
+                pid: has_tid.then_some(info.pid),
+                tid: has_tid.then_some(info.tid),
+                fields,
+                common_fields,
+                mapping: None,
+            }));
+        }
+    }
+}
+
+/// One decoded sample, as emitted by `--json` (one object per line).
+#[derive(Debug, Serialize)]
+pub struct SampleRecord {
+    pub name: String,
+    /// Timestamp in nanoseconds, if the capture recorded it
+    pub time: Option<u64>,
+    pub cpu: Option<u32>,
+    pub pid: Option<u32>,
+    pub tid: Option<u32>,
+    /// Every decoded field, by name
+    pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl SampleRecord {
+    /// Build a record from a decoded sample and the fields to emit.
+    fn new(event: &DecodedEvent, fields: &[(String, String)]) -> Self {
+        SampleRecord {
+            name: event.name.clone(),
+            time: event.time,
+            cpu: event.cpu,
+            pid: event.pid,
+            tid: event.tid,
+            fields: fields
+                .iter()
+                .map(|(name, value)| (name.clone(), serde_json::Value::String(value.clone())))
+                .collect(),
+        }
+    }
+}
+
+/// Output format of the `tracepoint` subcommand, besides its report.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TraceFormat {
+    /// The human-readable report only
+    #[default]
+    Text,
+    /// Also write the samples as Chrome Trace Event Format instant events,
+    /// for chrome://tracing and Perfetto
+    Chrome,
+}
+
+/// One Chrome Trace Event Format event.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChromeEvent {
+    pub name: String,
+    /// Tracepoint system, e.g. `sched` for `sched:sched_switch`
+    pub cat: String,
+    /// Event phase; `i` is an instant event
+    pub ph: &'static str,
+    /// Microseconds since the first sample
+    pub ts: f64,
+    pub pid: u32,
+    pub tid: u32,
+    /// Scope of an instant event; `t` draws it on its thread's track
+    pub s: &'static str,
+}
+
+/// Convert timestamped samples into Chrome trace instant events.
+///
+/// Events are sorted by time and `ts` counts from the earliest sample, so
+/// timestamps are monotonic and start at zero even across several files.
+/// Samples without a timestamp are skipped.
+pub fn to_chrome_trace(samples: &[SampleRecord]) -> Vec<ChromeEvent> {
+    let mut timed: Vec<(u64, &SampleRecord)> = samples
+        .iter()
+        .filter_map(|sample| Some((sample.time?, sample)))
+        .collect();
+    timed.sort_by_key(|(time, _)| *time);
+    let base = timed.first().map_or(0, |(time, _)| *time);
+
+    timed
+        .into_iter()
+        .map(|(time, sample)| ChromeEvent {
+            name: sample.name.clone(),
+            cat: sample
+                .name
+                .split_once(':')
+                .map_or("tracepoint", |(system, _)| system)
+                .to_string(),
+            ph: "i",
+            ts: (time - base) as f64 / 1000.0,
+            pid: sample.pid.unwrap_or(0),
+            tid: sample.tid.unwrap_or(0),
+            s: "t",
+        })
+        .collect()
+}
+
+/// Write timestamped samples to `path` as a Chrome trace (a JSON array of events).
+pub fn write_chrome_trace(path: &Path, samples: &[SampleRecord]) -> Result<()> {
+    let json = serde_json::to_string(&to_chrome_trace(samples))
+        .context("Failed to serialize Chrome trace")?;
+    std::fs::write(path, json)
+        .with_context(|| format!("Failed to write Chrome trace: {}", path.display()))
+}
+
+/// Settings for [`read_tracepoint_file`] and [`read_tracepoint_files`].
+#[derive(Debug, Default)]
+pub struct TracepointOptions {
+    /// Optional path of a bounded log receiving one line per record
+    pub debug_log: Option<PathBuf>,
+    /// If set, only count and decode samples whose event name matches one of
+    /// these patterns (see [`matches_event_pattern`])
+    pub filter: Option<Vec<String>>,
+    /// Print every sample as a JSON object per line instead of the
+    /// human-readable report, so stdout is valid NDJSON
+    pub json: bool,
+    /// Fields decoded per sample; unset decodes every field for `json` and the
+    /// first few for the preview
+    pub max_fields: Option<usize>,
+    /// Also decode the TraceFS common fields
+    pub include_common: bool,
+    /// Number of rows in the "Top Events" table
+    pub top: usize,
+    /// With several files, also print a one-line summary per file
+    pub per_file: bool,
+    /// Print the "Loaded Modules" table built from MMAP/MMAP2 records
+    pub modules: bool,
+    /// Widest event and module names get in tables; unset uses
+    /// [`table::DEFAULT_MAX_NAME_WIDTH`]
+    pub max_name_width: Option<usize>,
+    /// Numeric field whose distribution over all samples is summarized
+    pub stat_field: Option<String>,
+    /// Keep every timestamped sample in `TracepointStats::timeline`, for
+    /// [`write_chrome_trace`]
+    pub timeline: bool,
+    /// Only count and decode the samples in this time range
+    pub window: TimeWindow,
+}
+
+impl TracepointStats {
+    /// Add the counts of another file's statistics to these.
+    ///
+    /// `file_info` is left as is: it describes the first file merged.
+    pub fn merge(&mut self, other: TracepointStats) {
+        self.total_events += other.total_events;
+        self.sample_events += other.sample_events;
+        self.non_sample_events += other.non_sample_events;
+        self.filtered_events += other.filtered_events;
+        self.fields_decoded += other.fields_decoded;
+        self.cross_arch |= other.cross_arch;
+        self.window.merge(other.window);
+        self.field_summary.merge(&other.field_summary);
+        self.timeline.extend(other.timeline);
+        self.mappings.extend(other.mappings);
+        for (name, count) in other.by_name {
+            *self.by_name.entry(name).or_default() += count;
+        }
+        let mut by_type: HashMap<String, u64> = self.non_sample_by_type.drain(..).collect();
+        for (ty, count) in other.non_sample_by_type {
+            *by_type.entry(ty).or_default() += count;
+        }
+        self.non_sample_by_type = sorted_counts(by_type);
+    }
 }
 
 /// Read and decode a perf.data file containing tracepoint events.
@@ -21,167 +1281,395 @@ pub struct TracepointStats {
 /// # Arguments
 ///
 /// * `file_path` - Path to the perf.data file
+/// * `options` - What to decode and how to print it
+/// * `out` - Where the report (or the `--json` records) is written
 ///
 /// # Returns
 ///
 /// Returns statistics about the events found in the file.
-pub fn read_tracepoint_file(file_path: &str) -> Result<TracepointStats> {
-    let path = Path::new(file_path);
-    if !path.exists() {
-        anyhow::bail!("File not found: {}", file_path);
-    }
+pub fn read_tracepoint_file(
+    file_path: &str,
+    options: &TracepointOptions,
+    out: &mut dyn Write,
+) -> Result<TracepointStats> {
+    let mut debug_log = options
+        .debug_log
+        .as_deref()
+        .map(|path| DebugLog::create(path, debuglog::DEFAULT_MAX_BYTES))
+        .transpose()?;
 
-    println!("Reading tracepoint data from: {}", file_path);
-    println!();
+    let stats = decode_file(file_path, options, debug_log.as_mut(), out)?;
+    if !options.json {
+        print_summary(&stats, options, out)?;
+    }
+    Ok(stats)
+}
 
-    // Create the reader
-    let mut reader = PerfDataFileReader::new();
+/// Read several perf.data files and report their combined statistics.
+///
+/// A file that cannot be read produces a warning and is skipped; it is an
+/// error only if no file could be read.
+///
+/// # Arguments
+///
+/// * `paths` - Paths to the perf.data files
+/// * `options` - What to decode and how to print it
+/// * `out` - Where the report (or the `--json` records) is written
+///
+/// # Returns
+///
+/// Returns the statistics of all files read, merged.
+pub fn read_tracepoint_files(
+    paths: &[String],
+    options: &TracepointOptions,
+    out: &mut dyn Write,
+) -> Result<TracepointStats> {
+    let mut debug_log = options
+        .debug_log
+        .as_deref()
+        .map(|path| DebugLog::create(path, debuglog::DEFAULT_MAX_BYTES))
+        .transpose()?;
 
-    // Open the file with time-ordered events
-    reader
-        .open_file(file_path, PerfDataFileEventOrder::Time)
-        .context("Failed to open perf.data file")?;
+    let mut combined: Option<TracepointStats> = None;
+    let mut per_file = Vec::new();
+    for path in paths {
+        match decode_file(path, options, debug_log.as_mut(), out) {
+            Ok(stats) => {
+                per_file.push((path, stats.total_events, stats.sample_events));
+                match combined.as_mut() {
+                    Some(combined) => combined.merge(stats),
+                    None => combined = Some(stats),
+                }
+            }
+            Err(e) => eprintln!("Warning: skipping {}: {:#}", path, e),
+        }
+    }
+    let Some(combined) = combined else {
+        anyhow::bail!("None of the {} files could be read", paths.len());
+    };
+    if options.json {
+        return Ok(combined);
+    }
 
-    let mut stats = TracepointStats::default();
+    if options.per_file {
+        writeln!(out)?;
+        writeln!(out, "Per-File Summary:")?;
+        writeln!(out, "{:-<50}", "")?;
+        for (path, total, samples) in &per_file {
+            writeln!(out, "  {}: {} events, {} samples", path, total, samples)?;
+        }
+    }
+    print_summary(&combined, options, out)?;
+    writeln!(out, "Files Read: {} of {}", per_file.len(), paths.len())?;
 
-    // Print header information
-    println!("File Information:");
-    println!("{:-<50}", "");
+    Ok(combined)
+}
 
-    let hostname = reader.header_string(PerfHeaderIndex::Hostname);
-    if !hostname.is_empty() {
-        println!("  Hostname: {}", String::from_utf8_lossy(hostname));
+/// Decode one file, writing its metadata and preview to `out` unless `json` is set.
+fn decode_file(
+    file_path: &str,
+    options: &TracepointOptions,
+    mut debug_log: Option<&mut DebugLog>,
+    out: &mut dyn Write,
+) -> Result<TracepointStats> {
+    let mut reader = TracepointReader::open(file_path)?
+        .with_filter(options.filter.clone())
+        .with_window(options.window);
+    if !options.json {
+        eprintln!("Reading tracepoint data from: {}", file_path);
     }
 
-    let os_release = reader.header_string(PerfHeaderIndex::OSRelease);
-    if !os_release.is_empty() {
-        println!("  OS Release: {}", String::from_utf8_lossy(os_release));
-    }
+    let mut stats = TracepointStats::default();
 
-    let arch = reader.header_string(PerfHeaderIndex::Arch);
-    if !arch.is_empty() {
-        println!("  Architecture: {}", String::from_utf8_lossy(arch));
+    // Print header information
+    let file_info = reader.file_info().clone();
+    if !options.json {
+        print_file_info(&file_info, out)?;
     }
-    println!();
+    stats.cross_arch = file_info.is_cross_arch();
+    stats.file_info = file_info;
 
     // Print event descriptors
-    println!("Event Descriptors:");
-    println!("{:-<50}", "");
-    for desc in reader.event_desc_list() {
-        println!("  Event: {}", desc.name());
-        for id in desc.ids() {
-            println!("    ID: {}", id);
+    if !options.json {
+        writeln!(out, "Event Descriptors:")?;
+        writeln!(out, "{:-<50}", "")?;
+        for desc in reader.perf_reader().event_desc_list() {
+            writeln!(out, "  Event: {}", desc.name())?;
+            for id in desc.ids() {
+                writeln!(out, "    ID: {}", id)?;
+            }
         }
+        writeln!(out)?;
     }
-    println!();
-
-    // Create an enumerator context for EventHeader decoding
-    let mut enumerator_ctx = td::EventHeaderEnumeratorContext::new();
 
     // Read and process events
-    println!("Processing events...");
-    println!("{:-<50}", "");
+    if !options.json {
+        writeln!(out, "Processing events...")?;
+        writeln!(out, "{:-<50}", "")?;
+    }
 
     let mut sample_count = 0;
+    let mut non_sample_by_type: HashMap<String, u64> = HashMap::new();
+    let all_fields = options.json || options.stat_field.is_some();
 
     loop {
-        match reader.move_next_event() {
-            Err(e) => {
-                anyhow::bail!("Error reading event: {}", e);
+        // Fields are only needed for every sample, or for the preview
+        reader.set_decode_fields(all_fields || sample_count < PREVIEW_SAMPLES);
+        let event = match reader.next() {
+            None => break,
+            Some(Ok(event)) => event,
+            Some(Err(e)) => {
+                let error = e.downcast::<SampleInfoError>()?;
+                stats.total_events += 1;
+                stats.sample_events += 1;
+                sample_count += 1;
+                if let Some(log) = debug_log.as_deref_mut() {
+                    log.record("Sample", error.size as usize, None)
+                        .context("Failed to write debug log")?;
+                }
+                if !options.json && sample_count <= PREVIEW_SAMPLES {
+                    writeln!(out, "  Sample event #{} - {}", sample_count, error)?;
+                }
+                continue;
             }
-            Ok(false) => break, // EOF
-            Ok(true) => {}      // Got an event
-        }
-
-        let event = reader.current_event();
+        };
         stats.total_events += 1;
+        if let Some(log) = debug_log.as_deref_mut() {
+            let name = if event.is_sample() {
+                "Sample"
+            } else {
+                &event.name
+            };
+            log.record(name, event.size as usize, event.time)
+                .context("Failed to write debug log")?;
+        }
 
-        if event.header.ty != PerfEventHeaderType::Sample {
+        if !event.is_sample() {
             // Non-sample event
             stats.non_sample_events += 1;
+            *non_sample_by_type.entry(event.name).or_default() += 1;
+            if let Some(mapping) = event.mapping {
+                stats.mappings.push(mapping);
+            }
 
             // Only print first few non-sample events
-            if stats.non_sample_events <= 3 {
-                println!("  Non-sample event: {}", event.header.ty);
-                println!("    Size: {} bytes", event.header.size);
+            if !options.json && stats.non_sample_events <= 3 {
+                writeln!(out, "  Non-sample event: {}", event.ty)?;
+                writeln!(out, "    Size: {} bytes", event.size)?;
             }
-        } else {
-            // Sample event (tracepoint)
-            stats.sample_events += 1;
-            sample_count += 1;
+            continue;
+        }
 
-            // Get event info
-            let sample_event_info = match reader.get_sample_event_info(&event) {
-                Ok(info) => info,
-                Err(e) => {
-                    if sample_count <= 5 {
-                        println!(
-                            "  Sample event #{} - error getting info: {}",
-                            sample_count, e
-                        );
-                    }
-                    continue;
-                }
-            };
+        // Sample event (tracepoint)
+        stats.sample_events += 1;
+        sample_count += 1;
+        *stats.by_name.entry(event.name.clone()).or_default() += 1;
 
-            // Print first few sample events
-            if sample_count <= 5 {
-                println!(
-                    "  Sample event #{}: {}",
-                    sample_count,
-                    sample_event_info.name()
-                );
-
-                // Try to decode using EventHeader
-                if let Ok(mut enumerator) = enumerator_ctx.enumerate(&sample_event_info) {
-                    let eh_event_info = enumerator.event_info();
-                    println!(
-                        "    EventHeader info: {}",
-                        eh_event_info.json_meta_display(Some(&sample_event_info))
-                    );
-
-                    // Move past initial state and print first few fields
-                    enumerator.move_next();
-                    let mut field_count = 0;
-                    while enumerator.state() >= td::EventHeaderEnumeratorState::BeforeFirstItem
-                        && field_count < 3
-                    {
-                        let item_info = enumerator.item_info();
-                        println!("    Field: {}", item_info.name_and_tag_display());
-                        if !enumerator.move_next_sibling() {
-                            break;
-                        }
-                        field_count += 1;
-                    }
-                } else if let Some(event_format) = sample_event_info.format() {
-                    // Decode using TraceFS format
-                    let skip_fields = event_format.common_field_count();
-                    for (field_count, field_format) in
-                        event_format.fields().iter().skip(skip_fields).enumerate()
-                    {
-                        if field_count >= 3 {
-                            break;
-                        }
-                        let field_value = field_format.get_field_value(&sample_event_info);
-                        println!("    {}: {}", field_format.name(), field_value.display());
-                    }
-                }
+        if let Some(field) = &options.stat_field {
+            if let Some(value) = numeric_field(&event, field)? {
+                stats.field_summary.record(value);
             }
         }
+
+        if options.timeline && event.time.is_some() {
+            stats.timeline.push(SampleRecord::new(&event, &[]));
+        }
+
+        if options.json {
+            let limit = options.max_fields.unwrap_or(usize::MAX);
+            let fields = shown_fields(&event, options.include_common, limit);
+            stats.fields_decoded += fields.len() as u64;
+            let record = SampleRecord::new(&event, fields);
+            writeln!(
+                out,
+                "{}",
+                serde_json::to_string(&record).context("Failed to serialize sample")?
+            )?;
+            continue;
+        }
+
+        // Print first few sample events
+        if sample_count <= PREVIEW_SAMPLES {
+            writeln!(out, "  Sample event #{}: {}", sample_count, event.name)?;
+            stats.fields_decoded += print_sample_preview(&event, options, out)?;
+        }
     }
 
-    // Print summary
-    println!();
-    println!("Event Summary:");
-    println!("{:=<50}", "");
-    println!("  Total Events:      {:>10}", stats.total_events);
-    println!("  Sample Events:     {:>10}", stats.sample_events);
-    println!("  Non-Sample Events: {:>10}", stats.non_sample_events);
-    println!("{:=<50}", "");
+    stats.filtered_events = reader.filtered_events();
+    stats.window = reader.window_counts();
+    stats.total_events += stats.filtered_events + stats.window.before + stats.window.after;
+    stats.non_sample_by_type = sorted_counts(non_sample_by_type);
 
     Ok(stats)
 }
 
+/// The fields of a sample to show: at most `limit`, common fields only with `include_common`.
+fn shown_fields(event: &DecodedEvent, include_common: bool, limit: usize) -> &[(String, String)] {
+    let fields = if include_common {
+        event.fields.as_slice()
+    } else {
+        event.payload_fields()
+    };
+    &fields[..fields.len().min(limit)]
+}
+
+/// Print the preview lines of a sample below its name.
+///
+/// Well-known scheduler tracepoints get a one-line typed summary; other events
+/// show their first fields. Returns the number of fields shown.
+fn print_sample_preview(
+    event: &DecodedEvent,
+    options: &TracepointOptions,
+    out: &mut dyn Write,
+) -> io::Result<u64> {
+    let field = |name: &str| event.field(name).map(str::to_string);
+    if event.name == "sched:sched_switch" {
+        if let Some(switch) = SchedSwitch::from_fields(field) {
+            writeln!(
+                out,
+                "    {}[{}] (state {}) -> {}[{}]",
+                switch.prev_comm,
+                switch.prev_pid,
+                switch.prev_state,
+                switch.next_comm,
+                switch.next_pid
+            )?;
+            return Ok(0);
+        }
+    } else if event.name == "sched:sched_wakeup" || event.name == "sched:sched_wakeup_new" {
+        if let Some(wakeup) = SchedWakeup::from_fields(field) {
+            writeln!(
+                out,
+                "    wakeup {}[{}] on CPU {}",
+                wakeup.comm, wakeup.pid, wakeup.target_cpu
+            )?;
+            return Ok(0);
+        }
+    }
+    let limit = options.max_fields.unwrap_or(PREVIEW_FIELDS);
+    let fields = shown_fields(event, options.include_common, limit);
+    for (name, value) in fields {
+        writeln!(out, "    {}: {}", name, value)?;
+    }
+    Ok(fields.len() as u64)
+}
+
+/// Print the distribution of a `--stat-field` field.
+fn print_field_summary(field: &str, summary: &ValueSummary, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "Field Statistics: {}", field)?;
+    writeln!(out, "{:-<50}", "")?;
+    let Some(mean) = summary.mean() else {
+        writeln!(out, "  No sample has a field named {:?}", field)?;
+        return Ok(());
+    };
+    writeln!(out, "  Count:             {:>10}", summary.count)?;
+    writeln!(out, "  Sum:               {:>14.2}", summary.sum)?;
+    writeln!(out, "  Min:               {:>14.2}", summary.min)?;
+    writeln!(out, "  Max:               {:>14.2}", summary.max)?;
+    writeln!(out, "  Mean:              {:>14.2}", mean)?;
+    for p in [50.0, 90.0, 99.0] {
+        if let Some(value) = summary.percentile(p) {
+            writeln!(out, "  p{:<2} (approx):      {:>14.2}", p, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Print the event summary and "Top Events" table.
+fn print_summary(
+    stats: &TracepointStats,
+    options: &TracepointOptions,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    // Print summary
+    writeln!(out)?;
+    writeln!(out, "Event Summary:")?;
+    writeln!(out, "{:=<50}", "")?;
+    writeln!(out, "  Total Events:      {:>10}", stats.total_events)?;
+    writeln!(out, "  Sample Events:     {:>10}", stats.sample_events)?;
+    writeln!(out, "  Non-Sample Events: {:>10}", stats.non_sample_events)?;
+    if options.filter.is_some() {
+        writeln!(out, "  Filtered Out:      {:>10}", stats.filtered_events)?;
+    }
+    writeln!(out, "  Fields Decoded:    {:>10}", stats.fields_decoded)?;
+    if options.window.is_set() {
+        writeln!(out, "  Before Window:     {:>10}", stats.window.before)?;
+        writeln!(out, "  Within Window:     {:>10}", stats.window.within)?;
+        if stats.window.stopped_early {
+            // Later samples were never read, so the count is a lower bound
+            writeln!(
+                out,
+                "  After Window:      {:>10}+ (stopped reading)",
+                stats.window.after
+            )?;
+        } else {
+            writeln!(out, "  After Window:      {:>10}", stats.window.after)?;
+        }
+    }
+    for (ty, count) in &stats.non_sample_by_type {
+        writeln!(out, "    {:<15} {:>10}", ty, count)?;
+    }
+    writeln!(out, "{:=<50}", "")?;
+
+    if let Some(field) = &options.stat_field {
+        print_field_summary(field, &stats.field_summary, out)?;
+    }
+
+    let max_name_width = options
+        .max_name_width
+        .unwrap_or(table::DEFAULT_MAX_NAME_WIDTH);
+    if options.modules {
+        writeln!(out)?;
+        writeln!(out, "Loaded Modules:")?;
+        writeln!(out, "{:-<50}", "")?;
+        let modules = loaded_modules(&stats.mappings);
+        if modules.is_empty() {
+            writeln!(out, "  No executable MMAP/MMAP2 records in the capture")?;
+        } else {
+            let mut table = Table::new(&[
+                ("Module", Align::Name),
+                ("Start", Align::Right),
+                ("End", Align::Right),
+            ])
+            .with_max_name_width(max_name_width);
+            for (path, ranges) in modules {
+                // The module is named on its first range only
+                for (index, (start, end)) in ranges.into_iter().enumerate() {
+                    let name = if index == 0 { path } else { "" };
+                    table.push_row(vec![
+                        name.to_string(),
+                        format!("{:#018x}", start),
+                        format!("{:#018x}", end),
+                    ]);
+                }
+            }
+            table.render(out)?;
+        }
+    }
+
+    if !stats.by_name.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "Top Events:")?;
+        writeln!(out, "{:-<50}", "")?;
+        let mut table = Table::new(&[
+            ("Event", Align::Name),
+            ("Samples", Align::Right),
+            ("Share", Align::Right),
+        ])
+        .with_max_name_width(max_name_width);
+        for (name, count) in top_events(&stats.by_name, options.top) {
+            table.push_row(vec![
+                name.to_string(),
+                count.to_string(),
+                format!("{:.1}%", count as f64 * 100.0 / stats.sample_events as f64),
+            ]);
+        }
+        table.render(out)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -192,13 +1680,507 @@ mod tests {
         assert_eq!(stats.total_events, 0);
         assert_eq!(stats.sample_events, 0);
         assert_eq!(stats.non_sample_events, 0);
+        assert_eq!(stats.fields_decoded, 0);
+        assert!(stats.non_sample_by_type.is_empty());
+        assert!(!stats.cross_arch);
+    }
+
+    #[test]
+    fn test_arch_word_size() {
+        assert_eq!(arch_word_size("x86_64"), Some(64));
+        assert_eq!(arch_word_size("armv7l"), Some(32));
+        assert_eq!(arch_word_size("unknown"), None);
+    }
+
+    /// Encode a `perf_header_string` padded to 8 bytes.
+    fn header_string(value: &str) -> Vec<u8> {
+        let padded_len = (value.len() + 1).div_ceil(8) * 8;
+        let mut bytes = (padded_len as u32).to_le_bytes().to_vec();
+        bytes.extend(value.as_bytes());
+        bytes.resize(4 + padded_len, 0);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_string_list() {
+        let mut data = 2u32.to_le_bytes().to_vec();
+        data.extend(header_string("perf"));
+        data.extend(header_string("record"));
+        assert_eq!(
+            parse_string_list(&data, false),
+            Some(vec!["perf".to_string(), "record".to_string()])
+        );
+        assert_eq!(parse_string_list(&data[..10], false), None);
+        assert_eq!(parse_string_list(&[], false), None);
+    }
+
+    #[test]
+    fn test_parse_nr_cpus_big_endian() {
+        let mut data = 8u32.to_be_bytes().to_vec();
+        data.extend(4u32.to_be_bytes());
+        assert_eq!(parse_nr_cpus(&data, true), Some((8, 4)));
+    }
+
+    #[test]
+    fn test_format_header() {
+        assert_eq!(format_header("Hostname", &[], false), "(empty)");
+        assert_eq!(
+            format_header("Hostname", &header_string("build-box"), false),
+            "build-box"
+        );
+
+        let mut list = 2u32.to_le_bytes().to_vec();
+        list.extend(header_string("perf"));
+        list.extend(header_string("record"));
+        assert_eq!(format_header("Cmdline", &list, false), "perf | record");
+
+        let mut nr_cpus = 8u32.to_le_bytes().to_vec();
+        nr_cpus.extend(4u32.to_le_bytes());
+        assert_eq!(
+            format_header("NrCpus", &nr_cpus, false),
+            "4 online, 8 available"
+        );
+        assert_eq!(
+            format_header("TotalMem", &16384u64.to_le_bytes(), false),
+            "16384 kB"
+        );
+
+        let binary: Vec<u8> = (0..100).collect();
+        let dump = format_header("BuildId", &binary, false);
+        assert!(dump.starts_with("(binary, 100 bytes) 00 01 02"));
+        assert!(dump.ends_with("3e 3f ..."));
+    }
+
+    /// Encode a little-endian `build_id_event` with a 4-byte padded file name.
+    fn build_id_record(misc: u16, build_id: &[u8], file_name: &str) -> Vec<u8> {
+        let name_len = (file_name.len() + 1).next_multiple_of(4);
+        let mut record = Vec::new();
+        record.extend_from_slice(&0u32.to_le_bytes());
+        record.extend_from_slice(&misc.to_le_bytes());
+        record.extend_from_slice(&((BUILD_ID_RECORD_HEADER + name_len) as u16).to_le_bytes());
+        record.extend_from_slice(&(-1i32).to_le_bytes());
+        let mut field = [0u8; 24];
+        field[..build_id.len()].copy_from_slice(build_id);
+        field[BUILD_ID_DEFAULT_SIZE] = build_id.len() as u8;
+        record.extend_from_slice(&field);
+        record.extend_from_slice(file_name.as_bytes());
+        record.resize(BUILD_ID_RECORD_HEADER + name_len, 0);
+        record
+    }
+
+    #[test]
+    fn test_parse_build_ids() {
+        let mut data = build_id_record(0, &[0xab; 20], "/usr/bin/bash");
+        data.extend(build_id_record(
+            PERF_RECORD_MISC_BUILD_ID_SIZE,
+            &[0x01, 0x02, 0x03, 0x04],
+            "[kernel.kallsyms]",
+        ));
+        let build_ids = parse_build_ids(&data, false);
+        assert_eq!(
+            build_ids,
+            vec![
+                ("/usr/bin/bash".to_string(), "ab".repeat(20)),
+                ("[kernel.kallsyms]".to_string(), "01020304".to_string()),
+            ]
+        );
+
+        assert!(parse_build_ids(&[], false).is_empty());
+        // A truncated record is dropped rather than misread
+        assert_eq!(parse_build_ids(&data[..data.len() - 4], false).len(), 1);
+    }
+
+    /// Encode a little-endian MMAP2 record for `path`.
+    fn mmap2_record(pid: u32, start: u64, len: u64, prot: u32, path: &str) -> Vec<u8> {
+        let mut record = vec![0u8; PERF_EVENT_HEADER_SIZE];
+        record.extend_from_slice(&pid.to_le_bytes());
+        record.extend_from_slice(&pid.to_le_bytes());
+        record.extend_from_slice(&start.to_le_bytes());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&0x1000u64.to_le_bytes());
+        record.extend_from_slice(&[0u8; 24]);
+        record.extend_from_slice(&prot.to_le_bytes());
+        record.extend_from_slice(&0u32.to_le_bytes());
+        record.extend_from_slice(path.as_bytes());
+        record.extend_from_slice(&[0u8; 8]);
+        record
+    }
+
+    #[test]
+    fn test_parse_mmap_record() {
+        let record = mmap2_record(
+            42,
+            0x7f00_0000_0000,
+            0x2000,
+            PROT_EXEC | 1,
+            "/usr/lib/libc.so.6",
+        );
+        let (pid, map) = parse_mmap_record(PerfEventHeaderType::Mmap2, 0, &record, false).unwrap();
+        assert_eq!(pid, 42);
+        assert_eq!(map.start, 0x7f00_0000_0000);
+        assert_eq!(map.end, 0x7f00_0000_2000);
+        assert_eq!(map.file_offset, 0x1000);
+        assert_eq!(map.path, "/usr/lib/libc.so.6");
+
+        let data = mmap2_record(42, 0x1000, 0x1000, 1, "/usr/lib/libc.so.6");
+        assert!(parse_mmap_record(PerfEventHeaderType::Mmap2, 0, &data, false).is_none());
+        assert!(parse_mmap_record(PerfEventHeaderType::Comm, 0, &record, false).is_none());
+        assert!(parse_mmap_record(PerfEventHeaderType::Mmap2, 0, &record[..40], false).is_none());
+    }
+
+    #[test]
+    fn test_parse_live_mmap_record() {
+        let mut record = mmap2_record(42, 0x7f00_0000_0000, 0x2000, PROT_EXEC, "/usr/bin/app");
+        record[..4].copy_from_slice(&PERF_RECORD_MMAP2.to_ne_bytes());
+        let (pid, map) = parse_live_mmap_record(&record).unwrap();
+        assert_eq!(pid, 42);
+        assert_eq!(map.path, "/usr/bin/app");
+
+        assert!(parse_live_mmap_record(&record[..40]).is_none());
+        // COMM records and the like carry no mapping
+        record[..4].copy_from_slice(&3u32.to_ne_bytes());
+        assert!(parse_live_mmap_record(&record).is_none());
+    }
+
+    #[test]
+    fn test_loaded_modules() {
+        let map = |start, path: &str| MemoryMap {
+            start,
+            end: start + 0x1000,
+            file_offset: 0,
+            path: path.to_string(),
+        };
+        let mappings = vec![
+            (1, map(0x5000, "/bin/b")),
+            (1, map(0x1000, "/bin/a")),
+            (2, map(0x5000, "/bin/b")),
+            (2, map(0x3000, "/bin/b")),
+        ];
+        let modules = loaded_modules(&mappings);
+        assert_eq!(
+            modules.keys().copied().collect::<Vec<_>>(),
+            ["/bin/a", "/bin/b"]
+        );
+        assert_eq!(modules["/bin/b"], [(0x3000, 0x4000), (0x5000, 0x6000)]);
+    }
+
+    #[test]
+    fn test_stack_type_from_sample_type() {
+        assert_eq!(StackType::from_sample_type(0), None);
+        assert_eq!(
+            StackType::from_sample_type(PERF_SAMPLE_CALLCHAIN),
+            Some(StackType::FramePointer)
+        );
+        assert_eq!(
+            StackType::from_sample_type(PERF_SAMPLE_CALLCHAIN | PERF_SAMPLE_STACK_USER),
+            Some(StackType::Dwarf)
+        );
+    }
+
+    #[test]
+    fn test_sched_switch_from_fields() {
+        let fields: HashMap<&str, &str> = [
+            ("prev_comm", "worker"),
+            ("prev_pid", "42"),
+            ("prev_state", "1"),
+            ("next_comm", "swapper/0"),
+            ("next_pid", "0"),
+        ]
+        .into_iter()
+        .collect();
+        let switch = SchedSwitch::from_fields(|name| fields.get(name).map(|v| v.to_string()));
+        assert_eq!(
+            switch,
+            Some(SchedSwitch {
+                prev_comm: "worker".to_string(),
+                prev_pid: 42,
+                prev_state: 1,
+                next_comm: "swapper/0".to_string(),
+                next_pid: 0,
+                cpu: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_sched_wakeup_from_fields_missing_field() {
+        let fields: HashMap<&str, &str> = [("comm", "worker"), ("pid", "42")].into_iter().collect();
+        let wakeup = SchedWakeup::from_fields(|name| fields.get(name).map(|v| v.to_string()));
+        assert_eq!(wakeup, None);
+    }
+
+    #[test]
+    fn test_top_events() {
+        let by_name: HashMap<String, u64> = [
+            ("sched:sched_switch", 50),
+            ("irq:irq_handler_entry", 7),
+            ("sched:sched_wakeup", 20),
+            ("irq:irq_handler_exit", 7),
+            ("timer:hrtimer_start", 3),
+        ]
+        .into_iter()
+        .map(|(name, count)| (name.to_string(), count))
+        .collect();
+
+        assert_eq!(
+            top_events(&by_name, 4),
+            vec![
+                ("sched:sched_switch", 50),
+                ("sched:sched_wakeup", 20),
+                ("irq:irq_handler_entry", 7),
+                ("irq:irq_handler_exit", 7),
+            ]
+        );
+        assert_eq!(top_events(&by_name, 10).len(), 5);
+        assert!(top_events(&by_name, 0).is_empty());
+    }
+
+    #[test]
+    fn test_to_chrome_trace() {
+        let sample = |name: &str, time: Option<u64>, tid: u32| SampleRecord {
+            name: name.to_string(),
+            time,
+            cpu: Some(0),
+            pid: Some(100),
+            tid: Some(tid),
+            fields: serde_json::Map::new(),
+        };
+        let samples = vec![
+            sample("sched:sched_switch", Some(5_000_500), 101),
+            sample("irq:irq_handler_entry", Some(2_000_000), 102),
+            sample("sched:sched_wakeup", None, 103),
+            sample("custom_event", Some(2_000_000), 104),
+        ];
+
+        let events = to_chrome_trace(&samples);
+        let ts: Vec<f64> = events.iter().map(|event| event.ts).collect();
+        assert_eq!(ts, [0.0, 0.0, 3000.5]);
+        assert_eq!(events[0].cat, "irq");
+        assert_eq!(events[1].cat, "tracepoint");
+        assert_eq!(events[2].name, "sched:sched_switch");
+        assert_eq!((events[2].pid, events[2].tid), (100, 101));
+
+        let json = serde_json::to_value(&events[2]).unwrap();
+        assert_eq!(json["ph"], "i");
+        assert_eq!(json["ts"], 3000.5);
+        assert!(to_chrome_trace(&[]).is_empty());
+    }
+
+    fn decoded_sample(name: &str, fields: &[(&str, &str)], common_fields: usize) -> DecodedEvent {
+        DecodedEvent {
+            ty: PerfEventHeaderType::Sample,
+            size: 64,
+            name: name.to_string(),
+            time: Some(1000),
+            cpu: Some(2),
+            pid: Some(10),
+            tid: Some(11),
+            fields: fields
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            common_fields,
+            mapping: None,
+        }
+    }
+
+    #[test]
+    fn test_decoded_event_fields() {
+        let event = decoded_sample(
+            "irq:irq_handler_entry",
+            &[("common_pid", "10"), ("irq", "24"), ("name", "eth0")],
+            1,
+        );
+        assert!(event.is_sample());
+        assert_eq!(event.field("irq"), Some("24"));
+        assert_eq!(event.field("common_pid"), Some("10"));
+        assert_eq!(event.field("missing"), None);
+        assert_eq!(event.payload_fields().len(), 2);
+        assert_eq!(event.payload_fields()[0].0, "irq");
+        assert_eq!(numeric_field(&event, "irq").unwrap(), Some(24.0));
+        assert!(numeric_field(&event, "name").is_err());
+    }
+
+    #[test]
+    fn test_print_sample_preview() {
+        let options = TracepointOptions::default();
+        let switch = decoded_sample(
+            "sched:sched_switch",
+            &[
+                ("prev_comm", "bash"),
+                ("prev_pid", "100"),
+                ("prev_prio", "120"),
+                ("prev_state", "1"),
+                ("next_comm", "swapper/0"),
+                ("next_pid", "0"),
+                ("next_prio", "120"),
+            ],
+            0,
+        );
+        let mut out = Vec::new();
+        assert_eq!(
+            print_sample_preview(&switch, &options, &mut out).unwrap(),
+            0
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "    bash[100] (state 1) -> swapper/0[0]\n"
+        );
+
+        let fields = [
+            ("common_pid", "1"),
+            ("a", "1"),
+            ("b", "2"),
+            ("c", "3"),
+            ("d", "4"),
+        ];
+        let other = decoded_sample("test:event", &fields, 1);
+        let mut out = Vec::new();
+        assert_eq!(print_sample_preview(&other, &options, &mut out).unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "    a: 1\n    b: 2\n    c: 3\n"
+        );
+    }
+
+    #[test]
+    fn test_time_window_relative() {
+        let window = TimeWindow {
+            since: Some(100),
+            until: Some(200),
+            absolute: false,
+        };
+        assert!(window.is_set());
+        // Both ends are inclusive, measured from the first sample
+        assert_eq!(window.position(1_099, 1_000), Ordering::Less);
+        assert_eq!(window.position(1_100, 1_000), Ordering::Equal);
+        assert_eq!(window.position(1_200, 1_000), Ordering::Equal);
+        assert_eq!(window.position(1_201, 1_000), Ordering::Greater);
+
+        let until_only = TimeWindow {
+            until: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(until_only.position(1_000, 1_000), Ordering::Equal);
+        assert_eq!(until_only.position(1_001, 1_000), Ordering::Greater);
+        assert!(!TimeWindow::default().is_set());
+    }
+
+    #[test]
+    fn test_time_window_absolute() {
+        let window = TimeWindow {
+            since: Some(1_100),
+            until: None,
+            absolute: true,
+        };
+        assert_eq!(window.position(1_099, 0), Ordering::Less);
+        assert_eq!(window.position(1_100, 0), Ordering::Equal);
+        assert_eq!(window.position(u64::MAX, 1_000), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_print_field_summary() {
+        let mut summary = ValueSummary::default();
+        for value in [1.0, 2.0, 6.0] {
+            summary.record(value);
+        }
+        let mut out = Vec::new();
+        print_field_summary("len", &summary, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("Field Statistics: len"));
+        assert!(report.contains("  Count:                      3\n"));
+        assert!(report.contains("  Mean:                        3.00\n"));
+
+        let mut out = Vec::new();
+        print_field_summary("len", &ValueSummary::default(), &mut out).unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("No sample has a field named \"len\""));
+    }
+
+    #[test]
+    fn test_parse_numeric() {
+        assert_eq!(parse_numeric("4096"), Some(4096.0));
+        assert_eq!(parse_numeric(" -12.5 "), Some(-12.5));
+        assert_eq!(parse_numeric("0x1000"), Some(4096.0));
+        assert_eq!(parse_numeric("sda"), None);
+        assert_eq!(parse_numeric(""), None);
+    }
+
+    #[test]
+    fn test_matches_event_pattern() {
+        assert!(matches_event_pattern(
+            "sched:sched_switch",
+            "sched:sched_switch"
+        ));
+        assert!(matches_event_pattern("sched:sched_switch", "sched:*"));
+        assert!(matches_event_pattern("sched:sched_switch", "*"));
+        assert!(!matches_event_pattern(
+            "sched:sched_switch",
+            "sched:sched_wakeup"
+        ));
+        assert!(!matches_event_pattern("irq:irq_handler_entry", "sched:*"));
+        assert!(!matches_event_pattern(
+            "sched:sched_switch_extra",
+            "sched:sched_switch"
+        ));
     }
 
     #[test]
     fn test_read_nonexistent_file() {
-        let result = read_tracepoint_file("/nonexistent/file.data");
+        let result = read_tracepoint_file(
+            "/nonexistent/file.data",
+            &TracepointOptions::default(),
+            &mut Vec::new(),
+        );
         assert!(result.is_err());
         let err = result.unwrap_err();
         assert!(err.to_string().contains("File not found"));
     }
+
+    #[test]
+    fn test_read_files_none_readable() {
+        let paths = vec![
+            "/nonexistent/a.data".to_string(),
+            "/nonexistent/b.data".to_string(),
+        ];
+        let err = read_tracepoint_files(&paths, &TracepointOptions::default(), &mut Vec::new())
+            .unwrap_err();
+        assert!(err.to_string().contains("None of the 2 files"));
+    }
+
+    #[test]
+    fn test_merge_stats() {
+        let mut a = TracepointStats {
+            total_events: 10,
+            sample_events: 8,
+            non_sample_events: 2,
+            non_sample_by_type: vec![("Mmap".to_string(), 2)],
+            by_name: HashMap::from([("sched:sched_switch".to_string(), 8)]),
+            ..Default::default()
+        };
+        let b = TracepointStats {
+            total_events: 5,
+            sample_events: 2,
+            non_sample_events: 3,
+            non_sample_by_type: vec![("Comm".to_string(), 1), ("Mmap".to_string(), 2)],
+            by_name: HashMap::from([
+                ("sched:sched_switch".to_string(), 1),
+                ("irq:irq_handler_entry".to_string(), 1),
+            ]),
+            cross_arch: true,
+            ..Default::default()
+        };
+        a.merge(b);
+        assert_eq!(a.total_events, 15);
+        assert_eq!(a.sample_events, 10);
+        assert_eq!(a.non_sample_events, 5);
+        assert!(a.cross_arch);
+        assert_eq!(a.by_name["sched:sched_switch"], 9);
+        assert_eq!(a.by_name["irq:irq_handler_entry"], 1);
+        assert_eq!(
+            a.non_sample_by_type,
+            vec![("Mmap".to_string(), 4), ("Comm".to_string(), 1)]
+        );
+    }
 }